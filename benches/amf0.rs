@@ -0,0 +1,150 @@
+// Compares the owned Amf0Reader against the zero-copy Amf0BorrowedReader, on its own and
+// in the @setDataFrame parsing every publish goes through.
+
+// Path: benches/amf0.rs
+use bytes::BytesMut;
+use bytesio::{bytes_reader::BytesReader, bytes_writer::BytesWriter};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use indexmap::IndexMap;
+use rustic_rtmp::server::connection::message::amf0::{
+    amf0_borrowed::Amf0BorrowedReader, amf0_reader::Amf0Reader, amf0_writer::Amf0Writer,
+    define::Amf0ValueType,
+};
+use rustic_rtmp::server::connection::message::message::{SetDataFrame, SetDataFrameData};
+
+fn connect_payload() -> BytesMut {
+    let mut command_obj = IndexMap::new();
+    for (key, value) in [
+        ("app", "live"),
+        ("type", "nonprivate"),
+        ("flashVer", "FMLE/3.0 (compatible; FMSc/1.0)"),
+        ("swfUrl", "rtmp://127.0.0.1:1935/live"),
+        ("tcUrl", "rtmp://127.0.0.1:1935/live"),
+    ] {
+        command_obj.insert(key.to_owned(), Amf0ValueType::UTF8String(value.to_owned()));
+    }
+
+    let mut writer = Amf0Writer::new(BytesWriter::new());
+    writer
+        .write_anys(&vec![
+            Amf0ValueType::UTF8String("connect".to_owned()),
+            Amf0ValueType::Number(1.0),
+            Amf0ValueType::Object(command_obj),
+        ])
+        .unwrap();
+    writer.extract_current_bytes()
+}
+
+fn metadata_payload() -> BytesMut {
+    let mut metadata = IndexMap::new();
+    for (key, value) in [
+        ("duration", 0.0),
+        ("fileSize", 0.0),
+        ("width", 1920.0),
+        ("height", 1080.0),
+        ("videocodecid", 7.0),
+        ("videodatarate", 6000.0),
+        ("framerate", 60.0),
+        ("audiocodecid", 10.0),
+        ("audiodatarate", 160.0),
+        ("audiosamplerate", 48000.0),
+        ("audiosamplesize", 16.0),
+        ("audiochannels", 2.0),
+    ] {
+        metadata.insert(key.to_owned(), Amf0ValueType::Number(value));
+    }
+    metadata.insert("stereo".to_owned(), Amf0ValueType::Boolean(true));
+    metadata.insert(
+        "encoder".to_owned(),
+        Amf0ValueType::UTF8String("obs-output module (libobs version 29.1.3)".to_owned()),
+    );
+
+    let mut writer = Amf0Writer::new(BytesWriter::new());
+    writer
+        .write_anys(&vec![
+            Amf0ValueType::UTF8String("@setDataFrame".to_owned()),
+            Amf0ValueType::UTF8String("onMetaData".to_owned()),
+            Amf0ValueType::Object(metadata),
+        ])
+        .unwrap();
+    writer.extract_current_bytes()
+}
+
+fn bench_payload(c: &mut Criterion, name: &str, data: &[u8]) {
+    let mut group = c.benchmark_group(name);
+
+    group.bench_function("owned", |b| {
+        b.iter(|| {
+            let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(black_box(data))));
+            reader.read_all().unwrap()
+        })
+    });
+
+    group.bench_function("borrowed", |b| {
+        b.iter(|| {
+            let mut reader = Amf0BorrowedReader::new(black_box(data));
+            reader.read_all().unwrap().len()
+        })
+    });
+
+    group.bench_function("borrowed_to_owned", |b| {
+        b.iter(|| {
+            let mut reader = Amf0BorrowedReader::new(black_box(data));
+            reader
+                .read_all()
+                .unwrap()
+                .iter()
+                .map(|v| v.to_owned_value().unwrap())
+                .collect::<Vec<_>>()
+        })
+    });
+
+    group.bench_function("command_name_owned", |b| {
+        b.iter(|| {
+            let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(black_box(data))));
+            reader.read_any().unwrap()
+        })
+    });
+
+    group.bench_function("command_name_borrowed", |b| {
+        b.iter(|| {
+            let mut reader = Amf0BorrowedReader::new(black_box(data));
+            reader.read_any().unwrap().as_str().map(str::len)
+        })
+    });
+
+    group.finish();
+}
+
+fn bench_set_data_frame(c: &mut Criterion, data: &[u8]) {
+    let mut group = c.benchmark_group("set_data_frame");
+
+    // What SetDataFrame::parse used to do: decode everything, then pick the properties
+    group.bench_function("owned", |b| {
+        b.iter(|| {
+            let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(black_box(data))));
+            let values = reader.read_all().unwrap();
+            match &values[2] {
+                Amf0ValueType::Object(properties) => {
+                    SetDataFrameData::parse(properties.clone()).unwrap()
+                }
+                _ => unreachable!(),
+            }
+        })
+    });
+
+    group.bench_function("borrowed", |b| {
+        b.iter(|| SetDataFrame::parse(black_box(data)).unwrap())
+    });
+
+    group.finish();
+}
+
+fn amf0_benchmark(c: &mut Criterion) {
+    bench_payload(c, "amf0_connect", &connect_payload());
+    bench_payload(c, "amf0_metadata", &metadata_payload());
+    bench_set_data_frame(c, &metadata_payload());
+}
+
+criterion_group!(benches, amf0_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,937 @@
+// This file is the client side of RTMP, for services that push streams to or pull streams
+// from an RTMP server. RtmpClient does the handshake and connect with whatever connect object
+// it is given, then either publish: releaseStream and FCPublish, which servers need not
+// answer, createStream for the message stream and publish on it, or play, which turns the
+// client into a stream of what the server sends. Metadata and frames go out as they are
+// handed over, the client does no pacing of its own. rtmps:// runs all of it inside TLS.
+
+// Path: src/client.rs
+use crate::media::flv::TagType;
+use crate::protocol::{ChunkReader, ChunkWriter, RawMessage};
+use crate::server::connection::define::msg_type_id;
+use crate::server::connection::message::amf0::amf0_writer::Amf0Writer;
+use crate::server::connection::message::amf0::define::Amf0ValueType;
+use crate::server::connection::message::command::{
+    command_name, decode, DeleteStream, FCUnpublish,
+};
+use crate::server::connection::message::message::{
+    ConnectMessage, ConnectObject, CreateStream, FCPublish, PlayMessage, Publish, ReleaseStream,
+    SetDataFrame, SetDataFrameData,
+};
+use crate::server::connection::message::status::StatusCode;
+use crate::stream::MediaFrame;
+use crate::tls::{self, Transport};
+use bytesio::bytes_writer::BytesWriter;
+use futures_core::Stream;
+use indexmap::IndexMap;
+use log::{info, warn};
+use rand::Rng;
+use rustls_pki_types::ServerName;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::TlsConnector;
+
+const RTMP_VERSION: u8 = 3;
+const HANDSHAKE_SIZE: usize = 1536;
+// Announced right after the handshake, so frames go out in few chunks
+const CHUNK_SIZE: u32 = 4096;
+
+const CONTROL_CHUNK_STREAM: u32 = 2;
+const COMMAND_CHUNK_STREAM: u32 = 3;
+const AUDIO_CHUNK_STREAM: u32 = 4;
+const DATA_CHUNK_STREAM: u32 = 5;
+const VIDEO_CHUNK_STREAM: u32 = 6;
+
+// User control event types
+const STREAM_BEGIN: u16 = 0;
+const STREAM_EOF: u16 = 1;
+const SET_BUFFER_LENGTH: u16 = 3;
+const PING_REQUEST: u16 = 6;
+const PING_RESPONSE: u16 = 7;
+// What players tell the server they buffer, in milliseconds
+const BUFFER_LENGTH: u32 = 1000;
+
+/// The level, code and description of an onStatus or _error info object.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Status {
+    pub level: String,
+    pub code: String,
+    pub description: String,
+}
+
+impl Status {
+    fn from_info(info: Option<&Amf0ValueType>) -> Status {
+        let Some(Amf0ValueType::Object(properties)) = info else {
+            return Status::default();
+        };
+        let field = |key: &str| match properties.get(key) {
+            Some(Amf0ValueType::UTF8String(value)) => value.clone(),
+            _ => String::new(),
+        };
+        Status {
+            level: field("level"),
+            code: field("code"),
+            description: field("description"),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.level == "error"
+    }
+}
+
+/// What a played stream delivers, in the order the server sent it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayEvent {
+    // The onMetaData properties
+    Metadata(IndexMap<String, Amf0ValueType>),
+    // Audio and video messages, the bodies of FLV tags
+    Frame(MediaFrame),
+    Status(Status),
+    // User control events for the played stream
+    StreamBegin,
+    StreamEof,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    // The server sent something that is not RTMP as we know it
+    Protocol(String),
+    // The server turned a command down
+    Rejected(Status),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => write!(f, "io error: {}", err),
+            ClientError::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            ClientError::Rejected(status) => {
+                write!(f, "rejected: {} {}", status.code, status.description)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+fn protocol_error(err: impl fmt::Display) -> ClientError {
+    ClientError::Protocol(err.to_string())
+}
+
+fn read_u32(data: &[u8]) -> Result<u32, ClientError> {
+    let bytes = data
+        .get(..4)
+        .ok_or_else(|| protocol_error("Control message too short"))?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// The parts of an rtmp:// or rtmps:// URL: the server address, the app and the stream name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtmpUrl {
+    // rtmps://, RTMP inside TLS
+    pub tls: bool,
+    // host:port, the port defaults to 1935, or 443 with TLS
+    pub address: String,
+    pub app: String,
+    // With any query string, servers take tokens from it
+    pub stream: String,
+}
+
+impl RtmpUrl {
+    // The stream is the last path segment, the app everything before it
+    pub fn parse(url: &str) -> Option<RtmpUrl> {
+        let (tls, rest) = match url.strip_prefix("rtmps://") {
+            Some(rest) => (true, rest),
+            None => (false, url.strip_prefix("rtmp://")?),
+        };
+        let (host, path) = rest.split_once('/')?;
+        let (app, stream) = path.rsplit_once('/')?;
+        if host.is_empty() || app.is_empty() || stream.is_empty() {
+            return None;
+        }
+        let address = match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => host.to_owned(),
+            _ if tls => format!("{}:443", host),
+            _ => format!("{}:1935", host),
+        };
+        Some(RtmpUrl {
+            tls,
+            address,
+            app: app.to_owned(),
+            stream: stream.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for RtmpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = if self.tls { "rtmps" } else { "rtmp" };
+        write!(
+            f,
+            "{}://{}/{}/{}",
+            scheme, self.address, self.app, self.stream
+        )
+    }
+}
+
+/// A connection to an RTMP server, for publishing or playing a stream.
+pub struct RtmpClient {
+    stream: Box<dyn Transport>,
+    chunks: ChunkReader,
+    writer: ChunkWriter,
+    transaction_id: usize,
+    // Set by the server, 0 until then, and the bytes received and acknowledged so far
+    window_ack_size: u32,
+    received: u32,
+    acknowledged: u32,
+    // The message stream from createStream, 0 until there is one
+    stream_id: u32,
+    stream_key: String,
+}
+
+impl RtmpClient {
+    /// Connects to the server and to the app named in the connect object. A missing tcUrl is
+    /// filled in from the address.
+    pub async fn connect(
+        addr: impl ToSocketAddrs + fmt::Display,
+        mut connect_object: ConnectObject,
+    ) -> Result<RtmpClient, ClientError> {
+        if connect_object.tc_url.is_empty() {
+            connect_object.tc_url = format!("rtmp://{}/{}", addr, connect_object.app);
+        }
+        let stream = TcpStream::connect(addr).await?;
+        RtmpClient::start(Box::new(stream), connect_object).await
+    }
+
+    /// Like connect, but inside TLS. The server is checked for the host name in the address.
+    pub async fn connect_tls(
+        addr: impl ToSocketAddrs + fmt::Display,
+        connector: &TlsConnector,
+        mut connect_object: ConnectObject,
+    ) -> Result<RtmpClient, ClientError> {
+        if connect_object.tc_url.is_empty() {
+            connect_object.tc_url = format!("rtmps://{}/{}", addr, connect_object.app);
+        }
+        let address = addr.to_string();
+        let host = address
+            .rsplit_once(':')
+            .map_or(address.as_str(), |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_owned())
+            .map_err(|_| ClientError::Protocol(format!("Invalid server name {}", host)))?;
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(server_name, stream).await?;
+        RtmpClient::start(Box::new(stream), connect_object).await
+    }
+
+    /// Connects to the server of the URL, with TLS for rtmps:// checked against the webpki
+    /// roots.
+    pub async fn connect_url(
+        url: &RtmpUrl,
+        connect_object: ConnectObject,
+    ) -> Result<RtmpClient, ClientError> {
+        match url.tls {
+            true => {
+                RtmpClient::connect_tls(url.address.as_str(), &tls::connector(), connect_object)
+                    .await
+            }
+            false => RtmpClient::connect(url.address.as_str(), connect_object).await,
+        }
+    }
+
+    async fn start(
+        stream: Box<dyn Transport>,
+        connect_object: ConnectObject,
+    ) -> Result<RtmpClient, ClientError> {
+        let tc_url = connect_object.tc_url.clone();
+        let mut client = RtmpClient {
+            stream,
+            chunks: ChunkReader::new(),
+            writer: ChunkWriter::new(),
+            transaction_id: 0,
+            window_ack_size: 0,
+            received: 0,
+            acknowledged: 0,
+            stream_id: 0,
+            stream_key: String::new(),
+        };
+        client.handshake().await?;
+        client.send_chunk_size().await?;
+
+        let transaction_id = client.next_transaction_id();
+        let connect = ConnectMessage::new(transaction_id, connect_object)
+            .serialize()
+            .map_err(protocol_error)?;
+        client.send_command(&connect, 0).await?;
+        client.wait_for_result(transaction_id).await?;
+        info!("Connected to {}", tc_url);
+        Ok(client)
+    }
+
+    // The simple handshake, without the digests of the Flash Player variant
+    async fn handshake(&mut self) -> Result<(), ClientError> {
+        // C0, then C1: time and zero, both 0, and random bytes
+        let mut c0_c1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+        c0_c1[0] = RTMP_VERSION;
+        rand::thread_rng().fill(&mut c0_c1[9..]);
+        self.stream.write_all(&c0_c1).await?;
+
+        let mut s0_s1_s2 = vec![0u8; 1 + 2 * HANDSHAKE_SIZE];
+        self.stream.read_exact(&mut s0_s1_s2).await?;
+        if s0_s1_s2[0] != RTMP_VERSION {
+            let reason = format!("Unsupported RTMP version: {}", s0_s1_s2[0]);
+            return Err(ClientError::Protocol(reason));
+        }
+
+        // C2 echoes S1
+        self.stream
+            .write_all(&s0_s1_s2[1..1 + HANDSHAKE_SIZE])
+            .await?;
+        Ok(())
+    }
+
+    fn next_transaction_id(&mut self) -> usize {
+        self.transaction_id += 1;
+        self.transaction_id
+    }
+
+    /// The message stream commands and media of a stream are sent on, 0 before createStream.
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    pub async fn create_stream(&mut self) -> Result<u32, ClientError> {
+        let transaction_id = self.next_transaction_id();
+        let create_stream = CreateStream::new(transaction_id)
+            .serialize()
+            .map_err(protocol_error)?;
+        self.send_command(&create_stream, 0).await?;
+        let values = self.wait_for_result(transaction_id).await?;
+        match values.get(3) {
+            Some(Amf0ValueType::Number(stream_id)) => {
+                self.stream_id = *stream_id as u32;
+                Ok(self.stream_id)
+            }
+            _ => Err(protocol_error("Expected stream id")),
+        }
+    }
+
+    /// Publishes the stream as live, once this returns the server takes metadata and frames.
+    pub async fn publish(&mut self, stream_key: &str) -> Result<(), ClientError> {
+        let release = ReleaseStream::new(
+            command_name::RELEASE_STREAM.to_owned(),
+            self.next_transaction_id(),
+            Amf0ValueType::Null,
+            stream_key.to_owned(),
+        );
+        self.send_command(&release.serialize().map_err(protocol_error)?, 0)
+            .await?;
+        let fc_publish = FCPublish::new(
+            command_name::FC_PUBLISH.to_owned(),
+            self.next_transaction_id(),
+            Amf0ValueType::Null,
+            stream_key.to_owned(),
+        );
+        self.send_command(&fc_publish.serialize().map_err(protocol_error)?, 0)
+            .await?;
+
+        let stream_id = self.create_stream().await?;
+        let publish = Publish::new(
+            command_name::PUBLISH.to_owned(),
+            self.next_transaction_id(),
+            Amf0ValueType::Null,
+            stream_key.to_owned(),
+            "live".to_owned(),
+        );
+        self.send_command(&publish.serialize().map_err(protocol_error)?, stream_id)
+            .await?;
+        self.wait_for_status(StatusCode::PublishStart).await?;
+        self.stream_key = stream_key.to_owned();
+        info!("Publishing {}", stream_key);
+        Ok(())
+    }
+
+    /// Sends onMetaData, wrapped in @setDataFrame like encoders do.
+    pub async fn send_metadata(
+        &mut self,
+        properties: IndexMap<String, Amf0ValueType>,
+    ) -> Result<(), ClientError> {
+        let data = SetDataFrameData::parse(properties.clone()).map_err(protocol_error)?;
+        let mut set_data_frame =
+            SetDataFrame::new("@setDataFrame".to_owned(), "onMetaData".to_owned(), data);
+        set_data_frame.properties = properties;
+        let payload = set_data_frame.serialize().map_err(protocol_error)?;
+        self.send_message(
+            DATA_CHUNK_STREAM,
+            msg_type_id::DATA_AMF0,
+            0,
+            payload.to_vec(),
+        )
+        .await
+    }
+
+    /// Sends an audio message, the body of an FLV audio tag.
+    pub async fn send_audio(&mut self, timestamp: u32, data: &[u8]) -> Result<(), ClientError> {
+        self.send_message(
+            AUDIO_CHUNK_STREAM,
+            msg_type_id::AUDIO,
+            timestamp,
+            data.to_vec(),
+        )
+        .await
+    }
+
+    /// Sends a video message, the body of an FLV video tag.
+    pub async fn send_video(&mut self, timestamp: u32, data: &[u8]) -> Result<(), ClientError> {
+        self.send_message(
+            VIDEO_CHUNK_STREAM,
+            msg_type_id::VIDEO,
+            timestamp,
+            data.to_vec(),
+        )
+        .await
+    }
+
+    /// Sends a frame as a subscriber of a stream gets it, script frames as metadata.
+    pub async fn send_frame(&mut self, frame: &MediaFrame) -> Result<(), ClientError> {
+        match frame.tag_type {
+            TagType::Audio => self.send_audio(frame.timestamp, &frame.data).await,
+            TagType::Video => self.send_video(frame.timestamp, &frame.data).await,
+            TagType::Script => {
+                // The frame is onMetaData as players get it, the server expects it wrapped
+                let mut writer = Amf0Writer::new(BytesWriter::new());
+                writer
+                    .write_string(&"@setDataFrame".to_owned())
+                    .map_err(protocol_error)?;
+                let mut payload = writer.extract_current_bytes().to_vec();
+                payload.extend_from_slice(&frame.data);
+                self.send_message(DATA_CHUNK_STREAM, msg_type_id::DATA_AMF0, 0, payload)
+                    .await
+            }
+        }
+    }
+
+    /// Plays the stream, see PlayMessage for start and duration. Returns once the server
+    /// started playing, with everything that follows as a stream.
+    pub async fn play(
+        mut self,
+        stream_name: &str,
+        start: f64,
+        duration: f64,
+    ) -> Result<PlayStream, ClientError> {
+        let stream_id = self.create_stream().await?;
+        let mut buffer_length = SET_BUFFER_LENGTH.to_be_bytes().to_vec();
+        buffer_length.extend_from_slice(&stream_id.to_be_bytes());
+        buffer_length.extend_from_slice(&BUFFER_LENGTH.to_be_bytes());
+        self.send_control(msg_type_id::USER_CONTROL_EVENT, buffer_length)
+            .await?;
+
+        let mut play = PlayMessage::new(stream_name.to_owned());
+        play.transaction_id = self.next_transaction_id();
+        play.start = start;
+        play.duration = duration;
+        self.send_command(&play.serialize().map_err(protocol_error)?, stream_id)
+            .await?;
+        self.wait_for_status(StatusCode::PlayStart).await?;
+        info!("Playing {}", stream_name);
+        Ok(PlayStream {
+            next: Some(Box::pin(self.into_event())),
+        })
+    }
+
+    // Hands itself back with the event, so the stream can ask for the next one
+    async fn into_event(mut self) -> (RtmpClient, Result<Option<PlayEvent>, ClientError>) {
+        let event = self.next_event().await;
+        (self, event)
+    }
+
+    async fn next_event(&mut self) -> Result<Option<PlayEvent>, ClientError> {
+        loop {
+            let message = match self.read_message().await {
+                Ok(message) => message,
+                // The server is done with us
+                Err(ClientError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                Err(err) => return Err(err),
+            };
+            let event = match message.type_id {
+                msg_type_id::AUDIO => PlayEvent::Frame(MediaFrame::new(
+                    TagType::Audio,
+                    message.timestamp,
+                    message.payload,
+                )),
+                msg_type_id::VIDEO => PlayEvent::Frame(MediaFrame::new(
+                    TagType::Video,
+                    message.timestamp,
+                    message.payload,
+                )),
+                msg_type_id::DATA_AMF0 => {
+                    let values = decode(&message.payload).map_err(protocol_error)?;
+                    match metadata(&values) {
+                        Some(properties) => PlayEvent::Metadata(properties),
+                        None => continue,
+                    }
+                }
+                msg_type_id::COMMAND_AMF0 => {
+                    let values = decode(&message.payload).map_err(protocol_error)?;
+                    match values.first() {
+                        Some(Amf0ValueType::UTF8String(name))
+                            if name == command_name::ON_STATUS =>
+                        {
+                            PlayEvent::Status(Status::from_info(values.get(3)))
+                        }
+                        _ => continue,
+                    }
+                }
+                msg_type_id::USER_CONTROL_EVENT => match message.payload.get(..2) {
+                    Some(event) if event == STREAM_BEGIN.to_be_bytes() => PlayEvent::StreamBegin,
+                    Some(event) if event == STREAM_EOF.to_be_bytes() => PlayEvent::StreamEof,
+                    _ => continue,
+                },
+                type_id => {
+                    info!("Skipping message type {}", type_id);
+                    continue;
+                }
+            };
+            return Ok(Some(event));
+        }
+    }
+
+    /// Stops publishing and closes the connection.
+    pub async fn close(mut self) -> Result<(), ClientError> {
+        if self.stream_id != 0 {
+            if !self.stream_key.is_empty() {
+                let transaction_id = self.next_transaction_id();
+                let fc_unpublish = FCUnpublish::new(transaction_id, self.stream_key.clone());
+                self.send_command(&fc_unpublish.serialize().map_err(protocol_error)?, 0)
+                    .await?;
+            }
+            let delete_stream = DeleteStream::new(self.stream_id);
+            self.send_command(&delete_stream.serialize().map_err(protocol_error)?, 0)
+                .await?;
+        }
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn send_chunk_size(&mut self) -> Result<(), ClientError> {
+        let payload = CHUNK_SIZE.to_be_bytes().to_vec();
+        self.send_control(msg_type_id::SET_CHUNK_SIZE, payload)
+            .await?;
+        self.writer.set_chunk_size(CHUNK_SIZE as usize);
+        Ok(())
+    }
+
+    async fn send_control(&mut self, type_id: u8, payload: Vec<u8>) -> Result<(), ClientError> {
+        let message = RawMessage {
+            chunk_stream_id: CONTROL_CHUNK_STREAM,
+            timestamp: 0,
+            type_id,
+            stream_id: 0,
+            payload,
+        };
+        self.stream.write_all(&self.writer.chunks(&message)).await?;
+        Ok(())
+    }
+
+    async fn send_command(&mut self, command: &[u8], stream_id: u32) -> Result<(), ClientError> {
+        let message = RawMessage {
+            chunk_stream_id: COMMAND_CHUNK_STREAM,
+            timestamp: 0,
+            type_id: msg_type_id::COMMAND_AMF0,
+            stream_id,
+            payload: command.to_vec(),
+        };
+        self.stream.write_all(&self.writer.chunks(&message)).await?;
+        Ok(())
+    }
+
+    // Media and data go out on the message stream from createStream
+    async fn send_message(
+        &mut self,
+        chunk_stream_id: u32,
+        type_id: u8,
+        timestamp: u32,
+        payload: Vec<u8>,
+    ) -> Result<(), ClientError> {
+        let message = RawMessage {
+            chunk_stream_id,
+            timestamp,
+            type_id,
+            stream_id: self.stream_id,
+            payload,
+        };
+        self.stream.write_all(&self.writer.chunks(&message)).await?;
+        Ok(())
+    }
+
+    // The next message that is not protocol control, those are taken care of here
+    async fn read_message(&mut self) -> Result<RawMessage, ClientError> {
+        loop {
+            if let Some(message) = self.chunks.next_message().map_err(protocol_error)? {
+                match message.type_id {
+                    msg_type_id::SET_CHUNK_SIZE => {
+                        let chunk_size = read_u32(&message.payload)? & 0x7fff_ffff;
+                        self.chunks.set_chunk_size(chunk_size as usize);
+                    }
+                    msg_type_id::ABORT => {
+                        self.chunks.abort(read_u32(&message.payload)?);
+                    }
+                    msg_type_id::WIN_ACKNOWLEDGEMENT_SIZE => {
+                        self.window_ack_size = read_u32(&message.payload)?;
+                    }
+                    msg_type_id::USER_CONTROL_EVENT
+                        if message.payload.get(..2) == Some(&PING_REQUEST.to_be_bytes()) =>
+                    {
+                        let mut pong = PING_RESPONSE.to_be_bytes().to_vec();
+                        pong.extend_from_slice(&message.payload[2..]);
+                        self.send_control(msg_type_id::USER_CONTROL_EVENT, pong)
+                            .await?;
+                    }
+                    _ => return Ok(message),
+                }
+                continue;
+            }
+
+            let mut buffer = [0; 8192];
+            let size = self.stream.read(&mut buffer).await?;
+            if size == 0 {
+                let err = std::io::ErrorKind::UnexpectedEof;
+                return Err(std::io::Error::new(err, "Connection closed by the server").into());
+            }
+            self.chunks.push(&buffer[..size]);
+            self.acknowledge(size).await?;
+        }
+    }
+
+    // Tells the server how much arrived whenever another window's worth did
+    async fn acknowledge(&mut self, size: usize) -> Result<(), ClientError> {
+        self.received = self.received.wrapping_add(size as u32);
+        if self.window_ack_size == 0
+            || self.received.wrapping_sub(self.acknowledged) < self.window_ack_size
+        {
+            return Ok(());
+        }
+        self.acknowledged = self.received;
+        let payload = self.received.to_be_bytes().to_vec();
+        self.send_control(msg_type_id::ACKNOWLEDGEMENT, payload)
+            .await
+    }
+
+    // Commands as name and values, other messages are skipped
+    async fn read_command(&mut self) -> Result<(String, Vec<Amf0ValueType>), ClientError> {
+        loop {
+            let message = self.read_message().await?;
+            if message.type_id != msg_type_id::COMMAND_AMF0 {
+                info!("Skipping message type {}", message.type_id);
+                continue;
+            }
+            let values = decode(&message.payload).map_err(protocol_error)?;
+            match values.first() {
+                Some(Amf0ValueType::UTF8String(name)) => return Ok((name.clone(), values)),
+                _ => warn!("Command without a name: {:?}", values),
+            }
+        }
+    }
+
+    // The _result for the transaction, an _error for it fails
+    async fn wait_for_result(
+        &mut self,
+        transaction_id: usize,
+    ) -> Result<Vec<Amf0ValueType>, ClientError> {
+        loop {
+            let (name, values) = self.read_command().await?;
+            if values.get(1) != Some(&Amf0ValueType::Number(transaction_id as f64)) {
+                continue;
+            }
+            match name.as_str() {
+                command_name::RESULT => return Ok(values),
+                command_name::ERROR => {
+                    return Err(ClientError::Rejected(Status::from_info(values.get(3))))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Statuses other than the expected one are skipped, unless they are errors
+    async fn wait_for_status(&mut self, code: StatusCode) -> Result<Status, ClientError> {
+        loop {
+            let (name, values) = self.read_command().await?;
+            if name != command_name::ON_STATUS {
+                continue;
+            }
+            let status = Status::from_info(values.get(3));
+            if status.is_error() {
+                return Err(ClientError::Rejected(status));
+            }
+            if status.code == code.as_str() {
+                return Ok(status);
+            }
+            info!("Status: {} {}", status.code, status.description);
+        }
+    }
+}
+
+// onMetaData as players get it, or wrapped in @setDataFrame as publishers send it
+fn metadata(values: &[Amf0ValueType]) -> Option<IndexMap<String, Amf0ValueType>> {
+    let values = match values.first() {
+        Some(Amf0ValueType::UTF8String(name)) if name == "@setDataFrame" => &values[1..],
+        _ => values,
+    };
+    match values {
+        [Amf0ValueType::UTF8String(name), Amf0ValueType::EcmaArray(properties) | Amf0ValueType::Object(properties), ..]
+            if name == "onMetaData" =>
+        {
+            Some(properties.clone())
+        }
+        _ => None,
+    }
+}
+
+type PendingEvent =
+    Pin<Box<dyn Future<Output = (RtmpClient, Result<Option<PlayEvent>, ClientError>)> + Send>>;
+
+/// The events of a played stream. It ends when the server stops playing it, when the stream
+/// is unpublished or played to its end, or closes the connection.
+pub struct PlayStream {
+    // The client travels into the pending read and comes back with its result
+    next: Option<PendingEvent>,
+}
+
+impl Stream for PlayStream {
+    type Item = Result<PlayEvent, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(next) = self.next.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let Poll::Ready((client, event)) = next.as_mut().poll(cx) else {
+            return Poll::Pending;
+        };
+        let last = match &event {
+            Ok(Some(PlayEvent::Status(status))) => {
+                status.is_error()
+                    || status.code == StatusCode::PlayStop.as_str()
+                    || status.code == StatusCode::PlayUnpublishNotify.as_str()
+            }
+            Ok(Some(_)) => false,
+            Ok(None) | Err(_) => true,
+        };
+        self.next = match last {
+            true => None,
+            false => Some(Box::pin(client.into_event())),
+        };
+        Poll::Ready(event.transpose())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{ClientError, PlayEvent, PlayStream, RtmpClient, RtmpUrl};
+    use crate::config::Config;
+    use crate::media::flv::TagType;
+    use crate::server::connection::connection::Connection;
+    use crate::server::connection::message::amf0::define::Amf0ValueType;
+    use crate::server::connection::message::message::ConnectObject;
+    use crate::server::server::ServerContext;
+    use crate::stream::MediaFrame;
+    use futures_core::Stream;
+    use indexmap::IndexMap;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use tokio::sync::broadcast::error::RecvError;
+
+    // Serves the given number of connections on a thread of its own, like the server does
+    fn serve(context: ServerContext, connections: usize) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve_on(listener, context, connections);
+        addr
+    }
+
+    // For servers whose config needs the address before they are up
+    pub(crate) fn serve_on(
+        listener: std::net::TcpListener,
+        context: ServerContext,
+        connections: usize,
+    ) {
+        listener.set_nonblocking(true).unwrap();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            tokio::task::LocalSet::new().block_on(&runtime, async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let mut handled = Vec::new();
+                for _ in 0..connections {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let context = context.clone();
+                    handled.push(tokio::task::spawn_local(async move {
+                        let _ = Connection::with_context(stream, context).handle().await;
+                    }));
+                }
+                for connection in handled {
+                    let _ = connection.await;
+                }
+            });
+        });
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url = RtmpUrl::parse("rtmp://a.example.com/live/key?token=1").unwrap();
+        assert_eq!(url.address, "a.example.com:1935");
+        assert_eq!(
+            (url.app.as_str(), url.stream.as_str()),
+            ("live", "key?token=1")
+        );
+        assert_eq!(
+            url.to_string(),
+            "rtmp://a.example.com:1935/live/key?token=1"
+        );
+
+        let url = RtmpUrl::parse("rtmp://127.0.0.1:1936/app/inst/key").unwrap();
+        assert_eq!(url.address, "127.0.0.1:1936");
+        assert_eq!(url.app, "app/inst");
+        assert_eq!(
+            RtmpUrl::parse("rtmp://[::1]/live/key").unwrap().address,
+            "[::1]:1935"
+        );
+
+        let url = RtmpUrl::parse("rtmps://a.example.com/live/key").unwrap();
+        assert!(url.tls);
+        assert_eq!(url.address, "a.example.com:443");
+        assert_eq!(url.to_string(), "rtmps://a.example.com:443/live/key");
+        assert!(!RtmpUrl::parse("rtmp://a.example.com/live/key").unwrap().tls);
+
+        assert!(RtmpUrl::parse("http://a.example.com/live/key").is_none());
+        assert!(RtmpUrl::parse("rtmp://a.example.com/live").is_none());
+        assert!(RtmpUrl::parse("rtmp://a.example.com/live/").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let context = ServerContext::new(Config::default());
+        let addr = serve(context.clone(), 2);
+
+        let connect = || ConnectObject::new("live".to_owned());
+        let mut client = RtmpClient::connect(addr, connect()).await.unwrap();
+        client.publish("key").await.unwrap();
+        assert_eq!(client.stream_id(), 1);
+        assert!(context.streams.is_publishing("live", "key"));
+        let mut subscription = context.streams.subscribe("live/key").unwrap();
+
+        let mut properties = IndexMap::new();
+        properties.insert("width".to_owned(), Amf0ValueType::Number(1280.0));
+        client.send_metadata(properties).await.unwrap();
+        client
+            .send_audio(0, &[0xaf, 0x00, 0x12, 0x10])
+            .await
+            .unwrap();
+        // Larger than the chunk size, so it is split up
+        let mut keyframe = vec![0x17, 0x01, 0, 0, 0];
+        keyframe.resize(10_000, 0x42);
+        client.send_video(40, &keyframe).await.unwrap();
+
+        let metadata = subscription.frames.recv().await.unwrap();
+        assert_eq!(metadata.tag_type, TagType::Script);
+        let audio = subscription.frames.recv().await.unwrap();
+        assert_eq!(audio.data.as_ref(), &[0xaf, 0x00, 0x12, 0x10]);
+        let video = subscription.frames.recv().await.unwrap();
+        assert_eq!((video.timestamp, video.data.to_vec()), (40, keyframe));
+
+        // The stream name is taken, the second publisher is turned away
+        let mut second = RtmpClient::connect(addr, connect()).await.unwrap();
+        match second.publish("key").await {
+            Err(ClientError::Rejected(status)) => {
+                assert_eq!(status.code, "NetStream.Publish.BadName")
+            }
+            other => panic!("Expected a rejection, got {:?}", other.map(|_| ())),
+        }
+
+        client.close().await.unwrap();
+        assert!(matches!(
+            subscription.frames.recv().await,
+            Err(RecvError::Closed)
+        ));
+        assert!(!context.streams.is_publishing("live", "key"));
+    }
+
+    async fn next_event(stream: &mut PlayStream) -> Option<PlayEvent> {
+        let event = std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await;
+        event.map(|event| event.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_play() {
+        let context = ServerContext::new(Config::default());
+        let addr = serve(context.clone(), 2);
+
+        let connect = || ConnectObject::new("live".to_owned());
+        let mut publisher = RtmpClient::connect(addr, connect()).await.unwrap();
+        publisher.publish("key").await.unwrap();
+        let mut properties = IndexMap::new();
+        properties.insert("width".to_owned(), Amf0ValueType::Number(1280.0));
+        publisher.send_metadata(properties.clone()).await.unwrap();
+        let sequence_header = [0x17, 0x00, 0, 0, 0, 0x01];
+        publisher.send_video(0, &sequence_header).await.unwrap();
+        // Wait for the server to have taken both before a player joins
+        while context
+            .streams
+            .subscribe("live/key")
+            .is_none_or(|subscription| subscription.headers.len() < 2)
+        {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let player = RtmpClient::connect(addr, connect()).await.unwrap();
+        let mut stream = player.play("key", -1.0, -1.0).await.unwrap();
+        assert_eq!(
+            next_event(&mut stream).await,
+            Some(PlayEvent::Metadata(properties))
+        );
+        assert_eq!(
+            next_event(&mut stream).await,
+            Some(PlayEvent::Frame(MediaFrame::new(
+                TagType::Video,
+                0,
+                sequence_header.to_vec()
+            )))
+        );
+
+        let mut keyframe = vec![0x17, 0x01, 0, 0, 0];
+        keyframe.resize(10_000, 0x42);
+        publisher.send_video(40, &keyframe).await.unwrap();
+        assert_eq!(
+            next_event(&mut stream).await,
+            Some(PlayEvent::Frame(MediaFrame::new(
+                TagType::Video,
+                40,
+                keyframe
+            )))
+        );
+
+        // Unpublishing ends the stream
+        publisher.close().await.unwrap();
+        assert_eq!(next_event(&mut stream).await, Some(PlayEvent::StreamEof));
+        match next_event(&mut stream).await {
+            Some(PlayEvent::Status(status)) => {
+                assert_eq!(status.code, "NetStream.Play.UnpublishNotify")
+            }
+            other => panic!("Expected a status, got {:?}", other),
+        }
+        assert_eq!(next_event(&mut stream).await, None);
+    }
+}
@@ -0,0 +1,575 @@
+// This file defines the server configuration. It is read from a TOML file at startup and
+// every section falls back to sensible defaults, so an empty file is a valid config.
+
+// Path: src/config.rs
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub address: String,
+    // Applications clients may connect to, an empty list accepts any app
+    pub apps: Vec<AppConfig>,
+    pub reject: RejectConfig,
+    pub http: HttpConfig,
+    pub thumbnails: ThumbnailConfig,
+    pub record: RecordConfig,
+    pub hls: HlsConfig,
+    pub dash: DashConfig,
+    pub llhls: LlHlsConfig,
+    pub vod: VodConfig,
+    pub relay: RelayConfig,
+    pub rtmps: RtmpsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: "0.0.0.0:1935".to_owned(),
+            apps: Vec::new(),
+            reject: RejectConfig::default(),
+            http: HttpConfig::default(),
+            thumbnails: ThumbnailConfig::default(),
+            record: RecordConfig::default(),
+            hls: HlsConfig::default(),
+            dash: DashConfig::default(),
+            llhls: LlHlsConfig::default(),
+            vod: VodConfig::default(),
+            relay: RelayConfig::default(),
+            rtmps: RtmpsConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        Config::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    pub fn app(&self, name: &str) -> Option<&AppConfig> {
+        self.apps.iter().find(|app| app.name == name)
+    }
+
+    pub fn is_app_allowed(&self, name: &str) -> bool {
+        self.apps.is_empty() || self.app(name).is_some()
+    }
+
+    pub fn is_stream_key_allowed(&self, app: &str, stream_key: &str) -> bool {
+        match self.app(app) {
+            Some(app) => {
+                app.stream_keys.is_empty() || app.stream_keys.iter().any(|k| k == stream_key)
+            }
+            None => self.apps.is_empty(),
+        }
+    }
+
+    // The app may override the server wide record mode
+    pub fn record_mode(&self, app: &str) -> RecordMode {
+        self.app(app)
+            .and_then(|app| app.record)
+            .unwrap_or(self.record.mode)
+    }
+
+    pub fn push_targets(&self, app: &str) -> &[String] {
+        self.app(app).map_or(&[], |app| app.push.as_slice())
+    }
+
+    /// Where a stream of the app is pulled from when it is played but not published here,
+    /// the URL of the stream itself or else the template of the app.
+    pub fn pull_source(&self, app: &str, stream_name: &str) -> Option<&str> {
+        let app = self.app(app)?;
+        app.pull_streams
+            .get(stream_name)
+            .or(app.pull.as_ref())
+            .map(String::as_str)
+    }
+
+    pub fn has_pull_sources(&self) -> bool {
+        self.apps
+            .iter()
+            .any(|app| app.pull.is_some() || !app.pull_streams.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub name: String,
+    // Stream keys allowed to publish to this app, an empty list accepts any key
+    pub stream_keys: Vec<String>,
+    pub record: Option<RecordMode>,
+    // rtmp:// URLs every stream published to the app is pushed to, {app} and {stream} are
+    // replaced with the names it was published under
+    pub push: Vec<String>,
+    // rtmp:// URL streams played but not published here are pulled from, with {app} and
+    // {stream} replaced like for push
+    pub pull: Option<String>,
+    // Origins of single streams by name, these win over pull
+    pub pull_streams: HashMap<String, String>,
+}
+
+/// The reasons the server turns a client away for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    UnknownApp,
+    BadStreamKey,
+    DuplicatePublish,
+}
+
+/// What the client is told when it is rejected for a given reason.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RejectMessage {
+    pub description: String,
+    // Sent as ex.redirect so the client can retry somewhere else
+    pub redirect: Option<String>,
+}
+
+impl RejectMessage {
+    fn new(description: &str) -> RejectMessage {
+        RejectMessage {
+            description: description.to_owned(),
+            redirect: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RejectConfig {
+    pub unknown_app: RejectMessage,
+    pub bad_stream_key: RejectMessage,
+    pub duplicate_publish: RejectMessage,
+}
+
+impl Default for RejectConfig {
+    fn default() -> RejectConfig {
+        RejectConfig {
+            unknown_app: RejectMessage::new("Application not found."),
+            bad_stream_key: RejectMessage::new("Invalid stream key."),
+            duplicate_publish: RejectMessage::new("Stream is already being published."),
+        }
+    }
+}
+
+impl RejectConfig {
+    pub fn message(&self, reason: RejectReason) -> &RejectMessage {
+        match reason {
+            RejectReason::UnknownApp => &self.unknown_app,
+            RejectReason::BadStreamKey => &self.bad_stream_key,
+            RejectReason::DuplicatePublish => &self.duplicate_publish,
+        }
+    }
+}
+
+/// The HTTP server for the API, it is only started when enabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            enabled: false,
+            address: "0.0.0.0:8080".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+        }
+    }
+}
+
+/// Periodic snapshots of the latest keyframe of every live H.264 stream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ThumbnailConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    // Applied to both dimensions of the decoded picture, 0.25 gives a quarter of the width
+    pub scale: f64,
+    pub format: ImageFormat,
+    // JPEG quality from 1 to 100
+    pub quality: u8,
+    // Snapshots are written to <directory>/<app>/<stream>.<extension>
+    pub directory: PathBuf,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> ThumbnailConfig {
+        ThumbnailConfig {
+            enabled: false,
+            interval_secs: 10,
+            scale: 0.25,
+            format: ImageFormat::Jpeg,
+            quality: 80,
+            directory: PathBuf::from("thumbnails"),
+        }
+    }
+}
+
+/// Which published streams are recorded, and which of their tracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordMode {
+    Off,
+    All,
+    Video,
+    Audio,
+    // Off until started through the HTTP API
+    Manual,
+}
+
+impl RecordMode {
+    pub fn records_audio(&self) -> bool {
+        matches!(
+            self,
+            RecordMode::All | RecordMode::Audio | RecordMode::Manual
+        )
+    }
+
+    pub fn records_video(&self) -> bool {
+        matches!(
+            self,
+            RecordMode::All | RecordMode::Video | RecordMode::Manual
+        )
+    }
+}
+
+/// FLV recordings of published streams.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RecordConfig {
+    pub mode: RecordMode,
+    pub directory: PathBuf,
+    // Relative to the directory, {app}, {stream}, {timestamp} and {index} are replaced
+    pub path_template: String,
+    // A new file is started at the next keyframe once either limit is reached
+    pub max_size: Option<u64>,
+    pub max_duration_secs: Option<u64>,
+    pub mp4: Mp4RecordConfig,
+}
+
+impl Default for RecordConfig {
+    fn default() -> RecordConfig {
+        RecordConfig {
+            mode: RecordMode::Off,
+            directory: PathBuf::from("recordings"),
+            path_template: "{app}/{stream}-{timestamp}.flv".to_owned(),
+            max_size: None,
+            max_duration_secs: None,
+            mp4: Mp4RecordConfig::default(),
+        }
+    }
+}
+
+/// MP4 recordings next to the FLV ones, with the same mode, directory and limits.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Mp4RecordConfig {
+    pub enabled: bool,
+    pub path_template: String,
+    // Fragmented while recording, rewritten with a single moov once the file is done
+    pub finalize: bool,
+}
+
+impl Default for Mp4RecordConfig {
+    fn default() -> Mp4RecordConfig {
+        Mp4RecordConfig {
+            enabled: false,
+            path_template: "{app}/{stream}-{timestamp}.mp4".to_owned(),
+            finalize: true,
+        }
+    }
+}
+
+/// Live HLS output of every published stream, as MPEG-TS segments and a sliding playlist.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HlsConfig {
+    pub enabled: bool,
+    // Segments and playlists are written to <directory>/<app>/<stream>/
+    pub directory: PathBuf,
+    // Segments are cut at the first keyframe after this much time
+    pub segment_duration_secs: f64,
+    // Segments listed in the playlist, older ones are deleted
+    pub playlist_length: usize,
+}
+
+impl Default for HlsConfig {
+    fn default() -> HlsConfig {
+        HlsConfig {
+            enabled: false,
+            directory: PathBuf::from("hls"),
+            segment_duration_secs: 4.0,
+            playlist_length: 6,
+        }
+    }
+}
+
+/// Live MPEG-DASH output of every published stream, as CMAF segments and a dynamic MPD.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DashConfig {
+    pub enabled: bool,
+    // Segments and the manifest are written to <directory>/<app>/<stream>/
+    pub directory: PathBuf,
+    // Segments are cut at the first keyframe after this much time
+    pub segment_duration_secs: f64,
+    // Segments listed in the timeline, older ones are deleted
+    pub timeline_length: usize,
+}
+
+impl Default for DashConfig {
+    fn default() -> DashConfig {
+        DashConfig {
+            enabled: false,
+            directory: PathBuf::from("dash"),
+            segment_duration_secs: 4.0,
+            timeline_length: 6,
+        }
+    }
+}
+
+/// Low-latency HLS output of every published stream, as fMP4 parts of segments with blocking
+/// playlist reload.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LlHlsConfig {
+    pub enabled: bool,
+    // Segments, parts and playlists are written to <directory>/<app>/<stream>/
+    pub directory: PathBuf,
+    // Segments are cut at the first keyframe after this much time
+    pub segment_duration_secs: f64,
+    // Parts are cut before they grow longer than this
+    pub part_duration_secs: f64,
+    // Segments listed in the playlist, older ones are deleted
+    pub playlist_length: usize,
+}
+
+impl Default for LlHlsConfig {
+    fn default() -> LlHlsConfig {
+        LlHlsConfig {
+            enabled: false,
+            directory: PathBuf::from("llhls"),
+            segment_duration_secs: 2.0,
+            part_duration_secs: 0.5,
+            playlist_length: 6,
+        }
+    }
+}
+
+/// Playback of FLV files over RTMP, for play requests that name a file instead of a live stream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VodConfig {
+    pub enabled: bool,
+    // Playing <stream> in <app> reads <directory>/<app>/<stream>.flv, recordings by default
+    pub directory: PathBuf,
+}
+
+impl Default for VodConfig {
+    fn default() -> VodConfig {
+        VodConfig {
+            enabled: false,
+            directory: PathBuf::from("recordings"),
+        }
+    }
+}
+
+/// Relays of streams to and from other RTMP servers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    // A relay that fails waits this long before it reconnects, twice as long after every
+    // further failure, up to the maximum
+    pub retry_initial_secs: f64,
+    pub retry_max_secs: f64,
+    // A pulled stream is dropped once nobody has played it for this long
+    pub pull_idle_secs: f64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> RelayConfig {
+        RelayConfig {
+            retry_initial_secs: 1.0,
+            retry_max_secs: 30.0,
+            pull_idle_secs: 10.0,
+        }
+    }
+}
+
+/// RTMP over TLS, on a listener of its own next to the plain one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RtmpsConfig {
+    pub enabled: bool,
+    pub address: String,
+    // PEM files, read again when they change so renewed certificates need no restart
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    // How often the files are checked for changes
+    pub reload_interval_secs: f64,
+}
+
+impl Default for RtmpsConfig {
+    fn default() -> RtmpsConfig {
+        RtmpsConfig {
+            enabled: false,
+            address: "0.0.0.0:443".to_owned(),
+            cert_path: PathBuf::from("cert.pem"),
+            key_path: PathBuf::from("key.pem"),
+            reload_interval_secs: 10.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ImageFormat, RecordMode, RejectReason};
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            address = "127.0.0.1:1936"
+
+            [[apps]]
+            name = "live"
+            stream_keys = ["secret"]
+
+            [[apps]]
+            name = "open"
+            record = "manual"
+            push = ["rtmp://a.example.com/live/{stream}"]
+            pull = "rtmp://origin.example.com/{app}/{stream}"
+
+            [apps.pull_streams]
+            news = "rtmp://news.example.com/live/main"
+
+            [record]
+            mode = "video"
+            max_duration_secs = 3600
+
+            [record.mp4]
+            enabled = true
+
+            [hls]
+            enabled = true
+            segment_duration_secs = 2
+
+            [thumbnails]
+            enabled = true
+            format = "png"
+            scale = 0.5
+
+            [rtmps]
+            enabled = true
+            cert_path = "/etc/rtmp/fullchain.pem"
+            reload_interval_secs = 60
+
+            [reject.unknown_app]
+            description = "Try the other server"
+            redirect = "rtmp://backup.example.com/live"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.address, "127.0.0.1:1936");
+        assert!(config.thumbnails.enabled);
+        assert_eq!(config.thumbnails.format, ImageFormat::Png);
+        assert_eq!(config.thumbnails.scale, 0.5);
+        assert_eq!(config.thumbnails.interval_secs, 10);
+        assert!(!config.http.enabled);
+        assert_eq!(config.record_mode("live"), RecordMode::Video);
+        assert_eq!(config.record_mode("open"), RecordMode::Manual);
+        assert_eq!(config.record.max_duration_secs, Some(3600));
+        assert!(config.record.mp4.enabled && config.record.mp4.finalize);
+        assert!(config.hls.enabled);
+        assert_eq!(config.hls.segment_duration_secs, 2.0);
+        assert_eq!(config.hls.playlist_length, 6);
+        assert_eq!(
+            config.record.path_template,
+            "{app}/{stream}-{timestamp}.flv"
+        );
+        assert!(config.is_app_allowed("live"));
+        assert!(!config.is_app_allowed("vod"));
+        assert!(config.is_stream_key_allowed("live", "secret"));
+        assert!(!config.is_stream_key_allowed("live", "guess"));
+        assert!(config.is_stream_key_allowed("open", "anything"));
+        assert_eq!(
+            config.push_targets("open"),
+            ["rtmp://a.example.com/live/{stream}"]
+        );
+        assert!(config.push_targets("live").is_empty());
+        assert_eq!(config.relay.retry_max_secs, 30.0);
+        assert_eq!(
+            config.pull_source("open", "news"),
+            Some("rtmp://news.example.com/live/main")
+        );
+        assert_eq!(
+            config.pull_source("open", "other"),
+            Some("rtmp://origin.example.com/{app}/{stream}")
+        );
+        assert_eq!(config.pull_source("live", "news"), None);
+        assert!(config.has_pull_sources());
+        assert!(config.rtmps.enabled);
+        assert_eq!(config.rtmps.address, "0.0.0.0:443");
+        assert_eq!(
+            config.rtmps.cert_path.to_str(),
+            Some("/etc/rtmp/fullchain.pem")
+        );
+        assert_eq!(config.rtmps.reload_interval_secs, 60.0);
+
+        let rejected = config.reject.message(RejectReason::UnknownApp);
+        assert_eq!(rejected.description, "Try the other server");
+        assert_eq!(
+            rejected.redirect.as_deref(),
+            Some("rtmp://backup.example.com/live")
+        );
+        // sections that are left out keep their defaults
+        assert_eq!(
+            config
+                .reject
+                .message(RejectReason::BadStreamKey)
+                .description,
+            "Invalid stream key."
+        );
+    }
+
+    #[test]
+    fn test_empty_config_accepts_everything() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.address, "0.0.0.0:1935");
+        assert!(config.is_app_allowed("anything"));
+        assert!(config.is_stream_key_allowed("anything", "key"));
+    }
+}
@@ -0,0 +1,668 @@
+// This file writes the live MPEG-DASH output of published streams. Video and audio are
+// packaged separately as CMAF tracks, each with an init segment and one moof/mdat per media
+// segment, cut at keyframes by the same segmenter as the HLS output. A dynamic MPD lists the
+// latest segments in a SegmentTimeline and is rewritten with every segment. A change of the
+// tracks starts a new Period, written to a directory of its own next to the one of the period
+// before, which stays in the MPD until the period after it starts. When the stream ends the
+// MPD stops announcing updates, which tells players the presentation is over.
+
+// Path: src/dash.rs
+use crate::config::DashConfig;
+use crate::media::flv::{AudioTag, TagType, VideoTag};
+use crate::media::mp4::{init_segment, media_fragment, Track, TrackConfig, TIMESCALE};
+use crate::output::{run_sinks, MediaSink};
+use crate::segmenter::{SampleQueue, Segmenter};
+use crate::server::server::ServerContext;
+use crate::stream::MediaFrame;
+use crate::utils::{format_iso8601, sanitize};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MANIFEST: &str = "manifest.mpd";
+pub const INIT_SEGMENT: &str = "init.mp4";
+// Every representation is a file of its own, with a single track
+const TRACK_ID: u32 = 1;
+
+/// The directory the DASH output of a stream is written to.
+pub fn stream_directory(config: &DashConfig, app: &str, stream: &str) -> PathBuf {
+    config.directory.join(sanitize(app)).join(sanitize(stream))
+}
+
+// A segment in the timeline, in timescale units
+#[derive(Debug, Clone, Copy)]
+struct TimelineSegment {
+    start: u64,
+    duration: u64,
+    size: u64,
+}
+
+// One track, written to the directory named after it
+struct Representation {
+    name: &'static str,
+    track: Track,
+    samples: SampleQueue,
+    segments: VecDeque<TimelineSegment>,
+    // Out of the timeline but not yet deleted
+    retired: VecDeque<u64>,
+    fragments: u32,
+}
+
+impl Representation {
+    fn new(name: &'static str, config: TrackConfig) -> Representation {
+        Representation {
+            name,
+            track: Track::new(TRACK_ID, config),
+            samples: SampleQueue::new(TRACK_ID),
+            segments: VecDeque::new(),
+            retired: VecDeque::new(),
+            fragments: 0,
+        }
+    }
+
+    fn write_segment(
+        &mut self,
+        directory: &Path,
+        end: Option<u64>,
+        next: Option<u64>,
+    ) -> std::io::Result<()> {
+        let Some(run) = self.samples.take_run(end, next) else {
+            return Ok(());
+        };
+        let start = run.base_decode_time;
+        let duration = run
+            .samples
+            .iter()
+            .map(|sample| sample.duration as u64)
+            .sum();
+        self.fragments += 1;
+        let fragment = media_fragment(self.fragments, &[run]);
+        // Players only ask for segments in the timeline, which lists it once it is written
+        std::fs::write(
+            directory.join(self.name).join(segment_name(start)),
+            &fragment,
+        )?;
+        self.segments.push_back(TimelineSegment {
+            start,
+            duration,
+            size: fragment.len() as u64,
+        });
+        Ok(())
+    }
+
+    fn trim(&mut self, directory: &Path, length: usize) {
+        while self.segments.len() > length.max(1) {
+            if let Some(segment) = self.segments.pop_front() {
+                self.retired.push_back(segment.start);
+            }
+        }
+        while self.retired.len() > length {
+            if let Some(start) = self.retired.pop_front() {
+                let _ = std::fs::remove_file(directory.join(self.name).join(segment_name(start)));
+            }
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.segments
+            .back()
+            .map_or(0, |segment| segment.start + segment.duration)
+    }
+
+    // Bits per second over the segments in the timeline
+    fn bandwidth(&self) -> u64 {
+        let size: u64 = self.segments.iter().map(|segment| segment.size).sum();
+        let duration: u64 = self.segments.iter().map(|segment| segment.duration).sum();
+        (size * 8 * TIMESCALE as u64)
+            .checked_div(duration)
+            .unwrap_or(0)
+            .max(1)
+    }
+
+    fn adaptation_set(&self, id: usize, period: u32, mpd: &mut String) {
+        let (content_type, attributes) = match &self.track.config {
+            TrackConfig::Avc { width, height, .. } | TrackConfig::Hevc { width, height, .. } => (
+                "video",
+                format!(r#" width="{}" height="{}""#, width, height),
+            ),
+            TrackConfig::Aac { sample_rate, .. } => {
+                ("audio", format!(r#" audioSamplingRate="{}""#, sample_rate))
+            }
+        };
+        let _ = writeln!(
+            mpd,
+            r#"    <AdaptationSet id="{}" contentType="{}" mimeType="{}/mp4" segmentAlignment="true" startWithSAP="1">"#,
+            id, content_type, content_type
+        );
+        let _ = writeln!(
+            mpd,
+            r#"      <Representation id="{}" codecs="{}" bandwidth="{}"{}>"#,
+            self.name,
+            self.track.codec_string(),
+            self.bandwidth(),
+            attributes
+        );
+        if let TrackConfig::Aac { channels, .. } = &self.track.config {
+            let _ = writeln!(
+                mpd,
+                r#"        <AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="{}"/>"#,
+                channels
+            );
+        }
+        let _ = writeln!(
+            mpd,
+            r#"        <SegmentTemplate timescale="{}" initialization="{}/{}/{}" media="{}/{}/$Time$.m4s">"#,
+            TIMESCALE, period, self.name, INIT_SEGMENT, period, self.name
+        );
+        mpd.push_str("          <SegmentTimeline>\n");
+        // Runs of contiguous segments of the same duration are repeats of the first
+        let mut entries: Vec<(u64, u64, u32)> = Vec::new();
+        for segment in &self.segments {
+            if let Some((start, duration, repeat)) = entries.last_mut() {
+                let contiguous = *start + *duration * (*repeat as u64 + 1) == segment.start;
+                if contiguous && *duration == segment.duration {
+                    *repeat += 1;
+                    continue;
+                }
+            }
+            entries.push((segment.start, segment.duration, 0));
+        }
+        for (start, duration, repeat) in entries {
+            let _ = match repeat {
+                0 => writeln!(mpd, r#"            <S t="{}" d="{}"/>"#, start, duration),
+                _ => writeln!(
+                    mpd,
+                    r#"            <S t="{}" d="{}" r="{}"/>"#,
+                    start, duration, repeat
+                ),
+            };
+        }
+        mpd.push_str("          </SegmentTimeline>\n        </SegmentTemplate>\n");
+        mpd.push_str("      </Representation>\n    </AdaptationSet>\n");
+    }
+}
+
+fn segment_name(start: u64) -> String {
+    format!("{}.m4s", start)
+}
+
+// xs:duration with milliseconds
+fn duration(milliseconds: u64) -> String {
+    format!("PT{}.{:03}S", milliseconds / 1000, milliseconds % 1000)
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Writes one published stream as DASH, from the frames of a subscription.
+pub struct DashWriter {
+    config: DashConfig,
+    directory: PathBuf,
+    stream_path: String,
+    segmenter: Segmenter,
+    video_config: Option<TrackConfig>,
+    audio_config: Option<TrackConfig>,
+    video: Option<Representation>,
+    audio: Option<Representation>,
+    // The timestamp the current period started at, once it has
+    origin: Option<u32>,
+    // The wall clock time the presentation started at
+    started: Option<u64>,
+    // Id of the current period and its start in the presentation, in milliseconds
+    period: u32,
+    period_start: u64,
+    // Id and end of the period before, with its Period element
+    previous: Option<(u32, u64, String)>,
+}
+
+impl DashWriter {
+    pub fn new(config: &DashConfig, stream_path: &str) -> DashWriter {
+        let (app, stream) = stream_path.split_once('/').unwrap_or(("", stream_path));
+        let target_ms = (config.segment_duration_secs.max(0.0) * 1000.0) as u32;
+        DashWriter {
+            config: config.clone(),
+            directory: stream_directory(config, app, stream),
+            stream_path: stream_path.to_owned(),
+            segmenter: Segmenter::new(target_ms),
+            video_config: None,
+            audio_config: None,
+            video: None,
+            audio: None,
+            origin: None,
+            started: None,
+            period: 0,
+            period_start: 0,
+            previous: None,
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    // Files of the current period
+    fn period_directory(&self) -> PathBuf {
+        self.directory.join(self.period.to_string())
+    }
+
+    // Decode time in the period, audio from just before the first keyframe is moved up
+    fn decode_time(&self, timestamp: u32) -> u64 {
+        let start = self.origin.unwrap_or(timestamp);
+        let relative = timestamp.wrapping_sub(start);
+        if relative > i32::MAX as u32 {
+            0
+        } else {
+            relative as u64
+        }
+    }
+
+    fn write_video(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        let Ok(tag) = VideoTag::parse(&frame.data) else {
+            return Ok(());
+        };
+        if tag.header.is_sequence_header() {
+            let config = TrackConfig::from_video_tag(&tag)
+                .map_err(|err| warn!("DASH of {} skips the video: {}", self.stream_path, err))
+                .ok();
+            if config != self.video_config {
+                self.restart()?;
+            }
+            self.video_config = config;
+            return Ok(());
+        }
+        if !tag.header.is_coded_frames() || self.video_config.is_none() {
+            return Ok(());
+        }
+
+        let keyframe = tag.header.is_keyframe();
+        if self.segmenter.is_boundary(frame.timestamp, keyframe) {
+            self.cut(frame.timestamp)?;
+        }
+        let decode_time = self.decode_time(frame.timestamp);
+        if let Some(video) = &mut self.video {
+            let composition_offset = tag.header.composition_time();
+            video
+                .samples
+                .push(decode_time, composition_offset, keyframe, tag.body);
+        }
+        Ok(())
+    }
+
+    fn write_audio(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        let Ok(tag) = AudioTag::parse(&frame.data) else {
+            return Ok(());
+        };
+        if tag.header.is_sequence_header() {
+            let config = TrackConfig::from_audio_tag(&tag)
+                .map_err(|err| warn!("DASH of {} skips the audio: {}", self.stream_path, err))
+                .ok();
+            if config != self.audio_config {
+                self.restart()?;
+            }
+            self.audio_config = config;
+            return Ok(());
+        }
+        if self.audio_config.is_none() {
+            return Ok(());
+        }
+
+        // Without video any frame can start a segment
+        let cuttable = self.video_config.is_none();
+        if self.segmenter.is_boundary(frame.timestamp, cuttable) {
+            self.cut(frame.timestamp)?;
+        }
+        let decode_time = self.decode_time(frame.timestamp);
+        if let Some(audio) = &mut self.audio {
+            audio.samples.push(decode_time, 0, true, tag.body);
+        }
+        Ok(())
+    }
+
+    // Ends the segments before the timestamp, or starts the presentation at it
+    fn cut(&mut self, timestamp: u32) -> std::io::Result<()> {
+        if self.origin.is_none() {
+            return self.start(timestamp);
+        }
+        let end = self.decode_time(timestamp);
+        let directory = self.period_directory();
+        let has_video = self.video.is_some();
+        if let Some(video) = &mut self.video {
+            video.write_segment(&directory, Some(end), Some(end))?;
+        }
+        if let Some(audio) = &mut self.audio {
+            // Audio does not end exactly where the video does
+            let next = (!has_video).then_some(end);
+            audio.write_segment(&directory, Some(end), next)?;
+        }
+        self.trim();
+        self.write_manifest(false)
+    }
+
+    fn start(&mut self, timestamp: u32) -> std::io::Result<()> {
+        let video = self
+            .video_config
+            .clone()
+            .map(|config| Representation::new("video", config));
+        let audio = self
+            .audio_config
+            .clone()
+            .map(|config| Representation::new("audio", config));
+        if video.is_none() && audio.is_none() {
+            return Ok(());
+        }
+
+        match self.started {
+            // Later periods start where the wall clock is, but not before the last one ended
+            Some(started) => {
+                let previous_end = self.previous.as_ref().map_or(0, |previous| previous.1);
+                self.period_start = unix_millis().saturating_sub(started).max(previous_end);
+            }
+            None => {
+                // Whatever an earlier presentation of the stream left behind
+                match std::fs::remove_dir_all(&self.directory) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+                info!(
+                    "Writing DASH of {} to {}",
+                    self.stream_path,
+                    self.directory.display()
+                );
+                self.started = Some(unix_millis());
+            }
+        }
+        let period_directory = self.period_directory();
+        for representation in [&video, &audio].into_iter().flatten() {
+            let directory = period_directory.join(representation.name);
+            std::fs::create_dir_all(&directory)?;
+            let init = init_segment(std::slice::from_ref(&representation.track));
+            std::fs::write(directory.join(INIT_SEGMENT), init)?;
+        }
+        self.video = video;
+        self.audio = audio;
+        self.origin = Some(timestamp);
+        Ok(())
+    }
+
+    // The tracks changed, the next keyframe starts a new period. Players may still be in the
+    // current one, only the files of the period before it are deleted.
+    fn restart(&mut self) -> std::io::Result<()> {
+        if self.origin.is_some() {
+            self.finish_period()?;
+            let mut element = String::new();
+            let end = self.period_start + self.period_end();
+            self.write_period(&mut element, Some(end - self.period_start));
+            if let Some((id, _, _)) = self.previous.replace((self.period, end, element)) {
+                let _ = std::fs::remove_dir_all(self.directory.join(id.to_string()));
+            }
+            self.period += 1;
+        }
+        self.origin = None;
+        self.video = None;
+        self.audio = None;
+        self.segmenter.reset();
+        Ok(())
+    }
+
+    // Writes the pending samples, the last ones last as long as the ones before them
+    fn finish_period(&mut self) -> std::io::Result<()> {
+        let directory = self.period_directory();
+        for representation in [&mut self.video, &mut self.audio].into_iter().flatten() {
+            representation.write_segment(&directory, None, None)?;
+        }
+        self.trim();
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.origin.is_none() {
+            return Ok(());
+        }
+        self.finish_period()?;
+        self.write_manifest(true)
+    }
+
+    fn trim(&mut self) {
+        let directory = self.period_directory();
+        for representation in [&mut self.video, &mut self.audio].into_iter().flatten() {
+            representation.trim(&directory, self.config.timeline_length);
+        }
+    }
+
+    // End of the current period, relative to its start
+    fn period_end(&self) -> u64 {
+        [&self.video, &self.audio]
+            .into_iter()
+            .flatten()
+            .map(Representation::end)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn write_period(&self, mpd: &mut String, length: Option<u64>) {
+        let _ = write!(
+            mpd,
+            r#"  <Period id="{}" start="{}""#,
+            self.period,
+            duration(self.period_start)
+        );
+        if let Some(length) = length {
+            let _ = write!(mpd, r#" duration="{}""#, duration(length));
+        }
+        mpd.push_str(">\n");
+        let representations = [&self.video, &self.audio].into_iter().flatten();
+        for (id, representation) in representations.enumerate() {
+            representation.adaptation_set(id, self.period, mpd);
+        }
+        mpd.push_str("  </Period>\n");
+    }
+
+    fn write_manifest(&self, ended: bool) -> std::io::Result<()> {
+        let Some(started) = self.started else {
+            return Ok(());
+        };
+        let representations: Vec<&Representation> =
+            [&self.video, &self.audio].into_iter().flatten().collect();
+        let target_ms = (self.config.segment_duration_secs * 1000.0) as u64;
+        let depth = representations
+            .iter()
+            .map(|representation| representation.segments.iter().map(|s| s.duration).sum())
+            .max()
+            .unwrap_or(0);
+
+        let mut mpd = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = write!(
+            mpd,
+            r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011,urn:mpeg:dash:profile:cmaf:2019" type="dynamic" availabilityStartTime="{}" publishTime="{}" minBufferTime="{}" timeShiftBufferDepth="{}""#,
+            format_iso8601(started),
+            format_iso8601(unix_millis()),
+            duration(target_ms),
+            duration(depth)
+        );
+        if ended {
+            let end = self.period_start + self.period_end();
+            let _ = write!(mpd, r#" mediaPresentationDuration="{}""#, duration(end));
+        } else {
+            let _ = write!(
+                mpd,
+                r#" minimumUpdatePeriod="{}" suggestedPresentationDelay="{}""#,
+                duration(target_ms),
+                duration(target_ms * 2)
+            );
+        }
+        mpd.push_str(">\n");
+        if let Some((_, _, element)) = &self.previous {
+            mpd.push_str(element);
+        }
+        self.write_period(&mut mpd, None);
+        mpd.push_str("</MPD>\n");
+
+        // Players never read a half written manifest
+        let path = self.directory.join(MANIFEST);
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, mpd)?;
+        std::fs::rename(&temporary, &path)
+    }
+}
+
+impl MediaSink for DashWriter {
+    fn write_frame(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        match frame.tag_type {
+            TagType::Video => self.write_video(frame),
+            TagType::Audio => self.write_audio(frame),
+            TagType::Script => Ok(()),
+        }
+    }
+
+    // Samples are missing, the pending ones are dropped and the next keyframe cuts
+    fn discontinuity(&mut self) {
+        for representation in [&mut self.video, &mut self.audio].into_iter().flatten() {
+            representation.samples.clear();
+        }
+        self.segmenter.reset();
+    }
+
+    fn close(&mut self) {
+        if let Err(err) = self.finish() {
+            warn!("Failed to finish the DASH manifest: {}", err);
+        }
+        self.origin = None;
+    }
+}
+
+/// Starts a DASH writer for every published stream.
+pub struct DashOutput {
+    context: ServerContext,
+}
+
+impl DashOutput {
+    pub fn new(context: ServerContext) -> DashOutput {
+        DashOutput { context }
+    }
+
+    pub async fn run(self) {
+        let config = self.context.config.dash.clone();
+        run_sinks("DASH", self.context.streams.clone(), move |path| {
+            Some(DashWriter::new(&config, path))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DashWriter, MANIFEST};
+    use crate::config::DashConfig;
+    use crate::media::avc;
+    use crate::media::flv::TagType;
+    use crate::output::MediaSink;
+    use crate::stream::MediaFrame;
+
+    #[test]
+    fn test_dash_timeline_and_manifest() {
+        let config = DashConfig {
+            enabled: true,
+            directory: std::env::temp_dir().join(format!("dash-{}", std::process::id())),
+            segment_duration_secs: 1.0,
+            timeline_length: 2,
+        };
+        let mut writer = DashWriter::new(&config, "live/key");
+        let directory = writer.directory().to_path_buf();
+
+        let sequence_header = avc::fixtures::sequence_header();
+        let video =
+            |timestamp, data: &[u8]| MediaFrame::new(TagType::Video, timestamp, data.to_vec());
+        let audio =
+            |timestamp, data: &[u8]| MediaFrame::new(TagType::Audio, timestamp, data.to_vec());
+        writer.write_frame(&video(0, &sequence_header)).unwrap();
+        writer
+            .write_frame(&audio(0, &[0xaf, 0x00, 0x12, 0x10]))
+            .unwrap();
+
+        // A keyframe every 600 ms, so segments are cut at 1200 ms
+        for index in 0..10 {
+            let timestamp = index * 300;
+            let frame_type = if index % 2 == 0 { 0x17 } else { 0x27 };
+            writer
+                .write_frame(&video(timestamp, &[frame_type, 0x01, 0, 0, 0, 0xaa]))
+                .unwrap();
+            writer
+                .write_frame(&audio(timestamp, &[0xaf, 0x01, 0x21]))
+                .unwrap();
+        }
+
+        let mpd = std::fs::read_to_string(directory.join(MANIFEST)).unwrap();
+        assert!(mpd.contains(r#"type="dynamic""#));
+        assert!(mpd.contains(r#" minimumUpdatePeriod="PT1.000S""#));
+        assert!(mpd.contains(r#"codecs="avc1.42c028""#));
+        assert!(mpd.contains(r#"width="1920" height="1080""#));
+        assert!(mpd.contains(r#"<Period id="0" start="PT0.000S">"#));
+        assert!(mpd.contains(r#"initialization="0/audio/init.mp4" media="0/audio/$Time$.m4s""#));
+        // Both tracks cut at the same keyframes
+        assert_eq!(mpd.matches(r#"<S t="0" d="1200" r="1"/>"#).count(), 2);
+        let segment = std::fs::read(directory.join("0/video/1200.m4s")).unwrap();
+        assert_eq!(&segment[4..8], b"moof");
+        assert_eq!(
+            &std::fs::read(directory.join("0/video/init.mp4")).unwrap()[4..8],
+            b"ftyp"
+        );
+
+        // The last segment is estimated from the frame rate, and updates are no longer announced
+        writer.close();
+        let mpd = std::fs::read_to_string(directory.join(MANIFEST)).unwrap();
+        assert!(!mpd.contains("minimumUpdatePeriod"));
+        assert!(mpd.contains(r#" mediaPresentationDuration="PT3.000S""#));
+        assert!(mpd.contains("<S t=\"1200\" d=\"1200\"/>\n            <S t=\"2400\" d=\"600\"/>"));
+        // Out of the timeline but kept for players that are behind
+        assert!(directory.join("0/video/0.m4s").exists());
+        std::fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_dash_periods() {
+        let config = DashConfig {
+            enabled: true,
+            directory: std::env::temp_dir().join(format!("dash-periods-{}", std::process::id())),
+            segment_duration_secs: 1.0,
+            timeline_length: 2,
+        };
+        let mut writer = DashWriter::new(&config, "live/key");
+        let directory = writer.directory().to_path_buf();
+        let audio =
+            |timestamp, data: &[u8]| MediaFrame::new(TagType::Audio, timestamp, data.to_vec());
+        let mut write = |header: [u8; 2], timestamps: std::ops::Range<u32>| {
+            let header = [0xaf, 0x00, header[0], header[1]];
+            writer
+                .write_frame(&audio(timestamps.start, &header))
+                .unwrap();
+            for timestamp in timestamps.step_by(300) {
+                writer
+                    .write_frame(&audio(timestamp, &[0xaf, 0x01, 0x21]))
+                    .unwrap();
+            }
+        };
+
+        // 44.1 kHz, then 48 kHz starts a new period after the end of the first one
+        write([0x12, 0x10], 0..1800);
+        write([0x11, 0x90], 1800..3300);
+        let mpd = std::fs::read_to_string(directory.join(MANIFEST)).unwrap();
+        assert!(mpd.contains(r#"<Period id="0" start="PT0.000S" duration="PT1.800S">"#));
+        assert!(mpd.contains(r#"<Period id="1" start="PT1.800S">"#));
+        assert!(mpd.contains(r#"initialization="1/audio/init.mp4""#));
+        assert!(directory.join("0/audio/init.mp4").exists());
+        assert!(directory.join("1/audio/init.mp4").exists());
+
+        // Only the period before the current one is kept
+        write([0x12, 0x10], 3300..3600);
+        assert!(!directory.join("0").exists());
+        assert!(directory.join("1/audio/init.mp4").exists());
+        assert!(directory.join("2/audio/init.mp4").exists());
+        std::fs::remove_dir_all(&config.directory).unwrap();
+    }
+}
@@ -0,0 +1,534 @@
+// This file writes the live HLS output of published streams. Every stream gets a directory of
+// MPEG-TS segments, cut at keyframes once they reach the configured duration, or at any frame
+// once they reach the target duration of the playlist, and a playlist of the latest ones that
+// is rewritten as segments are finished. Segments that slide out of the playlist are kept
+// around for one more playlist length, for players that are behind, and then deleted. The
+// directory is cleared when a stream starts and left behind when it ends, with a playlist that
+// is marked as complete.
+
+// Path: src/hls.rs
+use crate::config::HlsConfig;
+use crate::media::aac::AudioSpecificConfig;
+use crate::media::avc::AvcDecoderConfigurationRecord;
+use crate::media::enhanced::FourCc;
+use crate::media::flv::{AudioTag, TagType, VideoTag};
+use crate::media::hevc::HevcDecoderConfigurationRecord;
+use crate::media::nal::{avcc_to_annexb, NalCodec};
+use crate::media::ts::{stream_type, TsMuxer};
+use crate::output::{run_sinks, MediaSink};
+use crate::segmenter::Segmenter;
+use crate::server::server::ServerContext;
+use crate::stream::MediaFrame;
+use crate::utils::sanitize;
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub const PLAYLIST: &str = "index.m3u8";
+
+// Access unit delimiters, HLS wants one in front of every H.264 and HEVC picture
+const AVC_AUD: [u8; 6] = [0x00, 0x00, 0x00, 0x01, 0x09, 0xf0];
+const HEVC_AUD: [u8; 7] = [0x00, 0x00, 0x00, 0x01, 0x46, 0x01, 0x50];
+
+/// The directory the HLS output of a stream is written to.
+pub fn stream_directory(config: &HlsConfig, app: &str, stream: &str) -> PathBuf {
+    config.directory.join(sanitize(app)).join(sanitize(stream))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VideoTrack {
+    codec: NalCodec,
+    nal_length_size: u8,
+    // Written in front of every keyframe, so each segment can be decoded on its own
+    parameter_sets: Vec<Vec<u8>>,
+}
+
+impl VideoTrack {
+    fn parse(tag: &VideoTag) -> Option<VideoTrack> {
+        let track = match tag.header.fourcc() {
+            Some(FourCc::AVC) => {
+                AvcDecoderConfigurationRecord::parse(tag.body).map(|record| VideoTrack {
+                    codec: NalCodec::Avc,
+                    nal_length_size: record.nal_length_size,
+                    parameter_sets: record.parameter_sets().into_iter().map(Vec::from).collect(),
+                })
+            }
+            Some(FourCc::HEVC) => {
+                HevcDecoderConfigurationRecord::parse(tag.body).map(|record| VideoTrack {
+                    codec: NalCodec::Hevc,
+                    nal_length_size: record.nal_length_size,
+                    parameter_sets: record.parameter_sets().into_iter().map(Vec::from).collect(),
+                })
+            }
+            fourcc => {
+                warn!("HLS does not support the {:?} video codec", fourcc);
+                return None;
+            }
+        };
+        track
+            .map_err(|err| warn!("Unparsable video sequence header: {}", err))
+            .ok()
+    }
+
+    fn stream_type(&self) -> u8 {
+        match self.codec {
+            NalCodec::Avc => stream_type::H264,
+            NalCodec::Hevc => stream_type::HEVC,
+        }
+    }
+}
+
+// A segment in the playlist
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    sequence: u64,
+    duration_ms: u32,
+    // The segment does not continue the one before it
+    discontinuity: bool,
+}
+
+// The segment being written
+struct OpenSegment {
+    segment: Segment,
+    start: u32,
+    last: u32,
+    writer: BufWriter<File>,
+}
+
+/// Writes one published stream as HLS, from the frames of a subscription.
+pub struct HlsWriter {
+    config: HlsConfig,
+    directory: PathBuf,
+    stream_path: String,
+    segmenter: Segmenter,
+    video: Option<VideoTrack>,
+    audio: Option<AudioSpecificConfig>,
+    // Kept from one segment to the next while the tracks stay the same
+    muxer: Option<TsMuxer>,
+    current: Option<OpenSegment>,
+    segments: VecDeque<Segment>,
+    // Out of the playlist but not yet deleted
+    retired: VecDeque<u64>,
+    next_sequence: u64,
+    discontinuity: bool,
+    // Discontinuities that slid out of the playlist
+    discontinuity_sequence: u64,
+    // In seconds, segments are at most this long
+    target_duration: u32,
+}
+
+impl HlsWriter {
+    pub fn new(config: &HlsConfig, stream_path: &str) -> HlsWriter {
+        let (app, stream) = stream_path.split_once('/').unwrap_or(("", stream_path));
+        let target_ms = (config.segment_duration_secs.max(0.0) * 1000.0) as u32;
+        HlsWriter {
+            config: config.clone(),
+            directory: stream_directory(config, app, stream),
+            stream_path: stream_path.to_owned(),
+            segmenter: Segmenter::new(target_ms),
+            video: None,
+            audio: None,
+            muxer: None,
+            current: None,
+            segments: VecDeque::new(),
+            retired: VecDeque::new(),
+            next_sequence: 0,
+            discontinuity: false,
+            discontinuity_sequence: 0,
+            target_duration: config.segment_duration_secs.ceil().max(1.0) as u32,
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn write_video(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        let Ok(tag) = VideoTag::parse(&frame.data) else {
+            return Ok(());
+        };
+        if tag.header.is_sequence_header() {
+            let track = VideoTrack::parse(&tag);
+            if track != self.video {
+                self.restart()?;
+            }
+            self.video = track;
+            return Ok(());
+        }
+        let Some(video) = &self.video else {
+            return Ok(());
+        };
+        if !tag.header.is_coded_frames() {
+            return Ok(());
+        }
+
+        let keyframe = tag.header.is_keyframe();
+        let parameter_sets: Vec<&[u8]> = video.parameter_sets.iter().map(Vec::as_slice).collect();
+        let annexb = match avcc_to_annexb(
+            tag.body,
+            video.nal_length_size,
+            video.codec,
+            &parameter_sets,
+        ) {
+            Ok(annexb) => annexb,
+            Err(err) => {
+                warn!("Dropped a video frame of {}: {}", self.stream_path, err);
+                return Ok(());
+            }
+        };
+        let aud: &[u8] = match video.codec {
+            NalCodec::Avc => &AVC_AUD,
+            NalCodec::Hevc => &HEVC_AUD,
+        };
+        let has_aud = annexb
+            .get(4..)
+            .and_then(|nal| video.codec.nal_type(nal))
+            .is_some_and(|nal_type| video.codec.is_access_unit_delimiter(nal_type));
+        let data = if has_aud {
+            annexb
+        } else {
+            [aud, &annexb].concat()
+        };
+
+        // EXT-X-TARGETDURATION can not change, a GOP longer than it is cut anyway
+        let overlong = self.current.as_ref().is_some_and(|segment| {
+            frame.timestamp.saturating_sub(segment.start) >= self.target_duration * 1000
+        });
+        if self
+            .segmenter
+            .is_boundary(frame.timestamp, keyframe || overlong)
+        {
+            self.start_segment(frame.timestamp)?;
+        }
+        let (Some(segment), Some(muxer)) = (&mut self.current, &mut self.muxer) else {
+            return Ok(());
+        };
+        let dts = frame.timestamp as u64 * 90;
+        let pts = (frame.timestamp as i64 + tag.header.composition_time() as i64).max(0) as u64;
+        segment.last = frame.timestamp;
+        segment
+            .writer
+            .write_all(&muxer.write_video(pts * 90, dts, &data, keyframe))
+    }
+
+    fn write_audio(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        let Ok(tag) = AudioTag::parse(&frame.data) else {
+            return Ok(());
+        };
+        if tag.header.is_sequence_header() {
+            let config = if tag.header.fourcc() == Some(FourCc::AAC) {
+                AudioSpecificConfig::parse(tag.body)
+                    .map_err(|err| warn!("Unparsable AAC sequence header: {}", err))
+                    .ok()
+            } else {
+                warn!(
+                    "HLS does not support the {:?} audio codec",
+                    tag.header.fourcc()
+                );
+                None
+            };
+            if config != self.audio {
+                self.restart()?;
+            }
+            self.audio = config;
+            return Ok(());
+        }
+        let Some(config) = &self.audio else {
+            return Ok(());
+        };
+        let data = match config.to_adts(tag.body) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Dropped an audio frame of {}: {}", self.stream_path, err);
+                return Ok(());
+            }
+        };
+
+        // Without video any frame can start a segment
+        if self
+            .segmenter
+            .is_boundary(frame.timestamp, self.video.is_none())
+        {
+            self.start_segment(frame.timestamp)?;
+        }
+        let (Some(segment), Some(muxer)) = (&mut self.current, &mut self.muxer) else {
+            return Ok(());
+        };
+        segment.last = segment.last.max(frame.timestamp);
+        segment
+            .writer
+            .write_all(&muxer.write_audio(frame.timestamp as u64 * 90, &data))
+    }
+
+    // The tracks changed, the PMT with them, so the next segment starts a new timeline
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.finish_segment(None)?;
+        self.muxer = None;
+        self.segmenter.reset();
+        self.discontinuity = self.next_sequence > 0;
+        Ok(())
+    }
+
+    fn start_segment(&mut self, timestamp: u32) -> std::io::Result<()> {
+        self.finish_segment(Some(timestamp))?;
+        if self.next_sequence == 0 {
+            // Whatever an earlier publish of the stream left behind
+            match std::fs::remove_dir_all(&self.directory) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+            std::fs::create_dir_all(&self.directory)?;
+            info!(
+                "Writing HLS of {} to {}",
+                self.stream_path,
+                self.directory.display()
+            );
+        }
+
+        let muxer = self.muxer.get_or_insert_with(|| {
+            TsMuxer::new(
+                self.video.as_ref().map(VideoTrack::stream_type),
+                self.audio.map(|_| stream_type::AAC_ADTS),
+            )
+        });
+        let sequence = self.next_sequence;
+        let mut writer = BufWriter::new(File::create(self.directory.join(segment_name(sequence)))?);
+        writer.write_all(&muxer.tables())?;
+        self.current = Some(OpenSegment {
+            segment: Segment {
+                sequence,
+                duration_ms: 0,
+                discontinuity: std::mem::take(&mut self.discontinuity),
+            },
+            start: timestamp,
+            last: timestamp,
+            writer,
+        });
+        self.next_sequence += 1;
+        Ok(())
+    }
+
+    // Adds the open segment to the playlist, it ends where the next one starts if that is known
+    fn finish_segment(&mut self, end: Option<u32>) -> std::io::Result<()> {
+        let Some(mut open) = self.current.take() else {
+            return Ok(());
+        };
+        open.writer.flush()?;
+        let end = end.unwrap_or(open.last).max(open.last);
+        open.segment.duration_ms = end.saturating_sub(open.start);
+        self.segments.push_back(open.segment);
+
+        while self.segments.len() > self.config.playlist_length.max(1) {
+            if let Some(segment) = self.segments.pop_front() {
+                self.discontinuity_sequence += segment.discontinuity as u64;
+                self.retired.push_back(segment.sequence);
+            }
+        }
+        while self.retired.len() > self.config.playlist_length {
+            if let Some(sequence) = self.retired.pop_front() {
+                let _ = std::fs::remove_file(self.directory.join(segment_name(sequence)));
+            }
+        }
+        self.write_playlist(false)
+    }
+
+    fn write_playlist(&self, ended: bool) -> std::io::Result<()> {
+        let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", self.target_duration);
+        let first = self.segments.front().map_or(0, |segment| segment.sequence);
+        let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", first);
+        if self.discontinuity_sequence > 0 {
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+                self.discontinuity_sequence
+            );
+        }
+        for segment in &self.segments {
+            if segment.discontinuity {
+                playlist.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            let _ = writeln!(
+                playlist,
+                "#EXTINF:{:.3},\n{}",
+                segment.duration_ms as f64 / 1000.0,
+                segment_name(segment.sequence)
+            );
+        }
+        if ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+
+        // Players never read a half written playlist
+        let path = self.directory.join(PLAYLIST);
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, playlist)?;
+        std::fs::rename(&temporary, &path)
+    }
+}
+
+impl MediaSink for HlsWriter {
+    fn write_frame(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        match frame.tag_type {
+            TagType::Video => self.write_video(frame),
+            TagType::Audio => self.write_audio(frame),
+            TagType::Script => Ok(()),
+        }
+    }
+
+    // The segment ends early, the next one starts at the next keyframe
+    fn discontinuity(&mut self) {
+        if let Err(err) = self.finish_segment(None) {
+            warn!("Failed to finish an HLS segment: {}", err);
+        }
+        self.segmenter.reset();
+        self.discontinuity = self.next_sequence > 0;
+    }
+
+    fn close(&mut self) {
+        if self.next_sequence == 0 {
+            return;
+        }
+        let result = self
+            .finish_segment(None)
+            .and_then(|_| self.write_playlist(true));
+        if let Err(err) = result {
+            warn!("Failed to finish the HLS playlist: {}", err);
+        }
+    }
+}
+
+fn segment_name(sequence: u64) -> String {
+    format!("{}.ts", sequence)
+}
+
+/// Starts an HLS writer for every published stream.
+pub struct HlsOutput {
+    context: ServerContext,
+}
+
+impl HlsOutput {
+    pub fn new(context: ServerContext) -> HlsOutput {
+        HlsOutput { context }
+    }
+
+    pub async fn run(self) {
+        let config = self.context.config.hls.clone();
+        run_sinks("HLS", self.context.streams.clone(), move |path| {
+            Some(HlsWriter::new(&config, path))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HlsWriter, PLAYLIST};
+    use crate::config::HlsConfig;
+    use crate::media::avc;
+    use crate::media::flv::TagType;
+    use crate::media::ts::PACKET_SIZE;
+    use crate::output::MediaSink;
+    use crate::stream::MediaFrame;
+
+    fn video(timestamp: u32, data: &[u8]) -> MediaFrame {
+        MediaFrame::new(TagType::Video, timestamp, data.to_vec())
+    }
+
+    fn audio(timestamp: u32, data: &[u8]) -> MediaFrame {
+        MediaFrame::new(TagType::Audio, timestamp, data.to_vec())
+    }
+
+    #[test]
+    fn test_hls_segments_and_playlist() {
+        let config = HlsConfig {
+            enabled: true,
+            directory: std::env::temp_dir().join(format!("hls-{}", std::process::id())),
+            segment_duration_secs: 1.0,
+            playlist_length: 2,
+        };
+        let mut writer = HlsWriter::new(&config, "live/key");
+        let directory = writer.directory().to_path_buf();
+
+        let sequence_header = avc::fixtures::sequence_header();
+        writer.write_frame(&video(0, &sequence_header)).unwrap();
+        writer
+            .write_frame(&audio(0, &[0xaf, 0x00, 0x12, 0x10]))
+            .unwrap();
+
+        // A keyframe every 600 ms, so segments are cut at 1200 ms
+        let keyframe = [
+            0x17, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x65, 0x88,
+        ];
+        let frame = [
+            0x27, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x41, 0x9a,
+        ];
+        for index in 0..10 {
+            let timestamp = index * 300;
+            let data: &[u8] = if index % 2 == 0 { &keyframe } else { &frame };
+            writer.write_frame(&video(timestamp, data)).unwrap();
+            writer
+                .write_frame(&audio(timestamp, &[0xaf, 0x01, 0x21, 0x00]))
+                .unwrap();
+        }
+
+        let playlist = std::fs::read_to_string(directory.join(PLAYLIST)).unwrap();
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXTINF:1.200,\n0.ts\n#EXTINF:1.200,\n1.ts\n"
+        );
+        let segment = std::fs::read(directory.join("0.ts")).unwrap();
+        assert_eq!(segment.len() % PACKET_SIZE, 0);
+        // PAT first, then the keyframe with an AUD and the parameter sets in front of it
+        assert_eq!(&segment[..4], &[0x47, 0x40, 0x00, 0x10]);
+        let annexb = [0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1, 0x67];
+        assert!(segment.windows(annexb.len()).any(|window| window == annexb));
+
+        writer.close();
+        let playlist = std::fs::read_to_string(directory.join(PLAYLIST)).unwrap();
+        // The first segment slid out of the playlist but stays for players that are behind
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:1\n"));
+        assert!(playlist.ends_with("#EXTINF:0.300,\n2.ts\n#EXT-X-ENDLIST\n"));
+        assert!(directory.join("0.ts").exists());
+        std::fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_hls_long_gop() {
+        let config = HlsConfig {
+            enabled: true,
+            directory: std::env::temp_dir().join(format!("hls-gop-{}", std::process::id())),
+            segment_duration_secs: 1.0,
+            playlist_length: 2,
+        };
+        let mut writer = HlsWriter::new(&config, "live/key");
+        let directory = writer.directory().to_path_buf();
+
+        let sequence_header = avc::fixtures::sequence_header();
+        writer.write_frame(&video(0, &sequence_header)).unwrap();
+
+        // A single keyframe, the segments are cut once they reach the target duration
+        let keyframe = [
+            0x17, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x65, 0x88,
+        ];
+        let frame = [
+            0x27, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x41, 0x9a,
+        ];
+        for index in 0..10 {
+            let data: &[u8] = if index == 0 { &keyframe } else { &frame };
+            writer.write_frame(&video(index * 300, data)).unwrap();
+        }
+
+        let playlist = std::fs::read_to_string(directory.join(PLAYLIST)).unwrap();
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXTINF:1.200,\n0.ts\n#EXTINF:1.200,\n1.ts\n"
+        );
+        std::fs::remove_dir_all(&config.directory).unwrap();
+    }
+}
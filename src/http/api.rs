@@ -0,0 +1,499 @@
+// This file routes the HTTP API. Thumbnails of the live streams are listed at
+// /api/thumbnails and served at /api/thumbnails/<app>/<stream>. Streams in manual record
+// mode are recorded after a POST to /api/recordings/<app>/<stream>, until a DELETE. The HLS
+// and DASH outputs of a stream are served from /hls/<app>/<stream>/ and /dash/<app>/<stream>/,
+// low-latency HLS from /llhls/<app>/<stream>/ with blocking playlist reload. The relays to
+// and from other servers are listed at /api/relays, the codecs of the published streams at
+// /api/streams and /api/streams/<app>/<stream>, together with their last onMetaData.
+
+// Path: src/http/api.rs
+use crate::config::RecordMode;
+use crate::dash::{self, INIT_SEGMENT, MANIFEST};
+use crate::hls::{self, PLAYLIST};
+use crate::http::request::HttpRequest;
+use crate::http::response::HttpResponse;
+use crate::llhls::{self, parse_part_name, Reload};
+use crate::server::server::ServerContext;
+use crate::stream::{StreamInfo, StreamRegistry};
+use crate::thumbnail::thumbnail_path;
+use crate::utils::sanitize;
+use serde_json::{json, Value};
+
+pub async fn route(request: &HttpRequest, context: &ServerContext) -> HttpResponse {
+    let method = request.method.as_str();
+    match request.segments().as_slice() {
+        ["api", "thumbnails"] if method == "GET" => list_thumbnails(context).await,
+        ["api", "thumbnails", app, stream] if method == "GET" => {
+            thumbnail(context, app, stream).await
+        }
+        ["api", "thumbnails"] | ["api", "thumbnails", _, _] => {
+            HttpResponse::method_not_allowed("GET")
+        }
+        ["api", "relays"] if method == "GET" => list_relays(context),
+        ["api", "relays"] => HttpResponse::method_not_allowed("GET"),
+        ["api", "streams"] if method == "GET" => list_streams(context),
+        ["api", "streams", app, stream] if method == "GET" => stream_details(context, app, stream),
+        ["api", "streams"] | ["api", "streams", _, _] => HttpResponse::method_not_allowed("GET"),
+        ["api", "recordings", app, stream] => match method {
+            "POST" => set_recording(context, app, stream, true),
+            "DELETE" => set_recording(context, app, stream, false),
+            _ => HttpResponse::method_not_allowed("POST, DELETE"),
+        },
+        ["hls", app, stream, file] if method == "GET" => hls_file(context, app, stream, file).await,
+        ["dash", app, stream, file @ ..] if method == "GET" => {
+            dash_file(context, app, stream, file).await
+        }
+        ["llhls", app, stream, file] if method == "GET" => {
+            llhls_file(request, context, app, stream, file).await
+        }
+        ["hls", _, _, _] | ["dash", _, _, ..] | ["llhls", _, _, _] => {
+            HttpResponse::method_not_allowed("GET")
+        }
+        _ => HttpResponse::not_found(),
+    }
+}
+
+async fn list_thumbnails(context: &ServerContext) -> HttpResponse {
+    let config = &context.config.thumbnails;
+    if !config.enabled {
+        return HttpResponse::not_found();
+    }
+
+    let mut paths = context.streams.stream_paths();
+    paths.sort();
+    let mut thumbnails = Vec::new();
+    for path in paths {
+        let file = thumbnail_path(config, &path);
+        if let Ok(metadata) = tokio::fs::metadata(&file).await {
+            let updated = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|since| since.as_secs());
+            thumbnails.push(json!({
+                "stream": path,
+                "url": format!("/api/thumbnails/{}", path),
+                "updated": updated,
+            }));
+        }
+    }
+    HttpResponse::json(&json!({ "thumbnails": thumbnails }))
+}
+
+fn list_relays(context: &ServerContext) -> HttpResponse {
+    let relays: Vec<_> = context
+        .relays
+        .list()
+        .into_iter()
+        .map(|relay| {
+            let since = relay
+                .since
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            json!({
+                "stream": relay.stream,
+                "url": relay.url,
+                "direction": relay.direction.as_str(),
+                "state": relay.state.as_str(),
+                "since": since,
+                "failures": relay.failures,
+                "error": relay.last_error,
+            })
+        })
+        .collect();
+    HttpResponse::json(&json!({ "relays": relays }))
+}
+
+fn list_streams(context: &ServerContext) -> HttpResponse {
+    let mut paths = context.streams.stream_paths();
+    paths.sort();
+    let streams: Vec<_> = paths
+        .iter()
+        .filter_map(|path| {
+            let (app, stream) = path.split_once('/')?;
+            let info = context.streams.stream_info(app, stream)?;
+            Some(stream_json(path, &info))
+        })
+        .collect();
+    HttpResponse::json(&json!({ "streams": streams }))
+}
+
+fn stream_details(context: &ServerContext, app: &str, stream: &str) -> HttpResponse {
+    match context.streams.stream_info(app, stream) {
+        Some(info) => HttpResponse::json(&stream_json(
+            &StreamRegistry::stream_path(app, stream),
+            &info,
+        )),
+        None => HttpResponse::not_found(),
+    }
+}
+
+fn stream_json(path: &str, info: &StreamInfo) -> Value {
+    let video = info.video.as_ref().map(|video| {
+        json!({
+            "codec": video.codec,
+            "width": video.width,
+            "height": video.height,
+            "frame_rate": video.frame_rate,
+            "chroma_format": video.chroma_format as u8,
+            "bit_depth": video.bit_depth,
+        })
+    });
+    let audio = info.audio.as_ref().map(|audio| {
+        json!({
+            "codec": audio.codec,
+            "sample_rate": audio.sample_rate,
+            "channels": audio.channels,
+            "sbr": audio.sbr,
+            "ps": audio.ps,
+        })
+    });
+    json!({
+        "stream": path,
+        "video": video,
+        "audio": audio,
+        "metadata": info.metadata,
+    })
+}
+
+async fn thumbnail(context: &ServerContext, app: &str, stream: &str) -> HttpResponse {
+    let config = &context.config.thumbnails;
+    // Only live streams, a file left behind by a crash is not served
+    if !config.enabled || !context.streams.is_publishing(app, stream) {
+        return HttpResponse::not_found();
+    }
+    let file = thumbnail_path(config, &StreamRegistry::stream_path(app, stream));
+    match tokio::fs::read(&file).await {
+        Ok(image) => HttpResponse::ok(config.format.content_type(), image)
+            .header("Cache-Control", "no-cache"),
+        Err(_) => HttpResponse::not_found(),
+    }
+}
+
+async fn hls_file(context: &ServerContext, app: &str, stream: &str, file: &str) -> HttpResponse {
+    let config = &context.config.hls;
+    let content_type = if file == PLAYLIST {
+        "application/vnd.apple.mpegurl"
+    } else if file.ends_with(".ts") {
+        "video/mp2t"
+    } else {
+        return HttpResponse::not_found();
+    };
+    if !config.enabled {
+        return HttpResponse::not_found();
+    }
+    let path = hls::stream_directory(config, app, stream).join(sanitize(file));
+    output_file(&path, content_type, file == PLAYLIST).await
+}
+
+async fn dash_file(
+    context: &ServerContext,
+    app: &str,
+    stream: &str,
+    file: &[&str],
+) -> HttpResponse {
+    let config = &context.config.dash;
+    let (content_type, relative) = match file {
+        [manifest] if *manifest == MANIFEST => ("application/dash+xml", MANIFEST.to_owned()),
+        [period, track @ ("video" | "audio"), name]
+            if period.parse::<u32>().is_ok()
+                && (*name == INIT_SEGMENT || name.ends_with(".m4s")) =>
+        {
+            (
+                if *track == "video" {
+                    "video/mp4"
+                } else {
+                    "audio/mp4"
+                },
+                format!("{}/{}/{}", period, track, sanitize(name)),
+            )
+        }
+        _ => return HttpResponse::not_found(),
+    };
+    if !config.enabled {
+        return HttpResponse::not_found();
+    }
+    let path = dash::stream_directory(config, app, stream).join(relative);
+    output_file(&path, content_type, file.len() == 1).await
+}
+
+// A playlist request with _HLS_msn, and _HLS_part, is held until the playlist lists that
+// segment or part. So is a request for the part the playlist hints at.
+async fn llhls_file(
+    request: &HttpRequest,
+    context: &ServerContext,
+    app: &str,
+    stream: &str,
+    file: &str,
+) -> HttpResponse {
+    let config = &context.config.llhls;
+    let content_type = if file == llhls::PLAYLIST {
+        "application/vnd.apple.mpegurl"
+    } else if file == llhls::INIT_SEGMENT || file.ends_with(".m4s") {
+        "video/mp4"
+    } else {
+        return HttpResponse::not_found();
+    };
+    if !config.enabled {
+        return HttpResponse::not_found();
+    }
+    let stream_path = StreamRegistry::stream_path(app, stream);
+    let path = llhls::stream_directory(config, app, stream).join(sanitize(file));
+
+    let (sequence, part) = if file == llhls::PLAYLIST {
+        let sequence = request
+            .query_param("_HLS_msn")
+            .map(|msn| msn.parse::<u64>());
+        let part = request
+            .query_param("_HLS_part")
+            .map(|part| part.parse::<u32>());
+        match (sequence, part) {
+            (None, None) => return output_file(&path, content_type, true).await,
+            (Some(Ok(sequence)), None) => (sequence, None),
+            (Some(Ok(sequence)), Some(Ok(part))) => (sequence, Some(part)),
+            _ => return HttpResponse::new(400),
+        }
+    } else {
+        match parse_part_name(file) {
+            Some((sequence, part)) => (sequence, Some(part)),
+            None => return output_file(&path, content_type, false).await,
+        }
+    };
+    match context
+        .llhls
+        .wait_for(&stream_path, Some(sequence), part)
+        .await
+    {
+        Reload::Ready(state) if file == llhls::PLAYLIST => {
+            HttpResponse::ok(content_type, state.playlist.into_bytes())
+                .header("Cache-Control", "no-cache")
+                .header("Access-Control-Allow-Origin", "*")
+        }
+        Reload::Ready(_) | Reload::NotLive => {
+            output_file(&path, content_type, file == llhls::PLAYLIST).await
+        }
+        Reload::TooFar => HttpResponse::new(400),
+        Reload::TimedOut => HttpResponse::new(503),
+    }
+}
+
+// Segments never change, playlists and manifests do with every segment
+async fn output_file(path: &std::path::Path, content_type: &str, changing: bool) -> HttpResponse {
+    match tokio::fs::read(path).await {
+        Ok(data) => HttpResponse::ok(content_type, data)
+            .header(
+                "Cache-Control",
+                if changing { "no-cache" } else { "max-age=3600" },
+            )
+            .header("Access-Control-Allow-Origin", "*"),
+        Err(_) => HttpResponse::not_found(),
+    }
+}
+
+// Starts or stops the recording of a stream published to an app in manual record mode
+fn set_recording(
+    context: &ServerContext,
+    app: &str,
+    stream: &str,
+    recording: bool,
+) -> HttpResponse {
+    let path = StreamRegistry::stream_path(app, stream);
+    if !context.streams.set_recording(&path, recording) {
+        return HttpResponse::not_found();
+    }
+    let manual = context.config.record_mode(app) == RecordMode::Manual;
+    HttpResponse::json(&json!({
+        "stream": path,
+        "recording": recording,
+        "manual": manual,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::route;
+    use crate::config::Config;
+    use crate::http::request::HttpRequest;
+    use crate::server::server::ServerContext;
+    use crate::stream::AudioInfo;
+
+    fn get(path: &str) -> HttpRequest {
+        HttpRequest::parse(&format!("GET {} HTTP/1.1\r\n\r\n", path)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_thumbnail_routes() {
+        let directory = std::env::temp_dir().join(format!("thumbnails-{}", std::process::id()));
+        let mut config = Config::default();
+        config.thumbnails.enabled = true;
+        config.thumbnails.directory = directory.clone();
+        let context = ServerContext::new(config);
+
+        let _guard = context.streams.claim_publish("live", "key").unwrap();
+        assert_eq!(
+            route(&get("/api/thumbnails/live/key"), &context)
+                .await
+                .status,
+            404
+        );
+
+        std::fs::create_dir_all(directory.join("live")).unwrap();
+        std::fs::write(directory.join("live/key.jpg"), [0xff, 0xd8]).unwrap();
+        let response = route(&get("/api/thumbnails/live/key"), &context).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, vec![0xff, 0xd8]);
+
+        let response = route(&get("/api/thumbnails"), &context).await;
+        let list: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(list["thumbnails"][0]["url"], "/api/thumbnails/live/key");
+
+        assert_eq!(route(&get("/api/other"), &context).await.status, 404);
+        let post = HttpRequest::parse("POST /api/thumbnails HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(route(&post, &context).await.status, 405);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recording_routes() {
+        let context = ServerContext::new(Config::default());
+        let request = |method: &str| {
+            HttpRequest::parse(&format!(
+                "{} /api/recordings/live/key HTTP/1.1\r\n\r\n",
+                method
+            ))
+            .unwrap()
+        };
+        assert_eq!(route(&request("POST"), &context).await.status, 404);
+
+        let guard = context.streams.claim_publish("live", "key").unwrap();
+        let switch = guard.recording_switch();
+        let response = route(&request("POST"), &context).await;
+        assert_eq!(response.status, 200);
+        assert!(switch.load(std::sync::atomic::Ordering::Relaxed));
+        route(&request("DELETE"), &context).await;
+        assert!(!switch.load(std::sync::atomic::Ordering::Relaxed));
+        assert_eq!(route(&request("GET"), &context).await.status, 405);
+    }
+
+    #[tokio::test]
+    async fn test_stream_routes() {
+        let context = ServerContext::new(Config::default());
+        assert_eq!(
+            route(&get("/api/streams/live/key"), &context).await.status,
+            404
+        );
+
+        let guard = context.streams.claim_publish("live", "key").unwrap();
+        guard.set_audio_info(AudioInfo {
+            codec: "mp4a.40.2".to_owned(),
+            sample_rate: 44100,
+            channels: 2,
+            sbr: false,
+            ps: false,
+        });
+        let response = route(&get("/api/streams/live/key"), &context).await;
+        assert_eq!(response.status, 200);
+        let stream: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(stream["stream"], "live/key");
+        assert_eq!(stream["audio"]["codec"], "mp4a.40.2");
+        assert_eq!(stream["audio"]["sample_rate"], 44100);
+        assert!(stream["video"].is_null());
+        assert!(stream["metadata"].is_null());
+        guard.set_metadata(serde_json::json!({ "width": 1280.0 }));
+        let response = route(&get("/api/streams/live/key"), &context).await;
+        let stream: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(stream["metadata"]["width"], 1280.0);
+
+        let response = route(&get("/api/streams"), &context).await;
+        let list: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(list["streams"][0]["audio"]["channels"], 2);
+        let post = HttpRequest::parse("POST /api/streams HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(route(&post, &context).await.status, 405);
+    }
+
+    #[tokio::test]
+    async fn test_hls_routes() {
+        let directory = std::env::temp_dir().join(format!("hls-routes-{}", std::process::id()));
+        let mut config = Config::default();
+        config.hls.enabled = true;
+        config.hls.directory = directory.clone();
+        let context = ServerContext::new(config);
+
+        std::fs::create_dir_all(directory.join("live/key")).unwrap();
+        std::fs::write(directory.join("live/key/index.m3u8"), "#EXTM3U\n").unwrap();
+        let response = route(&get("/hls/live/key/index.m3u8"), &context).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"#EXTM3U\n");
+        assert_eq!(
+            route(&get("/hls/live/key/0.ts"), &context).await.status,
+            404
+        );
+        assert_eq!(
+            route(&get("/hls/live/key/notes.txt"), &context)
+                .await
+                .status,
+            404
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dash_routes() {
+        let directory = std::env::temp_dir().join(format!("dash-routes-{}", std::process::id()));
+        let mut config = Config::default();
+        config.dash.enabled = true;
+        config.dash.directory = directory.clone();
+        let context = ServerContext::new(config);
+
+        std::fs::create_dir_all(directory.join("live/key/0/video")).unwrap();
+        std::fs::write(directory.join("live/key/manifest.mpd"), "<MPD/>").unwrap();
+        std::fs::write(directory.join("live/key/0/video/0.m4s"), [0, 0, 0, 8]).unwrap();
+        let response = route(&get("/dash/live/key/manifest.mpd"), &context).await;
+        assert_eq!(response.status, 200);
+        assert!(response
+            .headers
+            .contains(&("Content-Type".to_owned(), "application/dash+xml".to_owned())));
+        let response = route(&get("/dash/live/key/0/video/0.m4s"), &context).await;
+        assert_eq!(response.body, vec![0, 0, 0, 8]);
+        assert_eq!(
+            route(&get("/dash/live/key/0/other/0.m4s"), &context)
+                .await
+                .status,
+            404
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_llhls_routes() {
+        let directory = std::env::temp_dir().join(format!("llhls-routes-{}", std::process::id()));
+        let mut config = Config::default();
+        config.llhls.enabled = true;
+        config.llhls.directory = directory.clone();
+        let context = ServerContext::new(config);
+
+        std::fs::create_dir_all(directory.join("live/key")).unwrap();
+        std::fs::write(directory.join("live/key/index.m3u8"), "#EXTM3U\n").unwrap();
+        std::fs::write(directory.join("live/key/0.1.m4s"), [0, 0, 0, 8]).unwrap();
+        // Not live, what is on disk is served right away
+        let response = route(&get("/llhls/live/key/index.m3u8?_HLS_msn=3"), &context).await;
+        assert_eq!(response.body, b"#EXTM3U\n");
+        let response = route(&get("/llhls/live/key/0.1.m4s"), &context).await;
+        assert_eq!(response.body, vec![0, 0, 0, 8]);
+        assert_eq!(
+            route(&get("/llhls/live/key/index.m3u8?_HLS_part=1"), &context)
+                .await
+                .status,
+            400
+        );
+        assert_eq!(
+            route(&get("/llhls/live/key/0.ts"), &context).await.status,
+            404
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
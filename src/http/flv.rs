@@ -0,0 +1,459 @@
+// This file serves live streams as FLV over HTTP, for web players like flv.js and mpegts.js.
+// GET /<app>/<stream>.flv answers with a chunked FLV file that lasts as long as the stream is
+// published: the FLV header, the cached metadata, sequence headers and GOP, then every frame
+// as it arrives. A player that falls too far behind skips ahead to the next keyframe. The
+// same path upgraded to a WebSocket sends the same bytes as binary messages, one per tag, for
+// players that can only use WebSockets.
+
+// Path: src/http/flv.rs
+use crate::http::request::HttpRequest;
+use crate::http::response::{chunk, HttpResponse};
+use crate::http::websocket::{self, opcode, Frame};
+use crate::media::flv::{flv_header, FlvTag, TagType};
+use crate::server::server::ServerContext;
+use crate::stream::{KeyframeGate, MediaFrame, StreamRegistry, Subscription};
+use log::{info, warn};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+// Messages a WebSocket player may have waiting before frames are dropped
+const WEBSOCKET_QUEUE: usize = 256;
+// A player that answers nothing for two intervals is gone
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The stream an HTTP-FLV request is for, live/key for /live/key.flv.
+pub fn stream_path(request: &HttpRequest) -> Option<String> {
+    match request.segments().as_slice() {
+        [app, file] => file
+            .strip_suffix(".flv")
+            .filter(|stream| !stream.is_empty())
+            .map(|stream| StreamRegistry::stream_path(app, stream)),
+        _ => None,
+    }
+}
+
+// Web players are usually served from another origin
+fn cors(response: HttpResponse) -> HttpResponse {
+    response.header("Access-Control-Allow-Origin", "*")
+}
+
+/// Serves the stream as a chunked HTTP response, or as WebSocket messages when the request
+/// asks for an upgrade.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &HttpRequest,
+    path: &str,
+    remote: &str,
+    context: &ServerContext,
+) -> std::io::Result<()> {
+    match request.method.as_str() {
+        "GET" => {}
+        "OPTIONS" => {
+            let preflight = cors(HttpResponse::new(204))
+                .header("Access-Control-Allow-Methods", "GET, OPTIONS")
+                .header("Access-Control-Allow-Headers", "*");
+            return preflight.write_to(stream).await;
+        }
+        _ => {
+            let response = HttpResponse::method_not_allowed("GET, OPTIONS");
+            return cors(response).write_to(stream).await;
+        }
+    }
+    let upgrade = match websocket::is_upgrade(request) {
+        true => match websocket::handshake(request) {
+            Some(response) => Some(response),
+            None => return HttpResponse::new(400).write_to(stream).await,
+        },
+        false => None,
+    };
+    let protocol = if upgrade.is_some() {
+        "ws-flv"
+    } else {
+        "http-flv"
+    };
+
+    let subscription = context.streams.subscribe(path);
+    let player = context.streams.add_player(path, protocol, remote);
+    let (Some(subscription), Some(player)) = (subscription, player) else {
+        return cors(HttpResponse::not_found()).write_to(stream).await;
+    };
+    info!(
+        "{} player {} of {} from {}",
+        protocol,
+        player.id(),
+        path,
+        remote
+    );
+
+    let started = Instant::now();
+    let mut sent = 0;
+    let result = match upgrade {
+        Some(response) => {
+            stream.write_all(&response.upgrade_head()).await?;
+            write_websocket(stream, subscription, path, &mut sent).await
+        }
+        None => write_chunked(stream, subscription, path, &mut sent).await,
+    };
+    info!(
+        "{} player {} of {} left after {:.1}s, {} bytes sent",
+        protocol,
+        player.id(),
+        path,
+        started.elapsed().as_secs_f64(),
+        sent
+    );
+    result
+}
+
+// Players set up the tracks the header announces, both until a sequence header says more
+fn file_header(headers: &[MediaFrame]) -> [u8; 13] {
+    let has_audio = headers.iter().any(|frame| frame.tag_type == TagType::Audio);
+    let has_video = headers.iter().any(|frame| frame.tag_type == TagType::Video);
+    match (has_audio, has_video) {
+        (false, false) => flv_header(true, true),
+        (has_audio, has_video) => flv_header(has_audio, has_video),
+    }
+}
+
+fn flv_tag(frame: &MediaFrame) -> Vec<u8> {
+    FlvTag::new(frame.tag_type, frame.timestamp, frame.data.to_vec()).serialize()
+}
+
+async fn write_chunked<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    subscription: Subscription,
+    path: &str,
+    sent: &mut u64,
+) -> std::io::Result<()> {
+    let Subscription {
+        headers,
+        gop,
+        mut frames,
+    } = subscription;
+
+    let head = cors(HttpResponse::new(200))
+        .header("Content-Type", "video/x-flv")
+        .header("Cache-Control", "no-cache");
+    writer.write_all(&head.chunked_head()).await?;
+    let header = file_header(&headers);
+    writer.write_all(&chunk(&header)).await?;
+    *sent += header.len() as u64;
+    for frame in headers.iter().chain(&gop) {
+        let tag = flv_tag(frame);
+        writer.write_all(&chunk(&tag)).await?;
+        *sent += tag.len() as u64;
+    }
+    writer.flush().await?;
+
+    let mut gate = KeyframeGate::new(&headers);
+    loop {
+        let frame = match frames.recv().await {
+            Ok(frame) => frame,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("HTTP-FLV player of {} missed {} frames", path, missed);
+                gate.resync();
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if gate.skip(&frame) {
+            continue;
+        }
+        let tag = flv_tag(&frame);
+        writer.write_all(&chunk(&tag)).await?;
+        writer.flush().await?;
+        *sent += tag.len() as u64;
+    }
+
+    writer.write_all(&chunk(&[])).await?;
+    writer.flush().await
+}
+
+// Every FLV tag is a binary message. Frames go through a queue to the writer, so a player
+// that can not keep up fills the queue and gets frames dropped instead of holding up the
+// stream, and pings and close frames are answered while frames are written.
+async fn write_websocket<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    subscription: Subscription,
+    path: &str,
+    sent: &mut u64,
+) -> std::io::Result<()> {
+    let Subscription {
+        headers,
+        gop,
+        mut frames,
+    } = subscription;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let mut messages = vec![Frame::binary(file_header(&headers).to_vec())];
+    messages.extend(
+        headers
+            .iter()
+            .chain(&gop)
+            .map(|frame| Frame::binary(flv_tag(frame))),
+    );
+    for message in messages {
+        writer.write_all(&message.serialize()).await?;
+        *sent += message.payload.len() as u64;
+    }
+    writer.flush().await?;
+
+    let (queue, mut queued) = mpsc::channel::<Frame>(WEBSOCKET_QUEUE);
+    // Ends the connection after the close frame is out, the other halves wait for it
+    let write = async move {
+        while let Some(message) = queued.recv().await {
+            writer.write_all(&message.serialize()).await?;
+            writer.flush().await?;
+            match message.opcode {
+                opcode::CLOSE => break,
+                opcode::BINARY => *sent += message.payload.len() as u64,
+                _ => {}
+            }
+        }
+        Ok(())
+    };
+
+    let replies = queue.clone();
+    let read = async move {
+        loop {
+            let message =
+                match tokio::time::timeout(PING_INTERVAL * 2, Frame::read(&mut reader)).await {
+                    Ok(Ok(message)) => message,
+                    // The player closed the connection or broke the protocol, either way it is gone
+                    Ok(Err(_)) => return Ok(()),
+                    Err(_) => {
+                        let err = format!("WS-FLV player of {} stopped answering", path);
+                        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, err));
+                    }
+                };
+            match message.opcode {
+                opcode::PING => {
+                    let _ = replies
+                        .send(Frame::new(opcode::PONG, message.payload))
+                        .await;
+                }
+                opcode::CLOSE => {
+                    let _ = replies.send(Frame::close(1000)).await;
+                    return std::future::pending().await;
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let forward = async move {
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        ping.tick().await;
+        let mut gate = KeyframeGate::new(&headers);
+        loop {
+            let received = tokio::select! {
+                _ = ping.tick() => {
+                    let _ = queue.send(Frame::new(opcode::PING, Vec::new())).await;
+                    continue;
+                }
+                received = frames.recv() => received,
+            };
+            let frame = match received {
+                Ok(frame) => frame,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("WS-FLV player of {} missed {} frames", path, missed);
+                    gate.resync();
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    let _ = queue.send(Frame::close(1000)).await;
+                    return std::future::pending().await;
+                }
+            };
+            if gate.skip(&frame) {
+                continue;
+            }
+            let message = Frame::binary(flv_tag(&frame));
+            if frame.tag_type == TagType::Script || frame.is_sequence_header() {
+                let _ = queue.send(message).await;
+                continue;
+            }
+            match queue.try_send(message) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "WS-FLV player of {} is too slow, skipping to the next keyframe",
+                        path
+                    );
+                    gate.resync();
+                }
+                Err(TrySendError::Closed(_)) => return Ok(()),
+            }
+        }
+    };
+
+    tokio::select! {
+        result = write => result,
+        result = read => result,
+        result = forward => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, stream_path};
+    use crate::config::Config;
+    use crate::http::request::HttpRequest;
+    use crate::http::websocket::{opcode, Frame};
+    use crate::media::flv::TagType;
+    use crate::server::server::ServerContext;
+    use crate::stream::MediaFrame;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn request(method: &str, path: &str) -> HttpRequest {
+        HttpRequest::parse(&format!("{} {} HTTP/1.1\r\n\r\n", method, path)).unwrap()
+    }
+
+    // The body of a chunked response
+    fn dechunk(mut data: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        loop {
+            let line_end = data.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size =
+                usize::from_str_radix(std::str::from_utf8(&data[..line_end]).unwrap(), 16).unwrap();
+            if size == 0 {
+                return body;
+            }
+            body.extend_from_slice(&data[line_end + 2..line_end + 2 + size]);
+            data = &data[line_end + 4 + size..];
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_flv() {
+        assert_eq!(
+            stream_path(&request("GET", "/live/key.flv")).as_deref(),
+            Some("live/key")
+        );
+        assert_eq!(stream_path(&request("GET", "/live/key.mp4")), None);
+        assert_eq!(stream_path(&request("GET", "/api/live/key.flv")), None);
+
+        let context = ServerContext::new(Config::default());
+        let (mut client, mut server) = tokio::io::duplex(1 << 16);
+        serve(
+            &mut server,
+            &request("GET", "/live/key.flv"),
+            "live/key",
+            "",
+            &context,
+        )
+        .await
+        .unwrap();
+        let mut response = Vec::new();
+        drop(server);
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 404"));
+
+        let guard = context.streams.claim_publish("live", "key").unwrap();
+        let sequence_header = MediaFrame::new(TagType::Video, 0, vec![0x17, 0x00, 0, 0, 0, 0x01]);
+        let keyframe = MediaFrame::new(TagType::Video, 40, vec![0x17, 0x01, 0, 0, 0, 0xaa]);
+        guard.publish_frame(sequence_header);
+        guard.publish_frame(keyframe);
+
+        let (mut client, mut server) = tokio::io::duplex(1 << 16);
+        let served = context.clone();
+        let player = tokio::spawn(async move {
+            let request = request("GET", "/live/key.flv");
+            serve(&mut server, &request, "live/key", "127.0.0.1:1", &served).await
+        });
+        while context.streams.players("live/key").is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(context.streams.players("live/key")[0].protocol, "http-flv");
+        guard.publish_frame(MediaFrame::new(
+            TagType::Video,
+            80,
+            vec![0x27, 0x01, 0, 0, 0],
+        ));
+        drop(guard);
+        player.await.unwrap().unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = std::str::from_utf8(&response[..head_end]).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(head.contains("Access-Control-Allow-Origin: *\r\n"));
+
+        assert!(!head.contains("Content-Length"));
+
+        // Video only header, then the sequence header, the cached keyframe and the live frame
+        let body = dechunk(&response[head_end..]);
+        assert_eq!(&body[..5], b"FLV\x01\x01");
+        let tags = &body[13..];
+        assert_eq!(tags.len(), 3 * 15 + 6 + 6 + 5);
+        assert_eq!(&tags[21..28], &[0x09, 0x00, 0x00, 0x06, 0x00, 0x00, 40]);
+    }
+
+    // Server frames are unmasked and, in this test, short
+    async fn read_frame(client: &mut tokio::io::DuplexStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        client.read_exact(&mut header).await.unwrap();
+        assert!(header[1] < 126);
+        let mut payload = vec![0u8; header[1] as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (header[0] & 0x0f, payload)
+    }
+
+    #[tokio::test]
+    async fn test_websocket_flv() {
+        let context = ServerContext::new(Config::default());
+        let guard = context.streams.claim_publish("live", "key").unwrap();
+        guard.publish_frame(MediaFrame::new(
+            TagType::Audio,
+            0,
+            vec![0xaf, 0x00, 0x12, 0x10],
+        ));
+
+        let (mut client, mut server) = tokio::io::duplex(1 << 16);
+        let served = context.clone();
+        let player = tokio::spawn(async move {
+            let request = HttpRequest::parse(
+                "GET /live/key.flv HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+            serve(&mut server, &request, "live/key", "127.0.0.1:1", &served).await
+        });
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        assert_eq!(context.streams.players("live/key")[0].protocol, "ws-flv");
+
+        // Audio only header, then the sequence header
+        let (code, header) = read_frame(&mut client).await;
+        assert_eq!((code, &header[..5]), (opcode::BINARY, &b"FLV\x01\x04"[..]));
+        let (_, tag) = read_frame(&mut client).await;
+        assert_eq!(&tag[..4], &[0x08, 0x00, 0x00, 0x04]);
+
+        // A masked ping is answered with its payload
+        client
+            .write_all(&[0x89, 0x82, 0x01, 0x02, 0x03, 0x04, b'h' ^ 0x01, b'i' ^ 0x02])
+            .await
+            .unwrap();
+        assert_eq!(
+            read_frame(&mut client).await,
+            (opcode::PONG, b"hi".to_vec())
+        );
+
+        guard.publish_frame(MediaFrame::new(TagType::Audio, 23, vec![0xaf, 0x01, 0x21]));
+        let (code, tag) = read_frame(&mut client).await;
+        assert_eq!((code, tag.len()), (opcode::BINARY, 11 + 3 + 4));
+
+        drop(guard);
+        let close = read_frame(&mut client).await;
+        assert_eq!(close, (opcode::CLOSE, Frame::close(1000).payload));
+        player.await.unwrap().unwrap();
+    }
+}
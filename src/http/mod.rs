@@ -0,0 +1,6 @@
+pub mod api;
+pub mod flv;
+pub mod request;
+pub mod response;
+pub mod server;
+pub mod websocket;
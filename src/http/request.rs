@@ -0,0 +1,174 @@
+// This file parses the head of an HTTP/1.1 request: the request line and the headers. None
+// of the HTTP endpoints take a request body, so bodies are never read.
+
+// Path: src/http/request.rs
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Anything longer is not a request we serve
+const MAX_HEAD_SIZE: usize = 8192;
+// A client that stops sending halfway through the head would hold its task forever
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    // Percent decoded, without the query string
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    pub async fn read<R: AsyncRead + Unpin>(
+        reader: &mut R,
+    ) -> Result<HttpRequest, Box<dyn std::error::Error + Send + Sync>> {
+        HttpRequest::read_within(reader, HEAD_TIMEOUT).await
+    }
+
+    async fn read_within<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        timeout: Duration,
+    ) -> Result<HttpRequest, Box<dyn std::error::Error + Send + Sync>> {
+        let head = tokio::time::timeout(timeout, read_head(reader))
+            .await
+            .map_err(|_| "timed out reading the request head")??;
+        HttpRequest::parse(std::str::from_utf8(&head)?)
+    }
+
+    pub fn parse(head: &str) -> Result<HttpRequest, Box<dyn std::error::Error + Send + Sync>> {
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if !method.is_empty() => {
+                (method, target, version)
+            }
+            _ => return Err(format!("malformed request line: {}", request_line).into()),
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(format!("unsupported HTTP version: {}", version).into());
+        }
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_owned())),
+            None => (target, None),
+        };
+
+        let mut headers = Vec::new();
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(format!("malformed header: {}", line))?;
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+
+        Ok(HttpRequest {
+            method: method.to_owned(),
+            path: percent_decode(path),
+            query,
+            headers,
+        })
+    }
+
+    // Header names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.as_deref()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key == name).then(|| percent_decode(value))
+        })
+    }
+
+    // The non-empty segments of the path
+    pub fn segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|s| !s.is_empty()).collect()
+    }
+}
+
+async fn read_head<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut head = Vec::with_capacity(1024);
+    let mut byte = [0u8; 1];
+    // Byte by byte so nothing after the head is consumed
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err("request head too large".into());
+        }
+        if reader.read(&mut byte).await? == 0 {
+            return Err("connection closed before the end of the request head".into());
+        }
+        head.push(byte[0]);
+    }
+    Ok(head)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpRequest;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let mut data: &[u8] =
+            b"GET /api/thumbnails/live/my%20key?size=small HTTP/1.1\r\nHost: localhost\r\nACCEPT: */*\r\n\r\nbody";
+        let request = HttpRequest::read(&mut data).await.unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/api/thumbnails/live/my key");
+        assert_eq!(
+            request.segments(),
+            vec!["api", "thumbnails", "live", "my key"]
+        );
+        assert_eq!(request.query_param("size").as_deref(), Some("small"));
+        assert_eq!(request.header("accept"), Some("*/*"));
+        assert_eq!(data, b"body");
+
+        assert!(HttpRequest::parse("GET /\r\n\r\n").is_err());
+        assert!(HttpRequest::parse("GET / SPDY/3\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_request_timeout() {
+        // The client sends part of the head and then nothing, without closing
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: loc")
+            .await
+            .unwrap();
+        let err = HttpRequest::read_within(&mut server, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "timed out reading the request head");
+    }
+}
@@ -0,0 +1,155 @@
+// This file builds HTTP/1.1 responses. Every response carries a Content-Length, or is chunked
+// when the body is a live stream of unknown length, and closes the connection afterwards,
+// keep-alive is not supported.
+
+// Path: src/http/response.rs
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn ok(content_type: &str, body: Vec<u8>) -> HttpResponse {
+        HttpResponse::new(200).body(content_type, body)
+    }
+
+    pub fn json(value: &serde_json::Value) -> HttpResponse {
+        HttpResponse::ok("application/json", value.to_string().into_bytes())
+    }
+
+    pub fn not_found() -> HttpResponse {
+        HttpResponse::new(404).body("text/plain", b"Not Found".to_vec())
+    }
+
+    pub fn method_not_allowed(allow: &str) -> HttpResponse {
+        HttpResponse::new(405)
+            .header("Allow", allow)
+            .body("text/plain", b"Method Not Allowed".to_vec())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> HttpResponse {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body(self, content_type: &str, body: Vec<u8>) -> HttpResponse {
+        let mut response = self.header("Content-Type", content_type);
+        response.body = body;
+        response
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = self.head(Some(self.body.len()));
+        data.extend_from_slice(&self.body);
+        data
+    }
+
+    /// The status line and headers of a response whose body follows as chunks.
+    pub fn chunked_head(&self) -> Vec<u8> {
+        self.head(None)
+    }
+
+    /// The status line and headers alone, for a connection that switches protocols.
+    pub fn upgrade_head(&self) -> Vec<u8> {
+        let mut head = self.status_and_headers();
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    fn head(&self, content_length: Option<usize>) -> Vec<u8> {
+        let mut head = self.status_and_headers();
+        match content_length {
+            Some(length) => head.push_str(&format!("Content-Length: {}\r\n", length)),
+            None => head.push_str("Transfer-Encoding: chunked\r\n"),
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head.into_bytes()
+    }
+
+    fn status_and_headers(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.serialize()).await?;
+        writer.flush().await
+    }
+}
+
+/// A chunk of a chunked body, an empty one ends the body.
+pub fn chunk(data: &[u8]) -> Vec<u8> {
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk, HttpResponse};
+
+    #[test]
+    fn test_serialize() {
+        let response = HttpResponse::ok("text/plain", b"hello".to_vec()).header("X-Test", "1");
+        assert_eq!(
+            response.serialize(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nX-Test: 1\r\n\
+              Content-Length: 5\r\nConnection: close\r\n\r\nhello"
+        );
+
+        let response = HttpResponse::method_not_allowed("GET");
+        let data = String::from_utf8(response.serialize()).unwrap();
+        assert!(data.starts_with("HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\n"));
+        assert!(data.ends_with("\r\n\r\nMethod Not Allowed"));
+
+        // Statuses without a reason phrase of their own are still sent
+        assert!(HttpResponse::new(418)
+            .serialize()
+            .starts_with(b"HTTP/1.1 418 Unknown\r\n"));
+    }
+
+    #[test]
+    fn test_chunked() {
+        let response = HttpResponse::new(200).header("Content-Type", "video/x-flv");
+        assert_eq!(
+            response.chunked_head(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: video/x-flv\r\n\
+              Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+        );
+
+        // The size is in hex and the terminating chunk is empty
+        assert_eq!(chunk(&[0; 26])[..4], *b"1a\r\n");
+        assert_eq!(chunk(&[0; 26]).len(), 4 + 26 + 2);
+        assert_eq!(chunk(b"abc"), b"3\r\nabc\r\n");
+        assert_eq!(chunk(&[]), b"0\r\n\r\n");
+    }
+}
@@ -0,0 +1,65 @@
+// This file runs the HTTP server next to the RTMP one. Each connection serves a single
+// request with the same shared context as the RTMP connections: live FLV streams for web
+// players, everything else is routed by the API module.
+
+// Path: src/http/server.rs
+use crate::http::request::HttpRequest;
+use crate::http::response::HttpResponse;
+use crate::http::{api, flv};
+use crate::server::server::ServerContext;
+use log::{info, warn};
+use tokio::net::{TcpListener, TcpStream};
+
+pub struct HttpServer {
+    address: String,
+    context: ServerContext,
+}
+
+impl HttpServer {
+    pub fn new(context: ServerContext) -> HttpServer {
+        HttpServer {
+            address: context.config.http.address.clone(),
+            context,
+        }
+    }
+
+    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(&self.address).await?;
+        info!("HTTP listening on {}", self.address);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let context = self.context.clone();
+            tokio::spawn(async move {
+                if let Err(err) = Self::handle_connection(stream, &peer.to_string(), context).await
+                {
+                    warn!("HTTP request from {} failed: {}", peer, err);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(
+        mut stream: TcpStream,
+        peer: &str,
+        context: ServerContext,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let request = match HttpRequest::read(&mut stream).await {
+            Ok(request) => request,
+            Err(err) => {
+                warn!("Bad HTTP request: {}", err);
+                HttpResponse::new(400).write_to(&mut stream).await?;
+                return Ok(());
+            }
+        };
+        info!("HTTP {} {}", request.method, request.path);
+        if let Some(path) = flv::stream_path(&request) {
+            flv::serve(&mut stream, &request, &path, peer, &context).await?;
+            return Ok(());
+        }
+        api::route(&request, &context)
+            .await
+            .write_to(&mut stream)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+// This file implements the parts of WebSocket (RFC 6455) the server needs: the opening
+// handshake on top of an HTTP request, and reading and writing frames. Frames from clients
+// are masked, frames from the server are not.
+
+// Path: src/http/websocket.rs
+use crate::http::request::HttpRequest;
+use crate::http::response::HttpResponse;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Clients have no business sending more than a close reason or a ping payload
+const MAX_CLIENT_PAYLOAD: u64 = 64 * 1024;
+
+pub mod opcode {
+    pub const CONTINUATION: u8 = 0x0;
+    pub const TEXT: u8 = 0x1;
+    pub const BINARY: u8 = 0x2;
+    pub const CLOSE: u8 = 0x8;
+    pub const PING: u8 = 0x9;
+    pub const PONG: u8 = 0xa;
+}
+
+/// Whether the request asks to switch the connection to WebSocket.
+pub fn is_upgrade(request: &HttpRequest) -> bool {
+    let upgrade = request
+        .header("Upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let connection = request.header("Connection").is_some_and(|connection| {
+        connection
+            .split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    upgrade && connection
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+/// The 101 response that completes the handshake, None if the request is not a valid one.
+pub fn handshake(request: &HttpRequest) -> Option<HttpResponse> {
+    if request.method != "GET" || !is_upgrade(request) {
+        return None;
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return None;
+    }
+    let key = request.header("Sec-WebSocket-Key")?;
+    Some(
+        HttpResponse::new(101)
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Accept", &accept_key(key)),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: u8, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            opcode,
+            payload,
+        }
+    }
+
+    pub fn binary(payload: Vec<u8>) -> Frame {
+        Frame::new(opcode::BINARY, payload)
+    }
+
+    // A close frame with a status code, 1000 is a normal closure
+    pub fn close(code: u16) -> Frame {
+        Frame::new(opcode::CLOSE, code.to_be_bytes().to_vec())
+    }
+
+    pub fn is_control(&self) -> bool {
+        self.opcode & 0x8 != 0
+    }
+
+    /// The frame as the server sends it, unmasked.
+    pub fn serialize(&self) -> Vec<u8> {
+        let length = self.payload.len();
+        let mut data = Vec::with_capacity(length + 10);
+        data.push(if self.fin { 0x80 } else { 0x00 } | self.opcode);
+        if length < 126 {
+            data.push(length as u8);
+        } else if length <= u16::MAX as usize {
+            data.push(126);
+            data.extend_from_slice(&(length as u16).to_be_bytes());
+        } else {
+            data.push(127);
+            data.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+        data.extend_from_slice(&self.payload);
+        data
+    }
+
+    /// Reads a frame from a client, which must be masked.
+    pub async fn read<R: AsyncRead + Unpin>(
+        reader: &mut R,
+    ) -> Result<Frame, Box<dyn std::error::Error + Send + Sync>> {
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header).await?;
+        let length = match header[1] & 0x7f {
+            126 => reader.read_u16().await? as u64,
+            127 => reader.read_u64().await?,
+            length => length as u64,
+        };
+        if header[1] & 0x80 == 0 {
+            return Err("unmasked frame from the client".into());
+        }
+        if length > MAX_CLIENT_PAYLOAD {
+            return Err(format!("frame of {} bytes from the client", length).into());
+        }
+        let mut mask = [0u8; 4];
+        reader.read_exact(&mut mask).await?;
+        let mut payload = vec![0u8; length as usize];
+        reader.read_exact(&mut payload).await?;
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+        Ok(Frame {
+            fin: header[0] & 0x80 != 0,
+            opcode: header[0] & 0x0f,
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_key, handshake, opcode, Frame};
+    use crate::http::request::HttpRequest;
+
+    #[tokio::test]
+    async fn test_websocket() {
+        // The examples of RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let mut data: &[u8] = &[
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let frame = Frame::read(&mut data).await.unwrap();
+        assert_eq!(frame, Frame::new(opcode::TEXT, b"Hello".to_vec()));
+        assert!(Frame::read(&mut &[0x81u8, 0x05, b'H'][..]).await.is_err());
+
+        assert_eq!(Frame::close(1000).serialize(), vec![0x88, 0x02, 0x03, 0xe8]);
+        let frame = Frame::binary(vec![0; 256]).serialize();
+        assert_eq!(&frame[..4], &[0x82, 126, 0x01, 0x00]);
+
+        let request = HttpRequest::parse(
+            "GET /live/key.flv HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+        let head = String::from_utf8(handshake(&request).unwrap().upgrade_head()).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!head.contains("Content-Length"));
+    }
+}
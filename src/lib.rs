@@ -0,0 +1,24 @@
+// This file exposes the server modules as a library so they can be shared by the binary, benchmarks and integration tests.
+
+// Path: src/lib.rs
+// server::server and connection::connection mirror the file layout of the crate
+#![allow(clippy::module_inception)]
+
+pub mod client;
+pub mod config;
+pub mod dash;
+pub mod hls;
+pub mod http;
+pub mod llhls;
+pub mod media;
+pub mod output;
+pub mod protocol;
+pub mod record;
+pub mod relay;
+pub mod segmenter;
+pub mod server;
+pub mod stream;
+pub mod thumbnail;
+pub mod tls;
+pub mod utils;
+pub mod vod;
@@ -0,0 +1,697 @@
+// This file writes the low-latency HLS output of published streams. Segments are cut at
+// keyframes like the HLS output, but are CMAF fragments that are themselves split into short
+// parts, which are listed in the playlist as soon as they are written. The latest playlist of
+// every live stream is also kept in memory, so the HTTP server can hold a blocking playlist
+// reload (_HLS_msn and _HLS_part) or a request for the hinted next part until it is ready.
+
+// Path: src/llhls.rs
+use crate::config::LlHlsConfig;
+use crate::media::flv::{AudioTag, TagType, VideoTag};
+use crate::media::mp4::{init_segment, media_fragment, Track, TrackConfig, TrackRun};
+use crate::output::{run_sinks, MediaSink};
+use crate::segmenter::{SampleQueue, Segmenter};
+use crate::server::server::ServerContext;
+use crate::stream::MediaFrame;
+use crate::utils::sanitize;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+pub const PLAYLIST: &str = "index.m3u8";
+pub const INIT_SEGMENT: &str = "init.mp4";
+
+/// The directory the low-latency HLS output of a stream is written to.
+pub fn stream_directory(config: &LlHlsConfig, app: &str, stream: &str) -> PathBuf {
+    config.directory.join(sanitize(app)).join(sanitize(stream))
+}
+
+fn segment_name(sequence: u64) -> String {
+    format!("{}.m4s", sequence)
+}
+
+fn part_name(sequence: u64, index: usize) -> String {
+    format!("{}.{}.m4s", sequence, index)
+}
+
+/// The media sequence number and index of a part, from its file name.
+pub fn parse_part_name(name: &str) -> Option<(u64, u32)> {
+    let (sequence, index) = name.strip_suffix(".m4s")?.split_once('.')?;
+    Some((sequence.parse().ok()?, index.parse().ok()?))
+}
+
+/// The playlist of a live stream, and how far it got.
+#[derive(Debug, Clone, Default)]
+pub struct PlaylistState {
+    pub playlist: String,
+    // The segment being written and the number of its parts that are done
+    pub sequence: u64,
+    pub parts: u32,
+    pub target_duration: u32,
+    pub ended: bool,
+}
+
+impl PlaylistState {
+    /// Whether the playlist lists the segment, or the part of it when there is one.
+    pub fn contains(&self, sequence: u64, part: Option<u32>) -> bool {
+        self.ended
+            || self.sequence > sequence
+            || part.is_some_and(|part| self.sequence == sequence && self.parts > part)
+    }
+}
+
+/// What a blocking request gets.
+#[derive(Debug)]
+pub enum Reload {
+    Ready(PlaylistState),
+    // The stream is not written right now, the files on disk are all there is
+    NotLive,
+    // More than two segments ahead, which the playlist will not get to any time soon
+    TooFar,
+    TimedOut,
+}
+
+/// The playlists of the live streams, shared between the writers and the HTTP server.
+#[derive(Debug, Clone, Default)]
+pub struct LlHlsStreams {
+    streams: Arc<Mutex<HashMap<String, watch::Receiver<PlaylistState>>>>,
+}
+
+impl LlHlsStreams {
+    pub fn new() -> LlHlsStreams {
+        LlHlsStreams::default()
+    }
+
+    fn insert(&self, path: &str) -> watch::Sender<PlaylistState> {
+        let (sender, receiver) = watch::channel(PlaylistState::default());
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), receiver);
+        sender
+    }
+
+    // Only the writer that inserted the stream removes it, a new publish may have replaced it
+    fn remove(&self, path: &str, sender: &watch::Sender<PlaylistState>) {
+        let mut streams = self.streams.lock().unwrap();
+        if streams
+            .get(path)
+            .is_some_and(|receiver| receiver.same_channel(&sender.subscribe()))
+        {
+            streams.remove(path);
+        }
+    }
+
+    /// Waits up to three target durations for the playlist of a stream to contain the
+    /// segment, or the part of it, right away without one.
+    pub async fn wait_for(&self, path: &str, sequence: Option<u64>, part: Option<u32>) -> Reload {
+        let receiver = self.streams.lock().unwrap().get(path).cloned();
+        let Some(mut receiver) = receiver else {
+            return Reload::NotLive;
+        };
+        let state = receiver.borrow().clone();
+        // Nothing written yet
+        if state.playlist.is_empty() && !state.ended {
+            return Reload::NotLive;
+        }
+        let Some(sequence) = sequence else {
+            return Reload::Ready(state);
+        };
+        if sequence > state.sequence + 2 && !state.ended {
+            return Reload::TooFar;
+        }
+        let timeout = Duration::from_secs(state.target_duration.max(1) as u64 * 3);
+        let waited =
+            tokio::time::timeout(timeout, receiver.wait_for(|s| s.contains(sequence, part))).await;
+        match waited {
+            Ok(Ok(state)) => Reload::Ready(state.clone()),
+            // The writer went away
+            Ok(Err(_)) => Reload::NotLive,
+            Err(_) => Reload::TimedOut,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Part {
+    duration_ms: u64,
+    // Starts with a keyframe
+    independent: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    sequence: u64,
+    parts: Vec<Part>,
+}
+
+impl Segment {
+    fn duration_ms(&self) -> u64 {
+        self.parts.iter().map(|part| part.duration_ms).sum()
+    }
+}
+
+// The segment being written, its parts are appended to the file as they are cut
+struct OpenSegment {
+    segment: Segment,
+    part_start: u64,
+    file: File,
+}
+
+/// Writes one published stream as low-latency HLS, from the frames of a subscription.
+pub struct LlHlsWriter {
+    config: LlHlsConfig,
+    directory: PathBuf,
+    stream_path: String,
+    streams: LlHlsStreams,
+    state: watch::Sender<PlaylistState>,
+    segmenter: Segmenter,
+    part_target_ms: u64,
+    video_config: Option<TrackConfig>,
+    audio_config: Option<TrackConfig>,
+    video: Option<SampleQueue>,
+    audio: Option<SampleQueue>,
+    // The timestamp the presentation started at, once it has
+    origin: Option<u32>,
+    // Decode time of the last frame of the track parts are cut on
+    last_frame: Option<u64>,
+    current: Option<OpenSegment>,
+    segments: VecDeque<Segment>,
+    // Out of the playlist but not yet deleted
+    retired: VecDeque<Segment>,
+    next_sequence: u64,
+    fragments: u32,
+    target_duration: u32,
+}
+
+impl LlHlsWriter {
+    pub fn new(config: &LlHlsConfig, stream_path: &str, streams: &LlHlsStreams) -> LlHlsWriter {
+        let (app, stream) = stream_path.split_once('/').unwrap_or(("", stream_path));
+        let target_ms = (config.segment_duration_secs.max(0.0) * 1000.0) as u32;
+        LlHlsWriter {
+            config: config.clone(),
+            directory: stream_directory(config, app, stream),
+            stream_path: stream_path.to_owned(),
+            streams: streams.clone(),
+            state: streams.insert(stream_path),
+            segmenter: Segmenter::new(target_ms),
+            part_target_ms: (config.part_duration_secs.max(0.001) * 1000.0) as u64,
+            video_config: None,
+            audio_config: None,
+            video: None,
+            audio: None,
+            origin: None,
+            last_frame: None,
+            current: None,
+            segments: VecDeque::new(),
+            retired: VecDeque::new(),
+            next_sequence: 0,
+            fragments: 0,
+            target_duration: config.segment_duration_secs.ceil().max(1.0) as u32,
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    // Decode time in the presentation, audio from just before the first keyframe is moved up
+    fn decode_time(&self, timestamp: u32) -> u64 {
+        let relative = timestamp.wrapping_sub(self.origin.unwrap_or(timestamp));
+        if relative > i32::MAX as u32 {
+            0
+        } else {
+            relative as u64
+        }
+    }
+
+    fn write_video(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        let Ok(tag) = VideoTag::parse(&frame.data) else {
+            return Ok(());
+        };
+        if tag.header.is_sequence_header() {
+            let config = TrackConfig::from_video_tag(&tag)
+                .map_err(|err| warn!("LL-HLS of {} skips the video: {}", self.stream_path, err))
+                .ok();
+            if config != self.video_config {
+                self.restart()?;
+            }
+            self.video_config = config;
+            return Ok(());
+        }
+        if !tag.header.is_coded_frames() || self.video_config.is_none() {
+            return Ok(());
+        }
+
+        let keyframe = tag.header.is_keyframe();
+        self.advance(frame.timestamp, keyframe, true)?;
+        let decode_time = self.decode_time(frame.timestamp);
+        if let Some(video) = &mut self.video {
+            let composition_offset = tag.header.composition_time();
+            video.push(decode_time, composition_offset, keyframe, tag.body);
+        }
+        Ok(())
+    }
+
+    fn write_audio(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        let Ok(tag) = AudioTag::parse(&frame.data) else {
+            return Ok(());
+        };
+        if tag.header.is_sequence_header() {
+            let config = TrackConfig::from_audio_tag(&tag)
+                .map_err(|err| warn!("LL-HLS of {} skips the audio: {}", self.stream_path, err))
+                .ok();
+            if config != self.audio_config {
+                self.restart()?;
+            }
+            self.audio_config = config;
+            return Ok(());
+        }
+        if self.audio_config.is_none() {
+            return Ok(());
+        }
+
+        // Without video any frame can start a segment or a part
+        let audio_only = self.video_config.is_none();
+        self.advance(frame.timestamp, audio_only, audio_only)?;
+        let decode_time = self.decode_time(frame.timestamp);
+        if let Some(audio) = &mut self.audio {
+            audio.push(decode_time, 0, true, tag.body);
+        }
+        Ok(())
+    }
+
+    // Ends the segment or the part before the frame. Parts are cut on the frames of one track,
+    // before the next frame would make them longer than the target.
+    fn advance(&mut self, timestamp: u32, cuttable: bool, cuts_parts: bool) -> std::io::Result<()> {
+        if self.segmenter.is_boundary(timestamp, cuttable) {
+            if self.origin.is_none() {
+                self.start(timestamp)?;
+            }
+            let time = self.decode_time(timestamp);
+            self.last_frame = Some(time);
+            self.end_segment(Some(time))?;
+            return self.open_segment(time);
+        }
+        if !cuts_parts {
+            return Ok(());
+        }
+        let time = self.decode_time(timestamp);
+        let interval = self.last_frame.map_or(0, |last| time.saturating_sub(last));
+        self.last_frame = Some(time);
+        let Some(current) = &self.current else {
+            return Ok(());
+        };
+        if time > current.part_start && time - current.part_start + interval > self.part_target_ms {
+            self.end_part(Some(time))?;
+            self.publish(false)?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, timestamp: u32) -> std::io::Result<()> {
+        let mut tracks = Vec::new();
+        if let Some(config) = &self.video_config {
+            tracks.push(Track::new(1, config.clone()));
+        }
+        if let Some(config) = &self.audio_config {
+            tracks.push(Track::new(tracks.len() as u32 + 1, config.clone()));
+        }
+        if tracks.is_empty() {
+            return Ok(());
+        }
+
+        // Whatever an earlier presentation of the stream left behind
+        match std::fs::remove_dir_all(&self.directory) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        std::fs::create_dir_all(&self.directory)?;
+        std::fs::write(self.directory.join(INIT_SEGMENT), init_segment(&tracks))?;
+        info!(
+            "Writing LL-HLS of {} to {}",
+            self.stream_path,
+            self.directory.display()
+        );
+        let has_video = self.video_config.is_some();
+        self.video = has_video.then(|| SampleQueue::new(1));
+        self.audio = (self.audio_config.is_some()).then(|| SampleQueue::new(tracks.len() as u32));
+        self.origin = Some(timestamp);
+        Ok(())
+    }
+
+    fn open_segment(&mut self, start: u64) -> std::io::Result<()> {
+        if self.origin.is_none() {
+            return Ok(());
+        }
+        let sequence = self.next_sequence;
+        let file = File::create(self.directory.join(segment_name(sequence)))?;
+        self.next_sequence += 1;
+        self.current = Some(OpenSegment {
+            segment: Segment {
+                sequence,
+                parts: Vec::new(),
+            },
+            part_start: start,
+            file,
+        });
+        self.publish(false)
+    }
+
+    // Writes the samples before the end as a part, all of them without one
+    fn end_part(&mut self, end: Option<u64>) -> std::io::Result<()> {
+        let Some(current) = &mut self.current else {
+            return Ok(());
+        };
+        let has_video = self.video.is_some();
+        let video = self
+            .video
+            .as_mut()
+            .and_then(|video| video.take_run(end, end));
+        // Audio does not end exactly where the video does
+        let audio = self
+            .audio
+            .as_mut()
+            .and_then(|audio| audio.take_run(end, end.filter(|_| !has_video)));
+        let independent = match &video {
+            Some(run) => run.samples.first().is_some_and(|sample| sample.keyframe),
+            None => !has_video,
+        };
+        let runs: Vec<TrackRun> = video.into_iter().chain(audio).collect();
+        let Some(first) = runs.first() else {
+            return Ok(());
+        };
+        let end = end.unwrap_or_else(|| {
+            let duration: u64 = first.samples.iter().map(|s| s.duration as u64).sum();
+            first.base_decode_time + duration
+        });
+
+        self.fragments += 1;
+        let fragment = media_fragment(self.fragments, &runs);
+        let index = current.segment.parts.len();
+        let name = part_name(current.segment.sequence, index);
+        std::fs::write(self.directory.join(name), &fragment)?;
+        current.file.write_all(&fragment)?;
+        current.segment.parts.push(Part {
+            duration_ms: end.saturating_sub(current.part_start),
+            independent,
+        });
+        current.part_start = end;
+        Ok(())
+    }
+
+    fn end_segment(&mut self, end: Option<u64>) -> std::io::Result<()> {
+        self.end_part(end)?;
+        let Some(current) = self.current.take() else {
+            return Ok(());
+        };
+        let segment = current.segment;
+        if segment.parts.is_empty() {
+            drop(current.file);
+            let _ = std::fs::remove_file(self.directory.join(segment_name(segment.sequence)));
+            return Ok(());
+        }
+        let duration_secs = (segment.duration_ms() as f64 / 1000.0).round() as u32;
+        self.target_duration = self.target_duration.max(duration_secs);
+        self.segments.push_back(segment);
+
+        let length = self.config.playlist_length.max(1);
+        while self.segments.len() > length {
+            if let Some(segment) = self.segments.pop_front() {
+                self.retired.push_back(segment);
+            }
+        }
+        while self.retired.len() > self.config.playlist_length {
+            if let Some(segment) = self.retired.pop_front() {
+                let _ = std::fs::remove_file(self.directory.join(segment_name(segment.sequence)));
+                for index in 0..segment.parts.len() {
+                    let name = part_name(segment.sequence, index);
+                    let _ = std::fs::remove_file(self.directory.join(name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The tracks changed, the presentation ends and the next keyframe starts a new one
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.finish()?;
+        self.origin = None;
+        self.last_frame = None;
+        self.video = None;
+        self.audio = None;
+        self.segments.clear();
+        self.retired.clear();
+        self.next_sequence = 0;
+        self.segmenter.reset();
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.origin.is_none() {
+            return Ok(());
+        }
+        self.end_segment(None)?;
+        self.publish(true)
+    }
+
+    fn playlist(&self, ended: bool) -> String {
+        let part_target = self.part_target_ms as f64 / 1000.0;
+        let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:6\n");
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", self.target_duration);
+        let _ = writeln!(
+            playlist,
+            "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+            part_target * 3.0
+        );
+        let _ = writeln!(playlist, "#EXT-X-PART-INF:PART-TARGET={:.3}", part_target);
+        let open = self.current.as_ref().map(|current| &current.segment);
+        let first = self.segments.front().or(open).map_or(0, |s| s.sequence);
+        let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", first);
+        let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"{}\"", INIT_SEGMENT);
+
+        // Parts are only listed for the last three target durations
+        let window = self.target_duration as u64 * 3000;
+        let mut after = open.map_or(0, Segment::duration_ms);
+        let mut with_parts = vec![false; self.segments.len()];
+        for (index, segment) in self.segments.iter().enumerate().rev() {
+            with_parts[index] = after < window;
+            after += segment.duration_ms();
+        }
+        for (segment, with_parts) in self.segments.iter().zip(with_parts) {
+            if with_parts {
+                write_parts(&mut playlist, segment);
+            }
+            let duration = segment.duration_ms() as f64 / 1000.0;
+            let _ = writeln!(playlist, "#EXTINF:{:.3},", duration);
+            playlist.push_str(&segment_name(segment.sequence));
+            playlist.push('\n');
+        }
+        if let Some(open) = open {
+            write_parts(&mut playlist, open);
+            if !ended {
+                let hint = part_name(open.sequence, open.parts.len());
+                let _ = writeln!(playlist, "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}\"", hint);
+            }
+        }
+        if ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+        playlist
+    }
+
+    // Writes the playlist and lets the requests waiting for it go
+    fn publish(&self, ended: bool) -> std::io::Result<()> {
+        let playlist = self.playlist(ended);
+        // Players never read a half written playlist
+        let path = self.directory.join(PLAYLIST);
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, &playlist)?;
+        std::fs::rename(&temporary, &path)?;
+
+        let (sequence, parts) = match &self.current {
+            Some(current) => (current.segment.sequence, current.segment.parts.len() as u32),
+            None => (self.next_sequence, 0),
+        };
+        self.state.send_replace(PlaylistState {
+            playlist,
+            sequence,
+            parts,
+            target_duration: self.target_duration,
+            ended,
+        });
+        Ok(())
+    }
+}
+
+fn write_parts(playlist: &mut String, segment: &Segment) {
+    for (index, part) in segment.parts.iter().enumerate() {
+        let _ = write!(
+            playlist,
+            "#EXT-X-PART:DURATION={:.3},URI=\"{}\"",
+            part.duration_ms as f64 / 1000.0,
+            part_name(segment.sequence, index)
+        );
+        if part.independent {
+            playlist.push_str(",INDEPENDENT=YES");
+        }
+        playlist.push('\n');
+    }
+}
+
+impl MediaSink for LlHlsWriter {
+    fn write_frame(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        match frame.tag_type {
+            TagType::Video => self.write_video(frame),
+            TagType::Audio => self.write_audio(frame),
+            TagType::Script => Ok(()),
+        }
+    }
+
+    // Samples are missing, the pending ones are dropped and the next keyframe cuts
+    fn discontinuity(&mut self) {
+        for samples in [&mut self.video, &mut self.audio].into_iter().flatten() {
+            samples.clear();
+        }
+        self.segmenter.reset();
+    }
+
+    fn close(&mut self) {
+        if let Err(err) = self.finish() {
+            warn!("Failed to finish the LL-HLS playlist: {}", err);
+        }
+        self.origin = None;
+        self.streams.remove(&self.stream_path, &self.state);
+    }
+}
+
+/// Starts a low-latency HLS writer for every published stream.
+pub struct LlHlsOutput {
+    context: ServerContext,
+}
+
+impl LlHlsOutput {
+    pub fn new(context: ServerContext) -> LlHlsOutput {
+        LlHlsOutput { context }
+    }
+
+    pub async fn run(self) {
+        let config = self.context.config.llhls.clone();
+        let streams = self.context.llhls.clone();
+        run_sinks("LL-HLS", self.context.streams.clone(), move |path| {
+            Some(LlHlsWriter::new(&config, path, &streams))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_part_name, LlHlsStreams, LlHlsWriter, Reload, PLAYLIST};
+    use crate::config::LlHlsConfig;
+    use crate::media::avc;
+    use crate::media::flv::TagType;
+    use crate::output::MediaSink;
+    use crate::stream::MediaFrame;
+
+    #[tokio::test]
+    async fn test_parts_and_blocking_reload() {
+        let config = LlHlsConfig {
+            enabled: true,
+            directory: std::env::temp_dir().join(format!("llhls-{}", std::process::id())),
+            segment_duration_secs: 1.0,
+            part_duration_secs: 0.3,
+            playlist_length: 2,
+        };
+        let streams = LlHlsStreams::new();
+        let mut writer = LlHlsWriter::new(&config, "live/key", &streams);
+        let directory = writer.directory().to_path_buf();
+        assert!(matches!(
+            streams.wait_for("live/key", None, None).await,
+            Reload::NotLive
+        ));
+
+        let sequence_header = avc::fixtures::sequence_header();
+        let video =
+            |timestamp, data: &[u8]| MediaFrame::new(TagType::Video, timestamp, data.to_vec());
+        writer.write_frame(&video(0, &sequence_header)).unwrap();
+        writer
+            .write_frame(&MediaFrame::new(
+                TagType::Audio,
+                0,
+                vec![0xaf, 0x00, 0x12, 0x10],
+            ))
+            .unwrap();
+
+        // 10 fps with a keyframe every 500 ms, so segments of 1 s in parts of 300 ms and the rest
+        for index in 0..25 {
+            let timestamp = index * 100;
+            let frame_type = if index % 5 == 0 { 0x17 } else { 0x27 };
+            writer
+                .write_frame(&video(timestamp, &[frame_type, 0x01, 0, 0, 0, 0xaa]))
+                .unwrap();
+            writer
+                .write_frame(&MediaFrame::new(
+                    TagType::Audio,
+                    timestamp,
+                    vec![0xaf, 0x01, 0x21],
+                ))
+                .unwrap();
+        }
+
+        let Reload::Ready(state) = streams.wait_for("live/key", None, None).await else {
+            panic!("the stream is live");
+        };
+        assert_eq!((state.sequence, state.parts), (2, 1));
+        let playlist = std::fs::read_to_string(directory.join(PLAYLIST)).unwrap();
+        assert_eq!(state.playlist, playlist);
+        assert!(
+            playlist.contains("#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=0.900\n")
+        );
+        assert!(playlist.contains("#EXT-X-PART-INF:PART-TARGET=0.300\n#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert!(playlist.contains(
+            "#EXT-X-PART:DURATION=0.300,URI=\"1.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=0.300,URI=\"1.1.m4s\"\n\
+             #EXT-X-PART:DURATION=0.300,URI=\"1.2.m4s\"\n\
+             #EXT-X-PART:DURATION=0.100,URI=\"1.3.m4s\"\n#EXTINF:1.000,\n1.m4s\n"
+        ));
+        assert!(playlist.ends_with(
+            "#EXT-X-PART:DURATION=0.300,URI=\"2.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"2.1.m4s\"\n"
+        ));
+        // A segment is its parts one after the other
+        let parts: Vec<u8> = (0..4)
+            .flat_map(|index| std::fs::read(directory.join(format!("1.{}.m4s", index))).unwrap())
+            .collect();
+        assert_eq!(std::fs::read(directory.join("1.m4s")).unwrap(), parts);
+        assert_eq!(&parts[4..8], b"moof");
+        assert_eq!(parse_part_name("2.1.m4s"), Some((2, 1)));
+
+        // The hinted part is waited for, and the wait ends with the stream
+        assert!(matches!(
+            streams.wait_for("live/key", Some(5), None).await,
+            Reload::TooFar
+        ));
+        let waiting = tokio::spawn({
+            let streams = streams.clone();
+            async move { streams.wait_for("live/key", Some(2), Some(1)).await }
+        });
+        tokio::task::yield_now().await;
+        writer.close();
+        let Reload::Ready(state) = waiting.await.unwrap() else {
+            panic!("the playlist ended");
+        };
+        assert!(state.ended);
+        assert!(state.playlist.ends_with("2.m4s\n#EXT-X-ENDLIST\n"));
+        assert!(matches!(
+            streams.wait_for("live/key", None, None).await,
+            Reload::NotLive
+        ));
+        std::fs::remove_dir_all(&config.directory).unwrap();
+    }
+}
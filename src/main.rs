@@ -1,9 +1,12 @@
 // This file will contain the main function that starts the server. It should be responsible for setting up the server and starting the main event loop.
-mod server;
-
-use crate::server::server::Server;
 use flexi_logger::{FileSpec, Logger, WriteMode};
 use log::info;
+use rustic_rtmp::config::Config;
+use rustic_rtmp::server::server::Server;
+use std::path::Path;
+
+// Passing a path as the first argument loads that config, otherwise config.toml is used if present
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -11,10 +14,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .log_to_file(FileSpec::default().directory("logs").use_timestamp(false))
         .write_mode(WriteMode::BufferAndFlush)
         .start()?;
-    let ip = "0.0.0.0";
-    let port = "1935";
-    let addr = format!("{}:{}", ip, port);
-    let server = Server::new(addr.to_owned());
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::load(Path::new(&path))?,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            Config::load(Path::new(DEFAULT_CONFIG_PATH))?
+        }
+        None => Config::default(),
+    };
+    let addr = config.address.clone();
+    let server = Server::new(config);
     println!("Starting server on {}", addr);
     info!("Starting server");
     server.run().await?;
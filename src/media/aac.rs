@@ -0,0 +1,351 @@
+// This file decodes the AAC sequence header, an AudioSpecificConfig from ISO/IEC 14496-3,
+// and wraps the raw AAC frames that follow it in ADTS headers so they can be muxed into
+// MPEG-TS or written out as a plain .aac file.
+
+// Path: src/media/aac.rs
+use {
+    super::{
+        bits::BitReader,
+        errors::MediaError,
+        flv::{AacPacketType, AudioHeader, AudioTag},
+    },
+    std::io::{self, Write},
+};
+
+pub mod audio_object_type {
+    pub const AAC_MAIN: u8 = 1;
+    pub const AAC_LC: u8 = 2;
+    pub const AAC_SSR: u8 = 3;
+    pub const AAC_LTP: u8 = 4;
+    pub const SBR: u8 = 5;
+    pub const AAC_SCALABLE: u8 = 6;
+    pub const ER_AAC_LC: u8 = 17;
+    pub const ER_AAC_LD: u8 = 23;
+    pub const PS: u8 = 29;
+}
+
+pub const SAMPLING_FREQUENCIES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+// Written in the sampling frequency index field when the frequency follows explicitly
+const EXPLICIT_FREQUENCY_INDEX: u8 = 0x0f;
+const SBR_SYNC_EXTENSION: u32 = 0x2b7;
+const PS_SYNC_EXTENSION: u32 = 0x548;
+
+/// The body of an AAC sequence header tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioSpecificConfig {
+    // The core object type, AAC LC for HE-AAC streams
+    pub object_type: u8,
+    pub sampling_frequency_index: u8,
+    pub sampling_frequency: u32,
+    pub channel_configuration: u8,
+    pub frame_length_flag: bool,
+    // Set when spectral band replication (HE-AAC) is signalled
+    pub sbr: bool,
+    // Set when parametric stereo (HE-AACv2) is signalled
+    pub ps: bool,
+    // Output frequency of the SBR tool, usually twice the core frequency
+    pub extension_sampling_frequency: Option<u32>,
+}
+
+impl AudioSpecificConfig {
+    pub fn parse(data: &[u8]) -> Result<AudioSpecificConfig, MediaError> {
+        let mut reader = BitReader::new(data);
+
+        let mut object_type = read_object_type(&mut reader)?;
+        let (mut sampling_frequency_index, sampling_frequency) =
+            read_sampling_frequency(&mut reader)?;
+        let channel_configuration = reader.read_bits(4)? as u8;
+
+        // Explicit hierarchical signalling, the core config follows the extension frequency
+        let mut sbr = false;
+        let mut ps = false;
+        let mut extension_sampling_frequency = None;
+        if object_type == audio_object_type::SBR || object_type == audio_object_type::PS {
+            sbr = true;
+            ps = object_type == audio_object_type::PS;
+            extension_sampling_frequency = Some(read_sampling_frequency(&mut reader)?.1);
+            object_type = read_object_type(&mut reader)?;
+        }
+
+        let mut frame_length_flag = false;
+        let mut ga_parsed = false;
+        if matches!(object_type, 1..=4 | 6 | 7 | 17 | 19..=23) && channel_configuration != 0 {
+            frame_length_flag = reader.read_flag()?;
+            // depends_on_core_coder and its delay
+            if reader.read_flag()? {
+                reader.skip_bits(14)?;
+            }
+            let extension_flag = reader.read_flag()?;
+            if object_type == audio_object_type::AAC_SCALABLE || object_type == 20 {
+                // layerNr
+                reader.skip_bits(3)?;
+            }
+            if extension_flag {
+                if object_type == 22 {
+                    // numOfSubFrame and layer_length
+                    reader.skip_bits(16)?;
+                }
+                if matches!(object_type, 17 | 19 | 20 | 23) {
+                    // the three resilience flags
+                    reader.skip_bits(3)?;
+                }
+                // extensionFlag3
+                reader.skip_bits(1)?;
+            }
+            ga_parsed = true;
+        }
+
+        // Backward compatible implicit signalling appended to a plain AAC LC config
+        if ga_parsed
+            && !sbr
+            && reader.remaining() >= 16
+            && reader.read_bits(11)? == SBR_SYNC_EXTENSION
+            && read_object_type(&mut reader)? == audio_object_type::SBR
+        {
+            sbr = reader.read_flag()?;
+            if sbr {
+                extension_sampling_frequency = Some(read_sampling_frequency(&mut reader)?.1);
+                if reader.remaining() >= 12 && reader.read_bits(11)? == PS_SYNC_EXTENSION {
+                    ps = reader.read_flag()?;
+                }
+            }
+        }
+
+        // Map an explicitly coded frequency back to its index when it has one, ADTS needs it
+        if sampling_frequency_index == EXPLICIT_FREQUENCY_INDEX {
+            sampling_frequency_index = SAMPLING_FREQUENCIES
+                .iter()
+                .position(|frequency| *frequency == sampling_frequency)
+                .map(|index| index as u8)
+                .unwrap_or(EXPLICIT_FREQUENCY_INDEX);
+        }
+
+        Ok(AudioSpecificConfig {
+            object_type,
+            sampling_frequency_index,
+            sampling_frequency,
+            channel_configuration,
+            frame_length_flag,
+            sbr,
+            ps,
+            extension_sampling_frequency,
+        })
+    }
+
+    // Writes the two byte form without any extensions
+    pub fn serialize(&self) -> Vec<u8> {
+        let value = (self.object_type as u16) << 11
+            | (self.sampling_frequency_index as u16 & 0x0f) << 7
+            | (self.channel_configuration as u16 & 0x0f) << 3
+            | (self.frame_length_flag as u16) << 2;
+        value.to_be_bytes().to_vec()
+    }
+
+    pub fn channels(&self) -> u8 {
+        match self.channel_configuration {
+            7 => 8,
+            channels => channels,
+        }
+    }
+
+    // What a decoder outputs, the SBR rate for HE-AAC
+    pub fn output_sampling_frequency(&self) -> u32 {
+        self.extension_sampling_frequency
+            .unwrap_or(self.sampling_frequency)
+    }
+
+    // e.g. "mp4a.40.2", HE-AAC keeps the core type as players signal SBR implicitly
+    pub fn codec_string(&self) -> String {
+        format!("mp4a.40.{}", self.object_type)
+    }
+
+    /// The 7 byte ADTS header (without CRC) for a raw frame of `frame_len` bytes.
+    pub fn adts_header(&self, frame_len: usize) -> Result<[u8; 7], MediaError> {
+        if !(1..=4).contains(&self.object_type) {
+            return Err(MediaError::invalid_data(format!(
+                "object type {} can not be carried in ADTS",
+                self.object_type
+            )));
+        }
+        if self.sampling_frequency_index >= EXPLICIT_FREQUENCY_INDEX {
+            return Err(MediaError::invalid_data(
+                "ADTS needs one of the indexed sampling frequencies",
+            ));
+        }
+        let len = frame_len + 7;
+        if len > 0x1fff {
+            return Err(MediaError::invalid_data(format!(
+                "AAC frame of {} bytes is too large for ADTS",
+                frame_len
+            )));
+        }
+
+        let profile = self.object_type - 1;
+        let channels = self.channel_configuration & 0x07;
+        Ok([
+            0xff,
+            // MPEG-4, layer 0, no CRC
+            0xf1,
+            profile << 6 | self.sampling_frequency_index << 2 | channels >> 2,
+            (channels & 0x03) << 6 | (len >> 11) as u8,
+            (len >> 3) as u8,
+            // buffer fullness 0x7ff means variable bit rate
+            ((len & 0x07) as u8) << 5 | 0x1f,
+            0xfc,
+        ])
+    }
+
+    pub fn to_adts(&self, frame: &[u8]) -> Result<Vec<u8>, MediaError> {
+        let mut data = Vec::with_capacity(frame.len() + 7);
+        data.extend_from_slice(&self.adts_header(frame.len())?);
+        data.extend_from_slice(frame);
+        Ok(data)
+    }
+}
+
+fn read_object_type(reader: &mut BitReader) -> Result<u8, MediaError> {
+    let object_type = reader.read_bits(5)? as u8;
+    if object_type == 31 {
+        Ok(32 + reader.read_bits(6)? as u8)
+    } else {
+        Ok(object_type)
+    }
+}
+
+fn read_sampling_frequency(reader: &mut BitReader) -> Result<(u8, u32), MediaError> {
+    let index = reader.read_bits(4)? as u8;
+    if index == EXPLICIT_FREQUENCY_INDEX {
+        return Ok((index, reader.read_bits(24)?));
+    }
+    let frequency = SAMPLING_FREQUENCIES
+        .get(index as usize)
+        .ok_or(MediaError::unknown_value(
+            "sampling frequency index",
+            index as u32,
+        ))?;
+    Ok((index, *frequency))
+}
+
+/// Turns the AAC audio payloads of a stream into an ADTS stream, e.g. to dump a clean
+/// .aac file of what a publisher sends.
+pub struct AdtsWriter<W: Write> {
+    writer: W,
+    config: Option<AudioSpecificConfig>,
+}
+
+impl<W: Write> AdtsWriter<W> {
+    pub fn new(writer: W) -> AdtsWriter<W> {
+        AdtsWriter {
+            writer,
+            config: None,
+        }
+    }
+
+    pub fn config(&self) -> Option<&AudioSpecificConfig> {
+        self.config.as_ref()
+    }
+
+    // Takes whole audio payloads, frames before the first sequence header are dropped
+    pub fn write_audio(&mut self, data: &[u8]) -> io::Result<()> {
+        let tag = AudioTag::parse(data).map_err(io::Error::other)?;
+        let packet_type = match tag.header {
+            AudioHeader::Legacy(header) => header.aac_packet_type,
+            AudioHeader::Enhanced(_) => None,
+        };
+
+        match packet_type {
+            Some(AacPacketType::SequenceHeader) => {
+                self.config = Some(AudioSpecificConfig::parse(tag.body).map_err(io::Error::other)?);
+            }
+            Some(AacPacketType::Raw) => {
+                if let Some(config) = &self.config {
+                    let header = config
+                        .adts_header(tag.body.len())
+                        .map_err(io::Error::other)?;
+                    self.writer.write_all(&header)?;
+                    self.writer.write_all(tag.body)?;
+                }
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not an AAC audio payload",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_aac_lc() {
+        // AAC LC, 44.1 kHz, stereo
+        let config = AudioSpecificConfig::parse(&[0x12, 0x10]).unwrap();
+        assert_eq!(config.object_type, audio_object_type::AAC_LC);
+        assert_eq!(config.sampling_frequency, 44100);
+        assert_eq!(config.channels(), 2);
+        assert!(!config.sbr && !config.ps);
+        assert_eq!(config.codec_string(), "mp4a.40.2");
+        assert_eq!(config.serialize(), vec![0x12, 0x10]);
+    }
+
+    #[test]
+    fn test_parse_he_aac() {
+        // Explicit: SBR, 24 kHz core, stereo, 48 kHz extension, AAC LC core
+        let config = AudioSpecificConfig::parse(&[0x2b, 0x11, 0x88, 0x00]).unwrap();
+        assert_eq!(config.object_type, audio_object_type::AAC_LC);
+        assert_eq!(config.sampling_frequency, 24000);
+        assert!(config.sbr);
+        assert!(!config.ps);
+        assert_eq!(config.output_sampling_frequency(), 48000);
+
+        // Implicit: AAC LC 24 kHz mono followed by the SBR and PS sync extensions
+        let config =
+            AudioSpecificConfig::parse(&[0x13, 0x08, 0x56, 0xe5, 0x9d, 0x48, 0x80]).unwrap();
+        assert_eq!(config.channels(), 1);
+        assert!(config.sbr);
+        assert!(config.ps);
+        assert_eq!(config.output_sampling_frequency(), 48000);
+    }
+
+    #[test]
+    fn test_adts_header() {
+        let config = AudioSpecificConfig::parse(&[0x12, 0x10]).unwrap();
+        let adts = config.to_adts(&[0u8; 100]).unwrap();
+        assert_eq!(&adts[..7], &[0xff, 0xf1, 0x50, 0x80, 0x0d, 0x7f, 0xfc]);
+        assert_eq!(adts.len(), 107);
+
+        // frame_length is 13 bits spread over three bytes
+        let header = config.adts_header(8184).unwrap();
+        let len = ((header[3] as usize & 0x03) << 11)
+            | ((header[4] as usize) << 3)
+            | (header[5] as usize >> 5);
+        assert_eq!(len, 8191);
+        assert!(config.adts_header(8185).is_err());
+    }
+
+    #[test]
+    fn test_adts_writer() {
+        let mut writer = AdtsWriter::new(Vec::new());
+        // Frames before the sequence header have nothing to describe them
+        writer.write_audio(&[0xaf, 0x01, 0xaa]).unwrap();
+        writer.write_audio(&[0xaf, 0x00, 0x12, 0x10]).unwrap();
+        writer.write_audio(&[0xaf, 0x01, 0xbb, 0xcc]).unwrap();
+        assert!(writer.write_audio(&[0x2f, 0x00]).is_err());
+
+        let data = writer.into_inner();
+        assert_eq!(data.len(), 9);
+        assert_eq!(&data[7..], &[0xbb, 0xcc]);
+    }
+}
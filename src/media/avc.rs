@@ -0,0 +1,643 @@
+// This file decodes the H.264 sequence header sent before any coded frames: the
+// AVCDecoderConfigurationRecord from ISO/IEC 14496-15 and the sequence parameter sets it
+// carries, which describe the coded picture size, cropping, frame rate and chroma format.
+
+// Path: src/media/avc.rs
+use super::{bits::BitReader, errors::MediaError, nal::remove_emulation_prevention};
+
+pub mod nal_unit_type {
+    pub const NON_IDR: u8 = 1;
+    pub const IDR: u8 = 5;
+    pub const SEI: u8 = 6;
+    pub const SPS: u8 = 7;
+    pub const PPS: u8 = 8;
+    pub const AUD: u8 = 9;
+}
+
+/// The body of an AVC sequence header tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvcDecoderConfigurationRecord {
+    pub configuration_version: u8,
+    pub profile_indication: u8,
+    pub profile_compatibility: u8,
+    pub level_indication: u8,
+    // Size of the length prefix in front of every NAL unit of the coded frames
+    pub nal_length_size: u8,
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+}
+
+impl AvcDecoderConfigurationRecord {
+    pub fn parse(data: &[u8]) -> Result<AvcDecoderConfigurationRecord, MediaError> {
+        if data.len() < 6 {
+            return Err(MediaError::not_enough_data(6, data.len()));
+        }
+        if data[0] != 1 {
+            return Err(MediaError::unknown_value(
+                "AVC configuration version",
+                data[0] as u32,
+            ));
+        }
+
+        let mut position = 5;
+        let sps_count = (data[position] & 0x1f) as usize;
+        position += 1;
+        let sps = read_parameter_sets(data, &mut position, sps_count)?;
+
+        let pps_count = *data
+            .get(position)
+            .ok_or(MediaError::not_enough_data(position + 1, data.len()))?
+            as usize;
+        position += 1;
+        let pps = read_parameter_sets(data, &mut position, pps_count)?;
+
+        // High profiles may append chroma and bit depth fields, they repeat what the SPS says
+        Ok(AvcDecoderConfigurationRecord {
+            configuration_version: data[0],
+            profile_indication: data[1],
+            profile_compatibility: data[2],
+            level_indication: data[3],
+            nal_length_size: (data[4] & 0x03) + 1,
+            sps,
+            pps,
+        })
+    }
+
+    // Builds the record for parameter sets taken from an Annex B stream
+    pub fn from_parameter_sets(
+        sps: Vec<Vec<u8>>,
+        pps: Vec<Vec<u8>>,
+        nal_length_size: u8,
+    ) -> Result<AvcDecoderConfigurationRecord, MediaError> {
+        let first = sps
+            .first()
+            .ok_or(MediaError::invalid_data("no SPS to build the record from"))?;
+        if first.len() < 4 {
+            return Err(MediaError::not_enough_data(4, first.len()));
+        }
+        if pps.is_empty() {
+            return Err(MediaError::invalid_data("no PPS to build the record from"));
+        }
+        Ok(AvcDecoderConfigurationRecord {
+            configuration_version: 1,
+            profile_indication: first[1],
+            profile_compatibility: first[2],
+            level_indication: first[3],
+            nal_length_size,
+            sps,
+            pps,
+        })
+    }
+
+    // SPS first, then PPS, the order they go in front of a keyframe
+    pub fn parameter_sets(&self) -> Vec<&[u8]> {
+        self.sps
+            .iter()
+            .chain(&self.pps)
+            .map(|set| set.as_slice())
+            .collect()
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = vec![
+            self.configuration_version,
+            self.profile_indication,
+            self.profile_compatibility,
+            self.level_indication,
+            0xfc | (self.nal_length_size - 1),
+            0xe0 | self.sps.len() as u8,
+        ];
+        for sps in &self.sps {
+            data.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+            data.extend_from_slice(sps);
+        }
+        data.push(self.pps.len() as u8);
+        for pps in &self.pps {
+            data.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+            data.extend_from_slice(pps);
+        }
+        data
+    }
+
+    // The first SPS describes the stream, encoders only ever send one
+    pub fn sequence_parameter_set(&self) -> Result<Sps, MediaError> {
+        let sps = self.sps.first().ok_or(MediaError::invalid_data(
+            "no SPS in the configuration record",
+        ))?;
+        Sps::parse(sps)
+    }
+}
+
+fn read_parameter_sets(
+    data: &[u8],
+    position: &mut usize,
+    count: usize,
+) -> Result<Vec<Vec<u8>>, MediaError> {
+    let mut sets = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len_end = *position + 2;
+        let len = data
+            .get(*position..len_end)
+            .ok_or(MediaError::not_enough_data(len_end, data.len()))?;
+        let end = len_end + u16::from_be_bytes([len[0], len[1]]) as usize;
+        let set = data
+            .get(len_end..end)
+            .ok_or(MediaError::not_enough_data(end, data.len()))?;
+        sets.push(set.to_vec());
+        *position = end;
+    }
+    Ok(sets)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaFormat {
+    Monochrome = 0,
+    Yuv420 = 1,
+    Yuv422 = 2,
+    Yuv444 = 3,
+}
+
+impl ChromaFormat {
+    pub fn from_u32(value: u32) -> Option<ChromaFormat> {
+        match value {
+            0 => Some(ChromaFormat::Monochrome),
+            1 => Some(ChromaFormat::Yuv420),
+            2 => Some(ChromaFormat::Yuv422),
+            3 => Some(ChromaFormat::Yuv444),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Cropping {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+}
+
+/// The fields of a sequence parameter set that describe the decoded pictures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    pub chroma_format: ChromaFormat,
+    pub separate_colour_plane: bool,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub max_num_ref_frames: u32,
+    // Size in macroblocks, before cropping
+    pub pic_width_in_mbs: u32,
+    pub pic_height_in_map_units: u32,
+    pub frame_mbs_only: bool,
+    // In luma samples
+    pub cropping: Cropping,
+    pub sample_aspect_ratio: Option<(u16, u16)>,
+    // From the VUI timing info
+    pub num_units_in_tick: Option<u32>,
+    pub time_scale: Option<u32>,
+    pub fixed_frame_rate: bool,
+}
+
+impl Sps {
+    // Takes the SPS NAL unit including its one byte header
+    pub fn parse(nal: &[u8]) -> Result<Sps, MediaError> {
+        let header = *nal.first().ok_or(MediaError::not_enough_data(1, 0))?;
+        if header & 0x1f != nal_unit_type::SPS {
+            return Err(MediaError::unknown_value(
+                "SPS NAL unit type",
+                (header & 0x1f) as u32,
+            ));
+        }
+        let rbsp = remove_emulation_prevention(&nal[1..]);
+        let mut reader = BitReader::new(&rbsp);
+
+        let profile_idc = reader.read_bits(8)? as u8;
+        let constraint_flags = reader.read_bits(8)? as u8;
+        let level_idc = reader.read_bits(8)? as u8;
+        let seq_parameter_set_id = reader.read_ue()?;
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        let mut bit_depth_luma = 8;
+        let mut bit_depth_chroma = 8;
+        if matches!(
+            profile_idc,
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+        ) {
+            chroma_format_idc = reader.read_ue()?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = reader.read_flag()?;
+            }
+            bit_depth_luma = read_bit_depth(&mut reader)?;
+            bit_depth_chroma = read_bit_depth(&mut reader)?;
+            // qpprime_y_zero_transform_bypass_flag
+            reader.read_flag()?;
+            if reader.read_flag()? {
+                let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..lists {
+                    if reader.read_flag()? {
+                        skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+        let chroma_format = ChromaFormat::from_u32(chroma_format_idc).ok_or(
+            MediaError::unknown_value("chroma format", chroma_format_idc),
+        )?;
+
+        // log2_max_frame_num_minus4
+        reader.read_ue()?;
+        match reader.read_ue()? {
+            0 => {
+                // log2_max_pic_order_cnt_lsb_minus4
+                reader.read_ue()?;
+            }
+            1 => {
+                // delta_pic_order_always_zero_flag, offset_for_non_ref_pic,
+                // offset_for_top_to_bottom_field and the offsets of the cycle
+                reader.read_flag()?;
+                reader.read_se()?;
+                reader.read_se()?;
+                let cycle = reader.read_ue()?;
+                if cycle > 255 {
+                    return Err(MediaError::unknown_value(
+                        "num_ref_frames_in_pic_order_cnt_cycle",
+                        cycle,
+                    ));
+                }
+                for _ in 0..cycle {
+                    reader.read_se()?;
+                }
+            }
+            _ => {}
+        }
+        let max_num_ref_frames = reader.read_ue()?;
+        // gaps_in_frame_num_value_allowed_flag
+        reader.read_flag()?;
+        let pic_width_in_mbs = reader.read_ue()? + 1;
+        let pic_height_in_map_units = reader.read_ue()? + 1;
+        let frame_mbs_only = reader.read_flag()?;
+        // In luma samples, ue(v) goes up to 2^32 - 2 so the sizes may not fit
+        let frame_width = pic_width_in_mbs
+            .checked_mul(16)
+            .ok_or_else(|| MediaError::invalid_data("SPS picture width out of range"))?;
+        let frame_height = pic_height_in_map_units
+            .checked_mul(16 * (2 - frame_mbs_only as u32))
+            .ok_or_else(|| MediaError::invalid_data("SPS picture height out of range"))?;
+        if !frame_mbs_only {
+            // mb_adaptive_frame_field_flag
+            reader.read_flag()?;
+        }
+        // direct_8x8_inference_flag
+        reader.read_flag()?;
+
+        let mut cropping = Cropping::default();
+        if reader.read_flag()? {
+            let left = reader.read_ue()?;
+            let right = reader.read_ue()?;
+            let top = reader.read_ue()?;
+            let bottom = reader.read_ue()?;
+
+            // Offsets are coded in chroma samples (and field lines for interlaced video)
+            let chroma_array_type = if separate_colour_plane {
+                0
+            } else {
+                chroma_format_idc
+            };
+            let (sub_width, sub_height) = match chroma_array_type {
+                1 => (2, 2),
+                2 => (2, 1),
+                _ => (1, 1),
+            };
+            let unit_y = sub_height * (2 - frame_mbs_only as u32);
+            let crop = |offset: u32, unit: u32| {
+                offset
+                    .checked_mul(unit)
+                    .ok_or_else(|| MediaError::invalid_data("SPS cropping out of range"))
+            };
+            cropping = Cropping {
+                left: crop(left, sub_width)?,
+                right: crop(right, sub_width)?,
+                top: crop(top, unit_y)?,
+                bottom: crop(bottom, unit_y)?,
+            };
+            // The spec wants at least one sample left either way
+            let cropped_width = cropping.left.checked_add(cropping.right);
+            let cropped_height = cropping.top.checked_add(cropping.bottom);
+            if cropped_width.is_none_or(|cropped| cropped >= frame_width)
+                || cropped_height.is_none_or(|cropped| cropped >= frame_height)
+            {
+                return Err(MediaError::invalid_data(
+                    "SPS cropping is larger than the picture",
+                ));
+            }
+        }
+
+        let mut sps = Sps {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            seq_parameter_set_id,
+            chroma_format,
+            separate_colour_plane,
+            bit_depth_luma,
+            bit_depth_chroma,
+            max_num_ref_frames,
+            pic_width_in_mbs,
+            pic_height_in_map_units,
+            frame_mbs_only,
+            cropping,
+            sample_aspect_ratio: None,
+            num_units_in_tick: None,
+            time_scale: None,
+            fixed_frame_rate: false,
+        };
+        if reader.read_flag()? {
+            sps.parse_vui(&mut reader)?;
+        }
+        Ok(sps)
+    }
+
+    // Only reads the VUI up to and including the timing info
+    fn parse_vui(&mut self, reader: &mut BitReader) -> Result<(), MediaError> {
+        if reader.read_flag()? {
+            let aspect_ratio_idc = reader.read_bits(8)?;
+            self.sample_aspect_ratio = match aspect_ratio_idc {
+                // Extended_SAR
+                255 => Some((reader.read_bits(16)? as u16, reader.read_bits(16)? as u16)),
+                idc => SAMPLE_ASPECT_RATIOS.get(idc as usize).copied().flatten(),
+            };
+        }
+        if reader.read_flag()? {
+            // overscan_appropriate_flag
+            reader.read_flag()?;
+        }
+        if reader.read_flag()? {
+            // video_format and video_full_range_flag
+            reader.skip_bits(4)?;
+            if reader.read_flag()? {
+                // colour_primaries, transfer_characteristics and matrix_coefficients
+                reader.skip_bits(24)?;
+            }
+        }
+        if reader.read_flag()? {
+            // chroma_sample_loc_type_top_field and bottom_field
+            reader.read_ue()?;
+            reader.read_ue()?;
+        }
+        if reader.read_flag()? {
+            self.num_units_in_tick = Some(reader.read_bits(32)?);
+            self.time_scale = Some(reader.read_bits(32)?);
+            self.fixed_frame_rate = reader.read_flag()?;
+        }
+        Ok(())
+    }
+
+    // Saturating, parse makes sure nothing overflows but the fields are public
+    pub fn width(&self) -> u32 {
+        let cropped = self.cropping.left.saturating_add(self.cropping.right);
+        self.pic_width_in_mbs
+            .saturating_mul(16)
+            .saturating_sub(cropped)
+    }
+
+    pub fn height(&self) -> u32 {
+        let frame_height = self
+            .pic_height_in_map_units
+            .saturating_mul(16 * (2 - self.frame_mbs_only as u32));
+        let cropped = self.cropping.top.saturating_add(self.cropping.bottom);
+        frame_height.saturating_sub(cropped)
+    }
+
+    // A frame takes two ticks, one per field
+    pub fn frame_rate(&self) -> Option<f64> {
+        match (self.num_units_in_tick, self.time_scale) {
+            (Some(units), Some(scale)) if units > 0 => Some(scale as f64 / (2.0 * units as f64)),
+            _ => None,
+        }
+    }
+
+    // e.g. "avc1.64001f", as used by HLS and DASH manifests
+    pub fn codec_string(&self) -> String {
+        format!(
+            "avc1.{:02x}{:02x}{:02x}",
+            self.profile_idc, self.constraint_flags, self.level_idc
+        )
+    }
+}
+
+// Table E-1, indexed by aspect_ratio_idc
+const SAMPLE_ASPECT_RATIOS: [Option<(u16, u16)>; 17] = [
+    None,
+    Some((1, 1)),
+    Some((12, 11)),
+    Some((10, 11)),
+    Some((16, 11)),
+    Some((40, 33)),
+    Some((24, 11)),
+    Some((20, 11)),
+    Some((32, 11)),
+    Some((80, 33)),
+    Some((18, 11)),
+    Some((15, 11)),
+    Some((64, 33)),
+    Some((160, 99)),
+    Some((4, 3)),
+    Some((3, 2)),
+    Some((2, 1)),
+];
+
+// bit_depth_luma_minus8 and bit_depth_chroma_minus8 go up to 6, for 14 bit
+fn read_bit_depth(reader: &mut BitReader) -> Result<u8, MediaError> {
+    match reader.read_ue()? {
+        minus8 @ 0..=6 => Ok(minus8 as u8 + 8),
+        minus8 => Err(MediaError::unknown_value("bit depth minus 8", minus8)),
+    }
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Result<(), MediaError> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = reader.read_se()?;
+            if !(-128..=127).contains(&delta_scale) {
+                return Err(MediaError::invalid_data(format!(
+                    "Scaling list delta {} out of range",
+                    delta_scale
+                )));
+            }
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+/// Video the tests of the outputs and the connection are fed with.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    /// Baseline 1920x1080, coded as 1920x1088 and cropped by 8 lines.
+    pub(crate) const SPS_1080P: [u8; 10] =
+        [0x67, 0x42, 0xc0, 0x28, 0xda, 0x01, 0xe0, 0x08, 0x9f, 0x95];
+
+    /// The FLV video tag of a keyframe AVC sequence header with SPS_1080P and a PPS.
+    pub(crate) fn sequence_header() -> Vec<u8> {
+        let mut tag = vec![0x17, 0x00, 0x00, 0x00, 0x00];
+        tag.extend_from_slice(&[
+            0x01,
+            0x42,
+            0xc0,
+            0x28,
+            0xff,
+            0xe1,
+            0x00,
+            SPS_1080P.len() as u8,
+        ]);
+        tag.extend_from_slice(&SPS_1080P);
+        tag.extend_from_slice(&[0x01, 0x00, 0x04, 0x68, 0xce, 0x3c, 0x80]);
+        tag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // x264, High profile level 3.1, 1280x720 at 30 fps
+    const SPS_720P: [u8; 23] = [
+        0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xbb, 0x01, 0x10, 0x00, 0x00, 0x03,
+        0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xc0, 0x40,
+    ];
+    const PPS: [u8; 4] = [0x68, 0xeb, 0xe3, 0xcb];
+
+    #[test]
+    fn test_parse_configuration_record() {
+        let mut data = vec![0x01, 0x64, 0x00, 0x1f, 0xff, 0xe1];
+        data.extend_from_slice(&(SPS_720P.len() as u16).to_be_bytes());
+        data.extend_from_slice(&SPS_720P);
+        data.push(0x01);
+        data.extend_from_slice(&(PPS.len() as u16).to_be_bytes());
+        data.extend_from_slice(&PPS);
+
+        let record = AvcDecoderConfigurationRecord::parse(&data).unwrap();
+        assert_eq!(record.profile_indication, 100);
+        assert_eq!(record.level_indication, 31);
+        assert_eq!(record.nal_length_size, 4);
+        assert_eq!(record.sps, vec![SPS_720P.to_vec()]);
+        assert_eq!(record.pps, vec![PPS.to_vec()]);
+        assert_eq!(record.serialize(), data);
+
+        assert!(AvcDecoderConfigurationRecord::parse(&data[..20]).is_err());
+    }
+
+    #[test]
+    fn test_parse_sps() {
+        let sps = Sps::parse(&SPS_720P).unwrap();
+        assert_eq!(sps.profile_idc, 100);
+        assert_eq!(sps.level_idc, 31);
+        assert_eq!(sps.chroma_format, ChromaFormat::Yuv420);
+        assert_eq!(sps.bit_depth_luma, 8);
+        assert_eq!(sps.width(), 1280);
+        assert_eq!(sps.height(), 720);
+        assert_eq!(sps.sample_aspect_ratio, Some((1, 1)));
+        assert_eq!(sps.frame_rate(), Some(30.0));
+        assert_eq!(sps.codec_string(), "avc1.64001f");
+    }
+
+    #[test]
+    fn test_parse_sps_with_cropping() {
+        let sps = Sps::parse(&fixtures::SPS_1080P).unwrap();
+        assert_eq!(sps.pic_height_in_map_units * 16, 1088);
+        assert_eq!(sps.cropping.bottom, 8);
+        assert_eq!(sps.width(), 1920);
+        assert_eq!(sps.height(), 1080);
+    }
+
+    // Packs the bits into an SPS NAL unit with the stop bit, escaping them like an encoder
+    fn nal(mut bits: Vec<bool>) -> Vec<u8> {
+        bits.push(true);
+        bits.resize(bits.len().div_ceil(8) * 8, false);
+        let mut nal = vec![0x67];
+        for byte in bits.chunks(8) {
+            let byte = byte.iter().fold(0u8, |acc, &bit| acc << 1 | bit as u8);
+            if byte <= 3 && nal.ends_with(&[0, 0]) {
+                nal.push(0x03);
+            }
+            nal.push(byte);
+        }
+        nal
+    }
+
+    fn write_bits(bits: &mut Vec<bool>, value: u64, count: u32) {
+        bits.extend((0..count).rev().map(|i| value >> i & 1 == 1));
+    }
+
+    fn write_ue(bits: &mut Vec<bool>, value: u32) {
+        let code = value as u64 + 1;
+        let length = 64 - code.leading_zeros();
+        write_bits(bits, 0, length - 1);
+        write_bits(bits, code, length);
+    }
+
+    // Baseline, with cropping offsets if given
+    fn baseline_sps(width_in_mbs_minus1: u32, crop: Option<[u32; 4]>) -> Vec<u8> {
+        let mut bits = Vec::new();
+        write_bits(&mut bits, 0x42_00_1e, 24);
+        // seq_parameter_set_id, log2_max_frame_num_minus4, pic_order_cnt_type, max_num_ref_frames
+        for value in [0, 0, 2, 1] {
+            write_ue(&mut bits, value);
+        }
+        bits.push(false);
+        write_ue(&mut bits, width_in_mbs_minus1);
+        write_ue(&mut bits, 0);
+        // frame_mbs_only_flag and direct_8x8_inference_flag
+        bits.extend([true, true]);
+        bits.push(crop.is_some());
+        for offset in crop.into_iter().flatten() {
+            write_ue(&mut bits, offset);
+        }
+        // No VUI
+        bits.push(false);
+        nal(bits)
+    }
+
+    #[test]
+    fn test_parse_sps_out_of_range() {
+        let sps = Sps::parse(&baseline_sps(1, Some([1, 1, 0, 0]))).unwrap();
+        assert_eq!((sps.width(), sps.height()), (28, 16));
+
+        // Sizes that do not fit into 32 bits
+        assert!(Sps::parse(&baseline_sps(u32::MAX - 1, None)).is_err());
+        assert!(Sps::parse(&baseline_sps(1, Some([1 << 31, 0, 0, 0]))).is_err());
+        assert!(Sps::parse(&baseline_sps(1, Some([0, 0, u32::MAX - 1, u32::MAX - 1]))).is_err());
+        // Cropping away the whole picture
+        assert!(Sps::parse(&baseline_sps(1, Some([8, 8, 0, 0]))).is_err());
+
+        // High profile with a bit depth of 300 + 8
+        let mut bits = Vec::new();
+        write_bits(&mut bits, 0x64_00_1f, 24);
+        for value in [0, 1, 300] {
+            write_ue(&mut bits, value);
+        }
+        assert!(Sps::parse(&nal(bits)).is_err());
+
+        // A scaling list delta of 1000
+        let mut bits = Vec::new();
+        write_bits(&mut bits, 0x64_00_1f, 24);
+        for value in [0, 1, 0, 0] {
+            write_ue(&mut bits, value);
+        }
+        // qpprime_y_zero_transform_bypass_flag, seq_scaling_matrix_present_flag and the flag of
+        // the first list
+        bits.extend([false, true, true]);
+        write_ue(&mut bits, 2 * 1000 - 1);
+        assert!(Sps::parse(&nal(bits)).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+// This file implements a most significant bit first reader with Exp-Golomb support, as
+// used by the parameter sets of H.264 and HEVC and by the AAC AudioSpecificConfig.
+
+// Path: src/media/bits.rs
+use super::errors::MediaError;
+
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    // Position in bits
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, MediaError> {
+        let byte = self
+            .data
+            .get(self.position / 8)
+            .ok_or(MediaError::not_enough_data(
+                self.position / 8 + 1,
+                self.data.len(),
+            ))?;
+        let bit = (byte >> (7 - self.position % 8)) & 0x01;
+        self.position += 1;
+        Ok(bit == 1)
+    }
+
+    pub fn read_flag(&mut self) -> Result<bool, MediaError> {
+        self.read_bit()
+    }
+
+    // Reads up to 32 bits as an unsigned number
+    pub fn read_bits(&mut self, count: usize) -> Result<u32, MediaError> {
+        if count > 32 {
+            return Err(MediaError::invalid_data(format!(
+                "can not read {} bits at once",
+                count
+            )));
+        }
+        let mut value: u32 = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u32;
+        }
+        Ok(value)
+    }
+
+    pub fn skip_bits(&mut self, count: usize) -> Result<(), MediaError> {
+        if count > self.remaining() {
+            return Err(MediaError::not_enough_data(
+                (self.position + count).div_ceil(8),
+                self.data.len(),
+            ));
+        }
+        self.position += count;
+        Ok(())
+    }
+
+    // ue(v)
+    pub fn read_ue(&mut self) -> Result<u32, MediaError> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(MediaError::invalid_data("Exp-Golomb code too long"));
+            }
+        }
+        let suffix = self.read_bits(leading_zeros)?;
+        Ok(((1u64 << leading_zeros) - 1 + suffix as u64) as u32)
+    }
+
+    // se(v)
+    pub fn read_se(&mut self) -> Result<i32, MediaError> {
+        let code = self.read_ue()? as i64;
+        if code % 2 == 0 {
+            Ok((-(code / 2)) as i32)
+        } else {
+            Ok(((code + 1) / 2) as i32)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitReader;
+
+    #[test]
+    fn test_read_exp_golomb() {
+        // 1 | 010 | 011 | 00100 | 00101 -> ue 0, 1, 2, 3 and se -2
+        let data = [0b1010_0110, 0b0100_0010, 0b1000_0000];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_ue().unwrap(), 0);
+        assert_eq!(reader.read_ue().unwrap(), 1);
+        assert_eq!(reader.read_ue().unwrap(), 2);
+        assert_eq!(reader.read_ue().unwrap(), 3);
+        assert_eq!(reader.read_se().unwrap(), -2);
+        assert_eq!(reader.position(), 17);
+        assert!(reader.read_bits(8).is_err());
+    }
+}
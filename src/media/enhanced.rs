@@ -0,0 +1,639 @@
+// This file implements the Enhanced RTMP extensions to the FLV tag format. Codecs the
+// legacy codec id space can not express (HEVC, AV1, VP9, Opus, ...) are identified by a
+// FourCC instead, signalled by the IsExHeader bit of a video tag or sound format 9 of an
+// audio tag. Clients announce the codecs they handle with fourCcList and the
+// videoFourCcInfoMap / audioFourCcInfoMap properties of connect.
+
+// Path: src/media/enhanced.rs
+use {
+    super::{errors::MediaError, flv::FrameType},
+    crate::server::connection::message::amf0::define::Amf0ValueType,
+    indexmap::IndexMap,
+    std::fmt,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FourCc(pub [u8; 4]);
+
+impl FourCc {
+    pub const AVC: FourCc = FourCc(*b"avc1");
+    pub const HEVC: FourCc = FourCc(*b"hvc1");
+    pub const AV1: FourCc = FourCc(*b"av01");
+    pub const VP8: FourCc = FourCc(*b"vp08");
+    pub const VP9: FourCc = FourCc(*b"vp09");
+    pub const OPUS: FourCc = FourCc(*b"Opus");
+    pub const FLAC: FourCc = FourCc(*b"fLaC");
+    pub const AC3: FourCc = FourCc(*b"ac-3");
+    pub const EAC3: FourCc = FourCc(*b"ec-3");
+    pub const MP3: FourCc = FourCc(*b".mp3");
+    pub const AAC: FourCc = FourCc(*b"mp4a");
+
+    pub const VIDEO: &'static [FourCc] = &[
+        FourCc::AVC,
+        FourCc::HEVC,
+        FourCc::AV1,
+        FourCc::VP8,
+        FourCc::VP9,
+    ];
+    pub const AUDIO: &'static [FourCc] = &[
+        FourCc::OPUS,
+        FourCc::FLAC,
+        FourCc::AC3,
+        FourCc::EAC3,
+        FourCc::MP3,
+        FourCc::AAC,
+    ];
+
+    pub fn from_slice(data: &[u8]) -> Result<FourCc, MediaError> {
+        match data.get(..4) {
+            Some(bytes) => Ok(FourCc([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            None => Err(MediaError::not_enough_data(4, data.len())),
+        }
+    }
+
+    pub fn from_name(fourcc: &str) -> Option<FourCc> {
+        FourCc::from_slice(fourcc.as_bytes())
+            .ok()
+            .filter(|_| fourcc.len() == 4)
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).unwrap_or("????")
+    }
+
+    pub fn is_video(&self) -> bool {
+        FourCc::VIDEO.contains(self)
+    }
+
+    pub fn is_audio(&self) -> bool {
+        FourCc::AUDIO.contains(self)
+    }
+}
+
+impl fmt::Debug for FourCc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FourCc({:?})", self.as_str())
+    }
+}
+
+impl fmt::Display for FourCc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoPacketType {
+    SequenceStart = 0,
+    CodedFrames = 1,
+    SequenceEnd = 2,
+    // CodedFrames without the composition time, it is 0
+    CodedFramesX = 3,
+    Metadata = 4,
+    Mpeg2TsSequenceStart = 5,
+    Multitrack = 6,
+    ModEx = 7,
+}
+
+impl VideoPacketType {
+    pub fn from_u8(value: u8) -> Option<VideoPacketType> {
+        match value {
+            0 => Some(VideoPacketType::SequenceStart),
+            1 => Some(VideoPacketType::CodedFrames),
+            2 => Some(VideoPacketType::SequenceEnd),
+            3 => Some(VideoPacketType::CodedFramesX),
+            4 => Some(VideoPacketType::Metadata),
+            5 => Some(VideoPacketType::Mpeg2TsSequenceStart),
+            6 => Some(VideoPacketType::Multitrack),
+            7 => Some(VideoPacketType::ModEx),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioPacketType {
+    SequenceStart = 0,
+    CodedFrames = 1,
+    SequenceEnd = 2,
+    MultichannelConfig = 4,
+    Multitrack = 5,
+    ModEx = 7,
+}
+
+impl AudioPacketType {
+    pub fn from_u8(value: u8) -> Option<AudioPacketType> {
+        match value {
+            0 => Some(AudioPacketType::SequenceStart),
+            1 => Some(AudioPacketType::CodedFrames),
+            2 => Some(AudioPacketType::SequenceEnd),
+            4 => Some(AudioPacketType::MultichannelConfig),
+            5 => Some(AudioPacketType::Multitrack),
+            7 => Some(AudioPacketType::ModEx),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvMultitrackType {
+    OneTrack = 0,
+    ManyTracks = 1,
+    ManyTracksManyCodecs = 2,
+}
+
+impl AvMultitrackType {
+    pub fn from_u8(value: u8) -> Option<AvMultitrackType> {
+        match value {
+            0 => Some(AvMultitrackType::OneTrack),
+            1 => Some(AvMultitrackType::ManyTracks),
+            2 => Some(AvMultitrackType::ManyTracksManyCodecs),
+            _ => None,
+        }
+    }
+}
+
+/// The header of an Enhanced RTMP video tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExVideoTagHeader {
+    pub frame_type: FrameType,
+    // For multitrack tags this is the packet type of the tracks
+    pub packet_type: VideoPacketType,
+    pub multitrack: Option<AvMultitrackType>,
+    // None when every track carries its own codec
+    pub fourcc: Option<FourCc>,
+    // Only set for single track AVC and HEVC coded frames
+    pub composition_time: i32,
+    size: usize,
+}
+
+impl ExVideoTagHeader {
+    pub fn parse(data: &[u8]) -> Result<ExVideoTagHeader, MediaError> {
+        let first = *data.first().ok_or(MediaError::not_enough_data(1, 0))?;
+        if first & 0x80 == 0 {
+            return Err(MediaError::invalid_data("IsExHeader is not set"));
+        }
+
+        let frame_type = FrameType::from_u8((first >> 4) & 0x07).ok_or(
+            MediaError::unknown_value("frame type", ((first >> 4) & 0x07) as u32),
+        )?;
+        let mut reader = TagReader::new(data, 1);
+        let mut packet_type = video_packet_type(first & 0x0f)?;
+        while packet_type == VideoPacketType::ModEx {
+            packet_type = video_packet_type(reader.skip_mod_ex()?)?;
+        }
+
+        // Video info commands carry a single command byte and nothing else
+        if frame_type == FrameType::VideoInfoCommand && packet_type != VideoPacketType::Metadata {
+            reader.read_u8()?;
+            return Ok(ExVideoTagHeader {
+                frame_type,
+                packet_type,
+                multitrack: None,
+                fourcc: None,
+                composition_time: 0,
+                size: reader.position,
+            });
+        }
+
+        let mut multitrack = None;
+        if packet_type == VideoPacketType::Multitrack {
+            let byte = reader.read_u8()?;
+            multitrack = Some(multitrack_type(byte >> 4)?);
+            packet_type = video_packet_type(byte & 0x0f)?;
+        }
+
+        let fourcc = match multitrack {
+            Some(AvMultitrackType::ManyTracksManyCodecs) => None,
+            _ => Some(reader.read_fourcc()?),
+        };
+
+        let composition_time = match (multitrack, fourcc) {
+            (None, Some(FourCc::AVC | FourCc::HEVC))
+                if packet_type == VideoPacketType::CodedFrames =>
+            {
+                reader.read_i24()?
+            }
+            _ => 0,
+        };
+
+        Ok(ExVideoTagHeader {
+            frame_type,
+            packet_type,
+            multitrack,
+            fourcc,
+            composition_time,
+            size: reader.position,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type.is_keyframe()
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        matches!(
+            self.packet_type,
+            VideoPacketType::SequenceStart | VideoPacketType::Mpeg2TsSequenceStart
+        )
+    }
+
+    // Single track coded frames, the body is the frame itself
+    pub fn is_coded_frames(&self) -> bool {
+        self.multitrack.is_none()
+            && matches!(
+                self.packet_type,
+                VideoPacketType::CodedFrames | VideoPacketType::CodedFramesX
+            )
+    }
+}
+
+/// The header of an Enhanced RTMP audio tag, sound format 9.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExAudioTagHeader {
+    // For multitrack tags this is the packet type of the tracks
+    pub packet_type: AudioPacketType,
+    pub multitrack: Option<AvMultitrackType>,
+    // None when every track carries its own codec
+    pub fourcc: Option<FourCc>,
+    size: usize,
+}
+
+impl ExAudioTagHeader {
+    pub fn parse(data: &[u8]) -> Result<ExAudioTagHeader, MediaError> {
+        let first = *data.first().ok_or(MediaError::not_enough_data(1, 0))?;
+        if first >> 4 != 9 {
+            return Err(MediaError::invalid_data("sound format is not ExHeader"));
+        }
+
+        let mut reader = TagReader::new(data, 1);
+        let mut packet_type = audio_packet_type(first & 0x0f)?;
+        while packet_type == AudioPacketType::ModEx {
+            packet_type = audio_packet_type(reader.skip_mod_ex()?)?;
+        }
+
+        let mut multitrack = None;
+        if packet_type == AudioPacketType::Multitrack {
+            let byte = reader.read_u8()?;
+            multitrack = Some(multitrack_type(byte >> 4)?);
+            packet_type = audio_packet_type(byte & 0x0f)?;
+        }
+
+        let fourcc = match multitrack {
+            Some(AvMultitrackType::ManyTracksManyCodecs) => None,
+            _ => Some(reader.read_fourcc()?),
+        };
+
+        Ok(ExAudioTagHeader {
+            packet_type,
+            multitrack,
+            fourcc,
+            size: reader.position,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        self.packet_type == AudioPacketType::SequenceStart
+    }
+}
+
+/// One track of a multitrack tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExTrack<'a> {
+    pub fourcc: FourCc,
+    pub track_id: u8,
+    pub body: &'a [u8],
+}
+
+/// Splits the body of a multitrack tag into its tracks. `fourcc` is the codec from the
+/// tag header and is only None when every track names its own.
+pub fn split_tracks<'a>(
+    multitrack: AvMultitrackType,
+    fourcc: Option<FourCc>,
+    body: &'a [u8],
+) -> Result<Vec<ExTrack<'a>>, MediaError> {
+    let mut reader = TagReader::new(body, 0);
+    let mut tracks = Vec::new();
+
+    while reader.position < body.len() {
+        let track_fourcc = match fourcc {
+            Some(fourcc) => fourcc,
+            None => reader.read_fourcc()?,
+        };
+        let track_id = reader.read_u8()?;
+        let size = match multitrack {
+            // A single track runs to the end of the tag
+            AvMultitrackType::OneTrack => body.len() - reader.position,
+            _ => reader.read_u24()? as usize,
+        };
+
+        tracks.push(ExTrack {
+            fourcc: track_fourcc,
+            track_id,
+            body: reader.read_bytes(size)?,
+        });
+        if multitrack == AvMultitrackType::OneTrack {
+            break;
+        }
+    }
+    Ok(tracks)
+}
+
+pub mod fourcc_capability {
+    pub const CAN_DECODE: u32 = 0x01;
+    pub const CAN_ENCODE: u32 = 0x02;
+    pub const CAN_FORWARD: u32 = 0x04;
+}
+
+// Wildcard entry of fourCcList and the info maps, stands for every codec
+const ANY_FOURCC: &str = "*";
+
+/// The Enhanced RTMP codec properties of a connect command object or its _result.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EnhancedCapabilities {
+    pub fourcc_list: Vec<String>,
+    pub video_fourcc_info_map: IndexMap<String, u32>,
+    pub audio_fourcc_info_map: IndexMap<String, u32>,
+}
+
+impl EnhancedCapabilities {
+    /// Reads the properties a client sent with connect. Returns None for legacy clients.
+    pub fn from_properties(
+        properties: &IndexMap<String, Amf0ValueType>,
+    ) -> Option<EnhancedCapabilities> {
+        let fourcc_list = match properties.get("fourCcList") {
+            Some(Amf0ValueType::StrictArray(values)) => values
+                .iter()
+                .filter_map(|value| match value {
+                    Amf0ValueType::UTF8String(fourcc) => Some(fourcc.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let capabilities = EnhancedCapabilities {
+            fourcc_list,
+            video_fourcc_info_map: info_map(properties.get("videoFourCcInfoMap")),
+            audio_fourcc_info_map: info_map(properties.get("audioFourCcInfoMap")),
+        };
+
+        if capabilities == EnhancedCapabilities::default() {
+            None
+        } else {
+            Some(capabilities)
+        }
+    }
+
+    /// Answers what the client offered with the codecs in `supported` that the server
+    /// can handle with the given capability flags.
+    pub fn negotiate(&self, supported: &[FourCc], flags: u32) -> EnhancedCapabilities {
+        let mut negotiated = EnhancedCapabilities::default();
+        for fourcc in supported {
+            let in_list = offered(fourcc, &self.fourcc_list);
+            let in_video = fourcc.is_video() && offered(fourcc, self.video_fourcc_info_map.keys());
+            let in_audio = fourcc.is_audio() && offered(fourcc, self.audio_fourcc_info_map.keys());
+
+            if in_list || in_video || in_audio {
+                negotiated.fourcc_list.push(fourcc.to_string());
+            }
+            if fourcc.is_video() && (in_list || in_video) {
+                negotiated
+                    .video_fourcc_info_map
+                    .insert(fourcc.to_string(), flags);
+            }
+            if fourcc.is_audio() && (in_list || in_audio) {
+                negotiated
+                    .audio_fourcc_info_map
+                    .insert(fourcc.to_string(), flags);
+            }
+        }
+        negotiated
+    }
+
+    pub fn to_properties(&self) -> IndexMap<String, Amf0ValueType> {
+        let mut properties = IndexMap::new();
+        properties.insert(
+            "fourCcList".to_owned(),
+            Amf0ValueType::StrictArray(
+                self.fourcc_list
+                    .iter()
+                    .map(|fourcc| Amf0ValueType::UTF8String(fourcc.clone()))
+                    .collect(),
+            ),
+        );
+        let to_object = |map: &IndexMap<String, u32>| {
+            Amf0ValueType::Object(
+                map.iter()
+                    .map(|(fourcc, flags)| (fourcc.clone(), Amf0ValueType::Number(*flags as f64)))
+                    .collect(),
+            )
+        };
+        properties.insert(
+            "videoFourCcInfoMap".to_owned(),
+            to_object(&self.video_fourcc_info_map),
+        );
+        properties.insert(
+            "audioFourCcInfoMap".to_owned(),
+            to_object(&self.audio_fourcc_info_map),
+        );
+        properties
+    }
+}
+
+fn offered<'a>(fourcc: &FourCc, names: impl IntoIterator<Item = &'a String>) -> bool {
+    names
+        .into_iter()
+        .any(|name| name == ANY_FOURCC || name == fourcc.as_str())
+}
+
+fn info_map(value: Option<&Amf0ValueType>) -> IndexMap<String, u32> {
+    match value {
+        Some(Amf0ValueType::Object(map)) | Some(Amf0ValueType::EcmaArray(map)) => map
+            .iter()
+            .filter_map(|(fourcc, flags)| match flags {
+                Amf0ValueType::Number(flags) => Some((fourcc.clone(), *flags as u32)),
+                _ => None,
+            })
+            .collect(),
+        _ => IndexMap::new(),
+    }
+}
+
+fn video_packet_type(value: u8) -> Result<VideoPacketType, MediaError> {
+    VideoPacketType::from_u8(value)
+        .ok_or(MediaError::unknown_value("video packet type", value as u32))
+}
+
+fn audio_packet_type(value: u8) -> Result<AudioPacketType, MediaError> {
+    AudioPacketType::from_u8(value)
+        .ok_or(MediaError::unknown_value("audio packet type", value as u32))
+}
+
+fn multitrack_type(value: u8) -> Result<AvMultitrackType, MediaError> {
+    AvMultitrackType::from_u8(value)
+        .ok_or(MediaError::unknown_value("multitrack type", value as u32))
+}
+
+// Bounds checked cursor over a tag
+struct TagReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> TagReader<'a> {
+    fn new(data: &'a [u8], position: usize) -> TagReader<'a> {
+        TagReader { data, position }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], MediaError> {
+        let end = self.position + len;
+        let bytes = self
+            .data
+            .get(self.position..end)
+            .ok_or(MediaError::not_enough_data(end, self.data.len()))?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, MediaError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, MediaError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u24(&mut self) -> Result<u32, MediaError> {
+        let bytes = self.read_bytes(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
+    }
+
+    fn read_i24(&mut self) -> Result<i32, MediaError> {
+        Ok(super::flv::read_i24(self.read_bytes(3)?))
+    }
+
+    fn read_fourcc(&mut self) -> Result<FourCc, MediaError> {
+        FourCc::from_slice(self.read_bytes(4)?)
+    }
+
+    // Skips the data of a ModEx packet and returns the packet type that follows it
+    fn skip_mod_ex(&mut self) -> Result<u8, MediaError> {
+        let mut size = self.read_u8()? as usize + 1;
+        if size == 256 {
+            size = self.read_u16()? as usize + 1;
+        }
+        self.read_bytes(size)?;
+        Ok(self.read_u8()? & 0x0f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ex_video_header() {
+        // Keyframe, CodedFrames, hvc1 with a composition time of 33
+        let data = [0x91, b'h', b'v', b'c', b'1', 0x00, 0x00, 0x21, 0xaa];
+        let header = ExVideoTagHeader::parse(&data).unwrap();
+        assert!(header.is_keyframe());
+        assert_eq!(header.packet_type, VideoPacketType::CodedFrames);
+        assert_eq!(header.fourcc, Some(FourCc::HEVC));
+        assert_eq!(header.composition_time, 33);
+        assert_eq!(header.size(), 8);
+
+        // AV1 sequence start has no composition time
+        let data = [0x90, b'a', b'v', b'0', b'1', 0x81];
+        let header = ExVideoTagHeader::parse(&data).unwrap();
+        assert!(header.is_sequence_header());
+        assert_eq!(header.fourcc, Some(FourCc::AV1));
+        assert_eq!(header.size(), 5);
+
+        // A ModEx packet in front of CodedFramesX
+        let data = [0xa7, 0x00, 0x07, 0x03, b'v', b'p', b'0', b'9', 0x00];
+        let header = ExVideoTagHeader::parse(&data).unwrap();
+        assert_eq!(header.packet_type, VideoPacketType::CodedFramesX);
+        assert_eq!(header.fourcc, Some(FourCc::VP9));
+        assert_eq!(header.size(), 8);
+
+        assert!(ExVideoTagHeader::parse(&[0x91, b'h', b'v']).is_err());
+    }
+
+    #[test]
+    fn test_split_multitrack() {
+        // ManyTracksManyCodecs with an AV1 and a VP9 track
+        let mut data = vec![0x96, 0x23];
+        data.extend_from_slice(b"av01");
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x02, 0xaa, 0xbb]);
+        data.extend_from_slice(b"vp09");
+        data.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0xcc]);
+
+        let header = ExVideoTagHeader::parse(&data).unwrap();
+        assert_eq!(
+            header.multitrack,
+            Some(AvMultitrackType::ManyTracksManyCodecs)
+        );
+        assert_eq!(header.packet_type, VideoPacketType::CodedFramesX);
+        assert_eq!(header.fourcc, None);
+
+        let tracks = split_tracks(
+            header.multitrack.unwrap(),
+            header.fourcc,
+            &data[header.size()..],
+        )
+        .unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].fourcc, FourCc::AV1);
+        assert_eq!(tracks[0].body, &[0xaa, 0xbb]);
+        assert_eq!(tracks[1].track_id, 1);
+        assert_eq!(tracks[1].body, &[0xcc]);
+    }
+
+    #[test]
+    fn test_parse_ex_audio_header() {
+        let data = [0x90, b'O', b'p', b'u', b's', 0x01];
+        let header = ExAudioTagHeader::parse(&data).unwrap();
+        assert!(header.is_sequence_header());
+        assert_eq!(header.fourcc, Some(FourCc::OPUS));
+        assert_eq!(header.size(), 5);
+    }
+
+    #[test]
+    fn test_negotiate_capabilities() {
+        let mut properties = IndexMap::new();
+        properties.insert(
+            "fourCcList".to_owned(),
+            Amf0ValueType::StrictArray(vec![
+                Amf0ValueType::UTF8String("hvc1".to_owned()),
+                Amf0ValueType::UTF8String("xxxx".to_owned()),
+            ]),
+        );
+        let mut audio = IndexMap::new();
+        audio.insert("*".to_owned(), Amf0ValueType::Number(1.0));
+        properties.insert(
+            "audioFourCcInfoMap".to_owned(),
+            Amf0ValueType::Object(audio),
+        );
+
+        let client = EnhancedCapabilities::from_properties(&properties).unwrap();
+        let negotiated = client.negotiate(
+            &[FourCc::HEVC, FourCc::AV1, FourCc::OPUS],
+            fourcc_capability::CAN_FORWARD,
+        );
+        assert_eq!(negotiated.fourcc_list, vec!["hvc1", "Opus"]);
+        assert_eq!(negotiated.video_fourcc_info_map.get("hvc1"), Some(&4));
+        assert_eq!(negotiated.video_fourcc_info_map.get("av01"), None);
+        assert_eq!(negotiated.audio_fourcc_info_map.get("Opus"), Some(&4));
+
+        assert!(EnhancedCapabilities::from_properties(&IndexMap::new()).is_none());
+    }
+}
@@ -0,0 +1,50 @@
+// The failure derive emits its Display impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
+use {failure::Fail, std::fmt};
+
+#[derive(Debug, Fail)]
+pub enum MediaErrorValue {
+    #[fail(
+        display = "not enough data: needed {} bytes, got {}\n",
+        needed, available
+    )]
+    NotEnoughData { needed: usize, available: usize },
+    #[fail(display = "unknown {} value: {}\n", field, value)]
+    UnknownValue { field: &'static str, value: u32 },
+    #[fail(display = "invalid data: {}\n", _0)]
+    InvalidData(String),
+}
+
+#[derive(Debug)]
+pub struct MediaError {
+    pub value: MediaErrorValue,
+}
+
+impl MediaError {
+    pub fn not_enough_data(needed: usize, available: usize) -> MediaError {
+        MediaError {
+            value: MediaErrorValue::NotEnoughData { needed, available },
+        }
+    }
+
+    pub fn unknown_value(field: &'static str, value: u32) -> MediaError {
+        MediaError {
+            value: MediaErrorValue::UnknownValue { field, value },
+        }
+    }
+
+    pub fn invalid_data(reason: impl Into<String>) -> MediaError {
+        MediaError {
+            value: MediaErrorValue::InvalidData(reason.into()),
+        }
+    }
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.value, f)
+    }
+}
+
+impl std::error::Error for MediaError {}
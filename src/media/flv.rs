@@ -0,0 +1,618 @@
+// This file defines typed views over the payload of RTMP audio and video messages. The
+// payload of message types 8 and 9 is an FLV AUDIODATA / VIDEODATA tag body: a small
+// header describing the codec, followed by the codec data itself. It also writes those
+// bodies back out as the tags of an FLV file.
+
+// Path: src/media/flv.rs
+use super::{
+    enhanced::{ExAudioTagHeader, ExVideoTagHeader, FourCc},
+    errors::MediaError,
+};
+
+// Sound format 9 marks an Enhanced RTMP audio header
+const EX_HEADER_SOUND_FORMAT: u8 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundFormat {
+    LinearPcmPlatformEndian = 0,
+    Adpcm = 1,
+    Mp3 = 2,
+    LinearPcmLittleEndian = 3,
+    Nellymoser16kMono = 4,
+    Nellymoser8kMono = 5,
+    Nellymoser = 6,
+    G711ALaw = 7,
+    G711MuLaw = 8,
+    Aac = 10,
+    Speex = 11,
+    Mp38k = 14,
+    DeviceSpecific = 15,
+}
+
+impl SoundFormat {
+    pub fn from_u8(value: u8) -> Option<SoundFormat> {
+        match value {
+            0 => Some(SoundFormat::LinearPcmPlatformEndian),
+            1 => Some(SoundFormat::Adpcm),
+            2 => Some(SoundFormat::Mp3),
+            3 => Some(SoundFormat::LinearPcmLittleEndian),
+            4 => Some(SoundFormat::Nellymoser16kMono),
+            5 => Some(SoundFormat::Nellymoser8kMono),
+            6 => Some(SoundFormat::Nellymoser),
+            7 => Some(SoundFormat::G711ALaw),
+            8 => Some(SoundFormat::G711MuLaw),
+            10 => Some(SoundFormat::Aac),
+            11 => Some(SoundFormat::Speex),
+            14 => Some(SoundFormat::Mp38k),
+            15 => Some(SoundFormat::DeviceSpecific),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundRate {
+    Rate5512 = 0,
+    Rate11025 = 1,
+    Rate22050 = 2,
+    Rate44100 = 3,
+}
+
+impl SoundRate {
+    pub fn from_u8(value: u8) -> Option<SoundRate> {
+        match value {
+            0 => Some(SoundRate::Rate5512),
+            1 => Some(SoundRate::Rate11025),
+            2 => Some(SoundRate::Rate22050),
+            3 => Some(SoundRate::Rate44100),
+            _ => None,
+        }
+    }
+
+    pub fn hz(&self) -> u32 {
+        match self {
+            SoundRate::Rate5512 => 5512,
+            SoundRate::Rate11025 => 11025,
+            SoundRate::Rate22050 => 22050,
+            SoundRate::Rate44100 => 44100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundSize {
+    Bits8 = 0,
+    Bits16 = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundType {
+    Mono = 0,
+    Stereo = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AacPacketType {
+    SequenceHeader = 0,
+    Raw = 1,
+}
+
+impl AacPacketType {
+    pub fn from_u8(value: u8) -> Option<AacPacketType> {
+        match value {
+            0 => Some(AacPacketType::SequenceHeader),
+            1 => Some(AacPacketType::Raw),
+            _ => None,
+        }
+    }
+}
+
+/// The header of an FLV AUDIODATA tag body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioTagHeader {
+    pub sound_format: SoundFormat,
+    // AAC always signals 44 kHz stereo here, the real values are in the AudioSpecificConfig
+    pub sound_rate: SoundRate,
+    pub sound_size: SoundSize,
+    pub sound_type: SoundType,
+    // Only present for AAC
+    pub aac_packet_type: Option<AacPacketType>,
+}
+
+impl AudioTagHeader {
+    pub fn parse(data: &[u8]) -> Result<AudioTagHeader, MediaError> {
+        let first = *data.first().ok_or(MediaError::not_enough_data(1, 0))?;
+
+        let sound_format = SoundFormat::from_u8(first >> 4).ok_or(MediaError::unknown_value(
+            "sound format",
+            (first >> 4) as u32,
+        ))?;
+        // 2 bits, every value is valid
+        let sound_rate = SoundRate::from_u8((first >> 2) & 0x03).unwrap();
+        let sound_size = if first & 0x02 == 0 {
+            SoundSize::Bits8
+        } else {
+            SoundSize::Bits16
+        };
+        let sound_type = if first & 0x01 == 0 {
+            SoundType::Mono
+        } else {
+            SoundType::Stereo
+        };
+
+        let aac_packet_type = if sound_format == SoundFormat::Aac {
+            let packet_type = *data
+                .get(1)
+                .ok_or(MediaError::not_enough_data(2, data.len()))?;
+            Some(
+                AacPacketType::from_u8(packet_type).ok_or(MediaError::unknown_value(
+                    "AAC packet type",
+                    packet_type as u32,
+                ))?,
+            )
+        } else {
+            None
+        };
+
+        Ok(AudioTagHeader {
+            sound_format,
+            sound_rate,
+            sound_size,
+            sound_type,
+            aac_packet_type,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        match self.aac_packet_type {
+            Some(_) => 2,
+            None => 1,
+        }
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        self.aac_packet_type == Some(AacPacketType::SequenceHeader)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = vec![
+            (self.sound_format as u8) << 4
+                | (self.sound_rate as u8) << 2
+                | (self.sound_size as u8) << 1
+                | self.sound_type as u8,
+        ];
+        if let Some(packet_type) = self.aac_packet_type {
+            data.push(packet_type as u8);
+        }
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Keyframe = 1,
+    InterFrame = 2,
+    DisposableInterFrame = 3,
+    GeneratedKeyframe = 4,
+    VideoInfoCommand = 5,
+}
+
+impl FrameType {
+    pub fn from_u8(value: u8) -> Option<FrameType> {
+        match value {
+            1 => Some(FrameType::Keyframe),
+            2 => Some(FrameType::InterFrame),
+            3 => Some(FrameType::DisposableInterFrame),
+            4 => Some(FrameType::GeneratedKeyframe),
+            5 => Some(FrameType::VideoInfoCommand),
+            _ => None,
+        }
+    }
+
+    // Generated keyframes are what servers send on seeks, decoders can start at them too
+    pub fn is_keyframe(&self) -> bool {
+        matches!(self, FrameType::Keyframe | FrameType::GeneratedKeyframe)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecId {
+    Jpeg = 1,
+    SorensonH263 = 2,
+    ScreenVideo = 3,
+    Vp6 = 4,
+    Vp6Alpha = 5,
+    ScreenVideoV2 = 6,
+    Avc = 7,
+}
+
+impl CodecId {
+    pub fn from_u8(value: u8) -> Option<CodecId> {
+        match value {
+            1 => Some(CodecId::Jpeg),
+            2 => Some(CodecId::SorensonH263),
+            3 => Some(CodecId::ScreenVideo),
+            4 => Some(CodecId::Vp6),
+            5 => Some(CodecId::Vp6Alpha),
+            6 => Some(CodecId::ScreenVideoV2),
+            7 => Some(CodecId::Avc),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvcPacketType {
+    SequenceHeader = 0,
+    Nalu = 1,
+    EndOfSequence = 2,
+}
+
+impl AvcPacketType {
+    pub fn from_u8(value: u8) -> Option<AvcPacketType> {
+        match value {
+            0 => Some(AvcPacketType::SequenceHeader),
+            1 => Some(AvcPacketType::Nalu),
+            2 => Some(AvcPacketType::EndOfSequence),
+            _ => None,
+        }
+    }
+}
+
+/// The header of an FLV VIDEODATA tag body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoTagHeader {
+    pub frame_type: FrameType,
+    pub codec_id: CodecId,
+    // Only present for AVC
+    pub avc_packet_type: Option<AvcPacketType>,
+    // Presentation minus decode time in ms, 0 unless AVC NALUs
+    pub composition_time: i32,
+}
+
+impl VideoTagHeader {
+    pub fn parse(data: &[u8]) -> Result<VideoTagHeader, MediaError> {
+        let first = *data.first().ok_or(MediaError::not_enough_data(1, 0))?;
+
+        let frame_type = FrameType::from_u8(first >> 4)
+            .ok_or(MediaError::unknown_value("frame type", (first >> 4) as u32))?;
+        let codec_id = CodecId::from_u8(first & 0x0f)
+            .ok_or(MediaError::unknown_value("codec id", (first & 0x0f) as u32))?;
+
+        let (avc_packet_type, composition_time) = if codec_id == CodecId::Avc {
+            if data.len() < 5 {
+                return Err(MediaError::not_enough_data(5, data.len()));
+            }
+            let packet_type = AvcPacketType::from_u8(data[1])
+                .ok_or(MediaError::unknown_value("AVC packet type", data[1] as u32))?;
+            (Some(packet_type), read_i24(&data[2..5]))
+        } else {
+            (None, 0)
+        };
+
+        Ok(VideoTagHeader {
+            frame_type,
+            codec_id,
+            avc_packet_type,
+            composition_time,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        match self.avc_packet_type {
+            Some(_) => 5,
+            None => 1,
+        }
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type.is_keyframe()
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        self.avc_packet_type == Some(AvcPacketType::SequenceHeader)
+    }
+
+    pub fn is_coded_frames(&self) -> bool {
+        self.avc_packet_type == Some(AvcPacketType::Nalu)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = vec![(self.frame_type as u8) << 4 | self.codec_id as u8];
+        if let Some(packet_type) = self.avc_packet_type {
+            data.push(packet_type as u8);
+            data.extend_from_slice(&self.composition_time.to_be_bytes()[1..]);
+        }
+        data
+    }
+}
+
+/// The header of an audio payload, either the legacy FLV header or an Enhanced RTMP one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioHeader {
+    Legacy(AudioTagHeader),
+    Enhanced(ExAudioTagHeader),
+}
+
+impl AudioHeader {
+    pub fn parse(data: &[u8]) -> Result<AudioHeader, MediaError> {
+        match data.first() {
+            Some(first) if first >> 4 == EX_HEADER_SOUND_FORMAT => {
+                Ok(AudioHeader::Enhanced(ExAudioTagHeader::parse(data)?))
+            }
+            _ => Ok(AudioHeader::Legacy(AudioTagHeader::parse(data)?)),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            AudioHeader::Legacy(header) => header.size(),
+            AudioHeader::Enhanced(header) => header.size(),
+        }
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        match self {
+            AudioHeader::Legacy(header) => header.is_sequence_header(),
+            AudioHeader::Enhanced(header) => header.is_sequence_header(),
+        }
+    }
+
+    // The codec as a FourCC, None for legacy formats without one
+    pub fn fourcc(&self) -> Option<FourCc> {
+        match self {
+            AudioHeader::Legacy(header) => match header.sound_format {
+                SoundFormat::Aac => Some(FourCc::AAC),
+                SoundFormat::Mp3 | SoundFormat::Mp38k => Some(FourCc::MP3),
+                _ => None,
+            },
+            AudioHeader::Enhanced(header) => header.fourcc,
+        }
+    }
+}
+
+/// The header of a video payload, either the legacy FLV header or an Enhanced RTMP one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoHeader {
+    Legacy(VideoTagHeader),
+    Enhanced(ExVideoTagHeader),
+}
+
+impl VideoHeader {
+    pub fn parse(data: &[u8]) -> Result<VideoHeader, MediaError> {
+        match data.first() {
+            Some(first) if first & 0x80 != 0 => {
+                Ok(VideoHeader::Enhanced(ExVideoTagHeader::parse(data)?))
+            }
+            _ => Ok(VideoHeader::Legacy(VideoTagHeader::parse(data)?)),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            VideoHeader::Legacy(header) => header.size(),
+            VideoHeader::Enhanced(header) => header.size(),
+        }
+    }
+
+    pub fn frame_type(&self) -> FrameType {
+        match self {
+            VideoHeader::Legacy(header) => header.frame_type,
+            VideoHeader::Enhanced(header) => header.frame_type,
+        }
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type().is_keyframe()
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        match self {
+            VideoHeader::Legacy(header) => header.is_sequence_header(),
+            VideoHeader::Enhanced(header) => header.is_sequence_header(),
+        }
+    }
+
+    pub fn is_coded_frames(&self) -> bool {
+        match self {
+            VideoHeader::Legacy(header) => header.is_coded_frames(),
+            VideoHeader::Enhanced(header) => header.is_coded_frames(),
+        }
+    }
+
+    pub fn composition_time(&self) -> i32 {
+        match self {
+            VideoHeader::Legacy(header) => header.composition_time,
+            VideoHeader::Enhanced(header) => header.composition_time,
+        }
+    }
+
+    // The codec as a FourCC, None for legacy codecs without one
+    pub fn fourcc(&self) -> Option<FourCc> {
+        match self {
+            VideoHeader::Legacy(header) if header.codec_id == CodecId::Avc => Some(FourCc::AVC),
+            VideoHeader::Legacy(_) => None,
+            VideoHeader::Enhanced(header) => header.fourcc,
+        }
+    }
+}
+
+/// An audio payload split into its header and codec data.
+#[derive(Debug, Clone, Copy)]
+pub struct AudioTag<'a> {
+    pub header: AudioHeader,
+    pub body: &'a [u8],
+}
+
+impl<'a> AudioTag<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<AudioTag<'a>, MediaError> {
+        let header = AudioHeader::parse(data)?;
+        Ok(AudioTag {
+            header,
+            body: &data[header.size()..],
+        })
+    }
+}
+
+/// A video payload split into its header and codec data.
+#[derive(Debug, Clone, Copy)]
+pub struct VideoTag<'a> {
+    pub header: VideoHeader,
+    pub body: &'a [u8],
+}
+
+impl<'a> VideoTag<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<VideoTag<'a>, MediaError> {
+        let header = VideoHeader::parse(data)?;
+        Ok(VideoTag {
+            header,
+            body: &data[header.size()..],
+        })
+    }
+}
+
+// FLV file header flags
+const FLV_HEADER_AUDIO: u8 = 0x04;
+const FLV_HEADER_VIDEO: u8 = 0x01;
+pub const FLV_TAG_HEADER_SIZE: usize = 11;
+
+/// The 9 byte FLV file header followed by PreviousTagSize0.
+pub fn flv_header(has_audio: bool, has_video: bool) -> [u8; 13] {
+    let mut flags = 0;
+    if has_audio {
+        flags |= FLV_HEADER_AUDIO;
+    }
+    if has_video {
+        flags |= FLV_HEADER_VIDEO;
+    }
+    [b'F', b'L', b'V', 1, flags, 0, 0, 0, 9, 0, 0, 0, 0]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagType {
+    Audio = 8,
+    Video = 9,
+    Script = 18,
+}
+
+/// A tag of an FLV file, the data is the tag body as carried by the RTMP message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlvTag {
+    pub tag_type: TagType,
+    // In milliseconds
+    pub timestamp: u32,
+    pub data: Vec<u8>,
+}
+
+impl FlvTag {
+    pub fn new(tag_type: TagType, timestamp: u32, data: Vec<u8>) -> FlvTag {
+        FlvTag {
+            tag_type,
+            timestamp,
+            data,
+        }
+    }
+
+    // Tag header, data and the PreviousTagSize that follows every tag
+    pub fn serialize(&self) -> Vec<u8> {
+        let size = self.data.len() as u32;
+        let mut tag = Vec::with_capacity(FLV_TAG_HEADER_SIZE + self.data.len() + 4);
+        tag.push(self.tag_type as u8);
+        tag.extend_from_slice(&size.to_be_bytes()[1..]);
+        // The lower 24 bits of the timestamp, then the upper 8 bits
+        tag.extend_from_slice(&self.timestamp.to_be_bytes()[1..]);
+        tag.push((self.timestamp >> 24) as u8);
+        // Stream id, always 0
+        tag.extend_from_slice(&[0, 0, 0]);
+        tag.extend_from_slice(&self.data);
+        tag.extend_from_slice(&(size + FLV_TAG_HEADER_SIZE as u32).to_be_bytes());
+        tag
+    }
+}
+
+// SI24, sign extended from the top bit of the first byte
+pub(crate) fn read_i24(data: &[u8]) -> i32 {
+    let value = (data[0] as i32) << 16 | (data[1] as i32) << 8 | data[2] as i32;
+    (value << 8) >> 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_aac_audio() {
+        // AAC, 44 kHz, 16 bit, stereo followed by a raw frame
+        let data = [0xaf, 0x01, 0x21, 0x10];
+        let tag = AudioTag::parse(&data).unwrap();
+        assert!(!tag.header.is_sequence_header());
+        assert_eq!(tag.header.fourcc(), Some(FourCc::AAC));
+        assert_eq!(tag.body, &[0x21, 0x10]);
+
+        let header = AudioTagHeader::parse(&data).unwrap();
+        assert_eq!(header.sound_format, SoundFormat::Aac);
+        assert_eq!(header.sound_rate.hz(), 44100);
+        assert_eq!(header.sound_size, SoundSize::Bits16);
+        assert_eq!(header.sound_type, SoundType::Stereo);
+        assert_eq!(header.aac_packet_type, Some(AacPacketType::Raw));
+        assert_eq!(header.serialize(), vec![0xaf, 0x01]);
+
+        // MP3 has no packet type byte
+        let tag = AudioTag::parse(&[0x2e, 0xff]).unwrap();
+        assert_eq!(tag.body, &[0xff]);
+        let header = AudioTagHeader::parse(&[0x2e]).unwrap();
+        assert_eq!(header.sound_format, SoundFormat::Mp3);
+        assert_eq!(header.sound_type, SoundType::Mono);
+
+        assert!(AudioTagHeader::parse(&[0xaf]).is_err());
+        assert!(AudioTagHeader::parse(&[0x9f, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_parse_avc_video() {
+        let data = [0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64];
+        let tag = VideoTag::parse(&data).unwrap();
+        assert!(tag.header.is_keyframe());
+        assert!(tag.header.is_sequence_header());
+        assert_eq!(tag.header.fourcc(), Some(FourCc::AVC));
+        assert_eq!(tag.body, &[0x01, 0x64]);
+
+        // Composition time offsets may be negative
+        let data = [0x27, 0x01, 0xff, 0xff, 0xd8, 0x00];
+        let header = VideoTagHeader::parse(&data).unwrap();
+        assert_eq!(header.frame_type, FrameType::InterFrame);
+        assert_eq!(header.avc_packet_type, Some(AvcPacketType::Nalu));
+        assert_eq!(header.composition_time, -40);
+        assert_eq!(header.serialize(), data[..5].to_vec());
+        assert!(!header.is_keyframe());
+
+        // A generated keyframe is as good as a keyframe
+        let tag = VideoTag::parse(&[0x47, 0x01, 0x00, 0x00, 0x00, 0xaa]).unwrap();
+        assert_eq!(tag.header.frame_type(), FrameType::GeneratedKeyframe);
+        assert!(tag.header.is_keyframe());
+
+        assert!(VideoTagHeader::parse(&[0x17, 0x01]).is_err());
+        assert!(VideoTagHeader::parse(&[0x0f]).is_err());
+
+        // The IsExHeader bit switches to the enhanced header
+        let tag = VideoTag::parse(&[0x91, b'h', b'v', b'c', b'1', 0x00, 0x00, 0x00, 0xaa]).unwrap();
+        assert!(matches!(tag.header, VideoHeader::Enhanced(_)));
+        assert_eq!(tag.header.fourcc(), Some(FourCc::HEVC));
+        assert_eq!(tag.body, &[0xaa]);
+    }
+
+    #[test]
+    fn test_serialize_flv_tag() {
+        assert_eq!(
+            flv_header(true, true),
+            [b'F', b'L', b'V', 1, 5, 0, 0, 0, 9, 0, 0, 0, 0]
+        );
+        assert_eq!(flv_header(false, true)[4], 1);
+
+        let tag = FlvTag::new(TagType::Video, 0x0123_4567, vec![0x17, 0x01]).serialize();
+        assert_eq!(
+            tag,
+            vec![9, 0, 0, 2, 0x23, 0x45, 0x67, 0x01, 0, 0, 0, 0x17, 0x01, 0, 0, 0, 13]
+        );
+    }
+}
@@ -0,0 +1,409 @@
+// This file decodes the HEVC sequence header: the HEVCDecoderConfigurationRecord from
+// ISO/IEC 14496-15, whose NAL unit arrays carry the VPS, SPS and PPS, and the parts of the
+// sequence parameter set that describe the profile and the decoded pictures.
+
+// Path: src/media/hevc.rs
+use super::{avc::Cropping, bits::BitReader, errors::MediaError, nal::remove_emulation_prevention};
+
+pub mod nal_unit_type {
+    pub const BLA_W_LP: u8 = 16;
+    pub const IDR_W_RADL: u8 = 19;
+    pub const IDR_N_LP: u8 = 20;
+    pub const CRA: u8 = 21;
+    pub const VPS: u8 = 32;
+    pub const SPS: u8 = 33;
+    pub const PPS: u8 = 34;
+    pub const AUD: u8 = 35;
+    pub const PREFIX_SEI: u8 = 39;
+    pub const SUFFIX_SEI: u8 = 40;
+
+    // Intra random access points, 22 and 23 are reserved IRAP types
+    pub fn is_irap(nal_type: u8) -> bool {
+        (BLA_W_LP..=23).contains(&nal_type)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NalUnitArray {
+    pub array_completeness: bool,
+    pub nal_unit_type: u8,
+    pub nal_units: Vec<Vec<u8>>,
+}
+
+/// The body of an HEVC sequence header tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HevcDecoderConfigurationRecord {
+    pub configuration_version: u8,
+    pub general_profile_space: u8,
+    pub general_tier_flag: bool,
+    pub general_profile_idc: u8,
+    pub general_profile_compatibility_flags: u32,
+    // 48 bits
+    pub general_constraint_indicator_flags: u64,
+    pub general_level_idc: u8,
+    pub min_spatial_segmentation_idc: u16,
+    pub parallelism_type: u8,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub avg_frame_rate: u16,
+    pub constant_frame_rate: u8,
+    pub num_temporal_layers: u8,
+    pub temporal_id_nested: bool,
+    // Size of the length prefix in front of every NAL unit of the coded frames
+    pub nal_length_size: u8,
+    pub arrays: Vec<NalUnitArray>,
+}
+
+impl HevcDecoderConfigurationRecord {
+    pub fn parse(data: &[u8]) -> Result<HevcDecoderConfigurationRecord, MediaError> {
+        if data.len() < 23 {
+            return Err(MediaError::not_enough_data(23, data.len()));
+        }
+        if data[0] != 1 {
+            return Err(MediaError::unknown_value(
+                "HEVC configuration version",
+                data[0] as u32,
+            ));
+        }
+
+        let mut position = 23;
+        let mut arrays = Vec::with_capacity(data[22] as usize);
+        for _ in 0..data[22] {
+            let header_end = position + 3;
+            let header = data
+                .get(position..header_end)
+                .ok_or(MediaError::not_enough_data(header_end, data.len()))?;
+            let count = u16::from_be_bytes([header[1], header[2]]);
+            let array_completeness = header[0] & 0x80 != 0;
+            let nal_unit_type = header[0] & 0x3f;
+            position = header_end;
+
+            let mut nal_units = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let len_end = position + 2;
+                let len = data
+                    .get(position..len_end)
+                    .ok_or(MediaError::not_enough_data(len_end, data.len()))?;
+                let end = len_end + u16::from_be_bytes([len[0], len[1]]) as usize;
+                let unit = data
+                    .get(len_end..end)
+                    .ok_or(MediaError::not_enough_data(end, data.len()))?;
+                nal_units.push(unit.to_vec());
+                position = end;
+            }
+            arrays.push(NalUnitArray {
+                array_completeness,
+                nal_unit_type,
+                nal_units,
+            });
+        }
+
+        let mut constraint_flags = [0u8; 8];
+        constraint_flags[2..].copy_from_slice(&data[6..12]);
+        Ok(HevcDecoderConfigurationRecord {
+            configuration_version: data[0],
+            general_profile_space: data[1] >> 6,
+            general_tier_flag: data[1] & 0x20 != 0,
+            general_profile_idc: data[1] & 0x1f,
+            general_profile_compatibility_flags: u32::from_be_bytes([
+                data[2], data[3], data[4], data[5],
+            ]),
+            general_constraint_indicator_flags: u64::from_be_bytes(constraint_flags),
+            general_level_idc: data[12],
+            min_spatial_segmentation_idc: u16::from_be_bytes([data[13], data[14]]) & 0x0fff,
+            parallelism_type: data[15] & 0x03,
+            chroma_format_idc: data[16] & 0x03,
+            bit_depth_luma: (data[17] & 0x07) + 8,
+            bit_depth_chroma: (data[18] & 0x07) + 8,
+            avg_frame_rate: u16::from_be_bytes([data[19], data[20]]),
+            constant_frame_rate: data[21] >> 6,
+            num_temporal_layers: (data[21] >> 3) & 0x07,
+            temporal_id_nested: data[21] & 0x04 != 0,
+            nal_length_size: (data[21] & 0x03) + 1,
+            arrays,
+        })
+    }
+
+    // Builds the record for parameter sets taken from an Annex B stream
+    pub fn from_parameter_sets(
+        vps: Vec<Vec<u8>>,
+        sps: Vec<Vec<u8>>,
+        pps: Vec<Vec<u8>>,
+        nal_length_size: u8,
+    ) -> Result<HevcDecoderConfigurationRecord, MediaError> {
+        if vps.is_empty() || pps.is_empty() {
+            return Err(MediaError::invalid_data(
+                "a VPS and a PPS are needed to build the record",
+            ));
+        }
+        let parsed = HevcSps::parse(
+            sps.first()
+                .ok_or(MediaError::invalid_data("no SPS to build the record from"))?,
+        )?;
+        let array = |nal_unit_type, nal_units| NalUnitArray {
+            array_completeness: true,
+            nal_unit_type,
+            nal_units,
+        };
+        Ok(HevcDecoderConfigurationRecord {
+            configuration_version: 1,
+            general_profile_space: parsed.profile_space,
+            general_tier_flag: parsed.tier_flag,
+            general_profile_idc: parsed.profile_idc,
+            general_profile_compatibility_flags: parsed.profile_compatibility_flags,
+            general_constraint_indicator_flags: parsed.constraint_indicator_flags,
+            general_level_idc: parsed.level_idc,
+            min_spatial_segmentation_idc: 0,
+            parallelism_type: 0,
+            chroma_format_idc: parsed.chroma_format_idc,
+            bit_depth_luma: parsed.bit_depth_luma,
+            bit_depth_chroma: parsed.bit_depth_chroma,
+            avg_frame_rate: 0,
+            constant_frame_rate: 0,
+            num_temporal_layers: parsed.max_sub_layers,
+            temporal_id_nested: parsed.temporal_id_nesting,
+            nal_length_size,
+            arrays: vec![
+                array(nal_unit_type::VPS, vps),
+                array(nal_unit_type::SPS, sps),
+                array(nal_unit_type::PPS, pps),
+            ],
+        })
+    }
+
+    pub fn nal_units(&self, nal_unit_type: u8) -> impl Iterator<Item = &Vec<u8>> {
+        self.arrays
+            .iter()
+            .filter(move |array| array.nal_unit_type == nal_unit_type)
+            .flat_map(|array| &array.nal_units)
+    }
+
+    // VPS, SPS and PPS in that order, the order they go in front of a keyframe
+    pub fn parameter_sets(&self) -> Vec<&[u8]> {
+        [nal_unit_type::VPS, nal_unit_type::SPS, nal_unit_type::PPS]
+            .into_iter()
+            .flat_map(|nal_unit_type| self.nal_units(nal_unit_type))
+            .map(|set| set.as_slice())
+            .collect()
+    }
+
+    pub fn sequence_parameter_set(&self) -> Result<HevcSps, MediaError> {
+        let sps = self
+            .nal_units(nal_unit_type::SPS)
+            .next()
+            .ok_or(MediaError::invalid_data(
+                "no SPS in the configuration record",
+            ))?;
+        HevcSps::parse(sps)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = vec![
+            self.configuration_version,
+            (self.general_profile_space << 6)
+                | ((self.general_tier_flag as u8) << 5)
+                | self.general_profile_idc,
+        ];
+        data.extend_from_slice(&self.general_profile_compatibility_flags.to_be_bytes());
+        data.extend_from_slice(&self.general_constraint_indicator_flags.to_be_bytes()[2..]);
+        data.push(self.general_level_idc);
+        data.extend_from_slice(&(0xf000 | self.min_spatial_segmentation_idc).to_be_bytes());
+        data.push(0xfc | self.parallelism_type);
+        data.push(0xfc | self.chroma_format_idc);
+        data.push(0xf8 | (self.bit_depth_luma - 8));
+        data.push(0xf8 | (self.bit_depth_chroma - 8));
+        data.extend_from_slice(&self.avg_frame_rate.to_be_bytes());
+        data.push(
+            (self.constant_frame_rate << 6)
+                | (self.num_temporal_layers << 3)
+                | ((self.temporal_id_nested as u8) << 2)
+                | (self.nal_length_size - 1),
+        );
+        data.push(self.arrays.len() as u8);
+        for array in &self.arrays {
+            data.push(((array.array_completeness as u8) << 7) | array.nal_unit_type);
+            data.extend_from_slice(&(array.nal_units.len() as u16).to_be_bytes());
+            for unit in &array.nal_units {
+                data.extend_from_slice(&(unit.len() as u16).to_be_bytes());
+                data.extend_from_slice(unit);
+            }
+        }
+        data
+    }
+}
+
+/// The fields of an HEVC sequence parameter set up to the bit depths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HevcSps {
+    pub max_sub_layers: u8,
+    pub temporal_id_nesting: bool,
+    pub profile_space: u8,
+    pub tier_flag: bool,
+    pub profile_idc: u8,
+    pub profile_compatibility_flags: u32,
+    pub constraint_indicator_flags: u64,
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    pub chroma_format_idc: u8,
+    pub separate_colour_plane: bool,
+    // In luma samples, before cropping
+    pub pic_width: u32,
+    pub pic_height: u32,
+    // In luma samples
+    pub cropping: Cropping,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+}
+
+impl HevcSps {
+    // Takes the SPS NAL unit including its two byte header
+    pub fn parse(nal: &[u8]) -> Result<HevcSps, MediaError> {
+        if nal.len() < 2 {
+            return Err(MediaError::not_enough_data(2, nal.len()));
+        }
+        let nal_type = (nal[0] >> 1) & 0x3f;
+        if nal_type != nal_unit_type::SPS {
+            return Err(MediaError::unknown_value(
+                "HEVC SPS NAL unit type",
+                nal_type as u32,
+            ));
+        }
+        let rbsp = remove_emulation_prevention(&nal[2..]);
+        let mut reader = BitReader::new(&rbsp);
+
+        reader.skip_bits(4)?;
+        let max_sub_layers = reader.read_bits(3)? as u8 + 1;
+        let temporal_id_nesting = reader.read_flag()?;
+
+        // profile_tier_level
+        let profile_space = reader.read_bits(2)? as u8;
+        let tier_flag = reader.read_flag()?;
+        let profile_idc = reader.read_bits(5)? as u8;
+        let profile_compatibility_flags = reader.read_bits(32)?;
+        let constraint_indicator_flags =
+            ((reader.read_bits(16)? as u64) << 32) | reader.read_bits(32)? as u64;
+        let level_idc = reader.read_bits(8)? as u8;
+        let mut sub_layers = Vec::with_capacity(max_sub_layers as usize - 1);
+        for _ in 1..max_sub_layers {
+            sub_layers.push((reader.read_flag()?, reader.read_flag()?));
+        }
+        if max_sub_layers > 1 {
+            reader.skip_bits(2 * (9 - max_sub_layers as usize))?;
+        }
+        for (profile_present, level_present) in sub_layers {
+            if profile_present {
+                reader.skip_bits(88)?;
+            }
+            if level_present {
+                reader.skip_bits(8)?;
+            }
+        }
+
+        let seq_parameter_set_id = reader.read_ue()?;
+        let chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc > 3 {
+            return Err(MediaError::unknown_value(
+                "chroma_format_idc",
+                chroma_format_idc,
+            ));
+        }
+        let separate_colour_plane = chroma_format_idc == 3 && reader.read_flag()?;
+        let pic_width = reader.read_ue()?;
+        let pic_height = reader.read_ue()?;
+
+        let mut cropping = Cropping::default();
+        if reader.read_flag()? {
+            // The offsets count chroma samples
+            let (unit_x, unit_y) = match (chroma_format_idc, separate_colour_plane) {
+                (1, false) => (2, 2),
+                (2, false) => (2, 1),
+                _ => (1, 1),
+            };
+            cropping.left = reader.read_ue()? * unit_x;
+            cropping.right = reader.read_ue()? * unit_x;
+            cropping.top = reader.read_ue()? * unit_y;
+            cropping.bottom = reader.read_ue()? * unit_y;
+        }
+        let bit_depth_luma = reader.read_ue()? as u8 + 8;
+        let bit_depth_chroma = reader.read_ue()? as u8 + 8;
+
+        Ok(HevcSps {
+            max_sub_layers,
+            temporal_id_nesting,
+            profile_space,
+            tier_flag,
+            profile_idc,
+            profile_compatibility_flags,
+            constraint_indicator_flags,
+            level_idc,
+            seq_parameter_set_id,
+            chroma_format_idc: chroma_format_idc as u8,
+            separate_colour_plane,
+            pic_width,
+            pic_height,
+            cropping,
+            bit_depth_luma,
+            bit_depth_chroma,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.pic_width - self.cropping.left - self.cropping.right
+    }
+
+    pub fn height(&self) -> u32 {
+        self.pic_height - self.cropping.top - self.cropping.bottom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Main profile level 4.1, 1920x1080 coded as 1920x1088 with a conformance window
+    const SPS_1080P: [u8; 26] = [
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x03, 0x00, 0x7b, 0xa0, 0x03, 0xc0, 0x80, 0x11, 0x07, 0xcb, 0x96,
+    ];
+    const VPS: [u8; 4] = [0x40, 0x01, 0x0c, 0x01];
+    const PPS: [u8; 4] = [0x44, 0x01, 0xc1, 0x72];
+
+    #[test]
+    fn test_parse_sps() {
+        let sps = HevcSps::parse(&SPS_1080P).unwrap();
+        assert_eq!(sps.profile_idc, 1);
+        assert_eq!(sps.level_idc, 123);
+        assert_eq!(sps.profile_compatibility_flags, 0x6000_0000);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!((sps.pic_width, sps.pic_height), (1920, 1088));
+        assert_eq!((sps.width(), sps.height()), (1920, 1080));
+        assert_eq!(sps.bit_depth_luma, 8);
+        assert!(HevcSps::parse(&VPS).is_err());
+    }
+
+    #[test]
+    fn test_configuration_record() {
+        let record = HevcDecoderConfigurationRecord::from_parameter_sets(
+            vec![VPS.to_vec()],
+            vec![SPS_1080P.to_vec()],
+            vec![PPS.to_vec()],
+            4,
+        )
+        .unwrap();
+        assert_eq!(
+            record.parameter_sets(),
+            vec![&VPS[..], &SPS_1080P[..], &PPS[..]]
+        );
+
+        let data = record.serialize();
+        assert_eq!(data[1], 0x01);
+        assert_eq!(data[12], 123);
+        assert_eq!(data[21] & 0x03, 3);
+        let parsed = HevcDecoderConfigurationRecord::parse(&data).unwrap();
+        assert_eq!(parsed, record);
+        assert_eq!(parsed.sequence_parameter_set().unwrap().height(), 1080);
+
+        assert!(HevcDecoderConfigurationRecord::parse(&data[..30]).is_err());
+    }
+}
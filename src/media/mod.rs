@@ -0,0 +1,10 @@
+pub mod aac;
+pub mod avc;
+pub mod bits;
+pub mod enhanced;
+pub mod errors;
+pub mod flv;
+pub mod hevc;
+pub mod mp4;
+pub mod nal;
+pub mod ts;
@@ -0,0 +1,627 @@
+// This file writes ISO base media (MP4) boxes. A fragmented file is an init segment (ftyp and a
+// moov without samples) followed by moof/mdat pairs, a progressive file has a single moov with
+// the complete sample tables. Every track uses a millisecond timescale, like RTMP timestamps.
+
+// Path: src/media/mp4.rs
+use super::aac::AudioSpecificConfig;
+use super::avc::AvcDecoderConfigurationRecord;
+use super::enhanced::FourCc;
+use super::errors::MediaError;
+use super::flv::{AudioTag, VideoTag};
+use super::hevc::HevcDecoderConfigurationRecord;
+
+pub const TIMESCALE: u32 = 1000;
+
+// Sample flags of trun, sample_depends_on 2 for sync samples and 1 plus non-sync otherwise
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+// Identity transformation of mvhd and tkhd
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// The codec of a track with the configuration record from its sequence header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackConfig {
+    Avc {
+        width: u32,
+        height: u32,
+        // AVCDecoderConfigurationRecord
+        record: Vec<u8>,
+    },
+    Hevc {
+        width: u32,
+        height: u32,
+        // HEVCDecoderConfigurationRecord
+        record: Vec<u8>,
+    },
+    Aac {
+        sample_rate: u32,
+        channels: u8,
+        // AudioSpecificConfig
+        config: Vec<u8>,
+    },
+}
+
+impl TrackConfig {
+    /// The configuration of a video track from a sequence header, H.264 and HEVC only.
+    pub fn from_video_tag(tag: &VideoTag) -> Result<TrackConfig, MediaError> {
+        match tag.header.fourcc() {
+            Some(FourCc::AVC) => {
+                let sps =
+                    AvcDecoderConfigurationRecord::parse(tag.body)?.sequence_parameter_set()?;
+                Ok(TrackConfig::Avc {
+                    width: sps.width(),
+                    height: sps.height(),
+                    record: tag.body.to_vec(),
+                })
+            }
+            Some(FourCc::HEVC) => {
+                let sps =
+                    HevcDecoderConfigurationRecord::parse(tag.body)?.sequence_parameter_set()?;
+                Ok(TrackConfig::Hevc {
+                    width: sps.width(),
+                    height: sps.height(),
+                    record: tag.body.to_vec(),
+                })
+            }
+            fourcc => Err(MediaError::invalid_data(format!(
+                "unsupported video codec {:?}",
+                fourcc
+            ))),
+        }
+    }
+
+    /// The configuration of an audio track from a sequence header, AAC only.
+    pub fn from_audio_tag(tag: &AudioTag) -> Result<TrackConfig, MediaError> {
+        if tag.header.fourcc() != Some(FourCc::AAC) {
+            return Err(MediaError::invalid_data(format!(
+                "unsupported audio codec {:?}",
+                tag.header.fourcc()
+            )));
+        }
+        let config = AudioSpecificConfig::parse(tag.body)?;
+        Ok(TrackConfig::Aac {
+            sample_rate: config.output_sampling_frequency(),
+            channels: config.channels(),
+            config: tag.body.to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub id: u32,
+    pub config: TrackConfig,
+}
+
+impl Track {
+    pub fn new(id: u32, config: TrackConfig) -> Track {
+        Track { id, config }
+    }
+
+    pub fn is_video(&self) -> bool {
+        !matches!(self.config, TrackConfig::Aac { .. })
+    }
+
+    // RFC 6381 codec string as used in playlists and manifests
+    pub fn codec_string(&self) -> String {
+        match &self.config {
+            TrackConfig::Avc { record, .. } if record.len() >= 4 => {
+                format!("avc1.{:02x}{:02x}{:02x}", record[1], record[2], record[3])
+            }
+            TrackConfig::Avc { .. } => "avc1".to_owned(),
+            TrackConfig::Hevc { .. } => "hvc1".to_owned(),
+            TrackConfig::Aac { config, .. } => {
+                let object_type = config.first().map(|byte| byte >> 3).unwrap_or(2);
+                format!("mp4a.40.{}", object_type)
+            }
+        }
+    }
+}
+
+/// A sample as described in the sample tables or a track run, its data lives in an mdat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub duration: u32,
+    pub size: u32,
+    // Presentation minus decode time
+    pub composition_offset: i32,
+    pub keyframe: bool,
+}
+
+/// The samples of one track in a fragment, with their data in decode order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackRun {
+    pub track_id: u32,
+    pub base_decode_time: u64,
+    pub samples: Vec<Sample>,
+    pub data: Vec<u8>,
+}
+
+/// A track with all of its samples and where their data is in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackTable {
+    pub track: Track,
+    pub samples: Vec<Sample>,
+    pub offsets: Vec<u64>,
+}
+
+impl TrackTable {
+    fn duration(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|sample| sample.duration as u64)
+            .sum()
+    }
+}
+
+fn mp4_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(payload.len() + 8);
+    data.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+    data.extend_from_slice(box_type);
+    data.extend_from_slice(payload);
+    data
+}
+
+fn full_box(box_type: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = (flags & 0x00ff_ffff | (version as u32) << 24)
+        .to_be_bytes()
+        .to_vec();
+    data.extend_from_slice(payload);
+    mp4_box(box_type, &data)
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+pub fn ftyp() -> Vec<u8> {
+    let mut payload = b"isom".to_vec();
+    payload.extend_from_slice(&0x200u32.to_be_bytes());
+    for brand in [b"isom", b"iso2", b"iso5", b"iso6", b"avc1", b"mp41"] {
+        payload.extend_from_slice(brand);
+    }
+    mp4_box(b"ftyp", &payload)
+}
+
+/// ftyp and moov of a fragmented file, the samples follow in media fragments.
+pub fn init_segment(tracks: &[Track]) -> Vec<u8> {
+    let mut children: Vec<u8> = tracks
+        .iter()
+        .flat_map(|track| trak(track, 0, &empty_sample_tables()))
+        .collect();
+    let trex: Vec<u8> = tracks
+        .iter()
+        .flat_map(|track| full_box(b"trex", 0, 0, &u32s(&[track.id, 1, 0, 0, 0])))
+        .collect();
+    children.extend_from_slice(&mp4_box(b"mvex", &trex));
+
+    let mut data = ftyp();
+    data.extend_from_slice(&moov(tracks, 0, &children));
+    data
+}
+
+/// A moof with a traf per track run, followed by the mdat holding the runs' data.
+pub fn media_fragment(sequence_number: u32, runs: &[TrackRun]) -> Vec<u8> {
+    // The data offsets depend on the size of the moof, which does not depend on them
+    let moof_size = moof(sequence_number, runs, 0).len();
+    let mut data = moof(sequence_number, runs, moof_size as u32 + 8);
+
+    let mdat_size: usize = runs.iter().map(|run| run.data.len()).sum();
+    data.extend_from_slice(&(mdat_size as u32 + 8).to_be_bytes());
+    data.extend_from_slice(b"mdat");
+    for run in runs {
+        data.extend_from_slice(&run.data);
+    }
+    data
+}
+
+fn moof(sequence_number: u32, runs: &[TrackRun], data_offset: u32) -> Vec<u8> {
+    let mut payload = full_box(b"mfhd", 0, 0, &sequence_number.to_be_bytes());
+    let mut offset = data_offset;
+    for run in runs {
+        // default-base-is-moof, so data offsets count from the start of the moof
+        let mut traf = full_box(b"tfhd", 0, 0x02_0000, &run.track_id.to_be_bytes());
+        traf.extend_from_slice(&full_box(
+            b"tfdt",
+            1,
+            0,
+            &run.base_decode_time.to_be_bytes(),
+        ));
+
+        // data offset, duration, size, flags and composition offset for every sample
+        let mut trun = (run.samples.len() as u32).to_be_bytes().to_vec();
+        trun.extend_from_slice(&offset.to_be_bytes());
+        for sample in &run.samples {
+            let flags = if sample.keyframe {
+                SYNC_SAMPLE_FLAGS
+            } else {
+                NON_SYNC_SAMPLE_FLAGS
+            };
+            trun.extend_from_slice(&u32s(&[
+                sample.duration,
+                sample.size,
+                flags,
+                sample.composition_offset as u32,
+            ]));
+        }
+        traf.extend_from_slice(&full_box(b"trun", 1, 0x000f01, &trun));
+        payload.extend_from_slice(&mp4_box(b"traf", &traf));
+        offset += run.data.len() as u32;
+    }
+    mp4_box(b"moof", &payload)
+}
+
+/// The moov of a progressive file, the sample offsets point into the mdat after it.
+pub fn progressive_moov(tables: &[TrackTable]) -> Vec<u8> {
+    let tracks: Vec<Track> = tables.iter().map(|table| table.track.clone()).collect();
+    let duration = tables.iter().map(TrackTable::duration).max().unwrap_or(0);
+    let children: Vec<u8> = tables
+        .iter()
+        .flat_map(|table| trak(&table.track, table.duration(), &sample_tables(table)))
+        .collect();
+    moov(&tracks, duration, &children)
+}
+
+fn moov(tracks: &[Track], duration: u64, children: &[u8]) -> Vec<u8> {
+    let next_track_id = tracks.iter().map(|track| track.id).max().unwrap_or(0) + 1;
+    // Creation and modification time, timescale and duration
+    let mut mvhd = u32s(&[0, 0, TIMESCALE, duration as u32]);
+    // Rate 1.0, volume 1.0 and reserved
+    mvhd.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    mvhd.extend_from_slice(&0x0100u16.to_be_bytes());
+    mvhd.extend_from_slice(&[0; 10]);
+    mvhd.extend_from_slice(&u32s(&MATRIX));
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&next_track_id.to_be_bytes());
+
+    let mut payload = full_box(b"mvhd", 0, 0, &mvhd);
+    payload.extend_from_slice(children);
+    mp4_box(b"moov", &payload)
+}
+
+fn trak(track: &Track, duration: u64, stbl: &[u8]) -> Vec<u8> {
+    let (width, height) = match &track.config {
+        TrackConfig::Avc { width, height, .. } | TrackConfig::Hevc { width, height, .. } => {
+            (*width, *height)
+        }
+        TrackConfig::Aac { .. } => (0, 0),
+    };
+
+    // Enabled and in the movie
+    let mut tkhd = u32s(&[0, 0, track.id, 0, duration as u32, 0, 0]);
+    // Layer and alternate group, then the volume
+    tkhd.extend_from_slice(&[0; 4]);
+    tkhd.extend_from_slice(&if track.is_video() { [0, 0] } else { [1, 0] });
+    tkhd.extend_from_slice(&[0; 2]);
+    tkhd.extend_from_slice(&u32s(&MATRIX));
+    tkhd.extend_from_slice(&u32s(&[width << 16, height << 16]));
+
+    // Times, timescale, duration and the language "und"
+    let mut mdhd = u32s(&[0, 0, TIMESCALE, duration as u32]);
+    mdhd.extend_from_slice(&[0x55, 0xc4, 0, 0]);
+
+    let (handler, name, media_header) = if track.is_video() {
+        // Graphics mode and opcolor
+        (b"vide", "VideoHandler", full_box(b"vmhd", 0, 1, &[0; 8]))
+    } else {
+        // Balance and reserved
+        (b"soun", "SoundHandler", full_box(b"smhd", 0, 0, &[0; 4]))
+    };
+    let mut hdlr = vec![0; 4];
+    hdlr.extend_from_slice(handler);
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(name.as_bytes());
+    hdlr.push(0);
+
+    // A single self contained data reference
+    let mut dref = 1u32.to_be_bytes().to_vec();
+    dref.extend_from_slice(&full_box(b"url ", 0, 1, &[]));
+    let dinf = mp4_box(b"dinf", &full_box(b"dref", 0, 0, &dref));
+
+    let mut stsd = 1u32.to_be_bytes().to_vec();
+    stsd.extend_from_slice(&sample_entry(&track.config));
+    let mut stbl_payload = full_box(b"stsd", 0, 0, &stsd);
+    stbl_payload.extend_from_slice(stbl);
+
+    let mut minf = media_header;
+    minf.extend_from_slice(&dinf);
+    minf.extend_from_slice(&mp4_box(b"stbl", &stbl_payload));
+
+    let mut mdia = full_box(b"mdhd", 0, 0, &mdhd);
+    mdia.extend_from_slice(&full_box(b"hdlr", 0, 0, &hdlr));
+    mdia.extend_from_slice(&mp4_box(b"minf", &minf));
+
+    let mut payload = full_box(b"tkhd", 0, 3, &tkhd);
+    payload.extend_from_slice(&mp4_box(b"mdia", &mdia));
+    mp4_box(b"trak", &payload)
+}
+
+fn sample_entry(config: &TrackConfig) -> Vec<u8> {
+    // Reserved and the data reference index
+    let mut entry = vec![0, 0, 0, 0, 0, 0, 0, 1];
+    match config {
+        TrackConfig::Avc {
+            width,
+            height,
+            record,
+        }
+        | TrackConfig::Hevc {
+            width,
+            height,
+            record,
+        } => {
+            entry.extend_from_slice(&[0; 16]);
+            entry.extend_from_slice(&(*width as u16).to_be_bytes());
+            entry.extend_from_slice(&(*height as u16).to_be_bytes());
+            // 72 dpi, reserved and one frame per sample
+            entry.extend_from_slice(&u32s(&[0x0048_0000, 0x0048_0000, 0]));
+            entry.extend_from_slice(&1u16.to_be_bytes());
+            // Compressor name, depth and pre_defined -1
+            entry.extend_from_slice(&[0; 32]);
+            entry.extend_from_slice(&[0x00, 0x18, 0xff, 0xff]);
+            match config {
+                TrackConfig::Hevc { .. } => {
+                    entry.extend_from_slice(&mp4_box(b"hvcC", record));
+                    mp4_box(b"hvc1", &entry)
+                }
+                _ => {
+                    entry.extend_from_slice(&mp4_box(b"avcC", record));
+                    mp4_box(b"avc1", &entry)
+                }
+            }
+        }
+        TrackConfig::Aac {
+            sample_rate,
+            channels,
+            config,
+        } => {
+            entry.extend_from_slice(&[0; 8]);
+            entry.extend_from_slice(&(*channels as u16).to_be_bytes());
+            // 16 bit samples, pre_defined and reserved
+            entry.extend_from_slice(&[0x00, 0x10, 0, 0, 0, 0]);
+            entry.extend_from_slice(&((*sample_rate).min(0xffff) << 16).to_be_bytes());
+            entry.extend_from_slice(&esds(config));
+            mp4_box(b"mp4a", &entry)
+        }
+    }
+}
+
+// MPEG-4 descriptors carry their size in 7 bit groups
+fn descriptor(tag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut data = vec![tag];
+    let size = payload.len() as u32;
+    for shift in [21, 14, 7] {
+        if size >> shift > 0 {
+            data.push(0x80 | (size >> shift) as u8 & 0x7f);
+        }
+    }
+    data.push(size as u8 & 0x7f);
+    data.extend_from_slice(payload);
+    data
+}
+
+fn esds(audio_specific_config: &[u8]) -> Vec<u8> {
+    // MPEG-4 audio in an audio stream, no buffer size or bitrates
+    let mut decoder_config = vec![0x40, 0x15, 0, 0, 0];
+    decoder_config.extend_from_slice(&[0; 8]);
+    decoder_config.extend_from_slice(&descriptor(0x05, audio_specific_config));
+
+    // ES_ID 1, no flags, then the decoder config and the predefined SL config
+    let mut es = vec![0, 1, 0];
+    es.extend_from_slice(&descriptor(0x04, &decoder_config));
+    es.extend_from_slice(&descriptor(0x06, &[0x02]));
+    full_box(b"esds", 0, 0, &descriptor(0x03, &es))
+}
+
+fn empty_sample_tables() -> Vec<u8> {
+    let mut data = full_box(b"stts", 0, 0, &[0; 4]);
+    data.extend_from_slice(&full_box(b"stsc", 0, 0, &[0; 4]));
+    data.extend_from_slice(&full_box(b"stsz", 0, 0, &[0; 8]));
+    data.extend_from_slice(&full_box(b"stco", 0, 0, &[0; 4]));
+    data
+}
+
+// Every sample is a chunk of its own, so the offsets are per sample
+fn sample_tables(table: &TrackTable) -> Vec<u8> {
+    let samples = &table.samples;
+
+    let mut stts: Vec<(u32, u32)> = Vec::new();
+    for sample in samples {
+        match stts.last_mut() {
+            Some((count, duration)) if *duration == sample.duration => *count += 1,
+            _ => stts.push((1, sample.duration)),
+        }
+    }
+    let mut stts_payload = (stts.len() as u32).to_be_bytes().to_vec();
+    for (count, duration) in &stts {
+        stts_payload.extend_from_slice(&u32s(&[*count, *duration]));
+    }
+    let mut data = full_box(b"stts", 0, 0, &stts_payload);
+
+    if samples.iter().any(|sample| sample.composition_offset != 0) {
+        let mut ctts: Vec<(u32, i32)> = Vec::new();
+        for sample in samples {
+            match ctts.last_mut() {
+                Some((count, offset)) if *offset == sample.composition_offset => *count += 1,
+                _ => ctts.push((1, sample.composition_offset)),
+            }
+        }
+        let mut payload = (ctts.len() as u32).to_be_bytes().to_vec();
+        for (count, offset) in &ctts {
+            payload.extend_from_slice(&u32s(&[*count, *offset as u32]));
+        }
+        // Version 1 for signed offsets
+        data.extend_from_slice(&full_box(b"ctts", 1, 0, &payload));
+    }
+
+    if table.track.is_video() {
+        let sync: Vec<u32> = samples
+            .iter()
+            .enumerate()
+            .filter(|(_, sample)| sample.keyframe)
+            .map(|(index, _)| index as u32 + 1)
+            .collect();
+        let mut payload = (sync.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(&u32s(&sync));
+        data.extend_from_slice(&full_box(b"stss", 0, 0, &payload));
+    }
+
+    data.extend_from_slice(&full_box(b"stsc", 0, 0, &u32s(&[1, 1, 1, 1])));
+
+    let mut stsz = u32s(&[0, samples.len() as u32]);
+    stsz.extend_from_slice(&u32s(
+        &samples.iter().map(|sample| sample.size).collect::<Vec<_>>(),
+    ));
+    data.extend_from_slice(&full_box(b"stsz", 0, 0, &stsz));
+
+    let mut co64 = (table.offsets.len() as u32).to_be_bytes().to_vec();
+    for offset in &table.offsets {
+        co64.extend_from_slice(&offset.to_be_bytes());
+    }
+    data.extend_from_slice(&full_box(b"co64", 0, 0, &co64));
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The type and payload of the boxes directly inside the given data
+    fn boxes(data: &[u8]) -> Vec<(String, &[u8])> {
+        let mut boxes = Vec::new();
+        let mut position = 0;
+        while position < data.len() {
+            let size =
+                u32::from_be_bytes(data[position..position + 4].try_into().unwrap()) as usize;
+            let box_type = String::from_utf8_lossy(&data[position + 4..position + 8]).into_owned();
+            boxes.push((box_type, &data[position + 8..position + size]));
+            position += size;
+        }
+        assert_eq!(position, data.len());
+        boxes
+    }
+
+    fn find<'a>(data: &'a [u8], path: &[&str]) -> &'a [u8] {
+        path.iter().fold(data, |data, name| {
+            // Sample descriptions and sample entries have fields before their children
+            let skip = match *name {
+                "avc1" => 8,
+                "avcC" => 78,
+                _ => 0,
+            };
+            boxes(&data[skip..])
+                .into_iter()
+                .find(|(box_type, _)| box_type == name)
+                .unwrap_or_else(|| panic!("no {} box", name))
+                .1
+        })
+    }
+
+    fn tracks() -> Vec<Track> {
+        vec![
+            Track::new(
+                1,
+                TrackConfig::Avc {
+                    width: 1280,
+                    height: 720,
+                    record: vec![0x01, 0x64, 0x00, 0x1f, 0xff, 0xe0, 0x00],
+                },
+            ),
+            Track::new(
+                2,
+                TrackConfig::Aac {
+                    sample_rate: 44100,
+                    channels: 2,
+                    config: vec![0x12, 0x10],
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_init_segment() {
+        let tracks = tracks();
+        assert_eq!(tracks[0].codec_string(), "avc1.64001f");
+        assert_eq!(tracks[1].codec_string(), "mp4a.40.2");
+
+        let init = init_segment(&tracks);
+        let top: Vec<String> = boxes(&init).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(top, vec!["ftyp", "moov"]);
+        let moov = find(&init, &["moov"]);
+        let names: Vec<String> = boxes(moov).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["mvhd", "trak", "trak", "mvex"]);
+
+        let stsd = find(moov, &["trak", "mdia", "minf", "stbl", "stsd"]);
+        let avc_c = find(stsd, &["avc1", "avcC"]);
+        assert_eq!(avc_c, &[0x01, 0x64, 0x00, 0x1f, 0xff, 0xe0, 0x00]);
+
+        // The AudioSpecificConfig ends the decoder config inside esds
+        let audio = &boxes(moov)[2].1;
+        let stsd = find(audio, &["mdia", "minf", "stbl", "stsd"]);
+        assert!(stsd.windows(4).any(|window| window == b"esds"));
+        assert!(stsd
+            .windows(4)
+            .any(|window| window == [0x05, 0x02, 0x12, 0x10]));
+    }
+
+    #[test]
+    fn test_media_fragment() {
+        let runs = vec![
+            TrackRun {
+                track_id: 1,
+                base_decode_time: 1000,
+                samples: vec![
+                    Sample {
+                        duration: 40,
+                        size: 3,
+                        composition_offset: 80,
+                        keyframe: true,
+                    },
+                    Sample {
+                        duration: 40,
+                        size: 2,
+                        composition_offset: -40,
+                        keyframe: false,
+                    },
+                ],
+                data: vec![1, 2, 3, 4, 5],
+            },
+            TrackRun {
+                track_id: 2,
+                base_decode_time: 1000,
+                samples: vec![Sample {
+                    duration: 23,
+                    size: 1,
+                    composition_offset: 0,
+                    keyframe: true,
+                }],
+                data: vec![6],
+            },
+        ];
+        let fragment = media_fragment(7, &runs);
+        let top = boxes(&fragment);
+        assert_eq!(top[0].0, "moof");
+        assert_eq!(top[1], ("mdat".to_owned(), &[1u8, 2, 3, 4, 5, 6][..]));
+
+        let moof_size = top[0].1.len() + 8;
+        let trafs: Vec<&[u8]> = boxes(top[0].1)
+            .into_iter()
+            .filter(|(name, _)| name == "traf")
+            .map(|(_, payload)| payload)
+            .collect();
+        let trun = find(trafs[0], &["trun"]);
+        // version and flags, count, data offset, then the first sample
+        assert_eq!(trun[0], 1);
+        assert_eq!(&trun[4..8], &2u32.to_be_bytes());
+        assert_eq!(&trun[8..12], &(moof_size as u32 + 8).to_be_bytes());
+        assert_eq!(&trun[24..28], &80u32.to_be_bytes());
+        assert_eq!(&trun[40..44], &(-40i32).to_be_bytes());
+        let trun = find(trafs[1], &["trun"]);
+        assert_eq!(&trun[8..12], &(moof_size as u32 + 8 + 5).to_be_bytes());
+        assert_eq!(&find(trafs[1], &["tfdt"])[4..], &1000u64.to_be_bytes());
+    }
+}
@@ -0,0 +1,371 @@
+// This file converts coded H.264 and HEVC frames between the two NAL unit framings in use:
+// the length prefixed AVCC/HVCC form carried in FLV and RTMP, and the Annex B byte stream
+// with start codes that MPEG-TS, raw elementary streams and decoders expect.
+
+// Path: src/media/nal.rs
+use super::{avc, errors::MediaError, hevc};
+
+pub const START_CODE: [u8; 4] = [0x00, 0x00, 0x00, 0x01];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NalCodec {
+    Avc,
+    Hevc,
+}
+
+impl NalCodec {
+    pub fn nal_type(&self, nal: &[u8]) -> Option<u8> {
+        let header = *nal.first()?;
+        match self {
+            NalCodec::Avc => Some(header & 0x1f),
+            NalCodec::Hevc => Some((header >> 1) & 0x3f),
+        }
+    }
+
+    pub fn is_parameter_set(&self, nal_type: u8) -> bool {
+        match self {
+            NalCodec::Avc => matches!(nal_type, avc::nal_unit_type::SPS | avc::nal_unit_type::PPS),
+            NalCodec::Hevc => matches!(
+                nal_type,
+                hevc::nal_unit_type::VPS | hevc::nal_unit_type::SPS | hevc::nal_unit_type::PPS
+            ),
+        }
+    }
+
+    // A picture a decoder can start from, it needs the parameter sets in front of it
+    pub fn is_random_access(&self, nal_type: u8) -> bool {
+        match self {
+            NalCodec::Avc => nal_type == avc::nal_unit_type::IDR,
+            NalCodec::Hevc => hevc::nal_unit_type::is_irap(nal_type),
+        }
+    }
+
+    pub fn is_access_unit_delimiter(&self, nal_type: u8) -> bool {
+        match self {
+            NalCodec::Avc => nal_type == avc::nal_unit_type::AUD,
+            NalCodec::Hevc => nal_type == hevc::nal_unit_type::AUD,
+        }
+    }
+}
+
+/// Splits a length prefixed frame into its NAL units.
+pub fn split_avcc(data: &[u8], nal_length_size: u8) -> Result<Vec<&[u8]>, MediaError> {
+    if !(1..=4).contains(&nal_length_size) {
+        return Err(MediaError::unknown_value(
+            "NAL length size",
+            nal_length_size as u32,
+        ));
+    }
+    let length_size = nal_length_size as usize;
+    let mut units = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let body = position + length_size;
+        let length = data
+            .get(position..body)
+            .ok_or(MediaError::not_enough_data(body, data.len()))?
+            .iter()
+            .fold(0usize, |length, &byte| (length << 8) | byte as usize);
+        let end = body + length;
+        let unit = data
+            .get(body..end)
+            .ok_or(MediaError::not_enough_data(end, data.len()))?;
+        if !unit.is_empty() {
+            units.push(unit);
+        }
+        position = end;
+    }
+    Ok(units)
+}
+
+/// Splits an Annex B byte stream on its three and four byte start codes. Zero bytes in
+/// front of a start code are trailing_zero_8bits and do not belong to the NAL unit.
+pub fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut start = None;
+    let mut position = 0;
+    while position + 3 <= data.len() {
+        if data[position] == 0 && data[position + 1] == 0 && data[position + 2] == 1 {
+            if let Some(start) = start {
+                push_unit(&mut units, &data[start..position]);
+            }
+            position += 3;
+            start = Some(position);
+        } else {
+            position += 1;
+        }
+    }
+    if let Some(start) = start {
+        push_unit(&mut units, &data[start..]);
+    }
+    units
+}
+
+fn push_unit<'a>(units: &mut Vec<&'a [u8]>, unit: &'a [u8]) {
+    let end = unit
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |i| i + 1);
+    if end > 0 {
+        units.push(&unit[..end]);
+    }
+}
+
+/// Converts a length prefixed frame to Annex B. When the frame holds a random access
+/// picture but no parameter sets of its own, the given ones (from the sequence header) are
+/// written in front of it, after the access unit delimiter if there is one.
+pub fn avcc_to_annexb(
+    data: &[u8],
+    nal_length_size: u8,
+    codec: NalCodec,
+    parameter_sets: &[&[u8]],
+) -> Result<Vec<u8>, MediaError> {
+    let units = split_avcc(data, nal_length_size)?;
+    let types: Vec<u8> = units
+        .iter()
+        .filter_map(|unit| codec.nal_type(unit))
+        .collect();
+    let mut insert = types.iter().any(|&t| codec.is_random_access(t))
+        && !types.iter().any(|&t| codec.is_parameter_set(t));
+
+    let extra: usize = parameter_sets.iter().map(|set| set.len() + 4).sum();
+    let mut annexb = Vec::with_capacity(data.len() + extra);
+    for (unit, nal_type) in units.iter().zip(types) {
+        if insert && !codec.is_access_unit_delimiter(nal_type) {
+            for set in parameter_sets {
+                annexb.extend_from_slice(&START_CODE);
+                annexb.extend_from_slice(set);
+            }
+            insert = false;
+        }
+        annexb.extend_from_slice(&START_CODE);
+        annexb.extend_from_slice(unit);
+    }
+    Ok(annexb)
+}
+
+/// Converts an Annex B byte stream to length prefixed NAL units. Parameter sets are kept,
+/// callers that move them to a sequence header filter them out of `split_annexb` instead.
+pub fn annexb_to_avcc(data: &[u8], nal_length_size: u8) -> Result<Vec<u8>, MediaError> {
+    write_avcc(&split_annexb(data), nal_length_size)
+}
+
+/// Writes NAL units with a big endian length prefix of `nal_length_size` bytes.
+pub fn write_avcc(units: &[&[u8]], nal_length_size: u8) -> Result<Vec<u8>, MediaError> {
+    if !(1..=4).contains(&nal_length_size) {
+        return Err(MediaError::unknown_value(
+            "NAL length size",
+            nal_length_size as u32,
+        ));
+    }
+    let length_size = nal_length_size as usize;
+    let mut avcc = Vec::with_capacity(units.iter().map(|unit| unit.len() + length_size).sum());
+    for unit in units {
+        if (unit.len() as u64) >> (length_size * 8) != 0 {
+            return Err(MediaError::invalid_data(format!(
+                "NAL unit of {} bytes does not fit a {} byte length",
+                unit.len(),
+                length_size
+            )));
+        }
+        avcc.extend_from_slice(&(unit.len() as u32).to_be_bytes()[4 - length_size..]);
+        avcc.extend_from_slice(unit);
+    }
+    Ok(avcc)
+}
+
+/// Strips the emulation prevention bytes (the 0x03 in 0x00 0x00 0x03) from a NAL unit
+/// payload, giving the raw byte sequence the syntax elements are coded in.
+pub fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// The inverse of `remove_emulation_prevention`: escapes every 0x00 0x00 followed by a byte
+/// up to 0x03, and a payload ending in 0x00, so no start code can appear inside a NAL unit.
+pub fn add_emulation_prevention(rbsp: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(rbsp.len() + rbsp.len() / 64);
+    let mut zeros = 0;
+    for &byte in rbsp {
+        if zeros >= 2 && byte <= 0x03 {
+            data.push(0x03);
+            zeros = 0;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        data.push(byte);
+    }
+    if rbsp.last() == Some(&0x00) {
+        data.push(0x03);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::avc::{AvcDecoderConfigurationRecord, Sps};
+    use openh264::{
+        decoder::Decoder,
+        encoder::{Encoder, EncoderConfig},
+        formats::YUVBuffer,
+    };
+
+    // Encodes a moving gradient with openh264, giving the Annex B access units it wrote
+    fn encode_frames(width: usize, height: usize, count: usize) -> Vec<Vec<u8>> {
+        let mut encoder =
+            Encoder::with_config(EncoderConfig::new(width as u32, height as u32)).unwrap();
+        (0..count)
+            .map(|frame| {
+                let rgb: Vec<u8> = (0..width * height * 3)
+                    .map(|i| ((i / 3 % width + frame * 4) % 256) as u8)
+                    .collect();
+                let yuv = YUVBuffer::with_rgb(width, height, &rgb);
+                encoder.encode(&yuv).unwrap().to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split_avcc() {
+        let data = [0x00, 0x02, 0x65, 0x88, 0x00, 0x01, 0x41];
+        assert_eq!(
+            split_avcc(&data, 2).unwrap(),
+            vec![&[0x65, 0x88][..], &[0x41]]
+        );
+        assert!(split_avcc(&data[..5], 2).is_err());
+        assert!(split_avcc(&data, 5).is_err());
+    }
+
+    #[test]
+    fn test_split_annexb() {
+        let data = [
+            0x00, 0x00, 0x00, 0x01, 0x09, 0xf0, 0x00, 0x00, 0x01, 0x67, 0x42, 0x00, 0x00, 0x00,
+            0x00, 0x01, 0x65, 0x00, 0x00, 0x03, 0x01,
+        ];
+        assert_eq!(
+            split_annexb(&data),
+            vec![
+                &[0x09, 0xf0][..],
+                &[0x67, 0x42],
+                &[0x65, 0x00, 0x00, 0x03, 0x01]
+            ]
+        );
+        assert!(split_annexb(&[0x00, 0x00]).is_empty());
+    }
+
+    #[test]
+    fn test_emulation_prevention() {
+        let rbsp = [0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00];
+        let escaped = add_emulation_prevention(&rbsp);
+        assert_eq!(
+            escaped,
+            vec![
+                0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x04, 0x00, 0x03
+            ]
+        );
+        assert!(split_annexb(&escaped).is_empty());
+        assert_eq!(remove_emulation_prevention(&escaped)[..rbsp.len()], rbsp);
+        assert_eq!(
+            remove_emulation_prevention(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x03]),
+            vec![0x00, 0x00, 0x01, 0x00, 0x00, 0x03]
+        );
+    }
+
+    #[test]
+    fn test_insert_parameter_sets() {
+        let sps: &[u8] = &[0x67, 0x42];
+        let pps: &[u8] = &[0x68, 0xce];
+        let frame = write_avcc(&[&[0x09, 0xf0], &[0x65, 0x88]], 4).unwrap();
+        assert_eq!(
+            avcc_to_annexb(&frame, 4, NalCodec::Avc, &[sps, pps]).unwrap(),
+            [
+                &START_CODE[..],
+                &[0x09, 0xf0],
+                &START_CODE,
+                sps,
+                &START_CODE,
+                pps,
+                &START_CODE,
+                &[0x65, 0x88]
+            ]
+            .concat()
+        );
+
+        // Not for inter frames, nor for a keyframe with its own parameter sets
+        let frame = write_avcc(&[&[0x41, 0x9a]], 4).unwrap();
+        assert_eq!(
+            avcc_to_annexb(&frame, 4, NalCodec::Avc, &[sps, pps]).unwrap(),
+            [&START_CODE[..], &[0x41, 0x9a]].concat()
+        );
+        let frame = write_avcc(&[sps, pps, &[0x65, 0x88]], 4).unwrap();
+        assert_eq!(
+            split_annexb(&avcc_to_annexb(&frame, 4, NalCodec::Avc, &[sps, pps]).unwrap()).len(),
+            3
+        );
+
+        // HEVC keyframe: IDR_W_RADL behind VPS, SPS and PPS
+        let sets: [&[u8]; 3] = [&[0x40, 0x01], &[0x42, 0x01], &[0x44, 0x01]];
+        let frame = write_avcc(&[&[0x26, 0x01, 0xaf]], 4).unwrap();
+        let annexb = avcc_to_annexb(&frame, 4, NalCodec::Hevc, &sets).unwrap();
+        assert_eq!(
+            split_annexb(&annexb),
+            vec![sets[0], sets[1], sets[2], &[0x26, 0x01, 0xaf]]
+        );
+    }
+
+    #[test]
+    fn test_round_trip_encoder_output() {
+        let frames = encode_frames(64, 48, 3);
+
+        // Move the in-band parameter sets to a configuration record like a publisher does
+        let units = split_annexb(&frames[0]);
+        let nal_type = |unit: &&[u8]| NalCodec::Avc.nal_type(unit).unwrap();
+        let sps: Vec<Vec<u8>> = units
+            .iter()
+            .filter(|unit| nal_type(unit) == avc::nal_unit_type::SPS)
+            .map(|unit| unit.to_vec())
+            .collect();
+        let pps: Vec<Vec<u8>> = units
+            .iter()
+            .filter(|unit| nal_type(unit) == avc::nal_unit_type::PPS)
+            .map(|unit| unit.to_vec())
+            .collect();
+        let record = AvcDecoderConfigurationRecord::from_parameter_sets(sps, pps, 4).unwrap();
+        let parsed = Sps::parse(&record.sps[0]).unwrap();
+        assert_eq!((parsed.width(), parsed.height()), (64, 48));
+
+        let mut decoder = Decoder::new().unwrap();
+        for (i, frame) in frames.iter().enumerate() {
+            let units: Vec<&[u8]> = split_annexb(frame)
+                .into_iter()
+                .filter(|unit| !NalCodec::Avc.is_parameter_set(nal_type(unit)))
+                .collect();
+            let avcc = write_avcc(&units, record.nal_length_size).unwrap();
+            assert_eq!(split_avcc(&avcc, 4).unwrap(), units);
+
+            let annexb = avcc_to_annexb(
+                &avcc,
+                record.nal_length_size,
+                NalCodec::Avc,
+                &record.parameter_sets(),
+            )
+            .unwrap();
+            if i == 0 {
+                assert_eq!(split_annexb(&annexb), split_annexb(frame));
+            }
+            let decoded = decoder.decode(&annexb).unwrap();
+            if let Some(yuv) = decoded {
+                assert_eq!(yuv.dimension_y(), (64, 48));
+            }
+        }
+    }
+}
@@ -0,0 +1,319 @@
+// This file writes MPEG transport streams, the container of HLS segments. Every access unit is
+// a PES packet split into 188 byte TS packets, a segment starts with the PAT and PMT so it can
+// be decoded on its own. Timestamps are in 90 kHz units.
+
+// Path: src/media/ts.rs
+use std::collections::HashMap;
+
+pub const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+pub const VIDEO_PID: u16 = 0x0100;
+pub const AUDIO_PID: u16 = 0x0101;
+const PROGRAM_NUMBER: u16 = 1;
+
+const VIDEO_STREAM_ID: u8 = 0xe0;
+const AUDIO_STREAM_ID: u8 = 0xc0;
+
+// PTS and DTS are 33 bit counters
+const TIMESTAMP_MASK: u64 = 0x1_ffff_ffff;
+
+pub mod stream_type {
+    pub const AAC_ADTS: u8 = 0x0f;
+    pub const H264: u8 = 0x1b;
+    pub const HEVC: u8 = 0x24;
+}
+
+pub struct TsMuxer {
+    // Stream type of the video and audio elementary streams, if the program has them
+    video: Option<u8>,
+    audio: Option<u8>,
+    continuity: HashMap<u16, u8>,
+}
+
+impl TsMuxer {
+    pub fn new(video: Option<u8>, audio: Option<u8>) -> TsMuxer {
+        TsMuxer {
+            video,
+            audio,
+            continuity: HashMap::new(),
+        }
+    }
+
+    // Video carries the clock if there is video
+    fn pcr_pid(&self) -> u16 {
+        if self.video.is_some() {
+            VIDEO_PID
+        } else {
+            AUDIO_PID
+        }
+    }
+
+    /// The PAT and PMT, written at the start of every segment.
+    pub fn tables(&mut self) -> Vec<u8> {
+        let mut pat = vec![0x00, 0x01, 0xc1, 0x00, 0x00];
+        pat.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pat.extend_from_slice(&(0xe000 | PMT_PID).to_be_bytes());
+
+        let mut pmt = PROGRAM_NUMBER.to_be_bytes().to_vec();
+        pmt.extend_from_slice(&[0xc1, 0x00, 0x00]);
+        pmt.extend_from_slice(&(0xe000 | self.pcr_pid()).to_be_bytes());
+        // No program info
+        pmt.extend_from_slice(&[0xf0, 0x00]);
+        for (pid, stream_type) in [(VIDEO_PID, self.video), (AUDIO_PID, self.audio)] {
+            if let Some(stream_type) = stream_type {
+                pmt.push(stream_type);
+                pmt.extend_from_slice(&(0xe000 | pid).to_be_bytes());
+                pmt.extend_from_slice(&[0xf0, 0x00]);
+            }
+        }
+
+        let mut data = self.psi_packet(PAT_PID, 0x00, &pat);
+        data.extend_from_slice(&self.psi_packet(PMT_PID, 0x02, &pmt));
+        data
+    }
+
+    /// An access unit in Annex B format, with the clock reference in front of it.
+    pub fn write_video(&mut self, pts: u64, dts: u64, data: &[u8], keyframe: bool) -> Vec<u8> {
+        let pcr = (self.pcr_pid() == VIDEO_PID).then_some(dts);
+        let pes = pes_packet(VIDEO_STREAM_ID, pts, Some(dts), data);
+        self.packetize(VIDEO_PID, &pes, pcr, keyframe)
+    }
+
+    /// An AAC frame with its ADTS header.
+    pub fn write_audio(&mut self, pts: u64, data: &[u8]) -> Vec<u8> {
+        let pcr = (self.pcr_pid() == AUDIO_PID).then_some(pts);
+        let pes = pes_packet(AUDIO_STREAM_ID, pts, None, data);
+        self.packetize(AUDIO_PID, &pes, pcr, pcr.is_some())
+    }
+
+    fn next_continuity(&mut self, pid: u16) -> u8 {
+        let counter = self.continuity.entry(pid).or_insert(0x0f);
+        *counter = (*counter + 1) & 0x0f;
+        *counter
+    }
+
+    // A section with its header and CRC in a single packet, padded with 0xff
+    fn psi_packet(&mut self, pid: u16, table_id: u8, body: &[u8]) -> Vec<u8> {
+        let mut section = vec![table_id];
+        // Section syntax, then the length of what follows including the CRC
+        section.extend_from_slice(&(0xb000 | (body.len() as u16 + 4)).to_be_bytes());
+        section.extend_from_slice(body);
+        section.extend_from_slice(&crc32(&section).to_be_bytes());
+
+        let continuity = self.next_continuity(pid);
+        let mut packet = vec![
+            SYNC_BYTE,
+            0x40 | (pid >> 8) as u8,
+            pid as u8,
+            0x10 | continuity,
+        ];
+        // Pointer field
+        packet.push(0x00);
+        packet.extend_from_slice(&section);
+        packet.resize(PACKET_SIZE, 0xff);
+        packet
+    }
+
+    fn packetize(
+        &mut self,
+        pid: u16,
+        pes: &[u8],
+        pcr: Option<u64>,
+        random_access: bool,
+    ) -> Vec<u8> {
+        let mut data = Vec::with_capacity((pes.len() / 184 + 1) * PACKET_SIZE);
+        let mut position = 0;
+        while position < pes.len() {
+            let first = position == 0;
+            // The adaptation field without its length byte
+            let mut adaptation: Option<Vec<u8>> = None;
+            if first && (pcr.is_some() || random_access) {
+                let mut field = vec![if random_access { 0x40 } else { 0x00 }];
+                if let Some(pcr) = pcr {
+                    field[0] |= 0x10;
+                    field.extend_from_slice(&pcr_bytes(pcr));
+                }
+                adaptation = Some(field);
+            }
+
+            let header_size = adaptation.as_ref().map_or(0, |field| field.len() + 1);
+            let space = PACKET_SIZE - 4 - header_size;
+            let remaining = pes.len() - position;
+            if remaining < space {
+                // The last packet is filled up with stuffing in the adaptation field
+                let stuffing = space - remaining;
+                match &mut adaptation {
+                    Some(field) => field.resize(field.len() + stuffing, 0xff),
+                    None if stuffing == 1 => adaptation = Some(Vec::new()),
+                    None => {
+                        let mut field = vec![0x00];
+                        field.resize(stuffing - 1, 0xff);
+                        adaptation = Some(field);
+                    }
+                }
+            }
+            let size = remaining.min(space);
+
+            let continuity = self.next_continuity(pid);
+            let start = if first { 0x40 } else { 0x00 };
+            data.extend_from_slice(&[SYNC_BYTE, start | (pid >> 8) as u8, pid as u8]);
+            match &adaptation {
+                Some(field) => {
+                    data.push(0x30 | continuity);
+                    data.push(field.len() as u8);
+                    data.extend_from_slice(field);
+                }
+                None => data.push(0x10 | continuity),
+            }
+            data.extend_from_slice(&pes[position..position + size]);
+            position += size;
+        }
+        data
+    }
+}
+
+fn pes_packet(stream_id: u8, pts: u64, dts: Option<u64>, data: &[u8]) -> Vec<u8> {
+    let dts = dts.filter(|dts| *dts != pts);
+    let header_size = if dts.is_some() { 10 } else { 5 };
+    // Video packets may be too big for the length field, 0 leaves it open
+    let length = 3 + header_size + data.len();
+    let length = if length > u16::MAX as usize {
+        0
+    } else {
+        length as u16
+    };
+
+    let mut pes = vec![0x00, 0x00, 0x01, stream_id];
+    pes.extend_from_slice(&length.to_be_bytes());
+    pes.push(0x80);
+    match dts {
+        Some(dts) => {
+            pes.extend_from_slice(&[0xc0, header_size as u8]);
+            pes.extend_from_slice(&timestamp_bytes(0x3, pts));
+            pes.extend_from_slice(&timestamp_bytes(0x1, dts));
+        }
+        None => {
+            pes.extend_from_slice(&[0x80, header_size as u8]);
+            pes.extend_from_slice(&timestamp_bytes(0x2, pts));
+        }
+    }
+    pes.extend_from_slice(data);
+    pes
+}
+
+// A 33 bit timestamp split around marker bits, behind a 4 bit prefix
+fn timestamp_bytes(prefix: u8, timestamp: u64) -> [u8; 5] {
+    let timestamp = timestamp & TIMESTAMP_MASK;
+    [
+        prefix << 4 | ((timestamp >> 29) as u8 & 0x0e) | 1,
+        (timestamp >> 22) as u8,
+        ((timestamp >> 14) as u8 & 0xfe) | 1,
+        (timestamp >> 7) as u8,
+        ((timestamp << 1) as u8 & 0xfe) | 1,
+    ]
+}
+
+// The 33 bit base of the program clock reference, the 27 MHz extension is left at 0
+fn pcr_bytes(pcr: u64) -> [u8; 6] {
+    let base = pcr & TIMESTAMP_MASK;
+    [
+        (base >> 25) as u8,
+        (base >> 17) as u8,
+        (base >> 9) as u8,
+        (base >> 1) as u8,
+        ((base & 1) as u8) << 7 | 0x7e,
+        0x00,
+    ]
+}
+
+// CRC-32/MPEG-2 of the PSI sections
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                crc << 1 ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tables() {
+        let mut muxer = TsMuxer::new(Some(stream_type::H264), Some(stream_type::AAC_ADTS));
+        let tables = muxer.tables();
+        assert_eq!(tables.len(), 2 * PACKET_SIZE);
+        // The PAT as ffmpeg writes it, CRC included
+        assert_eq!(
+            &tables[..21],
+            &[
+                0x47, 0x40, 0x00, 0x10, 0x00, 0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00,
+                0x01, 0xf0, 0x00, 0x2a, 0xb1, 0x04, 0xb2
+            ]
+        );
+        // PCR on the video PID, then H.264 and AAC
+        let pmt = &tables[PACKET_SIZE..];
+        assert_eq!(&pmt[..4], &[0x47, 0x50, 0x00, 0x10]);
+        assert_eq!(&pmt[13..15], &[0xe1, 0x00]);
+        assert_eq!(
+            &pmt[17..27],
+            &[0x1b, 0xe1, 0x00, 0xf0, 0x00, 0x0f, 0xe1, 0x01, 0xf0, 0x00]
+        );
+        // The continuity counter counts up per PID
+        assert_eq!(muxer.tables()[3], 0x11);
+    }
+
+    #[test]
+    fn test_pes_packets() {
+        let mut muxer = TsMuxer::new(Some(stream_type::H264), Some(stream_type::AAC_ADTS));
+        let frame = vec![0xab; 400];
+        let data = muxer.write_video(3600 + 7200, 3600, &frame, true);
+        assert_eq!(data.len() % PACKET_SIZE, 0);
+        assert!(data
+            .chunks(PACKET_SIZE)
+            .all(|packet| packet[0] == SYNC_BYTE));
+
+        // Payload start, random access and PCR in the first packet
+        assert_eq!(&data[1..4], &[0x41, 0x00, 0x30]);
+        assert_eq!(data[5], 0x50);
+        assert_eq!(&data[6..12], &pcr_bytes(3600));
+        // PES header with PTS and DTS
+        let pes = &data[12..];
+        assert_eq!(&pes[..4], &[0x00, 0x00, 0x01, 0xe0]);
+        assert_eq!(&pes[7..9], &[0xc0, 10]);
+        assert_eq!(&pes[9..14], &timestamp_bytes(0x3, 10800));
+        assert_eq!(timestamp_bytes(0x2, 0x1_0000_0000), [0x29, 0, 1, 0, 1]);
+
+        // Every byte of the frame made it, after the headers and the stuffing
+        let payload: Vec<u8> = data
+            .chunks(PACKET_SIZE)
+            .flat_map(|packet| {
+                let start = if packet[3] & 0x20 != 0 {
+                    5 + packet[4] as usize
+                } else {
+                    4
+                };
+                packet[start..].to_vec()
+            })
+            .collect();
+        assert_eq!(&payload[19..], &frame[..]);
+
+        // Audio only carries a PTS, the counter of its own PID starts at 0
+        let data = muxer.write_audio(9000, &[0xff, 0xf1]);
+        assert_eq!(data.len(), PACKET_SIZE);
+        assert_eq!(data[3] & 0x0f, 0);
+        let pes = &data[data.len() - 16..];
+        assert_eq!(&pes[..4], &[0x00, 0x00, 0x01, 0xc0]);
+        assert_eq!(&pes[4..6], &[0x00, 10]);
+    }
+}
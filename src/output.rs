@@ -0,0 +1,75 @@
+// This file runs the outputs that follow every published stream, like the MP4 recorder and
+// the HLS segmenter. An output gets the frames of a stream through a subscription, like a
+// player, and writes its files synchronously on a thread of its own.
+
+// Path: src/output.rs
+use crate::stream::{MediaFrame, StreamRegistry, Subscription};
+use log::warn;
+use tokio::sync::broadcast::error::RecvError;
+
+/// The writer of one output for one published stream.
+pub trait MediaSink: Send + 'static {
+    fn write_frame(&mut self, frame: &MediaFrame) -> std::io::Result<()>;
+
+    // Frames were dropped because the sink fell behind, what depends on them has to go
+    fn discontinuity(&mut self);
+
+    // The stream ended
+    fn close(&mut self);
+}
+
+/// Creates a sink for every stream that starts being published, `create` returns None for
+/// streams the output skips.
+pub async fn run_sinks<S, F>(name: &'static str, streams: StreamRegistry, create: F)
+where
+    S: MediaSink,
+    F: Fn(&str) -> Option<S>,
+{
+    let mut published = streams.watch_published();
+    loop {
+        let path = match published.recv().await {
+            Ok(path) => path,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("{} missed {} published streams", name, missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Some(subscription) = streams.subscribe(&path) else {
+            continue;
+        };
+        let Some(sink) = create(&path) else {
+            continue;
+        };
+        // Not on the blocking pool, a sink holds its thread for as long as the stream lasts
+        let spawned = std::thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || feed(name, &path, sink, subscription));
+        if let Err(err) = spawned {
+            warn!("{} could not start a thread: {}", name, err);
+        }
+    }
+}
+
+fn feed<S: MediaSink>(name: &str, path: &str, mut sink: S, subscription: Subscription) {
+    let mut frames = subscription.frames;
+    let mut result = subscription
+        .headers
+        .iter()
+        .chain(&subscription.gop)
+        .try_for_each(|frame| sink.write_frame(frame));
+    while result.is_ok() {
+        match frames.blocking_recv() {
+            Ok(frame) => result = sink.write_frame(&frame),
+            Err(RecvError::Lagged(missed)) => {
+                warn!("{} of {} missed {} frames", name, path, missed);
+                sink.discontinuity();
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    if let Err(err) = result {
+        warn!("{} of {} stopped: {}", name, path, err);
+    }
+    sink.close();
+}
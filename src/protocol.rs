@@ -1,3 +1,419 @@
-// This file will handle the RTMP protocol. It should define functions for handling the handshake, chunking and dechunking messages, and processing different types of messages.
+// This file handles the RTMP chunk stream. Messages arrive split into chunks of at most the
+// negotiated chunk size, interleaved across chunk streams, with headers that only carry what
+// changed since the previous chunk of the same stream. The reader puts them back together,
+// the writer splits outgoing messages up the simplest way: a full header for every message.
 
 // Path: src/protocol.rs
+use std::collections::HashMap;
+
+pub const DEFAULT_CHUNK_SIZE: usize = 128;
+// Timestamps from this value on are carried in the extended timestamp field
+const EXTENDED_TIMESTAMP: u32 = 0xff_ffff;
+
+/// A complete message as reassembled from its chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawMessage {
+    pub chunk_stream_id: u32,
+    // Absolute, in milliseconds
+    pub timestamp: u32,
+    pub type_id: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+// What the previous chunk of a chunk stream said, later headers only carry the changes
+#[derive(Debug, Clone, Copy, Default)]
+struct ChunkHeader {
+    timestamp: u32,
+    timestamp_delta: u32,
+    length: u32,
+    type_id: u8,
+    stream_id: u32,
+    extended_timestamp: bool,
+}
+
+#[derive(Debug, Default)]
+struct ChunkStream {
+    header: ChunkHeader,
+    payload: Vec<u8>,
+}
+
+// The outcome of reading one chunk, with the number of bytes it took up
+enum Chunk {
+    Incomplete,
+    Partial(usize),
+    Complete(usize, RawMessage),
+}
+
+#[derive(Debug)]
+pub struct ChunkReader {
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    streams: HashMap<u32, ChunkStream>,
+}
+
+impl Default for ChunkReader {
+    fn default() -> ChunkReader {
+        ChunkReader::new()
+    }
+}
+
+impl ChunkReader {
+    pub fn new() -> ChunkReader {
+        ChunkReader {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            buffer: Vec::new(),
+            streams: HashMap::new(),
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    // Set by the peer's Set Chunk Size message, applies to every chunk after it
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+
+    // Drops the partial message of a chunk stream, as requested by an Abort message
+    pub fn abort(&mut self, chunk_stream_id: u32) {
+        if let Some(stream) = self.streams.get_mut(&chunk_stream_id) {
+            stream.payload.clear();
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete message, or None until more data has been pushed.
+    pub fn next_message(&mut self) -> Result<Option<RawMessage>, Box<dyn std::error::Error>> {
+        loop {
+            match self.read_chunk()? {
+                Chunk::Incomplete => return Ok(None),
+                Chunk::Partial(consumed) => {
+                    self.buffer.drain(..consumed);
+                }
+                Chunk::Complete(consumed, message) => {
+                    self.buffer.drain(..consumed);
+                    return Ok(Some(message));
+                }
+            }
+        }
+    }
+
+    // Reads one chunk from the front of the buffer. Nothing is changed while the buffer
+    // does not hold the whole chunk yet.
+    fn read_chunk(&mut self) -> Result<Chunk, Box<dyn std::error::Error>> {
+        let data = &self.buffer;
+        let Some(&first) = data.first() else {
+            return Ok(Chunk::Incomplete);
+        };
+        let fmt = first >> 6;
+        let (chunk_stream_id, mut position) = match first & 0x3f {
+            0 => match data.get(1) {
+                Some(&id) => (id as u32 + 64, 2),
+                None => return Ok(Chunk::Incomplete),
+            },
+            1 => match data.get(1..3) {
+                Some(id) => (id[1] as u32 * 256 + id[0] as u32 + 64, 3),
+                None => return Ok(Chunk::Incomplete),
+            },
+            id => (id as u32, 1),
+        };
+
+        let header_size = [11, 7, 3, 0][fmt as usize];
+        let Some(header) = data.get(position..position + header_size) else {
+            return Ok(Chunk::Incomplete);
+        };
+        position += header_size;
+        let read_u24 = |bytes: &[u8]| u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        let (previous, received) = match self.streams.get(&chunk_stream_id) {
+            Some(stream) => (stream.header, stream.payload.len()),
+            // Type 1 carries everything but the message stream id, some clients start with it
+            None if fmt <= 1 => (ChunkHeader::default(), 0),
+            None => {
+                return Err(format!(
+                    "chunk type {} on chunk stream {} without a previous header",
+                    fmt, chunk_stream_id
+                )
+                .into())
+            }
+        };
+        // Only type 3 chunks continue a message, every other header starts a new one
+        let new_message = fmt != 3 || received == 0;
+        let received = if new_message { 0 } else { received };
+
+        let mut state = previous;
+        if fmt <= 2 {
+            let mut field = read_u24(&header[0..3]);
+            state.extended_timestamp = field == EXTENDED_TIMESTAMP;
+            if state.extended_timestamp {
+                let Some(extended) = data.get(position..position + 4) else {
+                    return Ok(Chunk::Incomplete);
+                };
+                field = u32::from_be_bytes([extended[0], extended[1], extended[2], extended[3]]);
+                position += 4;
+            }
+            state.timestamp_delta = field;
+            state.timestamp = if fmt == 0 {
+                field
+            } else {
+                state.timestamp.wrapping_add(field)
+            };
+            if fmt <= 1 {
+                state.length = read_u24(&header[3..6]);
+                state.type_id = header[6];
+            }
+            if fmt == 0 {
+                state.stream_id = u32::from_le_bytes([header[7], header[8], header[9], header[10]]);
+            }
+        } else {
+            // Type 3 repeats the extended timestamp if the header it continues had one
+            if state.extended_timestamp {
+                if data.len() < position + 4 {
+                    return Ok(Chunk::Incomplete);
+                }
+                position += 4;
+            }
+            if new_message {
+                state.timestamp = state.timestamp.wrapping_add(state.timestamp_delta);
+            }
+        }
+
+        let length = state.length as usize;
+        let size = (length - received.min(length)).min(self.chunk_size);
+        let Some(body) = data.get(position..position + size) else {
+            return Ok(Chunk::Incomplete);
+        };
+        let body = body.to_vec();
+        position += size;
+
+        let stream = self.streams.entry(chunk_stream_id).or_default();
+        if new_message {
+            stream.payload.clear();
+        }
+        stream.header = state;
+        stream.payload.extend_from_slice(&body);
+
+        if stream.payload.len() < length {
+            return Ok(Chunk::Partial(position));
+        }
+        let message = RawMessage {
+            chunk_stream_id,
+            timestamp: state.timestamp,
+            type_id: state.type_id,
+            stream_id: state.stream_id,
+            payload: std::mem::take(&mut stream.payload),
+        };
+        Ok(Chunk::Complete(position, message))
+    }
+}
+
+/// Splits outgoing messages into chunks of the chunk size announced to the peer.
+#[derive(Debug)]
+pub struct ChunkWriter {
+    chunk_size: usize,
+}
+
+impl Default for ChunkWriter {
+    fn default() -> ChunkWriter {
+        ChunkWriter::new()
+    }
+}
+
+impl ChunkWriter {
+    pub fn new() -> ChunkWriter {
+        ChunkWriter {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    // Only once our Set Chunk Size message has been sent, the peer reads with the old size until then
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+
+    /// The message as a type 0 chunk followed by type 3 chunks for the rest of the payload.
+    pub fn chunks(&self, message: &RawMessage) -> Vec<u8> {
+        let extended = message.timestamp >= EXTENDED_TIMESTAMP;
+        let mut basic_header = match message.chunk_stream_id {
+            id @ 2..=63 => vec![id as u8],
+            id @ 64..=319 => vec![0, (id - 64) as u8],
+            id => {
+                let id = id.clamp(320, 65599) - 64;
+                vec![1, id as u8, (id >> 8) as u8]
+            }
+        };
+
+        let mut data = Vec::with_capacity(message.payload.len() + 18);
+        data.extend_from_slice(&basic_header);
+        let timestamp = message.timestamp.min(EXTENDED_TIMESTAMP);
+        data.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+        data.extend_from_slice(&(message.payload.len() as u32).to_be_bytes()[1..]);
+        data.push(message.type_id);
+        data.extend_from_slice(&message.stream_id.to_le_bytes());
+
+        basic_header[0] |= 0xc0;
+        for (index, chunk) in message.payload.chunks(self.chunk_size).enumerate() {
+            if index > 0 {
+                data.extend_from_slice(&basic_header);
+            }
+            // Repeated in every chunk of the message
+            if extended {
+                data.extend_from_slice(&message.timestamp.to_be_bytes());
+            }
+            data.extend_from_slice(chunk);
+        }
+        if message.payload.is_empty() && extended {
+            data.extend_from_slice(&message.timestamp.to_be_bytes());
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reassemble_chunks() {
+        let mut reader = ChunkReader::new();
+
+        // 300 byte video message in chunks of 128, type 0 header then type 3 continuations
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut data = vec![
+            0x06, 0x00, 0x00, 0x28, 0x00, 0x01, 0x2c, 0x09, 0x01, 0x00, 0x00, 0x00,
+        ];
+        data.extend_from_slice(&payload[..128]);
+        data.push(0xc6);
+        data.extend_from_slice(&payload[128..256]);
+        // An audio message on another chunk stream interleaved between the chunks
+        data.extend_from_slice(&[
+            0x04, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x02, 0x08, 0x01, 0x00, 0x00, 0x00, 0xaf, 0x01,
+        ]);
+        data.push(0xc6);
+        data.extend_from_slice(&payload[256..]);
+        // The next video message only sends the timestamp delta
+        data.extend_from_slice(&[0x86, 0x00, 0x00, 0x21, 0x17, 0x01]);
+
+        // Byte by byte, messages are only complete once all of their chunks arrived
+        let mut messages = Vec::new();
+        for byte in &data {
+            reader.push(&[*byte]);
+            while let Some(message) = reader.next_message().unwrap() {
+                messages.push(message);
+            }
+        }
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].type_id, 8);
+        assert_eq!(messages[0].timestamp, 42);
+        assert_eq!(messages[0].payload, vec![0xaf, 0x01]);
+        assert_eq!(messages[1].type_id, 9);
+        assert_eq!(messages[1].timestamp, 40);
+        assert_eq!(messages[1].stream_id, 1);
+        assert_eq!(messages[1].payload, payload);
+
+        // Type 2 keeps the length of 300, so the 2 bytes are only the start of the message
+        assert!(reader.next_message().unwrap().is_none());
+        reader.push(&payload[2..128]);
+        reader.push(&[0xc6]);
+        reader.push(&payload[128..256]);
+        assert!(reader.next_message().unwrap().is_none());
+        reader.push(&[0xc6]);
+        reader.push(&payload[256..]);
+        let message = reader.next_message().unwrap().unwrap();
+        assert_eq!(message.timestamp, 73);
+        assert_eq!(&message.payload[..2], &[0x17, 0x01]);
+    }
+
+    #[test]
+    fn test_extended_timestamp() {
+        let mut reader = ChunkReader::new();
+        let mut data = vec![
+            0x03, 0xff, 0xff, 0xff, 0x00, 0x00, 0x02, 0x14, 0x00, 0x00, 0x00, 0x00,
+        ];
+        data.extend_from_slice(&0x0100_0000u32.to_be_bytes());
+        data.extend_from_slice(&[0x05, 0x00]);
+        reader.push(&data);
+        let message = reader.next_message().unwrap().unwrap();
+        assert_eq!(message.timestamp, 0x0100_0000);
+        assert_eq!(message.payload, vec![0x05, 0x00]);
+
+        // A type 2 header has no length, it needs a previous header on its chunk stream
+        let mut reader = ChunkReader::new();
+        reader.push(&[0x84, 0x00, 0x00, 0x01, 0x00]);
+        assert!(reader.next_message().is_err());
+    }
+
+    #[test]
+    fn test_write_chunks() {
+        let mut writer = ChunkWriter::new();
+        writer.set_chunk_size(100);
+        let mut reader = ChunkReader::new();
+        reader.set_chunk_size(100);
+
+        let messages = [
+            RawMessage {
+                chunk_stream_id: 6,
+                timestamp: 40,
+                type_id: 9,
+                stream_id: 1,
+                payload: (0..250).map(|i| i as u8).collect(),
+            },
+            RawMessage {
+                chunk_stream_id: 300,
+                timestamp: 0x0100_0000,
+                type_id: 8,
+                stream_id: 1,
+                payload: vec![0xaf; 150],
+            },
+        ];
+        for message in &messages {
+            let data = writer.chunks(message);
+            reader.push(&data);
+            assert_eq!(reader.next_message().unwrap().as_ref(), Some(message));
+        }
+        // 12 byte header, then a 1 byte header for each of the two chunks that follow
+        assert_eq!(writer.chunks(&messages[0]).len(), 250 + 12 + 2);
+        assert!(reader.next_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_abort_and_chunk_size() {
+        let mut reader = ChunkReader::new();
+        // Chunk stream ids from 320 on take a 3 byte basic header, 400 - 64 = 0x150
+        let type0 = |length: u32| {
+            let mut data = vec![0x01, 0x50, 0x01, 0x00, 0x00, 0x00];
+            data.extend_from_slice(&length.to_be_bytes()[1..]);
+            data.extend_from_slice(&[0x14, 0x00, 0x00, 0x00, 0x00]);
+            data
+        };
+
+        // Aborted after the first chunk, what follows on that chunk stream starts over
+        reader.push(&type0(200));
+        reader.push(&[0x02; 128]);
+        assert!(reader.next_message().unwrap().is_none());
+        reader.abort(400);
+        reader.push(&type0(10));
+        reader.push(&[0x03; 10]);
+        let message = reader.next_message().unwrap().unwrap();
+        assert_eq!(message.chunk_stream_id, 400);
+        assert_eq!(message.type_id, 20);
+        assert_eq!(message.payload, vec![0x03; 10]);
+
+        // A new chunk size applies from the next chunk on, 200 bytes now fit in one chunk
+        reader.set_chunk_size(4096);
+        reader.push(&type0(200));
+        reader.push(&[0x02; 200]);
+        let message = reader.next_message().unwrap().unwrap();
+        assert_eq!(message.payload, vec![0x02; 200]);
+        assert_eq!(reader.chunk_size(), 4096);
+    }
+}
@@ -0,0 +1,581 @@
+// This file records published streams to FLV files. The recorder subscribes to a stream like a
+// player, so pulled streams are recorded as well. A recording starts at a keyframe with the
+// onMetaData of the publisher and the sequence headers seen so far, so every file can be
+// played on its own. Duration and file size are only known at the end and patched in on close.
+
+// Path: src/record/flv.rs
+use super::recording_path;
+use crate::config::{RecordConfig, RecordMode};
+use crate::media::flv::{
+    flv_header, AudioHeader, FlvTag, TagType, VideoHeader, FLV_TAG_HEADER_SIZE,
+};
+use crate::output::{run_sinks, MediaSink};
+use crate::server::connection::message::amf0::amf0_borrowed::Amf0BorrowedReader;
+use crate::server::connection::message::amf0::amf0_writer::Amf0Writer;
+use crate::server::connection::message::amf0::define::Amf0ValueType;
+use crate::server::server::ServerContext;
+use crate::stream::MediaFrame;
+use bytesio::bytes_writer::BytesWriter;
+use indexmap::IndexMap;
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// onMetaData properties the recorder fills in itself
+const DURATION: &str = "duration";
+const FILESIZE: &str = "filesize";
+
+// A file being written
+struct Recording {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    // Timestamps in the file count from the first tag
+    first_timestamp: u32,
+    last_timestamp: u32,
+    // Where the numbers of the onMetaData properties are, to patch them on close
+    duration_offset: u64,
+    filesize_offset: u64,
+}
+
+impl Recording {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_tag(&mut self, tag_type: TagType, timestamp: u32, data: &[u8]) -> std::io::Result<()> {
+        // Audio may be stamped slightly before the keyframe the file starts at
+        let relative = timestamp.wrapping_sub(self.first_timestamp);
+        let relative = if relative > i32::MAX as u32 {
+            0
+        } else {
+            relative
+        };
+        self.last_timestamp = self.last_timestamp.max(relative);
+        self.write(&FlvTag::new(tag_type, relative, data.to_vec()).serialize())
+    }
+
+    fn duration_ms(&self) -> u32 {
+        self.last_timestamp
+    }
+
+    fn finish(mut self) -> std::io::Result<(PathBuf, u64, u32)> {
+        let duration = self.duration_ms();
+        self.writer.seek(SeekFrom::Start(self.duration_offset))?;
+        self.writer
+            .write_all(&(duration as f64 / 1000.0).to_be_bytes())?;
+        self.writer.seek(SeekFrom::Start(self.filesize_offset))?;
+        self.writer.write_all(&(self.size as f64).to_be_bytes())?;
+        self.writer.flush()?;
+        Ok((self.path, self.size, duration))
+    }
+}
+
+/// Writes the audio and video of one published stream to FLV files, as the record mode allows.
+pub struct FlvRecorder {
+    config: RecordConfig,
+    mode: RecordMode,
+    app: String,
+    stream: String,
+    // Only consulted in manual mode
+    switch: Arc<AtomicBool>,
+    metadata: IndexMap<String, Amf0ValueType>,
+    audio_header: Option<Vec<u8>>,
+    video_header: Option<Vec<u8>>,
+    seen_audio: bool,
+    seen_video: bool,
+    recording: Option<Recording>,
+    // Files started so far, for {index}
+    index: u32,
+}
+
+impl FlvRecorder {
+    pub fn new(
+        config: &RecordConfig,
+        mode: RecordMode,
+        stream_path: &str,
+        switch: Arc<AtomicBool>,
+    ) -> FlvRecorder {
+        let (app, stream) = stream_path.split_once('/').unwrap_or(("", stream_path));
+        FlvRecorder {
+            config: config.clone(),
+            mode,
+            app: app.to_owned(),
+            stream: stream.to_owned(),
+            switch,
+            metadata: IndexMap::new(),
+            audio_header: None,
+            video_header: None,
+            seen_audio: false,
+            seen_video: false,
+            recording: None,
+            index: 0,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // Used from the next file on, the current one keeps what it started with
+    pub fn set_metadata(&mut self, properties: &IndexMap<String, Amf0ValueType>) {
+        self.metadata = properties.clone();
+    }
+
+    pub fn write_audio(
+        &mut self,
+        timestamp: u32,
+        header: Option<&AudioHeader>,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        if !self.mode.records_audio() {
+            return Ok(());
+        }
+        self.seen_audio = true;
+        let sequence_header = header.is_some_and(|header| header.is_sequence_header());
+        if sequence_header {
+            self.audio_header = Some(data.to_vec());
+        }
+        // Without video any audio frame is a good place to start a file
+        let start = !self.has_video() && !sequence_header;
+        self.write(TagType::Audio, timestamp, data, start)
+    }
+
+    pub fn write_video(
+        &mut self,
+        timestamp: u32,
+        header: Option<&VideoHeader>,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        if !self.mode.records_video() {
+            return Ok(());
+        }
+        self.seen_video = true;
+        let sequence_header = header.is_some_and(|header| header.is_sequence_header());
+        if sequence_header {
+            self.video_header = Some(data.to_vec());
+        }
+        let start = header.is_some_and(|header| header.is_keyframe()) && !sequence_header;
+        self.write(TagType::Video, timestamp, data, start)
+    }
+
+    pub fn close(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            match recording.finish() {
+                Ok((path, size, duration)) => info!(
+                    "Recorded {} ({} bytes, {:.1} s)",
+                    path.display(),
+                    size,
+                    duration as f64 / 1000.0
+                ),
+                Err(err) => warn!("Failed to finish the recording: {}", err),
+            }
+        }
+    }
+
+    fn has_video(&self) -> bool {
+        self.mode.records_video() && (self.seen_video || self.metadata.contains_key("videocodecid"))
+    }
+
+    fn has_audio(&self) -> bool {
+        self.mode.records_audio() && (self.seen_audio || self.metadata.contains_key("audiocodecid"))
+    }
+
+    fn write(
+        &mut self,
+        tag_type: TagType,
+        timestamp: u32,
+        data: &[u8],
+        start: bool,
+    ) -> std::io::Result<()> {
+        if self.mode == RecordMode::Manual && !self.switch.load(Ordering::Relaxed) {
+            self.close();
+            return Ok(());
+        }
+        if start
+            && self
+                .recording
+                .as_ref()
+                .is_some_and(|r| self.limit_reached(r))
+        {
+            self.close();
+        }
+        if self.recording.is_none() {
+            if !start {
+                return Ok(());
+            }
+            self.open(timestamp)?;
+        }
+        match &mut self.recording {
+            Some(recording) => recording.write_tag(tag_type, timestamp, data),
+            None => Ok(()),
+        }
+    }
+
+    fn limit_reached(&self, recording: &Recording) -> bool {
+        let size = self
+            .config
+            .max_size
+            .is_some_and(|max_size| recording.size >= max_size);
+        let duration = self
+            .config
+            .max_duration_secs
+            .is_some_and(|max_duration| recording.duration_ms() as u64 >= max_duration * 1000);
+        size || duration
+    }
+
+    fn open(&mut self, timestamp: u32) -> std::io::Result<()> {
+        let path = self.next_path();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = File::create(&path)?;
+        let mut recording = Recording {
+            path,
+            writer: BufWriter::new(file),
+            size: 0,
+            first_timestamp: timestamp,
+            last_timestamp: 0,
+            duration_offset: 0,
+            filesize_offset: 0,
+        };
+
+        recording.write(&flv_header(self.has_audio(), self.has_video()))?;
+        let (script, duration_offset, filesize_offset) = self.metadata_script()?;
+        let body_start = recording.size + FLV_TAG_HEADER_SIZE as u64;
+        recording.duration_offset = body_start + duration_offset as u64;
+        recording.filesize_offset = body_start + filesize_offset as u64;
+        recording.write_tag(TagType::Script, timestamp, &script)?;
+        if let Some(header) = self.video_header.as_ref().filter(|_| self.has_video()) {
+            recording.write_tag(TagType::Video, timestamp, header)?;
+        }
+        if let Some(header) = self.audio_header.as_ref().filter(|_| self.has_audio()) {
+            recording.write_tag(TagType::Audio, timestamp, header)?;
+        }
+
+        info!(
+            "Recording {}/{} to {}",
+            self.app,
+            self.stream,
+            recording.path.display()
+        );
+        self.index += 1;
+        self.recording = Some(recording);
+        Ok(())
+    }
+
+    // onMetaData with placeholders for the duration and file size, and where their numbers are
+    fn metadata_script(&self) -> std::io::Result<(Vec<u8>, usize, usize)> {
+        let mut properties = IndexMap::new();
+        properties.insert(DURATION.to_owned(), Amf0ValueType::Number(0.0));
+        properties.insert(FILESIZE.to_owned(), Amf0ValueType::Number(0.0));
+        for (key, value) in &self.metadata {
+            if key != DURATION && key != FILESIZE {
+                properties.insert(key.clone(), value.clone());
+            }
+        }
+
+        let mut writer = Amf0Writer::new(BytesWriter::new());
+        writer
+            .write_string(&"onMetaData".to_owned())
+            .and_then(|_| writer.write_ecma_array(&properties))
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+        let script = writer.extract_current_bytes().to_vec();
+
+        // The placeholders come first, so the first match is ours
+        let number_offset = |key: &str| {
+            let mut pattern = (key.len() as u16).to_be_bytes().to_vec();
+            pattern.extend_from_slice(key.as_bytes());
+            script
+                .windows(pattern.len())
+                .position(|window| window == pattern)
+                // Past the key and the number marker
+                .map(|position| position + pattern.len() + 1)
+                .unwrap_or_default()
+        };
+        let duration_offset = number_offset(DURATION);
+        let filesize_offset = number_offset(FILESIZE);
+        Ok((script, duration_offset, filesize_offset))
+    }
+
+    fn next_path(&self) -> PathBuf {
+        recording_path(
+            &self.config.directory,
+            &self.config.path_template,
+            &self.app,
+            &self.stream,
+            self.index,
+        )
+    }
+}
+
+// The properties of an onMetaData script frame
+fn metadata_properties(frame: &MediaFrame) -> Option<IndexMap<String, Amf0ValueType>> {
+    let mut reader = Amf0BorrowedReader::new(&frame.data);
+    if reader.read_any().ok()?.as_str() != Some("onMetaData") {
+        return None;
+    }
+    reader
+        .read_any()
+        .ok()?
+        .as_object()?
+        .to_owned_properties()
+        .ok()
+}
+
+impl MediaSink for FlvRecorder {
+    fn write_frame(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        // Without a header the tag could not be parsed, it is written as it is
+        match frame.tag_type {
+            TagType::Script => {
+                if let Some(properties) = metadata_properties(frame) {
+                    self.set_metadata(&properties);
+                }
+                Ok(())
+            }
+            TagType::Audio => {
+                let header = AudioHeader::parse(&frame.data).ok();
+                self.write_audio(frame.timestamp, header.as_ref(), &frame.data)
+            }
+            TagType::Video => {
+                let header = VideoHeader::parse(&frame.data).ok();
+                self.write_video(frame.timestamp, header.as_ref(), &frame.data)
+            }
+        }
+    }
+
+    // The GOP is broken, the next file starts at the next keyframe
+    fn discontinuity(&mut self) {
+        FlvRecorder::close(self);
+    }
+
+    fn close(&mut self) {
+        FlvRecorder::close(self);
+    }
+}
+
+/// Starts an FLV recorder for every stream that is published to an app that records.
+pub struct FlvRecordings {
+    context: ServerContext,
+}
+
+impl FlvRecordings {
+    pub fn new(context: ServerContext) -> FlvRecordings {
+        FlvRecordings { context }
+    }
+
+    pub async fn run(self) {
+        let context = self.context;
+        let streams = context.streams.clone();
+        run_sinks("FLV recording", streams, move |path| {
+            let app = path.split('/').next().unwrap_or_default();
+            let mode = context.config.record_mode(app);
+            let switch = context.streams.recording_switch(path)?;
+            (mode != RecordMode::Off)
+                .then(|| FlvRecorder::new(&context.config.record, mode, path, switch))
+        })
+        .await
+    }
+}
+
+impl Drop for FlvRecorder {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FlvRecorder;
+    use crate::config::{RecordConfig, RecordMode};
+    use crate::media::flv::{AudioHeader, TagType, VideoHeader};
+    use crate::output::MediaSink;
+    use crate::server::connection::message::amf0::amf0_writer::Amf0Writer;
+    use crate::server::connection::message::amf0::define::Amf0ValueType;
+    use crate::stream::MediaFrame;
+    use bytesio::bytes_writer::BytesWriter;
+    use indexmap::IndexMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const AVC_SEQUENCE_HEADER: [u8; 6] = [0x17, 0x00, 0x00, 0x00, 0x00, 0x01];
+    const KEYFRAME: [u8; 6] = [0x17, 0x01, 0x00, 0x00, 0x00, 0xaa];
+    const INTER_FRAME: [u8; 6] = [0x27, 0x01, 0x00, 0x00, 0x00, 0xbb];
+    const AAC_SEQUENCE_HEADER: [u8; 4] = [0xaf, 0x00, 0x12, 0x10];
+    const AAC_FRAME: [u8; 3] = [0xaf, 0x01, 0x21];
+
+    fn config(name: &str) -> RecordConfig {
+        let directory = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        RecordConfig {
+            mode: RecordMode::All,
+            directory,
+            path_template: "{app}/{stream}-{index}.flv".to_owned(),
+            max_size: None,
+            max_duration_secs: None,
+            ..RecordConfig::default()
+        }
+    }
+
+    fn video(recorder: &mut FlvRecorder, timestamp: u32, data: &[u8]) {
+        let header = VideoHeader::parse(data).unwrap();
+        recorder
+            .write_video(timestamp, Some(&header), data)
+            .unwrap();
+    }
+
+    fn audio(recorder: &mut FlvRecorder, timestamp: u32, data: &[u8]) {
+        let header = AudioHeader::parse(data).unwrap();
+        recorder
+            .write_audio(timestamp, Some(&header), data)
+            .unwrap();
+    }
+
+    // (type, timestamp, body) of every tag, checking the previous tag sizes on the way
+    fn read_tags(file: &[u8]) -> Vec<(u8, u32, Vec<u8>)> {
+        let mut tags = Vec::new();
+        let mut position = 13;
+        while position < file.len() {
+            let tag = &file[position..];
+            let size = u32::from_be_bytes([0, tag[1], tag[2], tag[3]]) as usize;
+            let timestamp = u32::from_be_bytes([tag[7], tag[4], tag[5], tag[6]]);
+            let previous = &tag[11 + size..15 + size];
+            assert_eq!(
+                u32::from_be_bytes(previous.try_into().unwrap()),
+                size as u32 + 11
+            );
+            tags.push((tag[0], timestamp, tag[11..11 + size].to_vec()));
+            position += 15 + size;
+        }
+        tags
+    }
+
+    fn number_after(script: &[u8], key: &str) -> f64 {
+        let position = script
+            .windows(key.len())
+            .position(|window| window == key.as_bytes())
+            .unwrap()
+            + key.len()
+            + 1;
+        f64::from_be_bytes(script[position..position + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_record_flv() {
+        let config = config("record-flv");
+        let mut recorder = FlvRecorder::new(&config, RecordMode::All, "live/key", Arc::default());
+        let mut metadata = IndexMap::new();
+        metadata.insert("width".to_owned(), Amf0ValueType::Number(1280.0));
+        metadata.insert("duration".to_owned(), Amf0ValueType::Number(0.0));
+        recorder.set_metadata(&metadata);
+
+        // Nothing is written before the first keyframe
+        video(&mut recorder, 1000, &AVC_SEQUENCE_HEADER);
+        audio(&mut recorder, 1000, &AAC_SEQUENCE_HEADER);
+        audio(&mut recorder, 1010, &AAC_FRAME);
+        video(&mut recorder, 1020, &INTER_FRAME);
+        assert!(!recorder.is_recording());
+
+        video(&mut recorder, 1040, &KEYFRAME);
+        audio(&mut recorder, 1030, &AAC_FRAME);
+        video(&mut recorder, 3540, &INTER_FRAME);
+        recorder.close();
+
+        let file = std::fs::read(config.directory.join("live/key-0.flv")).unwrap();
+        assert_eq!(&file[..5], b"FLV\x01\x05");
+        let tags = read_tags(&file);
+        let types: Vec<(u8, u32)> = tags.iter().map(|tag| (tag.0, tag.1)).collect();
+        assert_eq!(
+            types,
+            vec![(18, 0), (9, 0), (8, 0), (9, 0), (8, 0), (9, 2500)]
+        );
+        assert_eq!(tags[1].2, AVC_SEQUENCE_HEADER);
+        assert_eq!(tags[3].2, KEYFRAME);
+
+        // The duration and file size are patched in, the publisher's duration is dropped
+        let script = &tags[0].2;
+        assert_eq!(number_after(script, "duration"), 2.5);
+        assert_eq!(number_after(script, "filesize"), file.len() as f64);
+        assert_eq!(number_after(script, "width"), 1280.0);
+        assert_eq!(script.windows(8).filter(|w| w == b"duration").count(), 1);
+
+        std::fs::remove_dir_all(config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_record_frames() {
+        let config = config("record-frames");
+        let mut recorder = FlvRecorder::new(&config, RecordMode::All, "live/key", Arc::default());
+        let mut properties = IndexMap::new();
+        properties.insert("width".to_owned(), Amf0ValueType::Number(640.0));
+        let mut writer = Amf0Writer::new(BytesWriter::new());
+        writer.write_string(&"onMetaData".to_owned()).unwrap();
+        writer.write_ecma_array(&properties).unwrap();
+        let script = writer.extract_current_bytes().to_vec();
+
+        let frames = [
+            MediaFrame::new(TagType::Script, 0, script),
+            MediaFrame::new(TagType::Video, 0, AVC_SEQUENCE_HEADER.to_vec()),
+            MediaFrame::new(TagType::Video, 0, KEYFRAME.to_vec()),
+            MediaFrame::new(TagType::Audio, 20, AAC_FRAME.to_vec()),
+        ];
+        for frame in &frames {
+            MediaSink::write_frame(&mut recorder, frame).unwrap();
+        }
+        MediaSink::close(&mut recorder);
+
+        let file = std::fs::read(config.directory.join("live/key-0.flv")).unwrap();
+        let tags = read_tags(&file);
+        let types: Vec<(u8, u32)> = tags.iter().map(|tag| (tag.0, tag.1)).collect();
+        assert_eq!(types, vec![(18, 0), (9, 0), (9, 0), (8, 20)]);
+        assert_eq!(number_after(&tags[0].2, "width"), 640.0);
+
+        std::fs::remove_dir_all(config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_record_rotation_and_modes() {
+        let mut config = config("record-rotation");
+        config.max_duration_secs = Some(1);
+        let mut recorder = FlvRecorder::new(&config, RecordMode::Video, "live/key", Arc::default());
+        video(&mut recorder, 0, &AVC_SEQUENCE_HEADER);
+        video(&mut recorder, 0, &KEYFRAME);
+        audio(&mut recorder, 500, &AAC_FRAME);
+        // Past the limit, but the file only ends at the next keyframe
+        video(&mut recorder, 1500, &INTER_FRAME);
+        video(&mut recorder, 2000, &KEYFRAME);
+        drop(recorder);
+
+        let first = std::fs::read(config.directory.join("live/key-0.flv")).unwrap();
+        assert_eq!(first[4], 0x01);
+        let tags = read_tags(&first);
+        assert!(tags.iter().all(|tag| tag.0 != 8));
+        assert_eq!(tags.last().unwrap().1, 1500);
+        // The next file starts over with the sequence header
+        let second = read_tags(&std::fs::read(config.directory.join("live/key-1.flv")).unwrap());
+        assert_eq!(second[1], (9, 0, AVC_SEQUENCE_HEADER.to_vec()));
+        assert_eq!(second[2], (9, 0, KEYFRAME.to_vec()));
+
+        // Manual recordings follow the switch
+        let switch = Arc::new(AtomicBool::new(false));
+        let mut recorder =
+            FlvRecorder::new(&config, RecordMode::Manual, "live/manual", switch.clone());
+        video(&mut recorder, 0, &KEYFRAME);
+        assert!(!recorder.is_recording());
+        switch.store(true, Ordering::Relaxed);
+        video(&mut recorder, 40, &INTER_FRAME);
+        assert!(!recorder.is_recording());
+        video(&mut recorder, 80, &KEYFRAME);
+        assert!(recorder.is_recording());
+        switch.store(false, Ordering::Relaxed);
+        video(&mut recorder, 120, &INTER_FRAME);
+        assert!(!recorder.is_recording());
+
+        std::fs::remove_dir_all(config.directory).unwrap();
+    }
+}
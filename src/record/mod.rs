@@ -0,0 +1,41 @@
+// This file holds what the recorders share. FLV and MP4 files are each written by a
+// recorder that subscribes to the stream like a player.
+
+// Path: src/record/mod.rs
+use crate::utils::{sanitize, utc_timestamp};
+use std::path::{Path, PathBuf};
+
+pub mod flv;
+pub mod mp4;
+
+// Where the next file of a stream goes, names that are taken get a -1, -2, ... suffix
+fn recording_path(
+    directory: &Path,
+    template: &str,
+    app: &str,
+    stream: &str,
+    index: u32,
+) -> PathBuf {
+    let relative = template
+        .replace("{app}", &sanitize(app))
+        .replace("{stream}", &sanitize(stream))
+        .replace("{timestamp}", &utc_timestamp())
+        .replace("{index}", &index.to_string());
+    let path = directory.join(relative);
+    if !path.exists() {
+        return path;
+    }
+
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{}-{}{}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
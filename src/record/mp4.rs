@@ -0,0 +1,625 @@
+// This file records published streams to MP4 files. The recorder subscribes to a stream like a
+// player and writes a fragmented MP4 as the stream goes, one moof/mdat per GOP, so a file cut
+// short by a crash still plays. When a file is done it is rewritten with a single moov, if
+// configured, for editors that do not read fragmented files.
+
+// Path: src/record/mp4.rs
+use super::recording_path;
+use crate::config::{RecordConfig, RecordMode};
+use crate::media::flv::{AudioTag, TagType, VideoTag};
+use crate::media::mp4::{
+    ftyp, init_segment, media_fragment, progressive_moov, Track, TrackConfig, TrackTable,
+};
+use crate::output::{run_sinks, MediaSink};
+use crate::segmenter::{SampleQueue, Segmenter};
+use crate::server::server::ServerContext;
+use crate::stream::MediaFrame;
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const VIDEO_TRACK: u32 = 1;
+const AUDIO_TRACK: u32 = 2;
+// Audio only streams have no GOPs to cut fragments at
+const AUDIO_FRAGMENT_MS: u32 = 2000;
+
+struct TrackState {
+    track: Track,
+    samples: SampleQueue,
+    // Every sample written so far with its offset in the file, for the progressive rewrite
+    table: TrackTable,
+}
+
+impl TrackState {
+    fn new(track: Track) -> TrackState {
+        TrackState {
+            table: TrackTable {
+                track: track.clone(),
+                samples: Vec::new(),
+                offsets: Vec::new(),
+            },
+            samples: SampleQueue::new(track.id),
+            track,
+        }
+    }
+}
+
+// A file being written
+struct Mp4File {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    first_timestamp: u32,
+    last_decode_time: u32,
+    fragments: u32,
+    // Where fragments of a file without video are cut
+    audio_fragments: Segmenter,
+    video: Option<TrackState>,
+    audio: Option<TrackState>,
+}
+
+impl Mp4File {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    // Decode time in the file, audio from just before the first keyframe is moved up to it
+    fn decode_time(&self, timestamp: u32) -> u32 {
+        let relative = timestamp.wrapping_sub(self.first_timestamp);
+        if relative > i32::MAX as u32 {
+            0
+        } else {
+            relative
+        }
+    }
+
+    fn push(
+        &mut self,
+        track_id: u32,
+        timestamp: u32,
+        composition_offset: i32,
+        keyframe: bool,
+        data: &[u8],
+    ) {
+        let decode_time = self.decode_time(timestamp);
+        self.last_decode_time = self.last_decode_time.max(decode_time);
+        let state = match track_id {
+            VIDEO_TRACK => self.video.as_mut(),
+            _ => self.audio.as_mut(),
+        };
+        if let Some(state) = state {
+            state
+                .samples
+                .push(decode_time as u64, composition_offset, keyframe, data);
+        }
+    }
+
+    // Writes a moof/mdat with the samples before the end, all of them without one
+    fn write_fragment(&mut self, end: Option<u32>) -> std::io::Result<()> {
+        let end = end.map(u64::from);
+        let has_video = self.video.is_some();
+        let mut runs = Vec::new();
+        if let Some(video) = &mut self.video {
+            runs.extend(video.samples.take_run(end, end));
+        }
+        if let Some(audio) = &mut self.audio {
+            // Audio goes on past a keyframe, its last sample ends where the next one starts
+            runs.extend(audio.samples.take_run(end, end.filter(|_| !has_video)));
+        }
+        if runs.is_empty() {
+            return Ok(());
+        }
+
+        self.fragments += 1;
+        let fragment = media_fragment(self.fragments, &runs);
+        // The mdat payload ends the fragment, runs in order
+        let data_size: usize = runs.iter().map(|run| run.data.len()).sum();
+        let mut offset = self.size + (fragment.len() - data_size) as u64;
+        for run in &runs {
+            let state = match run.track_id {
+                VIDEO_TRACK => self.video.as_mut(),
+                _ => self.audio.as_mut(),
+            };
+            if let Some(state) = state {
+                for sample in &run.samples {
+                    state.table.samples.push(*sample);
+                    state.table.offsets.push(offset);
+                    offset += sample.size as u64;
+                }
+            }
+        }
+        self.write(&fragment)
+    }
+
+    fn finish(mut self, finalize: bool) -> std::io::Result<(PathBuf, u64)> {
+        // The last samples last as long as the ones before them
+        self.write_fragment(None)?;
+        self.writer.flush()?;
+        if !finalize {
+            return Ok((self.path, self.size));
+        }
+
+        let tables: Vec<TrackTable> = [self.video, self.audio]
+            .into_iter()
+            .flatten()
+            .map(|state| state.table)
+            .filter(|table| !table.samples.is_empty())
+            .collect();
+        let size = rewrite_progressive(&self.path, tables)?;
+        Ok((self.path, size))
+    }
+}
+
+// Rewrites a fragmented file as ftyp, moov and a single mdat, replacing it when done
+fn rewrite_progressive(path: &PathBuf, mut tables: Vec<TrackTable>) -> std::io::Result<u64> {
+    // Samples keep their order in the file, so the copy reads it front to back
+    let mut samples: Vec<(u64, u32, usize, usize)> = tables
+        .iter()
+        .enumerate()
+        .flat_map(|(track, table)| {
+            table
+                .offsets
+                .iter()
+                .zip(&table.samples)
+                .enumerate()
+                .map(move |(index, (offset, sample))| (*offset, sample.size, track, index))
+        })
+        .collect();
+    samples.sort();
+    let data_size: u64 = samples.iter().map(|sample| sample.1 as u64).sum();
+
+    // co64 has a fixed size per sample, so the moov is as big with the real offsets
+    let ftyp = ftyp();
+    let moov_size = progressive_moov(&tables).len() as u64;
+    let large = data_size + 8 > u32::MAX as u64;
+    let mdat_header_size = if large { 16 } else { 8 };
+    let mut position = ftyp.len() as u64 + moov_size + mdat_header_size;
+    for (_, size, track, index) in &samples {
+        tables[*track].offsets[*index] = position;
+        position += *size as u64;
+    }
+
+    let temporary = path.with_extension("mp4.tmp");
+    let mut output = BufWriter::new(File::create(&temporary)?);
+    output.write_all(&ftyp)?;
+    output.write_all(&progressive_moov(&tables))?;
+    if large {
+        output.write_all(&1u32.to_be_bytes())?;
+        output.write_all(b"mdat")?;
+        output.write_all(&(data_size + 16).to_be_bytes())?;
+    } else {
+        output.write_all(&(data_size as u32 + 8).to_be_bytes())?;
+        output.write_all(b"mdat")?;
+    }
+
+    let mut input = File::open(path)?;
+    let mut buffer = Vec::new();
+    for (offset, size, _, _) in &samples {
+        buffer.resize(*size as usize, 0);
+        input.seek(SeekFrom::Start(*offset))?;
+        input.read_exact(&mut buffer)?;
+        output.write_all(&buffer)?;
+    }
+    output.flush()?;
+    drop(output);
+    std::fs::rename(&temporary, path)?;
+    Ok(position)
+}
+
+/// Writes one published stream to MP4 files, from the frames of a subscription.
+pub struct Mp4Recorder {
+    config: RecordConfig,
+    mode: RecordMode,
+    app: String,
+    stream: String,
+    // Only consulted in manual mode
+    switch: Arc<AtomicBool>,
+    video_config: Option<TrackConfig>,
+    audio_config: Option<TrackConfig>,
+    file: Option<Mp4File>,
+    // Files started so far, for {index}
+    index: u32,
+}
+
+impl Mp4Recorder {
+    pub fn new(
+        config: &RecordConfig,
+        mode: RecordMode,
+        stream_path: &str,
+        switch: Arc<AtomicBool>,
+    ) -> Mp4Recorder {
+        let (app, stream) = stream_path.split_once('/').unwrap_or(("", stream_path));
+        Mp4Recorder {
+            config: config.clone(),
+            mode,
+            app: app.to_owned(),
+            stream: stream.to_owned(),
+            switch,
+            video_config: None,
+            audio_config: None,
+            file: None,
+            index: 0,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn write_frame(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        if self.mode == RecordMode::Manual && !self.switch.load(Ordering::Relaxed) {
+            self.close();
+            return Ok(());
+        }
+        match frame.tag_type {
+            TagType::Video if self.mode.records_video() => self.write_video(frame),
+            TagType::Audio if self.mode.records_audio() => self.write_audio(frame),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn close(&mut self) {
+        if let Some(file) = self.file.take() {
+            match file.finish(self.config.mp4.finalize) {
+                Ok((path, size)) => info!("Recorded {} ({} bytes)", path.display(), size),
+                Err(err) => warn!("Failed to finish the recording: {}", err),
+            }
+        }
+    }
+
+    fn write_video(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        let Ok(tag) = VideoTag::parse(&frame.data) else {
+            return Ok(());
+        };
+        if tag.header.is_sequence_header() {
+            let config = video_config(&tag);
+            // The tracks of a file are fixed, a new codec configuration needs a new file
+            if config != self.video_config {
+                self.close();
+            }
+            self.video_config = config;
+            return Ok(());
+        }
+        // Nothing to describe the track with, the codec is unknown or unsupported
+        if !tag.header.is_coded_frames() || self.video_config.is_none() {
+            return Ok(());
+        }
+
+        let keyframe = tag.header.is_keyframe();
+        if keyframe {
+            let rotate = self
+                .file
+                .as_ref()
+                .is_some_and(|file| self.limit_reached(file));
+            if rotate {
+                self.close();
+            }
+            match &mut self.file {
+                Some(file) => {
+                    let end = file.decode_time(frame.timestamp);
+                    file.write_fragment(Some(end))?;
+                }
+                None => self.open(frame.timestamp)?,
+            }
+        }
+
+        if let Some(file) = &mut self.file {
+            let composition_offset = tag.header.composition_time();
+            file.push(
+                VIDEO_TRACK,
+                frame.timestamp,
+                composition_offset,
+                keyframe,
+                tag.body,
+            );
+        }
+        Ok(())
+    }
+
+    fn write_audio(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        let Ok(tag) = AudioTag::parse(&frame.data) else {
+            return Ok(());
+        };
+        if tag.header.is_sequence_header() {
+            let config = audio_config(&tag);
+            if config != self.audio_config {
+                self.close();
+            }
+            self.audio_config = config;
+            return Ok(());
+        }
+
+        // Without video, audio starts the files and fragments are cut by time
+        if self.video_config.is_none() || !self.mode.records_video() {
+            if self
+                .file
+                .as_ref()
+                .is_some_and(|file| self.limit_reached(file))
+            {
+                self.close();
+            }
+            if self.file.is_none() && self.audio_config.is_some() {
+                self.open(frame.timestamp)?;
+            }
+            if let Some(file) = &mut self.file {
+                let decode_time = file.decode_time(frame.timestamp);
+                if file.audio_fragments.is_boundary(decode_time, true) {
+                    file.write_fragment(Some(decode_time))?;
+                }
+            }
+        }
+
+        if let Some(file) = &mut self.file {
+            file.push(AUDIO_TRACK, frame.timestamp, 0, true, tag.body);
+        }
+        Ok(())
+    }
+
+    fn limit_reached(&self, file: &Mp4File) -> bool {
+        let size = self
+            .config
+            .max_size
+            .is_some_and(|max_size| file.size >= max_size);
+        let duration = self
+            .config
+            .max_duration_secs
+            .is_some_and(|max_duration| file.last_decode_time as u64 >= max_duration * 1000);
+        size || duration
+    }
+
+    fn open(&mut self, timestamp: u32) -> std::io::Result<()> {
+        let video = self
+            .video_config
+            .clone()
+            .filter(|_| self.mode.records_video())
+            .map(|config| TrackState::new(Track::new(VIDEO_TRACK, config)));
+        let audio = self
+            .audio_config
+            .clone()
+            .filter(|_| self.mode.records_audio())
+            .map(|config| TrackState::new(Track::new(AUDIO_TRACK, config)));
+        let tracks: Vec<Track> = [&video, &audio]
+            .into_iter()
+            .flatten()
+            .map(|state| state.track.clone())
+            .collect();
+        if tracks.is_empty() {
+            return Ok(());
+        }
+
+        let path = recording_path(
+            &self.config.directory,
+            &self.config.mp4.path_template,
+            &self.app,
+            &self.stream,
+            self.index,
+        );
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = Mp4File {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            size: 0,
+            first_timestamp: timestamp,
+            last_decode_time: 0,
+            fragments: 0,
+            audio_fragments: Segmenter::new(AUDIO_FRAGMENT_MS),
+            video,
+            audio,
+        };
+        file.write(&init_segment(&tracks))?;
+
+        info!(
+            "Recording {}/{} to {}",
+            self.app,
+            self.stream,
+            file.path.display()
+        );
+        self.index += 1;
+        self.file = Some(file);
+        Ok(())
+    }
+}
+
+impl Drop for Mp4Recorder {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn video_config(tag: &VideoTag) -> Option<TrackConfig> {
+    TrackConfig::from_video_tag(tag)
+        .map_err(|err| warn!("MP4 recording skips the video: {}", err))
+        .ok()
+}
+
+fn audio_config(tag: &AudioTag) -> Option<TrackConfig> {
+    TrackConfig::from_audio_tag(tag)
+        .map_err(|err| warn!("MP4 recording skips the audio: {}", err))
+        .ok()
+}
+
+impl MediaSink for Mp4Recorder {
+    fn write_frame(&mut self, frame: &MediaFrame) -> std::io::Result<()> {
+        Mp4Recorder::write_frame(self, frame)
+    }
+
+    // The GOP is broken, the next file starts at the next keyframe
+    fn discontinuity(&mut self) {
+        self.close();
+    }
+
+    fn close(&mut self) {
+        Mp4Recorder::close(self);
+    }
+}
+
+/// Starts an MP4 recorder for every stream that is published to an app that records.
+pub struct Mp4Recordings {
+    context: ServerContext,
+}
+
+impl Mp4Recordings {
+    pub fn new(context: ServerContext) -> Mp4Recordings {
+        Mp4Recordings { context }
+    }
+
+    pub async fn run(self) {
+        let context = self.context;
+        let streams = context.streams.clone();
+        run_sinks("MP4 recording", streams, move |path| {
+            let app = path.split('/').next().unwrap_or_default();
+            let mode = context.config.record_mode(app);
+            let switch = context.streams.recording_switch(path)?;
+            (mode != RecordMode::Off)
+                .then(|| Mp4Recorder::new(&context.config.record, mode, path, switch))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mp4Recorder;
+    use crate::config::{RecordConfig, RecordMode};
+    use crate::media::avc;
+    use crate::media::flv::TagType;
+    use crate::stream::MediaFrame;
+    use std::sync::Arc;
+
+    // Type and payload of the top level boxes
+    fn boxes(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut boxes = Vec::new();
+        let mut position = 0;
+        while position < data.len() {
+            let size =
+                u32::from_be_bytes(data[position..position + 4].try_into().unwrap()) as usize;
+            let name = String::from_utf8_lossy(&data[position + 4..position + 8]).into_owned();
+            boxes.push((name, data[position + 8..position + size].to_vec()));
+            position += size;
+        }
+        boxes
+    }
+
+    // The payload of a full box found anywhere in the data, after its version and flags
+    fn full_box<'a>(data: &'a [u8], name: &str) -> &'a [u8] {
+        let position = data
+            .windows(4)
+            .position(|window| window == name.as_bytes())
+            .unwrap_or_else(|| panic!("no {} box", name));
+        let size = u32::from_be_bytes(data[position - 4..position].try_into().unwrap()) as usize;
+        &data[position + 8..position - 4 + size]
+    }
+
+    fn u32_at(data: &[u8], position: usize) -> u32 {
+        u32::from_be_bytes(data[position..position + 4].try_into().unwrap())
+    }
+
+    fn frames() -> Vec<MediaFrame> {
+        // AVC sequence header with a 1920x1080 baseline SPS, then AAC LC 44.1 kHz stereo
+        let sequence_header = avc::fixtures::sequence_header();
+
+        let video =
+            |timestamp, data: &[u8]| MediaFrame::new(TagType::Video, timestamp, data.to_vec());
+        let audio =
+            |timestamp, data: &[u8]| MediaFrame::new(TagType::Audio, timestamp, data.to_vec());
+        vec![
+            video(1000, &sequence_header),
+            audio(1000, &[0xaf, 0x00, 0x12, 0x10]),
+            // Dropped, the file starts at the keyframe
+            video(1000, &[0x27, 0x01, 0x00, 0x00, 0x00, 0x0f]),
+            // Keyframe presented 80 ms after its decode time, then a B-frame
+            video(1040, &[0x17, 0x01, 0x00, 0x00, 0x50, 0xaa, 0xaa]),
+            audio(1040, &[0xaf, 0x01, 0x01]),
+            video(1080, &[0x27, 0x01, 0x00, 0x00, 0x00, 0xbb]),
+            audio(1063, &[0xaf, 0x01, 0x02]),
+            video(1120, &[0x17, 0x01, 0x00, 0x00, 0x28, 0xcc]),
+            audio(1086, &[0xaf, 0x01, 0x03]),
+        ]
+    }
+
+    fn record(finalize: bool) -> Vec<u8> {
+        let mut config = RecordConfig {
+            directory: std::env::temp_dir().join(format!(
+                "record-mp4-{}-{}",
+                finalize,
+                std::process::id()
+            )),
+            ..RecordConfig::default()
+        };
+        config.mp4.path_template = "{app}/{stream}.mp4".to_owned();
+        config.mp4.finalize = finalize;
+        let _ = std::fs::remove_dir_all(&config.directory);
+
+        let mut recorder = Mp4Recorder::new(&config, RecordMode::All, "live/key", Arc::default());
+        for frame in frames() {
+            recorder.write_frame(&frame).unwrap();
+        }
+        assert!(recorder.is_recording());
+        drop(recorder);
+
+        let data = std::fs::read(config.directory.join("live/key.mp4")).unwrap();
+        std::fs::remove_dir_all(config.directory).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_record_fragmented_mp4() {
+        let data = record(false);
+        let names: Vec<String> = boxes(&data).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["ftyp", "moov", "moof", "mdat", "moof", "mdat"]);
+
+        let top = boxes(&data);
+        // The first GOP: keyframe and B-frame, and the audio before the next keyframe
+        assert_eq!(top[3].1, vec![0xaa, 0xaa, 0xbb, 0x01, 0x02]);
+        let trun = full_box(&top[2].1, "trun");
+        assert_eq!(u32_at(trun, 0), 2);
+        assert_eq!(u32_at(trun, 8), 40);
+        assert_eq!(u32_at(trun, 20) as i32, 80);
+        assert_eq!(top[5].1, vec![0xcc, 0x03]);
+    }
+
+    #[test]
+    fn test_record_progressive_mp4() {
+        let data = record(true);
+        let top = boxes(&data);
+        let names: Vec<&str> = top.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["ftyp", "moov", "mdat"]);
+
+        let moov = &top[1].1;
+        let video = &boxes(moov)[1].1;
+        let stsz = full_box(video, "stsz");
+        assert_eq!(
+            &stsz[4..],
+            &[0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1]
+        );
+        // Sync samples 1 and 3, every duration 40 ms
+        assert_eq!(
+            full_box(video, "stss"),
+            &[0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 3]
+        );
+        assert_eq!(
+            full_box(video, "stts"),
+            &[0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 40]
+        );
+        assert_eq!(u32_at(full_box(video, "ctts"), 8), 80);
+
+        // The chunk offsets point at the sample data in the new mdat
+        let co64 = full_box(video, "co64");
+        let offset = u64::from_be_bytes(co64[12..20].try_into().unwrap()) as usize;
+        assert_eq!(data[offset], 0xbb);
+        let audio = &boxes(moov)[2].1;
+        let co64 = full_box(audio, "co64");
+        for (index, expected) in [0x01, 0x02, 0x03].into_iter().enumerate() {
+            let entry = &co64[4 + index * 8..12 + index * 8];
+            let offset = u64::from_be_bytes(entry.try_into().unwrap()) as usize;
+            assert_eq!(data[offset], expected);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// This file holds what the relays share. Relays move streams between this server and other
+// RTMP servers with the RTMP client. Each one reports its state here, so the HTTP API can
+// list them, and a relay that fails waits longer before every new attempt. Players of
+// streams that are not published here ask for them to be pulled through here as well.
+
+// Path: src/relay/mod.rs
+use crate::config::RelayConfig;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+
+pub mod pull;
+pub mod push;
+
+// Pull requests waiting to be picked up
+const PULL_REQUEST_BUFFER: usize = 64;
+
+/// A relay URL with {app} and {stream} replaced.
+pub fn relay_url(template: &str, app: &str, stream: &str) -> String {
+    template.replace("{app}", app).replace("{stream}", stream)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayDirection {
+    // A local stream published to another server
+    Push,
+    // A stream of another server published here
+    Pull,
+}
+
+impl RelayDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelayDirection::Push => "push",
+            RelayDirection::Pull => "pull",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayState {
+    Connecting,
+    Relaying,
+    // Waiting to reconnect after a failure
+    Retrying,
+}
+
+impl RelayState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelayState::Connecting => "connecting",
+            RelayState::Relaying => "relaying",
+            RelayState::Retrying => "retrying",
+        }
+    }
+}
+
+/// Where a relay is at, as listed by the HTTP API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayStatus {
+    // The local stream, app/stream
+    pub stream: String,
+    pub url: String,
+    pub direction: RelayDirection,
+    pub state: RelayState,
+    // Failures since the relay last got through
+    pub failures: u32,
+    pub last_error: Option<String>,
+    // When the relay got into its state
+    pub since: SystemTime,
+}
+
+/// A stream to pull, and where from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PullRequest {
+    pub path: String,
+    pub url: String,
+}
+
+/// The state of every running relay.
+#[derive(Debug, Clone)]
+pub struct Relays {
+    statuses: Arc<Mutex<BTreeMap<(String, String), RelayStatus>>>,
+    pull_requests: broadcast::Sender<PullRequest>,
+}
+
+impl Default for Relays {
+    fn default() -> Relays {
+        Relays {
+            statuses: Arc::default(),
+            pull_requests: broadcast::channel(PULL_REQUEST_BUFFER).0,
+        }
+    }
+}
+
+impl Relays {
+    pub fn new() -> Relays {
+        Relays::default()
+    }
+
+    /// Ordered by stream, then URL.
+    pub fn list(&self) -> Vec<RelayStatus> {
+        self.statuses.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Asks for a stream to be pulled, a pull that is already running is simply kept.
+    pub fn request_pull(&self, path: &str, url: &str) {
+        let request = PullRequest {
+            path: path.to_owned(),
+            url: url.to_owned(),
+        };
+        // Without pull relays running nobody can answer, the player just gets nothing
+        let _ = self.pull_requests.send(request);
+    }
+
+    pub fn watch_pull_requests(&self) -> broadcast::Receiver<PullRequest> {
+        self.pull_requests.subscribe()
+    }
+
+    // Lists the relay as connecting until the reporter is dropped, None if it is running
+    // already
+    fn report(&self, stream: &str, url: &str, direction: RelayDirection) -> Option<RelayReporter> {
+        let key = (stream.to_owned(), url.to_owned());
+        let mut statuses = self.statuses.lock().unwrap();
+        if statuses.contains_key(&key) {
+            return None;
+        }
+        let status = RelayStatus {
+            stream: stream.to_owned(),
+            url: url.to_owned(),
+            direction,
+            state: RelayState::Connecting,
+            failures: 0,
+            last_error: None,
+            since: SystemTime::now(),
+        };
+        statuses.insert(key.clone(), status);
+        Some(RelayReporter {
+            relays: self.clone(),
+            key,
+        })
+    }
+}
+
+/// Updates the status of one relay, and removes it when dropped.
+struct RelayReporter {
+    relays: Relays,
+    key: (String, String),
+}
+
+impl RelayReporter {
+    fn update(&self, update: impl FnOnce(&mut RelayStatus)) {
+        if let Some(status) = self.relays.statuses.lock().unwrap().get_mut(&self.key) {
+            update(status);
+        }
+    }
+
+    fn set_state(&self, state: RelayState) {
+        self.update(|status| {
+            if state == RelayState::Relaying {
+                status.failures = 0;
+            }
+            status.state = state;
+            status.since = SystemTime::now();
+        });
+    }
+
+    // Returns the number of failures in a row
+    fn failed(&self, error: &str) -> u32 {
+        let mut failures = 0;
+        self.update(|status| {
+            status.state = RelayState::Retrying;
+            status.failures += 1;
+            status.last_error = Some(error.to_owned());
+            status.since = SystemTime::now();
+            failures = status.failures;
+        });
+        failures
+    }
+}
+
+impl Drop for RelayReporter {
+    fn drop(&mut self) {
+        self.relays.statuses.lock().unwrap().remove(&self.key);
+    }
+}
+
+// Waited before reconnecting after that many failures in a row
+fn retry_delay(config: &RelayConfig, failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(30) as i32;
+    let secs = config.retry_initial_secs * 2f64.powi(doublings);
+    Duration::from_secs_f64(secs.min(config.retry_max_secs).max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{relay_url, retry_delay, RelayDirection, RelayState, Relays};
+    use crate::config::RelayConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn test_report_and_retry() {
+        let relays = Relays::new();
+        let url = relay_url("rtmp://a.example.com/{app}/{stream}?k=1", "live", "key");
+        assert_eq!(url, "rtmp://a.example.com/live/key?k=1");
+        let reporter = relays
+            .report("live/key", &url, RelayDirection::Push)
+            .unwrap();
+        assert_eq!(relays.list()[0].state, RelayState::Connecting);
+        // One relay per stream and URL
+        assert!(relays
+            .report("live/key", &url, RelayDirection::Push)
+            .is_none());
+
+        assert_eq!(reporter.failed("Connection refused"), 1);
+        assert_eq!(reporter.failed("Connection refused"), 2);
+        let status = &relays.list()[0];
+        assert_eq!((status.state, status.failures), (RelayState::Retrying, 2));
+        assert_eq!(status.last_error.as_deref(), Some("Connection refused"));
+        reporter.set_state(RelayState::Relaying);
+        assert_eq!(relays.list()[0].failures, 0);
+        drop(reporter);
+        assert!(relays.list().is_empty());
+
+        let config = RelayConfig::default();
+        let delays: Vec<Duration> = [1, 2, 3, 5, 6, 100]
+            .into_iter()
+            .map(|failures| retry_delay(&config, failures))
+            .collect();
+        let secs = [1, 2, 4, 16, 30, 30].map(Duration::from_secs);
+        assert_eq!(delays, secs);
+    }
+}
@@ -0,0 +1,319 @@
+// This file pulls streams from an origin for edge servers. Playing a stream that is not
+// published here, in an app with a pull source, starts one pull of it that publishes it
+// here, so every further player shares the upstream connection. The pull reconnects with
+// backoff without letting go of the stream, and is dropped once nobody plays it any more.
+
+// Path: src/relay/pull.rs
+use crate::client::{ClientError, PlayEvent, PlayStream, RtmpClient, RtmpUrl};
+use crate::media::flv::TagType;
+use crate::relay::{
+    relay_url, retry_delay, PullRequest, RelayDirection, RelayReporter, RelayState,
+};
+use crate::server::connection::message::amf0::amf0_json::properties_to_json;
+use crate::server::connection::message::amf0::define::Amf0ValueType;
+use crate::server::connection::message::message::{ConnectObject, SetDataFrame, SetDataFrameData};
+use crate::server::server::ServerContext;
+use crate::stream::{MediaFrame, PublishGuard, StreamRegistry};
+use futures_core::Stream;
+use indexmap::IndexMap;
+use log::{info, warn};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+// Connecting and starting to play has to be done by then
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a player waits for a pulled stream to be published
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+// How often a pull looks for players
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+/// Has the stream pulled from its origin, if the app has one, and waits until it is
+/// published here. False if there is no origin or the stream did not show up in time.
+pub async fn pull_stream(context: &ServerContext, app: &str, stream_name: &str) -> bool {
+    let path = StreamRegistry::stream_path(app, stream_name);
+    let stream = path.split_once('/').map_or("", |(_, stream)| stream);
+    let Some(source) = context.config.pull_source(app, stream) else {
+        return false;
+    };
+    // Watching first, so the stream can not be published in between
+    let mut published = context.streams.watch_published();
+    context
+        .relays
+        .request_pull(&path, &relay_url(source, app, stream));
+
+    let wait = async {
+        while !context.streams.is_publishing(app, stream) {
+            if let Err(RecvError::Closed) = published.recv().await {
+                return;
+            }
+        }
+    };
+    tokio::time::timeout(PUBLISH_TIMEOUT, wait).await.is_ok()
+}
+
+/// Starts the pulls players ask for.
+pub struct PullRelays {
+    context: ServerContext,
+}
+
+impl PullRelays {
+    pub fn new(context: ServerContext) -> PullRelays {
+        PullRelays { context }
+    }
+
+    pub async fn run(self) {
+        let mut requests = self.context.relays.watch_pull_requests();
+        loop {
+            let request = match requests.recv().await {
+                Ok(request) => request,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Pull relays missed {} requests", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if let Some(pull) = Pull::new(&self.context, request) {
+                tokio::spawn(pull.run());
+            }
+        }
+    }
+}
+
+struct Pull {
+    context: ServerContext,
+    path: String,
+    url: String,
+    target: RtmpUrl,
+    reporter: RelayReporter,
+    // Taken with the first successful play and kept across reconnects, so players stay on
+    publish: Option<PublishGuard>,
+    last_played: Instant,
+}
+
+impl Pull {
+    // None if the stream is pulled from there already
+    fn new(context: &ServerContext, request: PullRequest) -> Option<Pull> {
+        let Some(target) = RtmpUrl::parse(&request.url) else {
+            warn!(
+                "Not pulling {} from {}, the URL is invalid",
+                request.path, request.url
+            );
+            return None;
+        };
+        let reporter = context
+            .relays
+            .report(&request.path, &request.url, RelayDirection::Pull)?;
+        Some(Pull {
+            context: context.clone(),
+            path: request.path,
+            url: request.url,
+            target,
+            reporter,
+            publish: None,
+            last_played: Instant::now(),
+        })
+    }
+
+    async fn run(mut self) {
+        loop {
+            self.reporter.set_state(RelayState::Connecting);
+            let error = match self.pull().await {
+                Ok(()) => return,
+                Err(err) => err.to_string(),
+            };
+            let failures = self.reporter.failed(&error);
+            let delay = retry_delay(&self.context.config.relay, failures);
+            warn!(
+                "Pull of {} from {} failed, retrying in {:.1}s: {}",
+                self.path,
+                self.url,
+                delay.as_secs_f64(),
+                error
+            );
+            tokio::time::sleep(delay).await;
+            if self.idle() {
+                info!("Nobody plays {}, not pulling it any more", self.path);
+                return;
+            }
+        }
+    }
+
+    fn idle(&mut self) -> bool {
+        if !self.context.streams.players(&self.path).is_empty() {
+            self.last_played = Instant::now();
+        }
+        self.last_played.elapsed().as_secs_f64() >= self.context.config.relay.pull_idle_secs
+    }
+
+    // Ok once the origin stopped playing the stream, or nobody plays it here any more
+    async fn pull(&mut self) -> Result<(), ClientError> {
+        let connect = async {
+            let connect_object = ConnectObject::new(self.target.app.clone());
+            let client = RtmpClient::connect_url(&self.target, connect_object).await?;
+            // Live only, for as long as it goes
+            client.play(&self.target.stream, -1.0, -1.0).await
+        };
+        let mut events = tokio::time::timeout(CONNECT_TIMEOUT, connect)
+            .await
+            .map_err(|_| ClientError::Io(std::io::ErrorKind::TimedOut.into()))??;
+        if self.publish.is_none() {
+            let (app, stream) = self.path.split_once('/').unwrap_or_default();
+            let Some(publish) = self.context.streams.claim_publish(app, stream) else {
+                info!("{} got published here, not pulling it", self.path);
+                return Ok(());
+            };
+            self.publish = Some(publish);
+        }
+        info!("Pulling {} from {}", self.path, self.target);
+        self.reporter.set_state(RelayState::Relaying);
+
+        let mut idle_check = tokio::time::interval(IDLE_CHECK);
+        loop {
+            tokio::select! {
+                event = next_event(&mut events) => match event {
+                    Some(event) => self.ingest(event?),
+                    None => {
+                        info!("{} ended at {}", self.path, self.target);
+                        return Ok(());
+                    }
+                },
+                _ = idle_check.tick() => {
+                    if self.idle() {
+                        info!("Nobody plays {} any more, not pulling it", self.path);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn ingest(&self, event: PlayEvent) {
+        let Some(publish) = &self.publish else {
+            return;
+        };
+        match event {
+            PlayEvent::Metadata(properties) => {
+                publish.set_metadata(properties_to_json(&properties));
+                match on_metadata(properties) {
+                    Ok(frame) => publish.publish_frame(frame),
+                    Err(err) => warn!("Unusable metadata from {}: {}", self.target, err),
+                }
+            }
+            PlayEvent::Frame(frame) => {
+                // Passed on even if it tells nothing about the stream, like from a publisher
+                let _ = publish.update_stream_info(&frame);
+                publish.publish_frame(frame);
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn next_event(events: &mut PlayStream) -> Option<Result<PlayEvent, ClientError>> {
+    std::future::poll_fn(|cx| Pin::new(&mut *events).poll_next(cx)).await
+}
+
+// The script frame players expect for the metadata
+fn on_metadata(
+    properties: IndexMap<String, Amf0ValueType>,
+) -> Result<MediaFrame, Box<dyn std::error::Error>> {
+    let data = SetDataFrameData::parse(properties.clone())?;
+    let mut set_data_frame =
+        SetDataFrame::new("@setDataFrame".to_owned(), "onMetaData".to_owned(), data);
+    set_data_frame.properties = properties;
+    let script = set_data_frame.on_metadata()?;
+    Ok(MediaFrame::new(TagType::Script, 0, script.freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PullRelays;
+    use crate::client::tests::serve_on;
+    use crate::client::{PlayEvent, PlayStream, RtmpClient};
+    use crate::config::{AppConfig, Config};
+    use crate::media::flv::TagType;
+    use crate::relay::{RelayDirection, RelayState};
+    use crate::server::connection::message::message::ConnectObject;
+    use crate::server::server::ServerContext;
+    use crate::stream::MediaFrame;
+    use std::time::Duration;
+
+    async fn play(addr: std::net::SocketAddr) -> PlayStream {
+        let connect_object = ConnectObject::new("edge".to_owned());
+        let client = RtmpClient::connect(addr, connect_object).await.unwrap();
+        client.play("key", -1.0, -1.0).await.unwrap()
+    }
+
+    async fn next_frame(events: &mut PlayStream) -> MediaFrame {
+        loop {
+            match super::next_event(events).await.unwrap().unwrap() {
+                PlayEvent::Frame(frame) => return frame,
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull() {
+        let origin_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin_addr = origin_listener.local_addr().unwrap();
+        let mut origin_config = Config::default();
+        origin_config.apps.push(AppConfig {
+            name: "live".to_owned(),
+            ..AppConfig::default()
+        });
+        let origin = ServerContext::new(origin_config);
+        serve_on(origin_listener, origin.clone(), 2);
+
+        let edge_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let edge_addr = edge_listener.local_addr().unwrap();
+        let mut edge_config = Config::default();
+        edge_config.apps.push(AppConfig {
+            name: "edge".to_owned(),
+            pull: Some(format!("rtmp://{}/live/{{stream}}", origin_addr)),
+            ..AppConfig::default()
+        });
+        edge_config.relay.pull_idle_secs = 0.2;
+        let edge = ServerContext::new(edge_config);
+        serve_on(edge_listener, edge.clone(), 2);
+        tokio::spawn(PullRelays::new(edge.clone()).run());
+
+        let connect_object = ConnectObject::new("live".to_owned());
+        let mut publisher = RtmpClient::connect(origin_addr, connect_object)
+            .await
+            .unwrap();
+        publisher.publish("key").await.unwrap();
+        let sequence_header = [0x17, 0x00, 0, 0, 0, 0x01];
+        publisher.send_video(0, &sequence_header).await.unwrap();
+        let sequence_header = MediaFrame::new(TagType::Video, 0, sequence_header.to_vec());
+
+        // The first player starts the pull, the second one shares it
+        let mut first = play(edge_addr).await;
+        assert_eq!(next_frame(&mut first).await, sequence_header);
+        let mut second = play(edge_addr).await;
+        assert_eq!(next_frame(&mut second).await, sequence_header);
+        assert_eq!(edge.streams.players("edge/key").len(), 2);
+        assert_eq!(origin.streams.players("live/key").len(), 1);
+        let relays = edge.relays.list();
+        assert_eq!(relays.len(), 1);
+        assert_eq!(relays[0].direction, RelayDirection::Pull);
+        assert_eq!(relays[0].state, RelayState::Relaying);
+        assert_eq!(relays[0].url, format!("rtmp://{}/live/key", origin_addr));
+
+        publisher
+            .send_video(40, &[0x17, 0x01, 0, 0, 0])
+            .await
+            .unwrap();
+        assert_eq!(next_frame(&mut first).await.timestamp, 40);
+
+        // Once the players are gone the pull is dropped
+        drop((first, second));
+        while edge.streams.is_publishing("edge", "key") || !edge.relays.list().is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        while !origin.streams.players("live/key").is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
@@ -0,0 +1,210 @@
+// This file pushes published streams to other RTMP servers, to go out to several platforms
+// from one ingest. Every stream published to an app with push targets gets a relay per
+// target, which publishes it there and forwards metadata and frames until the stream ends.
+// A relay that fails reconnects with backoff and starts over with the cached headers and GOP.
+
+// Path: src/relay/push.rs
+use crate::client::{ClientError, RtmpClient, RtmpUrl};
+use crate::relay::{relay_url, retry_delay, RelayDirection, RelayReporter, RelayState};
+use crate::server::connection::message::message::ConnectObject;
+use crate::server::server::ServerContext;
+use crate::stream::{KeyframeGate, MediaFrame, Subscription};
+use log::{info, warn};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// Connecting and publishing has to be done by then
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Starts the relays of every stream published to an app with push targets.
+pub struct PushRelays {
+    context: ServerContext,
+}
+
+impl PushRelays {
+    pub fn new(context: ServerContext) -> PushRelays {
+        PushRelays { context }
+    }
+
+    pub async fn run(self) {
+        let mut published = self.context.streams.watch_published();
+        loop {
+            let path = match published.recv().await {
+                Ok(path) => path,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Push relays missed {} published streams", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let Some((app, stream)) = path.split_once('/') else {
+                continue;
+            };
+            for template in self.context.config.push_targets(app) {
+                let url = relay_url(template, app, stream);
+                tokio::spawn(relay(self.context.clone(), path.clone(), url));
+            }
+        }
+    }
+}
+
+async fn relay(context: ServerContext, path: String, url: String) {
+    let Some(target) = RtmpUrl::parse(&url) else {
+        warn!("Not pushing {} to {}, the URL is invalid", path, url);
+        return;
+    };
+    let Some(subscription) = context.streams.subscribe(&path) else {
+        return;
+    };
+    // Tells when the publish the relay was started for is over, a new one gets its own relay
+    let mut publish = subscription.frames.resubscribe();
+    let Some(reporter) = context.relays.report(&path, &url, RelayDirection::Push) else {
+        return;
+    };
+    let mut subscription = Some(subscription);
+
+    loop {
+        // Every attempt starts over from the headers and the GOP
+        let Some(attempt) = subscription
+            .take()
+            .or_else(|| context.streams.subscribe(&path))
+        else {
+            return;
+        };
+        reporter.set_state(RelayState::Connecting);
+        let result = match push(&target, attempt, &reporter).await {
+            Ok(()) => {
+                info!("Push of {} to {} is done", path, url);
+                return;
+            }
+            Err(err) => err.to_string(),
+        };
+
+        let failures = reporter.failed(&result);
+        let delay = retry_delay(&context.config.relay, failures);
+        warn!(
+            "Push of {} to {} failed, retrying in {:.1}s: {}",
+            path,
+            url,
+            delay.as_secs_f64(),
+            result
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = ended(&mut publish) => return,
+        }
+    }
+}
+
+async fn ended(publish: &mut broadcast::Receiver<MediaFrame>) {
+    while !matches!(publish.recv().await, Err(RecvError::Closed)) {}
+}
+
+// Ok once the stream ended, an error if the target could not be reached or went away
+async fn push(
+    target: &RtmpUrl,
+    subscription: Subscription,
+    reporter: &RelayReporter,
+) -> Result<(), ClientError> {
+    let Subscription {
+        headers,
+        gop,
+        mut frames,
+    } = subscription;
+
+    let connect = async {
+        let connect_object = ConnectObject::new(target.app.clone());
+        let mut client = RtmpClient::connect_url(target, connect_object).await?;
+        client.publish(&target.stream).await?;
+        Ok::<RtmpClient, ClientError>(client)
+    };
+    let mut client = tokio::time::timeout(CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| ClientError::Io(std::io::ErrorKind::TimedOut.into()))??;
+    info!("Pushing to {}", target);
+    reporter.set_state(RelayState::Relaying);
+
+    for frame in headers.iter().chain(&gop) {
+        client.send_frame(frame).await?;
+    }
+    let mut gate = KeyframeGate::new(&headers);
+    loop {
+        match frames.recv().await {
+            Ok(frame) => {
+                if !gate.skip(&frame) {
+                    client.send_frame(&frame).await?;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Push to {} missed {} frames", target, missed);
+                gate.resync();
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    client.close().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PushRelays;
+    use crate::client::tests::serve_on;
+    use crate::client::RtmpClient;
+    use crate::config::{AppConfig, Config};
+    use crate::media::flv::TagType;
+    use crate::relay::{RelayDirection, RelayState};
+    use crate::server::connection::message::message::ConnectObject;
+    use crate::server::server::ServerContext;
+    use crate::stream::MediaFrame;
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::RecvError;
+
+    #[tokio::test]
+    async fn test_push() {
+        // The server pushes to itself, from live to copy
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut config = Config::default();
+        config.apps.push(AppConfig {
+            name: "live".to_owned(),
+            push: vec![format!("rtmp://{}/copy/{{stream}}-copy", addr)],
+            ..AppConfig::default()
+        });
+        config.apps.push(AppConfig {
+            name: "copy".to_owned(),
+            ..AppConfig::default()
+        });
+        let context = ServerContext::new(config);
+        serve_on(listener, context.clone(), 2);
+        tokio::spawn(PushRelays::new(context.clone()).run());
+
+        let connect_object = ConnectObject::new("live".to_owned());
+        let mut publisher = RtmpClient::connect(addr, connect_object).await.unwrap();
+        publisher.publish("key").await.unwrap();
+        let mut copy = loop {
+            match context.streams.subscribe("copy/key-copy") {
+                Some(copy) => break copy,
+                None => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let relays = context.relays.list();
+        assert_eq!(relays[0].stream, "live/key");
+        assert_eq!(relays[0].direction, RelayDirection::Push);
+        assert_eq!(relays[0].url, format!("rtmp://{}/copy/key-copy", addr));
+
+        let sequence_header = [0x17, 0x00, 0, 0, 0, 0x01];
+        publisher.send_video(0, &sequence_header).await.unwrap();
+        assert_eq!(
+            copy.frames.recv().await.unwrap(),
+            MediaFrame::new(TagType::Video, 0, sequence_header.to_vec())
+        );
+        assert_eq!(context.relays.list()[0].state, RelayState::Relaying);
+
+        // The copy ends with the stream, and the relay is gone
+        publisher.close().await.unwrap();
+        assert!(matches!(copy.frames.recv().await, Err(RecvError::Closed)));
+        while !context.relays.list().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// This file decides where segmented outputs cut a live stream. A segment starts at a keyframe
+// once the current one has reached the target duration, so every segment can be decoded on
+// its own. Streams without video are cut on any frame, by time alone. The samples of fMP4
+// outputs wait in a queue until they are cut, a sample lasts until the next one starts.
+
+// Path: src/segmenter.rs
+use crate::media::mp4::{Sample, TrackRun};
+
+// Used for the last sample of a run, when there is no next one to measure against
+const DEFAULT_SAMPLE_DURATION: u64 = 23;
+
+/// Segment boundaries of one stream, with timestamps in milliseconds.
+#[derive(Debug, Clone)]
+pub struct Segmenter {
+    target_ms: u32,
+    // Start of the current segment, None before the first one
+    start: Option<u32>,
+}
+
+impl Segmenter {
+    pub fn new(target_ms: u32) -> Segmenter {
+        Segmenter {
+            target_ms,
+            start: None,
+        }
+    }
+
+    pub fn segment_start(&self) -> Option<u32> {
+        self.start
+    }
+
+    /// Whether a new segment starts with this frame. `cuttable` frames are keyframes, or any
+    /// frame of a stream without video.
+    pub fn is_boundary(&mut self, timestamp: u32, cuttable: bool) -> bool {
+        if !cuttable {
+            return false;
+        }
+        let boundary = match self.start {
+            None => true,
+            // A timestamp going back is a new timeline, cut as soon as possible
+            Some(start) => match timestamp.checked_sub(start) {
+                Some(elapsed) => elapsed >= self.target_ms,
+                None => true,
+            },
+        };
+        if boundary {
+            self.start = Some(timestamp);
+        }
+        boundary
+    }
+
+    /// Starts over, the next cuttable frame starts a segment.
+    pub fn reset(&mut self) {
+        self.start = None;
+    }
+}
+
+struct PendingSample {
+    decode_time: u64,
+    composition_offset: i32,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+/// Samples of one track waiting to be written to a fragment, decode times in milliseconds.
+pub struct SampleQueue {
+    track_id: u32,
+    pending: Vec<PendingSample>,
+    last_duration: u64,
+}
+
+impl SampleQueue {
+    pub fn new(track_id: u32) -> SampleQueue {
+        SampleQueue {
+            track_id,
+            pending: Vec::new(),
+            last_duration: DEFAULT_SAMPLE_DURATION,
+        }
+    }
+
+    pub fn push(&mut self, decode_time: u64, composition_offset: i32, keyframe: bool, data: &[u8]) {
+        self.pending.push(PendingSample {
+            decode_time,
+            composition_offset,
+            keyframe,
+            data: data.to_vec(),
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Takes the samples before the end, all of them without one. `next` is where the last
+    /// of them ends if known, otherwise it lasts as long as the one before it.
+    pub fn take_run(&mut self, end: Option<u64>, next: Option<u64>) -> Option<TrackRun> {
+        let count = match end {
+            Some(end) => self
+                .pending
+                .iter()
+                .take_while(|sample| sample.decode_time < end)
+                .count(),
+            None => self.pending.len(),
+        };
+        if count == 0 {
+            return None;
+        }
+        let mut taken: Vec<PendingSample> = self.pending.drain(..count).collect();
+        let following = self
+            .pending
+            .first()
+            .map(|sample| sample.decode_time)
+            .or(next);
+
+        let mut run = TrackRun {
+            track_id: self.track_id,
+            base_decode_time: taken[0].decode_time,
+            ..TrackRun::default()
+        };
+        for index in 0..taken.len() {
+            let next_time = taken
+                .get(index + 1)
+                .map(|sample| sample.decode_time)
+                .or(following);
+            let sample = &mut taken[index];
+            let duration = match next_time {
+                Some(next_time) => next_time.saturating_sub(sample.decode_time),
+                None => self.last_duration,
+            };
+            if duration > 0 {
+                self.last_duration = duration;
+            }
+            run.samples.push(Sample {
+                duration: duration as u32,
+                size: sample.data.len() as u32,
+                composition_offset: sample.composition_offset,
+                keyframe: sample.keyframe,
+            });
+            run.data.append(&mut sample.data);
+        }
+        Some(run)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SampleQueue, Segmenter};
+
+    #[test]
+    fn test_segment_boundaries() {
+        let mut segmenter = Segmenter::new(2000);
+        // Nothing starts before the first keyframe
+        assert!(!segmenter.is_boundary(0, false));
+        assert!(segmenter.is_boundary(40, true));
+        assert_eq!(segmenter.segment_start(), Some(40));
+        assert!(!segmenter.is_boundary(1000, true));
+        assert!(!segmenter.is_boundary(2100, false));
+        assert!(segmenter.is_boundary(2240, true));
+        // Timestamps going back cut right away
+        assert!(segmenter.is_boundary(100, true));
+        segmenter.reset();
+        assert!(segmenter.is_boundary(200, true));
+    }
+
+    #[test]
+    fn test_sample_queue() {
+        let mut queue = SampleQueue::new(2);
+        assert!(queue.take_run(None, None).is_none());
+        for (decode_time, data) in [(0, [1]), (21, [2]), (43, [3])] {
+            queue.push(decode_time, 0, true, &data);
+        }
+
+        // The end cuts between samples, the next decode time ends the last one taken
+        let run = queue.take_run(Some(30), None).unwrap();
+        assert_eq!((run.track_id, run.base_decode_time), (2, 0));
+        let durations: Vec<u32> = run.samples.iter().map(|sample| sample.duration).collect();
+        assert_eq!(durations, vec![21, 22]);
+        assert_eq!(run.data, vec![1, 2]);
+        // Nothing left to measure against, the last duration is reused
+        let run = queue.take_run(None, None).unwrap();
+        assert_eq!((run.base_decode_time, run.samples[0].duration), (43, 22));
+    }
+}
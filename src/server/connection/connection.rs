@@ -1,28 +1,151 @@
 // Path: src/server/connection.rs
+use crate::config::RejectReason;
+use crate::media::enhanced::{fourcc_capability, FourCc};
+use crate::media::flv::TagType;
+use crate::protocol::{ChunkReader, ChunkWriter, RawMessage};
+use crate::relay::pull;
 use crate::server::connection::define::msg_type_id;
+use crate::server::connection::message::amf0::define::Amf0ValueType;
+use crate::server::connection::message::command::{
+    command_header, command_name, encode, CheckBandwidth, Command, GetStreamLength, InvalidCommand,
+    ReceiveAudio, ReceiveVideo, Seek,
+};
 use crate::server::connection::message::message::{
     AcknowledgementMessage, AudioData, BasicCommand, CommandObject, ConnectMessage, CreateStream,
-    Event, FCPublish, OnStatus, PauseMessage, PlayMessage, Publish, ReleaseStream, ResultObject,
-    RtmpMessage, SetChunkSizeMessage, SetDataFrame, VideoData,
+    ErrorResponse, Event, OnStatus, OnStatusObject, PauseMessage, PlayMessage, Publish,
+    ResultObject, RtmpMessage, SetDataFrame, SetDataFrameData, VideoData,
+};
+use crate::server::connection::message::status::StatusCode;
+use crate::server::server::ServerContext;
+use crate::stream::{
+    AudioInfo, KeyframeGate, MediaFrame, PlayerGuard, PublishGuard, SequenceHeader, StreamRegistry,
+    VideoInfo,
 };
+use crate::tls::Transport;
+use crate::vod::{self, FlvFile, Playback, VodPlayer};
 
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use rand::{Rng, SeedableRng};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
 
 pub const WINDOW_ACKNOWLEDGEMENT_SIZE: u32 = 4096;
 pub const SET_BANDWIDTH_SIZE: u32 = 4096;
+// Announced before the first media message, so frames go out in few chunks
+pub const OUTGOING_CHUNK_SIZE: u32 = 4096;
+
+// The message stream handed out by createStream, everything a player gets is sent on it
+const MEDIA_STREAM_ID: u32 = 1;
+const AUDIO_CHUNK_STREAM: u32 = 4;
+const DATA_CHUNK_STREAM: u32 = 5;
+const VIDEO_CHUNK_STREAM: u32 = 6;
+
+// User control event types
+const STREAM_BEGIN: u16 = 0;
+const STREAM_EOF: u16 = 1;
+
+// A published stream this connection plays
+struct LivePlay {
+    path: String,
+    frames: broadcast::Receiver<MediaFrame>,
+    gate: KeyframeGate,
+    // Frames are dropped while paused, playback resumes at the next keyframe
+    paused: bool,
+    _player: PlayerGuard,
+}
+
+// The tracks a player asked for with receiveAudio and receiveVideo
+struct Tracks {
+    audio: bool,
+    video: bool,
+    // Video turned back on starts at the next keyframe
+    awaiting_keyframe: bool,
+}
+
+impl Default for Tracks {
+    fn default() -> Tracks {
+        Tracks {
+            audio: true,
+            video: true,
+            awaiting_keyframe: false,
+        }
+    }
+}
+
+impl Tracks {
+    // Metadata and sequence headers always go out, players need them whatever they receive
+    fn admit(&mut self, frame: &MediaFrame) -> bool {
+        if frame.tag_type == TagType::Script || frame.is_sequence_header() {
+            return true;
+        }
+        match frame.tag_type {
+            TagType::Audio => self.audio,
+            TagType::Video if !self.video => false,
+            TagType::Video if self.awaiting_keyframe => {
+                self.awaiting_keyframe = !frame.is_keyframe();
+                !self.awaiting_keyframe
+            }
+            _ => true,
+        }
+    }
+}
 
 pub struct Connection {
-    stream: TcpStream,
-    marker: usize,
+    stream: Box<dyn Transport>,
+    // The client's address, for the player list
+    remote: String,
+    chunks: ChunkReader,
+    writer: ChunkWriter,
+    context: ServerContext,
+    // Sent as clientid in status messages
+    id: String,
+    // App the client connected to, empty until connect succeeds
+    app: String,
+    // Held while this connection publishes, releases the stream name on drop
+    publishing: Option<PublishGuard>,
+    // What the encoder claims in @setDataFrame, checked against the sequence headers
+    metadata: Option<SetDataFrameData>,
+    // The file being played, if the client plays one
+    vod: Option<VodPlayer>,
+    // The live stream being played, if the client plays one
+    live: Option<LivePlay>,
+    tracks: Tracks,
 }
 
 #[warn(unreachable_code)]
 impl Connection {
     pub fn new(stream: TcpStream) -> Connection {
-        Connection { stream, marker: 0 }
+        Connection::with_context(stream, ServerContext::default())
+    }
+
+    pub fn with_context(stream: TcpStream, context: ServerContext) -> Connection {
+        let remote = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown".to_owned(), |addr| addr.to_string());
+        Connection::with_transport(stream, remote, context)
+    }
+
+    /// A connection over something else than plain TCP, like RTMPS.
+    pub fn with_transport(
+        stream: impl Transport + 'static,
+        remote: String,
+        context: ServerContext,
+    ) -> Connection {
+        Connection {
+            stream: Box::new(stream),
+            remote,
+            chunks: ChunkReader::new(),
+            writer: ChunkWriter::new(),
+            context,
+            id: format!("{:08x}", rand::random::<u32>()),
+            app: String::new(),
+            publishing: None,
+            metadata: None,
+            vod: None,
+            live: None,
+            tracks: Tracks::default(),
+        }
     }
 
     pub async fn handle(&mut self) -> Result<(), Box<dyn std::error::Error>> {
@@ -32,30 +155,68 @@ impl Connection {
         // Handle RTMP messages.
         // ...
         loop {
-            warn!("Listening for msg");
-            let message = self.read_message().await?;
+            // A file being played sends its next tags whenever they are due
+            let playback = match &mut self.vod {
+                Some(vod) => vod.poll()?,
+                None => Playback::Idle,
+            };
+            let message = match playback {
+                Playback::Due(due) => tokio::select! {
+                    message = self.read_message() => message?,
+                    _ = tokio::time::sleep_until(due.into()) => {
+                        self.send_due_tags().await?;
+                        continue;
+                    }
+                },
+                Playback::Ended => {
+                    self.end_vod().await?;
+                    continue;
+                }
+                // Frames of a live stream go out as they arrive
+                Playback::Idle => match self.live.take() {
+                    Some(mut live) => tokio::select! {
+                        message = self.read_message() => {
+                            self.live = Some(live);
+                            message?
+                        }
+                        frame = live.frames.recv() => {
+                            self.live = Some(live);
+                            self.send_live_frame(frame).await?;
+                            continue;
+                        }
+                    },
+                    None => {
+                        warn!("Listening for msg");
+                        self.read_message().await?
+                    }
+                },
+            };
 
             match message {
-                RtmpMessage::Connect(connect_message) => {
-                    self.handle_connect(connect_message).await?;
-                }
-                RtmpMessage::CreateStream(create_stream_message) => {
-                    self.handle_create_stream(create_stream_message).await?;
-                }
-                RtmpMessage::_Play(play_message) => {
-                    self.handle_play(play_message).await?;
+                RtmpMessage::Command(command) => {
+                    self.handle_command(command).await?;
                 }
-                RtmpMessage::_Pause(pause_message) => {
-                    self.handle_pause(pause_message).await?;
-                }
-                RtmpMessage::Publish(publish_message) => {
-                    self.handle_publish(publish_message).await?;
+                RtmpMessage::InvalidCommand(invalid) => {
+                    self.handle_invalid_command(invalid).await?;
                 }
                 RtmpMessage::AudioData(audio_data) => {
-                    Self::handle_audio_data(audio_data.data)?;
+                    self.handle_audio_data(audio_data)?;
                 }
                 RtmpMessage::VideoData(video_data) => {
-                    Self::handle_video_data(video_data.data)?;
+                    self.handle_video_data(video_data)?;
+                }
+                RtmpMessage::SetDataFrame(set_data_frame) => {
+                    let metadata = set_data_frame.to_json();
+                    info!("Metadata: {:#}", metadata);
+                    if let (Some(guard), Ok(script)) =
+                        (&self.publishing, set_data_frame.on_metadata())
+                    {
+                        guard.publish_frame(MediaFrame::new(TagType::Script, 0, script.freeze()));
+                    }
+                    if let Some(guard) = &self.publishing {
+                        guard.set_metadata(metadata);
+                    }
+                    self.metadata = Some(set_data_frame.data);
                 }
                 _ => {
                     error!("Unhandled message: {:?}", message);
@@ -131,15 +292,9 @@ impl Connection {
         pub fn insert_bytes(dst: &mut [u8; 12], data: u32, start_idx: usize, end_idx: usize) {
             let data_as_bytes = data.to_be_bytes();
 
-            let mut i: usize = 0;
-            if end_idx - start_idx == 3 {
-                i = 1;
-            }
-
-            for idx in start_idx..end_idx {
-                dst[idx] = data_as_bytes[i];
-                i += 1;
-            }
+            // 3 byte fields take the low bytes of the big endian value
+            let len = end_idx - start_idx;
+            dst[start_idx..end_idx].copy_from_slice(&data_as_bytes[4 - len..]);
         }
 
         let mut header = [0; 12];
@@ -153,6 +308,168 @@ impl Connection {
         header
     }
 
+    async fn handle_command(&mut self, command: Command) -> Result<(), Box<dyn std::error::Error>> {
+        match command {
+            Command::Connect(connect_message) => {
+                self.handle_connect(connect_message).await?;
+            }
+            Command::CreateStream(create_stream_message) => {
+                self.handle_create_stream(create_stream_message).await?;
+            }
+            Command::Play(play_message) => {
+                self.handle_play(play_message).await?;
+            }
+            Command::Pause(pause_message) => {
+                self.handle_pause(pause_message).await?;
+            }
+            Command::Publish(publish_message) => {
+                self.handle_publish(publish_message).await?;
+            }
+            Command::Seek(seek) => {
+                self.handle_seek(seek).await?;
+            }
+            Command::ReceiveAudio(receive_audio) => {
+                self.handle_receive_audio(receive_audio).await?;
+            }
+            Command::ReceiveVideo(receive_video) => {
+                self.handle_receive_video(receive_video).await?;
+            }
+            Command::GetStreamLength(get_stream_length) => {
+                self.handle_get_stream_length(get_stream_length).await?;
+            }
+            Command::FCUnpublish(_) | Command::DeleteStream(_) | Command::CloseStream(_) => {
+                // A player closing its stream is done with what it played
+                self.vod = None;
+                self.live = None;
+                self.handle_unpublish().await?;
+            }
+            Command::CheckBandwidth(check_bandwidth) => {
+                self.handle_check_bandwidth(check_bandwidth).await?;
+            }
+            Command::Call(call) => {
+                // Unknown procedures are answered instead of closing the session.
+                warn!("Unknown command: {:?}", call.procedure_name);
+                if call.transaction_id != 0 {
+                    let description = format!("Method not found ({}).", call.procedure_name);
+                    let response = ErrorResponse::call_failed(call.transaction_id, &description);
+                    self.write_command(&response.parse()?, 0).await?;
+                }
+            }
+            _ => {
+                info!("Unhandled command: {:?}", command);
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_invalid_command(
+        &mut self,
+        invalid: InvalidCommand,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        warn!(
+            "Invalid {} command: {}",
+            invalid.command_name, invalid.error
+        );
+        if invalid.transaction_id != 0 {
+            let description = format!("Invalid arguments ({}).", invalid.command_name);
+            let response = ErrorResponse::call_failed(invalid.transaction_id, &description);
+            self.write_command(&response.parse()?, 0).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_check_bandwidth(
+        &mut self,
+        msg: CheckBandwidth,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Nothing is measured, encoders only wait for the answer before they go on
+        let mut values = command_header(command_name::RESULT, msg.transaction_id);
+        values.push(Amf0ValueType::Null);
+        self.write_command(&encode(&values)?, 0).await
+    }
+
+    async fn write_command(
+        &mut self,
+        command: &[u8],
+        stream_id: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Split at the chunk size the client was told, commands like _result can be longer
+        let command_msg = self.writer.chunks(&RawMessage {
+            chunk_stream_id: 3,
+            timestamp: 0,
+            type_id: msg_type_id::COMMAND_AMF0,
+            stream_id,
+            payload: command.to_vec(),
+        });
+        debug!("command msg: {:?}", command_msg);
+
+        self.stream.write_all(&command_msg).await?;
+        Ok(())
+    }
+
+    // Status messages go out on the message stream handed out by createStream
+    async fn send_status(
+        &mut self,
+        info: OnStatusObject,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let on_status = OnStatus::new(info.client_id(self.id.clone())).parse()?;
+        self.write_command(&on_status, 1).await
+    }
+
+    async fn send_stream_begin(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_event(STREAM_BEGIN).await
+    }
+
+    async fn send_event(&mut self, event_type: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let event_header = self.write_header(4, 6, 0, 0, 2);
+        let event = Event::new(event_type, MEDIA_STREAM_ID).parse();
+        let mut event_msg = Vec::new();
+        event_msg.extend_from_slice(&event_header);
+        event_msg.extend_from_slice(&event);
+        info!("event msg: {:?}", event_msg);
+        self.stream.write_all(&event_msg).await?;
+        Ok(())
+    }
+
+    // Sent once, before the first message that may not fit into the default chunk size
+    async fn send_chunk_size(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.writer.chunk_size() == OUTGOING_CHUNK_SIZE as usize {
+            return Ok(());
+        }
+        let header = self.write_header(msg_type_id::SET_CHUNK_SIZE, 4, 0, 0, 2);
+        let mut chunk_size_msg = header.to_vec();
+        chunk_size_msg.extend_from_slice(&OUTGOING_CHUNK_SIZE.to_be_bytes());
+        self.stream.write_all(&chunk_size_msg).await?;
+        self.writer.set_chunk_size(OUTGOING_CHUNK_SIZE as usize);
+        Ok(())
+    }
+
+    async fn write_media(
+        &mut self,
+        frames: &[MediaFrame],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut data = Vec::new();
+        for frame in frames {
+            if !self.tracks.admit(frame) {
+                continue;
+            }
+            let chunk_stream_id = match frame.tag_type {
+                TagType::Audio => AUDIO_CHUNK_STREAM,
+                TagType::Video => VIDEO_CHUNK_STREAM,
+                TagType::Script => DATA_CHUNK_STREAM,
+            };
+            data.extend(self.writer.chunks(&RawMessage {
+                chunk_stream_id,
+                timestamp: frame.timestamp,
+                type_id: frame.tag_type as u8,
+                stream_id: MEDIA_STREAM_ID,
+                payload: frame.data.to_vec(),
+            }));
+        }
+        self.stream.write_all(&data).await?;
+        Ok(())
+    }
+
     async fn handle_connect(
         &mut self,
         msg: ConnectMessage,
@@ -162,11 +479,34 @@ impl Connection {
         // send win ack size
         // connect to app
         // send set peer bandwidth
-        // read ack
         // make and send StreamBegin
         // make and send _result
         info!("==========Start Connect msg Handle==========");
-        info!("Connect message: {:?}", msg);
+        info!(
+            "Connect message {}: {:#}",
+            msg.id,
+            Amf0ValueType::Object(msg.connect_object.to_properties())
+        );
+
+        // Before any command, the _result alone is longer than the default chunk size and so
+        // is a rejection with a redirect
+        self.send_chunk_size().await?;
+
+        let app = msg.connect_object.app.clone();
+        if !self.context.config.is_app_allowed(&app) {
+            let reject = self
+                .context
+                .config
+                .reject
+                .message(RejectReason::UnknownApp)
+                .clone();
+            warn!("Rejecting connect to unknown app {:?}", app);
+            let response = ErrorResponse::connect_rejected(msg.id, &reject.description)
+                .with_redirect(reject.redirect);
+            self.write_command(&response.parse()?, 0).await?;
+            return Err(format!("Connection to app {:?} rejected", app).into());
+        }
+        self.app = app;
 
         let ack_header = self.write_header(5, 4, 0, 0, 2);
         let mut ack_msg: [u8; 16] = [0; 16];
@@ -184,9 +524,30 @@ impl Connection {
 
         bandwidth_msg[12..16].copy_from_slice(&bandwidth_as_bytes);
         bandwidth_msg[16] = 2;
-        let e = CommandObject::new("FMS/3,0,1,123".to_string(), 31);
-        let mut result_obj = ResultObject::new("_result".to_string(), 1, 0);
+        let object_encoding = match msg.connect_object.extra.get("objectEncoding") {
+            Some(Amf0ValueType::Number(encoding)) => *encoding,
+            _ => 0.0,
+        };
+        let mut e = CommandObject::new("FMS/3,0,1,123".to_string(), 31);
+        // Every Enhanced RTMP codec is forwarded as is, so offer all the client knows
+        if let Some(client) = msg.connect_object.enhanced_capabilities() {
+            let mut supported = FourCc::VIDEO.to_vec();
+            supported.extend_from_slice(FourCc::AUDIO);
+            let negotiated = client.negotiate(&supported, fourcc_capability::CAN_FORWARD);
+            info!(
+                "Negotiated Enhanced RTMP codecs: {:?}",
+                negotiated.fourcc_list
+            );
+            e = e.with_properties(negotiated.to_properties());
+        }
+        let mut result_obj = ResultObject::new("_result".to_string(), msg.id, 0);
         result_obj.set_command_object(e);
+        result_obj.set_info(
+            OnStatusObject::new(StatusCode::ConnectSuccess)
+                .description("Connection succeeded.")
+                .client_id(self.id.clone())
+                .property("objectEncoding", Amf0ValueType::Number(object_encoding)),
+        );
         let command = result_obj.parse()?;
         let command_vec: Vec<u8> = command.freeze().to_vec();
 
@@ -201,8 +562,6 @@ impl Connection {
         info!("set peer bandwidth: {:?}", set_peer_bandwidth);
         self.stream.write_all(&set_peer_bandwidth).await?;
 
-        self.read_message().await?;
-
         info!("==========End Connect msg Handle==========");
         Ok(())
     }
@@ -230,444 +589,547 @@ impl Connection {
         // Handle a Play message.
         // ...
         info!("Play message: {:?}", msg);
+        let vod = match self.vod_path(&msg.stream_name) {
+            // -1 asks for a live stream only
+            Some(path) if msg.start != -1.0 => {
+                let start = msg.start.max(0.0) as u32;
+                let duration = (msg.duration > 0.0).then_some(msg.duration as u32);
+                match VodPlayer::open(&path, start, duration) {
+                    Ok(vod) => Some(vod),
+                    Err(err) => {
+                        warn!("Failed to open {}: {}", path.display(), err);
+                        let info = OnStatusObject::new(StatusCode::PlayFailed)
+                            .description(format!("Failed to play {}.", msg.stream_name))
+                            .details(msg.stream_name.clone());
+                        self.send_status(info).await?;
+                        return Ok(());
+                    }
+                }
+            }
+            _ => None,
+        };
+        let live = match vod {
+            Some(_) => None,
+            None => match self.join_live(&msg.stream_name).await {
+                Some(live) => Some(live),
+                None => {
+                    info!("Not playing {}, it is not published", msg.stream_name);
+                    let info = OnStatusObject::new(StatusCode::PlayStreamNotFound)
+                        .description(format!("{} is not published.", msg.stream_name))
+                        .details(msg.stream_name.clone());
+                    self.send_status(info).await?;
+                    return Ok(());
+                }
+            },
+        };
+        self.vod = None;
+        self.live = None;
+        self.tracks = Tracks::default();
+        self.send_stream_begin().await?;
+
+        if msg.reset {
+            let reset = OnStatusObject::new(StatusCode::PlayReset)
+                .description(format!("Playing and resetting {}.", msg.stream_name))
+                .details(msg.stream_name.clone());
+            self.send_status(reset).await?;
+        }
+        let start = OnStatusObject::new(StatusCode::PlayStart)
+            .description(format!("Started playing {}.", msg.stream_name))
+            .details(msg.stream_name.clone());
+        self.send_status(start).await?;
+
+        self.send_chunk_size().await?;
+        if let Some(vod) = vod {
+            info!("Playing {} from {} ms", msg.stream_name, vod.position());
+            self.write_media(vod.headers()).await?;
+            self.vod = Some(vod);
+        } else if let Some((live, frames)) = live {
+            self.write_media(&frames).await?;
+            self.live = Some(live);
+        }
+        Ok(())
+    }
+
+    // The live stream with the headers and GOP to start with. None if it is not published and
+    // can not be pulled either.
+    async fn join_live(&self, stream_name: &str) -> Option<(LivePlay, Vec<MediaFrame>)> {
+        let path = StreamRegistry::stream_path(&self.app, stream_name);
+        let streams = &self.context.streams;
+        if !streams.is_publishing(&self.app, stream_name)
+            && !pull::pull_stream(&self.context, &self.app, stream_name).await
+        {
+            return None;
+        }
+        let subscription = streams.subscribe(&path)?;
+        let player = streams.add_player(&path, "rtmp", self.remote.clone())?;
+        info!("RTMP player {} of {}", player.id(), path);
+        let mut frames = subscription.headers.clone();
+        frames.extend(subscription.gop);
+        let live = LivePlay {
+            path,
+            frames: subscription.frames,
+            gate: KeyframeGate::new(&subscription.headers),
+            paused: false,
+            _player: player,
+        };
+        Some((live, frames))
+    }
+
+    async fn send_live_frame(
+        &mut self,
+        frame: Result<MediaFrame, RecvError>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(live) = &mut self.live else {
+            return Ok(());
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("RTMP player of {} missed {} frames", live.path, missed);
+                live.gate.resync();
+                return Ok(());
+            }
+            Err(RecvError::Closed) => {
+                info!("{} is no longer published", live.path);
+                self.live = None;
+                self.send_event(STREAM_EOF).await?;
+                let info = OnStatusObject::new(StatusCode::PlayUnpublishNotify)
+                    .description("Stream is no longer published.");
+                self.send_status(info).await?;
+                return Ok(());
+            }
+        };
+        if live.paused {
+            live.gate.resync();
+            return Ok(());
+        }
+        if live.gate.skip(&frame) {
+            return Ok(());
+        }
+        self.write_media(&[frame]).await
+    }
+
+    // The file a stream name stands for, if VOD is enabled and there is one
+    fn vod_path(&self, stream_name: &str) -> Option<std::path::PathBuf> {
+        let config = &self.context.config.vod;
+        if !config.enabled {
+            return None;
+        }
+        vod::file_path(config, &self.app, stream_name).filter(|path| path.is_file())
+    }
+
+    async fn send_due_tags(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let frames = match &mut self.vod {
+            Some(vod) => vod.take_due(std::time::Instant::now())?,
+            None => return Ok(()),
+        };
+        self.write_media(&frames).await
+    }
+
+    // The file is over, the player is told so but may still seek back into it
+    async fn end_vod(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_event(STREAM_EOF).await?;
+        let info = OnStatusObject::new(StatusCode::PlayStop).description("Stopped playing.");
+        self.send_status(info).await?;
         Ok(())
     }
 
+    async fn handle_get_stream_length(
+        &mut self,
+        msg: GetStreamLength,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Live streams and unknown names have no length
+        let duration_ms = match self.vod_path(&msg.stream_name) {
+            Some(path) => FlvFile::open(&path).map_or(0, |file| file.duration_ms()),
+            None => 0,
+        };
+        let mut values = command_header(command_name::RESULT, msg.transaction_id);
+        values.push(Amf0ValueType::Null);
+        values.push(Amf0ValueType::Number(duration_ms as f64 / 1000.0));
+        self.write_command(&encode(&values)?, 0).await
+    }
+
     async fn handle_pause(&mut self, msg: PauseMessage) -> Result<(), Box<dyn std::error::Error>> {
         // Handle a Pause message.
         // ...
         info!("Pause message: {:?}", msg);
+        if let Some(vod) = &mut self.vod {
+            vod.pause(msg.is_paused);
+        }
+        if let Some(live) = &mut self.live {
+            live.paused = msg.is_paused;
+        }
+        let info = if msg.is_paused {
+            OnStatusObject::new(StatusCode::PauseNotify).description("Paused stream.")
+        } else {
+            OnStatusObject::new(StatusCode::UnpauseNotify).description("Unpaused stream.")
+        };
+        self.send_status(info).await?;
         Ok(())
     }
 
-    async fn handle_publish(&mut self, msg: Publish) -> Result<(), Box<dyn std::error::Error>> {
-        // Handle a Publish message.
-        // ...
-        let stream_begin_header = self.write_header(4, 6, 0, 0, 2);
-        let stream_begin = Event::new(0, 1).parse();
-        let mut stream_begin_msg = Vec::new();
-        stream_begin_msg.extend_from_slice(&stream_begin_header);
-        stream_begin_msg.extend_from_slice(&stream_begin);
-        info!("stream begin msg: {:?}", stream_begin_msg);
-        self.stream.write_all(&stream_begin_msg).await?;
-
-        let on_status = OnStatus::new(msg.transaction_id).parse();
-        let mut on_status_vec: Vec<u8> = Vec::new();
-        on_status_vec.extend_from_slice(&on_status.unwrap());
-        let on_status_header = self.write_header(20, on_status_vec.len() as u32, 0, 1, 3);
-        let mut on_status_msg = Vec::new();
-        on_status_msg.extend_from_slice(&on_status_header);
-        on_status_msg.extend_from_slice(&on_status_vec);
-        info!("on status msg: {:?}", on_status_msg);
-        self.stream.write_all(&on_status_msg).await?;
+    async fn handle_seek(&mut self, msg: Seek) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Seek message: {:?}", msg);
+        // Live streams can only be played from where they are
+        let Some(vod) = &mut self.vod else {
+            let info = OnStatusObject::new(StatusCode::SeekFailed)
+                .description(format!("Failed to seek to {}.", msg.milliseconds));
+            self.send_status(info).await?;
+            return Ok(());
+        };
+        let position = match vod.seek(msg.milliseconds.max(0.0) as u32) {
+            Ok(position) => position,
+            Err(err) => {
+                warn!("Seek failed: {}", err);
+                let info = OnStatusObject::new(StatusCode::SeekFailed)
+                    .description(format!("Failed to seek to {}.", msg.milliseconds));
+                self.send_status(info).await?;
+                return Ok(());
+            }
+        };
+        // The player flushes its buffer and starts over from the keyframe
+        self.send_stream_begin().await?;
+        let info = OnStatusObject::new(StatusCode::SeekNotify)
+            .description(format!("Seeking {} (stream ID: 1).", position));
+        self.send_status(info).await?;
+        let info = OnStatusObject::new(StatusCode::PlayStart).description("Started playing.");
+        self.send_status(info).await?;
         Ok(())
     }
 
-    fn handle_audio_data(data: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
-        // Handle audio data.
-        // ...
-        info!("Audio data: {:?}", data);
+    async fn handle_receive_audio(
+        &mut self,
+        msg: ReceiveAudio,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!("receiveAudio: {}", msg.receive);
+        let resumed = msg.receive && !self.tracks.audio;
+        self.tracks.audio = msg.receive;
+        if resumed {
+            self.send_track_resumed("audio").await?;
+        }
         Ok(())
     }
 
-    fn handle_video_data(data: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
-        // Handle video data.
-        // ...
-        info!("Video data: {:?}", data);
+    async fn handle_receive_video(
+        &mut self,
+        msg: ReceiveVideo,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!("receiveVideo: {}", msg.receive);
+        let resumed = msg.receive && !self.tracks.video;
+        self.tracks.video = msg.receive;
+        if resumed {
+            self.tracks.awaiting_keyframe = true;
+            self.send_track_resumed("video").await?;
+        }
         Ok(())
     }
 
-    async fn read_message(&mut self) -> Result<RtmpMessage, Box<dyn std::error::Error>> {
-        // Create a buffer to hold the message data.
-        let mut buffer = vec![0; 12288]; // Adjust the size as needed.
+    // A track turned back on is announced like a seek to where playback is
+    async fn send_track_resumed(&mut self, track: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let info =
+            OnStatusObject::new(StatusCode::SeekNotify).description(format!("Resuming {}.", track));
+        self.send_status(info).await?;
+        let info = OnStatusObject::new(StatusCode::PlayStart).description("Started playing.");
+        self.send_status(info).await?;
+        Ok(())
+    }
 
-        // Read data from the client into the buffer.
-        let size = self.stream.read(&mut buffer).await?;
-        // info!("Read {} bytes", size);
-        self.marker = 0;
+    async fn handle_publish(&mut self, msg: Publish) -> Result<(), Box<dyn std::error::Error>> {
+        // Handle a Publish message.
+        // ...
+        let stream_key = msg.stream_key.split('?').next().unwrap_or_default();
+        let guard = if !self
+            .context
+            .config
+            .is_stream_key_allowed(&self.app, stream_key)
+        {
+            Err(RejectReason::BadStreamKey)
+        } else {
+            self.context
+                .streams
+                .claim_publish(&self.app, &msg.stream_key)
+                .ok_or(RejectReason::DuplicatePublish)
+        };
 
-        // Parse the data into an RtmpMessage.
-        let message = Self::parse_message(self, &buffer[0..size])?;
+        let guard = match guard {
+            Ok(guard) => guard,
+            Err(reason) => {
+                // The client stays connected and may retry with another name.
+                warn!("Rejecting publish of {:?}: {:?}", msg.stream_key, reason);
+                let description = &self.context.config.reject.message(reason).description;
+                let info = OnStatusObject::new(StatusCode::PublishBadName)
+                    .description(description.as_str())
+                    .details(stream_key);
+                self.send_status(info).await?;
+                return Ok(());
+            }
+        };
+        info!("Publishing {}", guard.path());
+        self.publishing = Some(guard);
+
+        self.send_stream_begin().await?;
+        let info = OnStatusObject::new(StatusCode::PublishStart)
+            .description(format!("{} is now published.", stream_key))
+            .details(stream_key);
+        self.send_status(info).await?;
+        Ok(())
+    }
 
-        Ok(message)
+    async fn handle_unpublish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // FCUnpublish, closeStream and deleteStream may all arrive, only answer the first
+        if let Some(guard) = self.publishing.take() {
+            info!("Unpublishing {}", guard.path());
+            let stream_key = guard
+                .path()
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_owned();
+            drop(guard);
+
+            let info = OnStatusObject::new(StatusCode::UnpublishSuccess)
+                .description(format!("{} is now unpublished.", stream_key))
+                .details(stream_key);
+            self.send_status(info).await?;
+        }
+        Ok(())
     }
 
-    pub fn parse_msg_header(
+    fn handle_audio_data(
         &mut self,
-        data: &[u8],
-    ) -> Result<RtmpMessage, Box<dyn std::error::Error>> {
-        if data.is_empty() {
-            error!("No message to read");
-            return Err("No message to read".into());
+        audio_data: AudioData,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let frame = MediaFrame::new(TagType::Audio, audio_data.timestamp, audio_data.data);
+        if let Some(guard) = &self.publishing {
+            match guard.update_stream_info(&frame) {
+                Ok(Some(SequenceHeader::Audio(audio))) => self.check_audio_info(&audio),
+                Ok(_) => {}
+                // What we can not make sense of may still play, it is passed on as it is
+                Err(err) => warn!("Unparsable audio data, passing it on as it is: {}", err),
+            }
+            guard.publish_frame(frame);
         }
+        Ok(())
+    }
 
-        error!("Marker Before CBH: {}", self.marker);
-        let basic_header = ChunkBasicHeader::new(&data[self.marker]);
-        self.marker += 1;
-        info!("fmt: {}, cs: {}", basic_header.fmt, basic_header.cs);
-        let msg = Connection::read_header_types(self, data, basic_header)?;
-        Ok(msg)
+    // Logs the parameters of a sequence header, and where the metadata claims otherwise
+    fn check_audio_info(&self, audio: &AudioInfo) {
+        info!(
+            "Audio: {} {} Hz, {} channels (SBR: {}, PS: {})",
+            audio.codec, audio.sample_rate, audio.channels, audio.sbr, audio.ps
+        );
+
+        if let Some(metadata) = &self.metadata {
+            if metadata.audio_sample_rate > 0.0
+                && metadata.audio_sample_rate as u32 != audio.sample_rate
+            {
+                warn!(
+                    "Metadata claims {} Hz but the AudioSpecificConfig signals {} Hz",
+                    metadata.audio_sample_rate, audio.sample_rate
+                );
+            }
+            if metadata.audio_channels > 0.0 && metadata.audio_channels as u8 != audio.channels {
+                warn!(
+                    "Metadata claims {} channels but the AudioSpecificConfig signals {}",
+                    metadata.audio_channels, audio.channels
+                );
+            }
+        }
     }
 
-    pub fn read_header_types(
+    fn handle_video_data(
         &mut self,
-        data: &[u8],
-        header: ChunkBasicHeader,
-    ) -> Result<RtmpMessage, Box<dyn std::error::Error>> {
-        match ChunkFmt::from_u8(header.fmt) {
-            Some(ChunkFmt::Type0) => {
-                let read_to = self.marker + 11;
-                let chunk_message_header = ChunkMessageHeader::type0(&data[self.marker..read_to]);
-                self.marker = read_to;
-                let msg = Connection::read_msg_type(self, data, chunk_message_header);
-                return msg;
-            }
-            Some(ChunkFmt::Type1) => {
-                let read_to = self.marker + 7;
-                let chunk_message_header = ChunkMessageHeader::type1(&data[self.marker..read_to]);
-                self.marker = read_to;
-                let msg = Connection::read_msg_type(self, data, chunk_message_header);
-                return msg;
-            }
-            Some(ChunkFmt::Type2) => {
-                let read_to = self.marker + 3;
-                let chunk_message_header = ChunkMessageHeader::type2(&data[self.marker..read_to]);
-                self.marker = read_to;
-                let msg = Connection::read_msg_type(self, data, chunk_message_header);
-                return msg;
-            }
-            Some(ChunkFmt::Type3) => {
-                let chunk_message_header = ChunkMessageHeader::type3();
-                let msg = Connection::read_msg_type(self, data, chunk_message_header);
-                return msg;
+        video_data: VideoData,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let frame = MediaFrame::new(TagType::Video, video_data.timestamp, video_data.data);
+        if let Some(guard) = &self.publishing {
+            match guard.update_stream_info(&frame) {
+                Ok(Some(SequenceHeader::Video(video))) => self.check_video_info(&video),
+                Ok(_) => {}
+                Err(err) => warn!("Unparsable video data, passing it on as it is: {}", err),
             }
-            _ => {
-                info!("Unknown chunk format");
+            guard.publish_frame(frame);
+        }
+        Ok(())
+    }
+
+    fn check_video_info(&self, video: &VideoInfo) {
+        info!(
+            "Video: {} {}x{} at {:?} fps, {:?} {} bit",
+            video.codec,
+            video.width,
+            video.height,
+            video.frame_rate,
+            video.chroma_format,
+            video.bit_depth
+        );
+
+        if let Some(metadata) = &self.metadata {
+            let claimed = (metadata.width as u32, metadata.height as u32);
+            if claimed != (0, 0) && claimed != (video.width, video.height) {
+                warn!(
+                    "Metadata claims {}x{} but the SPS codes {}x{}",
+                    claimed.0, claimed.1, video.width, video.height
+                );
+            }
+            if let Some(frame_rate) = video.frame_rate {
+                if metadata.frame_rate > 0.0 && (metadata.frame_rate - frame_rate).abs() > 0.5 {
+                    warn!(
+                        "Metadata claims {} fps but the SPS signals {} fps",
+                        metadata.frame_rate, frame_rate
+                    );
+                }
             }
         }
-        error!("Unknown chunk format");
-        Err("Unknown chunk format".into())
     }
 
-    pub fn read_msg_type(
-        &mut self,
-        data: &[u8],
-        msg_header: ChunkMessageHeader,
-    ) -> Result<RtmpMessage, Box<dyn std::error::Error>> {
-        match msg_header.message_type_id {
-            Some(msg_type_id::SET_CHUNK_SIZE) => {
-                info!("Message type: Set Chunk Size");
-                let read_to = self.marker + 4;
-                let chunk_size = Self::read_set_chunk(&data[self.marker..read_to])?;
-                info!("chunk_size: {}", chunk_size);
-                self.marker = read_to;
-                let set_chunk_size = SetChunkSizeMessage::new(chunk_size);
-                return Ok(RtmpMessage::SetChunkSize(set_chunk_size));
-            }
-            Some(msg_type_id::ABORT) => {
-                info!("Message type: Abort");
-            }
-            Some(msg_type_id::ACKNOWLEDGEMENT) => {
+    async fn read_message(&mut self) -> Result<RtmpMessage, Box<dyn std::error::Error>> {
+        loop {
+            if let Some(message) = self.chunks.next_message()? {
+                match message.type_id {
+                    // Both only change how the chunks that follow are read
+                    msg_type_id::SET_CHUNK_SIZE => {
+                        let chunk_size = Self::read_set_chunk(&message.payload)?;
+                        info!("chunk_size: {}", chunk_size);
+                        self.chunks.set_chunk_size(chunk_size as usize);
+                    }
+                    msg_type_id::ABORT => {
+                        let chunk_stream_id = Self::read_u32(&message.payload)?;
+                        self.chunks.abort(chunk_stream_id);
+                    }
+                    _ => return Self::parse_message(message),
+                }
+                continue;
+            }
+
+            let mut buffer = [0; 8192];
+            let size = self.stream.read(&mut buffer).await?;
+            if size == 0 {
+                return Err("Connection closed by the peer".into());
+            }
+            self.chunks.push(&buffer[..size]);
+        }
+    }
+
+    fn parse_message(message: RawMessage) -> Result<RtmpMessage, Box<dyn std::error::Error>> {
+        let data = &message.payload;
+        match message.type_id {
+            msg_type_id::ACKNOWLEDGEMENT => {
                 info!("Message type: Acknowledgement");
-                let read_to = self.marker + 4;
-                let ack_sequence_number = Self::read_ack(&data[self.marker..read_to])?;
-                self.marker = read_to;
-                let ack = AcknowledgementMessage::new(ack_sequence_number);
+                let ack = AcknowledgementMessage::new(Self::read_ack(data)?);
                 info!("ack: {:?}", ack);
-                return Ok(RtmpMessage::Acknowledgement(ack));
-            }
-            Some(msg_type_id::USER_CONTROL_EVENT) => {
-                info!("Message type: User Control");
+                Ok(RtmpMessage::Acknowledgement(ack))
             }
-            Some(msg_type_id::WIN_ACKNOWLEDGEMENT_SIZE) => {
-                info!("Message type: Window Acknowledgement Size");
+            msg_type_id::AUDIO => {
+                let audio_data = AudioData::new(message.stream_id, message.payload)
+                    .with_timestamp(message.timestamp);
+                Ok(RtmpMessage::AudioData(audio_data))
             }
-            Some(msg_type_id::SET_PEER_BANDWIDTH) => {
-                info!("Message type: Set Peer Bandwidth");
+            msg_type_id::VIDEO => {
+                let video_data = VideoData::new(message.stream_id, message.payload)
+                    .with_timestamp(message.timestamp);
+                Ok(RtmpMessage::VideoData(video_data))
             }
-            Some(msg_type_id::AUDIO) => {
-                info!("Message type: Audio");
-                let read_to = self.marker + msg_header.message_length.unwrap() as usize;
-                let audio_data = AudioData::new(
-                    msg_header.message_stream_id.unwrap(),
-                    data[self.marker..read_to].to_vec(),
-                );
-                self.marker = read_to;
-                return Ok(RtmpMessage::AudioData(audio_data));
-            }
-            Some(msg_type_id::VIDEO) => {
-                info!("Message type: Video");
-                let read_to = self.marker + msg_header.message_length.unwrap() as usize;
-                let video_data = VideoData::new(
-                    msg_header.message_stream_id.unwrap(),
-                    data[self.marker..read_to].to_vec(),
-                );
-                self.marker = read_to;
-                return Ok(RtmpMessage::VideoData(video_data));
-            }
-            Some(msg_type_id::COMMAND_AMF3) => {
-                info!("Message type: Command AMF3");
-            }
-            Some(msg_type_id::DATA_AMF3) => {
-                info!("Message type: Data AMF3");
-            }
-            Some(msg_type_id::SHARED_OBJ_AMF3) => {
-                info!("Message type: Shared Object AMF3");
-            }
-            Some(msg_type_id::DATA_AMF0) => {
-                info!("Message type: Data AMF0");
-                if let Some(msg_len) = msg_header.message_length {
-                    let read_to = self.marker + msg_len as usize;
-                    let msg_name = BasicCommand::parse(&data[self.marker..read_to])?.command_name;
-                    info!("msg_name: {:?}", msg_name);
-                    match msg_name.as_str() {
-                        "@setDataFrame" => {
-                            let message = SetDataFrame::parse(&data[self.marker..read_to])?;
-                            self.marker = read_to;
-                            info!("message: {:?}", message);
-                            return Ok(RtmpMessage::SetDataFrame(message));
-                        }
-                        _ => {
-                            error!("Unknown Data: {:?}", msg_name);
-                            return Err("Unknown Data".into());
-                        }
+            msg_type_id::DATA_AMF0 => {
+                let msg_name = BasicCommand::parse(data)?.command_name;
+                info!("msg_name: {:?}", msg_name);
+                match msg_name.as_str() {
+                    "@setDataFrame" => {
+                        let message = SetDataFrame::parse(data)?;
+                        info!("message: {:?}", message);
+                        Ok(RtmpMessage::SetDataFrame(message))
+                    }
+                    _ => {
+                        warn!("Unknown Data: {:?}", msg_name);
+                        Ok(RtmpMessage::Ignored(message.type_id))
                     }
                 }
             }
-            Some(msg_type_id::SHARED_OBJ_AMF0) => {
-                info!("Message type: Shared Object AMF0");
-            }
-            Some(msg_type_id::AGGREGATE) => {
-                info!("Message type: Aggregate");
-            }
-            Some(msg_type_id::COMMAND_AMF0) => {
+            msg_type_id::COMMAND_AMF0 => {
                 info!("Message type: Command AMF0");
-                if let Some(msg_len) = msg_header.message_length {
-                    let read_to = self.marker + msg_len as usize;
-                    let command_name =
-                        BasicCommand::parse(&data[self.marker..read_to])?.command_name;
-                    info!("command_name: {:?}", command_name);
-                    match command_name.as_str() {
-                        "connect" => {
-                            let message = ConnectMessage::parse(&data[self.marker..read_to])?;
-                            self.marker = read_to;
-                            return Ok(RtmpMessage::Connect(message));
-                        }
-                        "releaseStream" => {
-                            let message = ReleaseStream::parse(&data[self.marker..read_to])?;
-                            info!("releaseStream: {:?}", message);
-                            self.marker = read_to;
-                            return Ok(RtmpMessage::ReleaseStream(message));
-                        }
-                        "FCPublish" => {
-                            let message = FCPublish::parse(&data[self.marker..read_to])?;
-                            info!("FCPublish: {:?}", message);
-                            self.marker = read_to;
-                            return Ok(RtmpMessage::FCPublish(message));
-                        }
-                        "createStream" => {
-                            let message = CreateStream::parse(&data[self.marker..read_to])?;
-                            info!("createStream: {:?}", message);
-                            self.marker = read_to;
-                            return Ok(RtmpMessage::CreateStream(message));
-                        }
-                        "publish" => {
-                            let message = Publish::parse(&data[self.marker..read_to])?;
-                            info!("publish: {:?}", message);
-                            self.marker = read_to;
-                            return Ok(RtmpMessage::Publish(message));
-                        }
-                        _ => {
-                            error!("Unknown command: {:?}", command_name);
-                            return Err("Unknown command".into());
-                        }
-                    };
-                } else {
-                    let command_name = BasicCommand::parse(&data[self.marker..])?.command_name;
-                    info!("command_name: {:?}", command_name);
-                    match command_name.as_str() {
-                        "connect" => {
-                            let message = ConnectMessage::parse(&data[self.marker..])?;
-                            return Ok(RtmpMessage::Connect(message));
-                        }
-                        "releaseStream" => {
-                            let message = ReleaseStream::parse(&data[self.marker..])?;
-                            info!("releaseStream: {:?}", message);
-                            return Ok(RtmpMessage::ReleaseStream(message));
-                        }
-                        "FCPublish" => {
-                            let message = FCPublish::parse(&data[self.marker..])?;
-                            info!("FCPublish: {:?}", message);
-                            return Ok(RtmpMessage::FCPublish(message));
-                        }
-                        "createStream" => {
-                            let message = CreateStream::parse(&data[self.marker..])?;
-                            info!("createStream: {:?}", message);
-                            return Ok(RtmpMessage::CreateStream(message));
-                        }
-                        "publish" => {
-                            let message = Publish::parse(&data[self.marker..])?;
-                            info!("publish: {:?}", message);
-                            return Ok(RtmpMessage::Publish(message));
-                        }
-                        _ => {
-                            error!("Unknown command: {:?}", command_name);
-                            return Err("Unknown command".into());
-                        }
-                    };
+                match Command::parse(data) {
+                    Ok(command) => {
+                        info!("command: {:?}", command);
+                        Ok(RtmpMessage::Command(command))
+                    }
+                    // Bad arguments fail the command, not the session
+                    Err(err) => Ok(RtmpMessage::InvalidCommand(InvalidCommand::parse(
+                        data,
+                        err.to_string(),
+                    )?)),
                 }
             }
+            msg_type_id::USER_CONTROL_EVENT
+            | msg_type_id::WIN_ACKNOWLEDGEMENT_SIZE
+            | msg_type_id::SET_PEER_BANDWIDTH
+            | msg_type_id::COMMAND_AMF3
+            | msg_type_id::DATA_AMF3
+            | msg_type_id::SHARED_OBJ_AMF3
+            | msg_type_id::SHARED_OBJ_AMF0
+            | msg_type_id::AGGREGATE => {
+                info!("Message type {} is not handled", message.type_id);
+                Ok(RtmpMessage::Ignored(message.type_id))
+            }
             _ => {
                 error!("Message type: Unknown");
-                return Err("Unknown message type".into());
+                Err("Unknown message type".into())
             }
         }
-        error!("Unknown message type");
-        Err("Unknown message type".into())
-    }
-
-    fn parse_message(&mut self, data: &[u8]) -> Result<RtmpMessage, Box<dyn std::error::Error>> {
-        info!("Parse Message data: {:?}", data);
-
-        let msg = Connection::parse_msg_header(self, data)?;
-
-        // check for more msg types
-        if self.marker < data.len() && &data[self.marker] != &0 {
-            warn!("more msg types");
-            let message = Self::parse_message(self, data)?;
-            return Ok(message);
-        }
-
-        Ok(msg)
     }
 
     fn read_set_chunk(data: &[u8]) -> Result<u32, Box<dyn std::error::Error>> {
-        let tmp_data = (data[0] << 1) >> 1;
-        let chunk_size = u32::from_be_bytes([tmp_data, data[1], data[2], data[3]]);
-        Ok(chunk_size)
+        // The top bit is reserved and must be 0
+        Ok(Self::read_u32(data)? & 0x7fff_ffff)
     }
 
     fn read_ack(data: &[u8]) -> Result<u32, Box<dyn std::error::Error>> {
-        let tmp_data = (data[0] << 1) >> 1;
-        let ack = u32::from_be_bytes([tmp_data, data[1], data[2], data[3]]);
-        Ok(ack)
+        Ok(Self::read_u32(data)? & 0x7fff_ffff)
     }
-}
-
-enum ChunkFmt {
-    Type0,
-    Type1,
-    Type2,
-    Type3,
-}
 
-impl ChunkFmt {
-    fn from_u8(value: u8) -> Option<Self> {
-        match value {
-            0 => Some(Self::Type0),
-            1 => Some(Self::Type1),
-            2 => Some(Self::Type2),
-            3 => Some(Self::Type3),
-            _ => None,
-        }
+    fn read_u32(data: &[u8]) -> Result<u32, Box<dyn std::error::Error>> {
+        let bytes = data.get(..4).ok_or("Control message too short")?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
     }
 }
 
-#[derive(Debug)]
-pub struct ChunkBasicHeader {
-    fmt: u8,
-    cs: u8,
-}
-
-impl ChunkBasicHeader {
-    fn new(byte: &u8) -> ChunkBasicHeader {
-        // split into the chunk header and the message body
-        let cs = byte & 0b_00111111;
-        let fmt = (byte >> 6) & 0b_00000011;
+#[allow(unused_mut)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, Config};
+    use crate::media::avc;
+    use crate::server::connection::message::amf0::amf0_reader::Amf0Reader;
+    use crate::server::connection::message::command::{Call, FCUnpublish};
+    use crate::server::connection::message::message::ConnectObject;
+    use bytes::BytesMut;
+    use bytesio::bytes_reader::BytesReader;
+    use tokio::net::TcpListener;
 
-        ChunkBasicHeader { fmt, cs }
+    // The client side of a test connection, reads chunks like a client would
+    struct TestClient {
+        stream: TcpStream,
+        chunks: ChunkReader,
     }
-}
 
-pub struct ChunkMessageHeader {
-    timestamp: Option<u32>,
-    timestamp_delta: Option<u32>,
-    message_length: Option<u32>,
-    message_type_id: Option<u8>,
-    message_stream_id: Option<u32>,
-}
+    impl std::ops::Deref for TestClient {
+        type Target = TcpStream;
 
-impl ChunkMessageHeader {
-    fn default() -> ChunkMessageHeader {
-        ChunkMessageHeader {
-            timestamp: None,
-            timestamp_delta: None,
-            message_length: None,
-            message_type_id: None,
-            message_stream_id: None,
+        fn deref(&self) -> &TcpStream {
+            &self.stream
         }
     }
 
-    fn type0(bytes: &[u8]) -> ChunkMessageHeader {
-        let mut chunk_message_header = ChunkMessageHeader::default();
-
-        let timestamp = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
-        let message_length = u32::from_be_bytes([0, bytes[3], bytes[4], bytes[5]]); // maybe switch 0 to end of arguments?
-        let message_type_id = bytes[6];
-        let message_stream_id = u32::from_be_bytes([bytes[10], bytes[9], bytes[8], bytes[7]]);
-
-        chunk_message_header.timestamp = Some(timestamp);
-        chunk_message_header.message_length = Some(message_length);
-        chunk_message_header.message_type_id = Some(message_type_id);
-        chunk_message_header.message_stream_id = Some(message_stream_id);
-
-        info!("timestamp: {}", timestamp);
-        info!("message_length: {}", message_length);
-        info!("message_type_id: {}", message_type_id);
-        info!("message_stream_id: {}", message_stream_id);
-
-        chunk_message_header
-    }
-
-    fn type1(bytes: &[u8]) -> ChunkMessageHeader {
-        let mut chunk_message_header = ChunkMessageHeader::default();
-
-        let timestamp_delta = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
-        let message_length = u32::from_be_bytes([0, bytes[3], bytes[4], bytes[5]]);
-        let message_type_id = bytes[6];
-
-        chunk_message_header.timestamp_delta = Some(timestamp_delta);
-        chunk_message_header.message_length = Some(message_length);
-        chunk_message_header.message_type_id = Some(message_type_id);
-
-        info!("timestamp_delta: {}", timestamp_delta);
-        info!("message_length: {}", message_length);
-        info!("message_type_id: {}", message_type_id);
-
-        chunk_message_header
-    }
-
-    fn type2(bytes: &[u8]) -> ChunkMessageHeader {
-        let mut chunk_message_header = ChunkMessageHeader::default();
-
-        chunk_message_header.timestamp_delta =
-            Some(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]));
-
-        chunk_message_header
+    impl std::ops::DerefMut for TestClient {
+        fn deref_mut(&mut self) -> &mut TcpStream {
+            &mut self.stream
+        }
     }
 
-    fn type3() -> ChunkMessageHeader {
-        ChunkMessageHeader::default()
+    async fn setup() -> (Connection, TestClient) {
+        setup_with_context(ServerContext::default()).await
     }
-}
-
-#[allow(unused_mut)]
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio::net::TcpListener;
 
-    async fn setup() -> (Connection, TcpStream) {
+    async fn setup_with_context(context: ServerContext) -> (Connection, TestClient) {
         // Start a TcpListener to accept connections (server-side)
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -679,8 +1141,12 @@ mod tests {
         let (server, _) = listener.accept().await.unwrap();
 
         // Create the Connection instance
-        let mut conn = Connection::new(server);
+        let mut conn = Connection::with_context(server, context);
 
+        let client = TestClient {
+            stream: client,
+            chunks: ChunkReader::new(),
+        };
         (conn, client)
     }
 
@@ -709,7 +1175,7 @@ mod tests {
         // Read & handle the message in the Connection instance
         let message = conn.read_message().await.expect("Failed to read message");
         let result = match message {
-            RtmpMessage::Connect(connect_message) => {
+            RtmpMessage::Command(Command::Connect(connect_message)) => {
                 assert_eq!(
                     connect_message.connect_object.app, "live",
                     "App should be 'live'"
@@ -785,10 +1251,18 @@ mod tests {
             .await
             .expect("Failed to write mock data");
 
+        // The commands come out one at a time, in the order they were sent
+        for expected in ["releaseStream", "FCPublish"] {
+            match conn.read_message().await.expect("Failed to read message") {
+                RtmpMessage::Command(command) => assert_eq!(command.command_name(), expected),
+                other => panic!("Expected {} but received {:?}", expected, other),
+            }
+        }
+
         // Read & handle the message in the Connection instance
         let message = conn.read_message().await.expect("Failed to read message");
         let result = match message {
-            RtmpMessage::CreateStream(create_stream) => {
+            RtmpMessage::Command(Command::CreateStream(create_stream)) => {
                 assert_eq!(
                     create_stream.command_name, "createStream",
                     "Command name should be 'createStream'"
@@ -807,6 +1281,94 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_unknown_command_error() {
+        let (mut conn, mut client) = setup().await;
+
+        let payload = Call::new("getServerTime".to_owned(), 7)
+            .serialize()
+            .unwrap();
+        let mut mock_data = vec![3, 0, 0, 0, 0, 0, payload.len() as u8, 20, 0, 0, 0, 0];
+        mock_data.extend_from_slice(&payload);
+        client
+            .write_all(&mock_data)
+            .await
+            .expect("Failed to write mock data");
+
+        let message = conn.read_message().await.expect("Failed to read message");
+        let command = match message {
+            RtmpMessage::Command(command) => command,
+            other => panic!("Expected a command but received {:?}", other),
+        };
+        assert_eq!(command.command_name(), "getServerTime");
+
+        // The session stays up and the client gets an _error for its transaction
+        conn.handle_command(command)
+            .await
+            .expect("Unknown command should not fail the connection");
+
+        let mut response = [0u8; 4096];
+        let read = client.read(&mut response).await.unwrap();
+        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(&response[12..read])));
+        let values = reader.read_all().unwrap();
+        assert_eq!(values[0], Amf0ValueType::UTF8String("_error".to_owned()));
+        assert_eq!(values[1], Amf0ValueType::Number(7.0));
+        match &values[3] {
+            Amf0ValueType::Object(info) => assert_eq!(
+                info.get("code"),
+                Some(&Amf0ValueType::UTF8String(
+                    "NetConnection.Call.Failed".to_owned()
+                ))
+            ),
+            other => panic!("Expected info object, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_command_error() {
+        let (mut conn, mut client) = setup().await;
+
+        // A play without its stream name
+        let mut values = command_header(command_name::PLAY, 5);
+        values.push(Amf0ValueType::Null);
+        let payload = encode(&values).unwrap();
+        let mut mock_data = vec![3, 0, 0, 0, 0, 0, payload.len() as u8, 20, 0, 0, 0, 0];
+        mock_data.extend_from_slice(&payload);
+        client.write_all(&mock_data).await.unwrap();
+
+        let invalid = match conn.read_message().await.expect("Failed to read message") {
+            RtmpMessage::InvalidCommand(invalid) => invalid,
+            other => panic!("Expected an invalid command but received {:?}", other),
+        };
+        assert_eq!(invalid.command_name, "play");
+        conn.handle_invalid_command(invalid)
+            .await
+            .expect("Invalid arguments should not fail the connection");
+
+        let mut response = [0u8; 4096];
+        let read = client.read(&mut response).await.unwrap();
+        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(&response[12..read])));
+        let values = reader.read_all().unwrap();
+        assert_eq!(values[0], Amf0ValueType::UTF8String("_error".to_owned()));
+        assert_eq!(values[1], Amf0ValueType::Number(5.0));
+    }
+
+    #[tokio::test]
+    async fn test_check_bandwidth() {
+        let (mut conn, mut client) = setup().await;
+
+        conn.handle_command(Command::CheckBandwidth(CheckBandwidth::new(9)))
+            .await
+            .unwrap();
+
+        let mut response = [0u8; 4096];
+        let read = client.read(&mut response).await.unwrap();
+        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(&response[12..read])));
+        let values = reader.read_all().unwrap();
+        assert_eq!(values[0], Amf0ValueType::UTF8String("_result".to_owned()));
+        assert_eq!(values[1], Amf0ValueType::Number(9.0));
+    }
+
     #[tokio::test]
     async fn test_handle_connect() {
         let listener = TcpListener::bind("127.0.0.1:0")
@@ -841,10 +1403,11 @@ mod tests {
             // ...
             // Read server responses
             let mut response_buffer = [0u8; 4096];
-            client_stream
+            let read = client_stream
                 .read(&mut response_buffer)
                 .await
                 .expect("Failed to read server response");
+            assert!(read > 0, "Server should respond to connect");
 
             // TODO: Parse the server's response if necessary and determine if an acknowledgment or other message should be sent.
 
@@ -864,7 +1427,7 @@ mod tests {
         let mut conn = Connection::new(server_stream);
         let rtmp_message = conn.read_message().await.expect("Failed to read message");
 
-        if let RtmpMessage::Connect(connect_msg) = rtmp_message {
+        if let RtmpMessage::Command(Command::Connect(connect_msg)) = rtmp_message {
             conn.handle_connect(connect_msg)
                 .await
                 .expect("Failed to handle connect message");
@@ -875,4 +1438,523 @@ mod tests {
 
         // Add any further assertions or verifications here
     }
+
+    fn restricted_context() -> ServerContext {
+        let mut config = Config::default();
+        config.apps.push(AppConfig {
+            name: "live".to_owned(),
+            stream_keys: vec!["secret".to_owned()],
+            ..AppConfig::default()
+        });
+        config.reject.unknown_app.redirect = Some("rtmp://backup/live".to_owned());
+        ServerContext::new(config)
+    }
+
+    // Reads messages off the client side until a command arrives and returns its values and
+    // info object
+    async fn read_info(client: &mut TestClient) -> (Vec<Amf0ValueType>, Amf0ValueType) {
+        loop {
+            let (type_id, payload) = read_raw(client).await;
+            if type_id == msg_type_id::COMMAND_AMF0 {
+                return decode_info(&payload);
+            }
+        }
+    }
+
+    fn decode_info(payload: &[u8]) -> (Vec<Amf0ValueType>, Amf0ValueType) {
+        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(payload)));
+        let values = reader.read_all().unwrap();
+        let info = values[3].clone();
+        (values, info)
+    }
+
+    fn info_field<'a>(info: &'a Amf0ValueType, key: &str) -> Option<&'a Amf0ValueType> {
+        match info {
+            Amf0ValueType::Object(properties) => properties.get(key),
+            other => panic!("Expected info object, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_rejected() {
+        let (mut conn, mut client) = setup_with_context(restricted_context()).await;
+
+        let connect = ConnectMessage::new(1, ConnectObject::new("vod".to_owned()));
+        assert!(conn.handle_connect(connect).await.is_err());
+
+        // With the redirect the _error does not fit into one chunk of the default size, it has
+        // to come after the new chunk size
+        let (type_id, _) = read_raw(&mut client).await;
+        assert_eq!(type_id, msg_type_id::SET_CHUNK_SIZE);
+        let (type_id, payload) = read_raw(&mut client).await;
+        assert_eq!(type_id, msg_type_id::COMMAND_AMF0);
+        assert!(payload.len() > 128);
+        let (values, info) = decode_info(&payload);
+        assert_eq!(values[0], Amf0ValueType::UTF8String("_error".to_owned()));
+        assert_eq!(values[1], Amf0ValueType::Number(1.0));
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetConnection.Connect.Rejected".to_owned()
+            ))
+        );
+        assert_eq!(
+            info_field(&info, "description"),
+            Some(&Amf0ValueType::UTF8String(
+                "Application not found.".to_owned()
+            ))
+        );
+        let ex = info_field(&info, "ex").expect("Redirect should be sent as ex");
+        assert_eq!(
+            info_field(ex, "redirect"),
+            Some(&Amf0ValueType::UTF8String("rtmp://backup/live".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_bad_name() {
+        let context = restricted_context();
+        let (mut conn, mut client) = setup_with_context(context.clone()).await;
+        conn.app = "live".to_owned();
+
+        let publish = |key: &str| {
+            Publish::new(
+                "publish".to_owned(),
+                5,
+                Amf0ValueType::Null,
+                key.to_owned(),
+                "live".to_owned(),
+            )
+        };
+
+        conn.handle_publish(publish("guess"))
+            .await
+            .expect("A bad stream key should not fail the connection");
+        let (values, info) = read_info(&mut client).await;
+        assert_eq!(values[0], Amf0ValueType::UTF8String("onStatus".to_owned()));
+        assert_eq!(
+            info_field(&info, "level"),
+            Some(&Amf0ValueType::UTF8String("error".to_owned()))
+        );
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Publish.BadName".to_owned()
+            ))
+        );
+        assert_eq!(
+            info_field(&info, "description"),
+            Some(&Amf0ValueType::UTF8String("Invalid stream key.".to_owned()))
+        );
+
+        // A second publisher of the same stream is turned away while the first is live
+        let _guard = context.streams.claim_publish("live", "secret").unwrap();
+        conn.handle_publish(publish("secret?token=1"))
+            .await
+            .unwrap();
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "description"),
+            Some(&Amf0ValueType::UTF8String(
+                "Stream is already being published.".to_owned()
+            ))
+        );
+        assert!(conn.publishing.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_publish_status_messages() {
+        let (mut conn, mut client) = setup().await;
+        conn.app = "live".to_owned();
+
+        let publish = Publish::new(
+            "publish".to_owned(),
+            5,
+            Amf0ValueType::Null,
+            "key?token=1".to_owned(),
+            "live".to_owned(),
+        );
+        conn.handle_publish(publish).await.unwrap();
+        let (values, info) = read_info(&mut client).await;
+        assert_eq!(values[0], Amf0ValueType::UTF8String("onStatus".to_owned()));
+        assert_eq!(values[1], Amf0ValueType::Number(0.0));
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Publish.Start".to_owned()
+            ))
+        );
+        assert_eq!(
+            info_field(&info, "details"),
+            Some(&Amf0ValueType::UTF8String("key".to_owned()))
+        );
+        assert_eq!(
+            info_field(&info, "clientid"),
+            Some(&Amf0ValueType::UTF8String(conn.id.clone()))
+        );
+
+        conn.handle_command(Command::FCUnpublish(FCUnpublish::new(6, "key".to_owned())))
+            .await
+            .unwrap();
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Unpublish.Success".to_owned()
+            ))
+        );
+        assert!(!conn.context.streams.is_publishing("live", "key"));
+
+        let pause = PauseMessage::new(true, 1000.0);
+        conn.handle_pause(pause).await.unwrap();
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Pause.Notify".to_owned()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_negotiates_fourcc_list() {
+        let (mut conn, mut client) = setup().await;
+
+        let mut connect_object = ConnectObject::new("live".to_owned());
+        connect_object.extra.insert(
+            "fourCcList".to_owned(),
+            Amf0ValueType::StrictArray(vec![
+                Amf0ValueType::UTF8String("hvc1".to_owned()),
+                Amf0ValueType::UTF8String("av01".to_owned()),
+            ]),
+        );
+        conn.handle_connect(ConnectMessage::new(1, connect_object))
+            .await
+            .unwrap();
+
+        let (values, info) = read_info(&mut client).await;
+        assert_eq!(values[0], Amf0ValueType::UTF8String("_result".to_owned()));
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetConnection.Connect.Success".to_owned()
+            ))
+        );
+        assert_eq!(
+            info_field(&values[2], "fourCcList"),
+            Some(&Amf0ValueType::StrictArray(vec![
+                Amf0ValueType::UTF8String("hvc1".to_owned()),
+                Amf0ValueType::UTF8String("av01".to_owned()),
+            ]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sequence_header_updates_stream_info() {
+        let (mut conn, mut client) = setup().await;
+        conn.app = "live".to_owned();
+        let publish = Publish::new(
+            "publish".to_owned(),
+            5,
+            Amf0ValueType::Null,
+            "key".to_owned(),
+            "live".to_owned(),
+        );
+        conn.handle_publish(publish).await.unwrap();
+        read_info(&mut client).await;
+
+        // Keyframe, AVC sequence header wrapping a 1920x1080 baseline SPS
+        let data = avc::fixtures::sequence_header();
+        conn.handle_video_data(VideoData::new(1, data)).unwrap();
+        // AAC LC, 44.1 kHz stereo
+        conn.handle_audio_data(AudioData::new(1, vec![0xaf, 0x00, 0x12, 0x10]))
+            .unwrap();
+
+        let info = conn.context.streams.stream_info("live", "key").unwrap();
+        let video = info.video.expect("Video info should be set");
+        assert_eq!((video.width, video.height), (1920, 1080));
+        assert_eq!(video.codec, "avc1.42c028");
+        let audio = info.audio.expect("Audio info should be set");
+        assert_eq!((audio.sample_rate, audio.channels), (44100, 2));
+        assert_eq!(audio.codec, "mp4a.40.2");
+    }
+
+    #[tokio::test]
+    async fn test_unparsable_media_is_passed_on() {
+        let (mut conn, mut client) = setup().await;
+        conn.app = "live".to_owned();
+        let publish = Publish::new(
+            "publish".to_owned(),
+            5,
+            Amf0ValueType::Null,
+            "key".to_owned(),
+            "live".to_owned(),
+        );
+        conn.handle_publish(publish).await.unwrap();
+        read_info(&mut client).await;
+        let mut subscription = conn.context.streams.subscribe("live/key").unwrap();
+
+        // Too short for an AVC and an AAC header, players may still make something of them
+        conn.handle_video_data(VideoData::new(1, vec![0x17, 0x01]))
+            .unwrap();
+        conn.handle_audio_data(AudioData::new(1, vec![0xaf]))
+            .unwrap();
+        assert_eq!(
+            subscription.frames.recv().await.unwrap(),
+            MediaFrame::new(TagType::Video, 0, vec![0x17, 0x01])
+        );
+        assert_eq!(
+            subscription.frames.recv().await.unwrap(),
+            MediaFrame::new(TagType::Audio, 0, vec![0xaf])
+        );
+    }
+
+    // Reads the next message off the client side and returns its type id and payload. Bytes are
+    // taken off the stream one at a time, so tests can still read the rest themselves.
+    async fn read_raw(client: &mut TestClient) -> (u8, Vec<u8>) {
+        loop {
+            let Some(message) = client.chunks.next_message().unwrap() else {
+                let byte = client.stream.read_u8().await.unwrap();
+                client.chunks.push(&[byte]);
+                continue;
+            };
+            if message.type_id == msg_type_id::SET_CHUNK_SIZE {
+                let chunk_size = Connection::read_set_chunk(&message.payload).unwrap();
+                client.chunks.set_chunk_size(chunk_size as usize);
+            }
+            return (message.type_id, message.payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_play_file() {
+        use crate::media::flv::{flv_header, FlvTag};
+
+        let directory = std::env::temp_dir().join(format!("vod-play-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("vod")).unwrap();
+        let mut file = flv_header(false, true).to_vec();
+        file.extend(FlvTag::new(TagType::Video, 0, vec![0x17, 0x00, 0, 0, 0, 0x01]).serialize());
+        for index in 0..3u32 {
+            let frame_type = if index == 0 { 0x17 } else { 0x27 };
+            let data = vec![frame_type, 0x01, 0, 0, 0, index as u8];
+            file.extend(FlvTag::new(TagType::Video, index * 100, data).serialize());
+        }
+        std::fs::write(directory.join("vod/clip.flv"), file).unwrap();
+
+        let mut config = Config::default();
+        config.vod.enabled = true;
+        config.vod.directory = directory.clone();
+        let (mut conn, mut client) = setup_with_context(ServerContext::new(config)).await;
+        conn.app = "vod".to_owned();
+
+        conn.handle_play(PlayMessage::new("clip".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_raw(&mut client).await,
+            (msg_type_id::USER_CONTROL_EVENT, vec![0, 0, 0, 0, 0, 1])
+        );
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Play.Reset".to_owned()
+            ))
+        );
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Play.Start".to_owned()
+            ))
+        );
+        assert_eq!(
+            read_raw(&mut client).await,
+            (
+                msg_type_id::SET_CHUNK_SIZE,
+                OUTGOING_CHUNK_SIZE.to_be_bytes().to_vec()
+            )
+        );
+        // The sequence header goes out right away, the frames once they are due
+        let (type_id, payload) = read_raw(&mut client).await;
+        assert_eq!((type_id, payload[1]), (msg_type_id::VIDEO, 0x00));
+        conn.send_due_tags().await.unwrap();
+        for index in 0..3u8 {
+            let (type_id, payload) = read_raw(&mut client).await;
+            assert_eq!((type_id, payload[5]), (msg_type_id::VIDEO, index));
+        }
+
+        assert!(matches!(
+            conn.vod.as_mut().unwrap().poll(),
+            Ok(Playback::Ended)
+        ));
+        conn.end_vod().await.unwrap();
+        assert_eq!(
+            read_raw(&mut client).await,
+            (msg_type_id::USER_CONTROL_EVENT, vec![0, 1, 0, 0, 0, 1])
+        );
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String("NetStream.Play.Stop".to_owned()))
+        );
+
+        conn.handle_get_stream_length(GetStreamLength::new(4, "clip".to_owned()))
+            .await
+            .unwrap();
+        let (values, _) = read_info(&mut client).await;
+        assert_eq!(values[0], Amf0ValueType::UTF8String("_result".to_owned()));
+        assert_eq!(values[3], Amf0ValueType::Number(0.2));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    // Hands the next frame of the live stream to the connection, as its loop would
+    async fn forward_live(conn: &mut Connection) {
+        let frame = conn.live.as_mut().unwrap().frames.recv().await;
+        conn.send_live_frame(frame).await.unwrap();
+    }
+
+    fn video(frame_type: u8, index: u8) -> MediaFrame {
+        let data = vec![frame_type, 0x01, 0, 0, 0, index];
+        MediaFrame::new(TagType::Video, index as u32 * 100, data)
+    }
+
+    #[tokio::test]
+    async fn test_play_unpublished() {
+        let (mut conn, mut client) = setup().await;
+        conn.app = "live".to_owned();
+
+        conn.handle_play(PlayMessage::new("missing".to_owned()))
+            .await
+            .unwrap();
+        assert!(conn.live.is_none());
+        assert!(conn.context.streams.players("live/missing").is_empty());
+        // Only the status, neither Stream Begin nor Play.Start
+        let (type_id, payload) = read_raw(&mut client).await;
+        assert_eq!(type_id, msg_type_id::COMMAND_AMF0);
+        let (_, info) = decode_info(&payload);
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Play.StreamNotFound".to_owned()
+            ))
+        );
+        assert_eq!(
+            info_field(&info, "level"),
+            Some(&Amf0ValueType::UTF8String("error".to_owned()))
+        );
+
+        // What goes out for the next play starts with Stream Begin
+        let _guard = conn.context.streams.claim_publish("live", "key").unwrap();
+        conn.handle_play(PlayMessage::new("key".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_raw(&mut client).await.0,
+            msg_type_id::USER_CONTROL_EVENT
+        );
+    }
+
+    #[tokio::test]
+    async fn test_play_live() {
+        let (mut conn, mut client) = setup().await;
+        conn.app = "live".to_owned();
+        let guard = conn.context.streams.claim_publish("live", "key").unwrap();
+        guard.publish_frame(MediaFrame::new(
+            TagType::Video,
+            0,
+            vec![0x17, 0x00, 0, 0, 0, 0x01],
+        ));
+        guard.publish_frame(video(0x17, 0));
+
+        conn.handle_play(PlayMessage::new("key".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(conn.context.streams.players("live/key")[0].protocol, "rtmp");
+        // Reset, then Start
+        read_info(&mut client).await;
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Play.Start".to_owned()
+            ))
+        );
+        assert_eq!(read_raw(&mut client).await.0, msg_type_id::SET_CHUNK_SIZE);
+        // The sequence header, then the cached GOP
+        assert_eq!(read_raw(&mut client).await.1[1], 0x00);
+        assert_eq!(read_raw(&mut client).await.1[5], 0);
+
+        // Nothing goes out while paused, and after resuming only from the next keyframe
+        conn.handle_pause(PauseMessage::new(true, 0.0))
+            .await
+            .unwrap();
+        read_info(&mut client).await;
+        guard.publish_frame(video(0x27, 1));
+        forward_live(&mut conn).await;
+        conn.handle_pause(PauseMessage::new(false, 0.0))
+            .await
+            .unwrap();
+        read_info(&mut client).await;
+        guard.publish_frame(video(0x27, 2));
+        forward_live(&mut conn).await;
+        guard.publish_frame(video(0x17, 3));
+        forward_live(&mut conn).await;
+        assert_eq!(read_raw(&mut client).await.1[5], 3);
+
+        // Without video only audio goes out, video comes back at a keyframe
+        conn.handle_receive_video(ReceiveVideo::new(false))
+            .await
+            .unwrap();
+        guard.publish_frame(video(0x17, 4));
+        forward_live(&mut conn).await;
+        guard.publish_frame(MediaFrame::new(TagType::Audio, 500, vec![0xaf, 0x01, 5]));
+        forward_live(&mut conn).await;
+        assert_eq!(
+            read_raw(&mut client).await,
+            (msg_type_id::AUDIO, vec![0xaf, 0x01, 5])
+        );
+        conn.handle_receive_video(ReceiveVideo::new(true))
+            .await
+            .unwrap();
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Seek.Notify".to_owned()
+            ))
+        );
+        read_info(&mut client).await;
+        guard.publish_frame(video(0x27, 6));
+        forward_live(&mut conn).await;
+        guard.publish_frame(video(0x17, 7));
+        forward_live(&mut conn).await;
+        assert_eq!(read_raw(&mut client).await.1[5], 7);
+
+        // Live streams can not seek
+        conn.handle_seek(Seek::new(0.0)).await.unwrap();
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Seek.Failed".to_owned()
+            ))
+        );
+
+        drop(guard);
+        forward_live(&mut conn).await;
+        assert_eq!(
+            read_raw(&mut client).await,
+            (msg_type_id::USER_CONTROL_EVENT, vec![0, 1, 0, 0, 0, 1])
+        );
+        let (_, info) = read_info(&mut client).await;
+        assert_eq!(
+            info_field(&info, "code"),
+            Some(&Amf0ValueType::UTF8String(
+                "NetStream.Play.UnpublishNotify".to_owned()
+            ))
+        );
+        assert!(conn.live.is_none());
+    }
 }
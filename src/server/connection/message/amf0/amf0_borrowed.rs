@@ -0,0 +1,467 @@
+// Zero-copy AMF0 decoding. Values borrow from the input buffer and objects are only
+// walked when they are iterated, so dispatching on a command name or peeking into
+// metadata does not allocate.
+
+// Path: src/server/connection/message/amf0/amf0_borrowed.rs
+use {
+    super::{amf0_markers, errors::Amf0ReadErrorValue},
+    crate::server::connection::message::amf0::{define::Amf0ValueType, errors::Amf0ReadError},
+    bytesio::bytes_errors::{BytesReadError, BytesReadErrorValue},
+    indexmap::IndexMap,
+    std::str,
+};
+
+/// A borrowed view of an AMF0 value. Strings point into the source buffer and
+/// objects keep their raw encoded body, decoding properties on demand.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Amf0Value<'a> {
+    Number(f64),
+    Boolean(bool),
+    UTF8String(&'a str),
+    Object(Amf0Object<'a>),
+    Null,
+    Undefined,
+    EcmaArray(Amf0Object<'a>),
+    StrictArray(Amf0Array<'a>),
+    Date { unix_time: f64, time_zone: i16 },
+    LongUTF8String(&'a str),
+    END,
+}
+
+impl<'a> Amf0Value<'a> {
+    pub fn as_number(&self) -> Option<f64> {
+        match *self {
+            Amf0Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Amf0Value::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        match *self {
+            Amf0Value::UTF8String(s) | Amf0Value::LongUTF8String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&Amf0Object<'a>> {
+        match self {
+            Amf0Value::Object(obj) | Amf0Value::EcmaArray(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    /// Converts the view into the owned representation, decoding any nested
+    /// objects on the way. The result is identical to what `Amf0Reader` produces
+    /// for the same bytes.
+    pub fn to_owned_value(&self) -> Result<Amf0ValueType, Amf0ReadError> {
+        let value = match *self {
+            Amf0Value::Number(n) => Amf0ValueType::Number(n),
+            Amf0Value::Boolean(b) => Amf0ValueType::Boolean(b),
+            Amf0Value::UTF8String(s) => Amf0ValueType::UTF8String(s.to_owned()),
+            Amf0Value::Object(ref obj) => Amf0ValueType::Object(obj.to_owned_properties()?),
+            Amf0Value::Null => Amf0ValueType::Null,
+            Amf0Value::Undefined => Amf0ValueType::Undefined,
+            Amf0Value::EcmaArray(ref obj) => Amf0ValueType::EcmaArray(obj.to_owned_properties()?),
+            Amf0Value::StrictArray(ref array) => {
+                Amf0ValueType::StrictArray(array.to_owned_values()?)
+            }
+            Amf0Value::Date {
+                unix_time,
+                time_zone,
+            } => Amf0ValueType::Date {
+                unix_time,
+                time_zone,
+            },
+            Amf0Value::LongUTF8String(s) => Amf0ValueType::LongUTF8String(s.to_owned()),
+            Amf0Value::END => Amf0ValueType::END,
+        };
+        Ok(value)
+    }
+}
+
+impl<'a> TryFrom<&Amf0Value<'a>> for Amf0ValueType {
+    type Error = Amf0ReadError;
+
+    fn try_from(value: &Amf0Value<'a>) -> Result<Self, Self::Error> {
+        value.to_owned_value()
+    }
+}
+
+/// The encoded property list of an AMF0 object or ECMA array, up to and including
+/// the object end marker.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Amf0Object<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Amf0Object<'a> {
+    pub fn raw(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn iter(&self) -> Amf0ObjectIter<'a> {
+        Amf0ObjectIter {
+            reader: Amf0BorrowedReader::new(self.data),
+            done: false,
+        }
+    }
+
+    /// Looks up a property by walking the object until the key is found.
+    pub fn get(&self, key: &str) -> Result<Option<Amf0Value<'a>>, Amf0ReadError> {
+        for property in self.iter() {
+            let (name, value) = property?;
+            if name == key {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn to_owned_properties(&self) -> Result<IndexMap<String, Amf0ValueType>, Amf0ReadError> {
+        let mut properties = IndexMap::new();
+        for property in self.iter() {
+            let (key, value) = property?;
+            properties.insert(key.to_owned(), value.to_owned_value()?);
+        }
+        Ok(properties)
+    }
+}
+
+impl<'a> IntoIterator for &Amf0Object<'a> {
+    type Item = Result<(&'a str, Amf0Value<'a>), Amf0ReadError>;
+    type IntoIter = Amf0ObjectIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// The encoded elements of an AMF0 strict array.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Amf0Array<'a> {
+    len: u32,
+    data: &'a [u8],
+}
+
+impl<'a> Amf0Array<'a> {
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn raw(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn iter(&self) -> Amf0ArrayIter<'a> {
+        Amf0ArrayIter {
+            reader: Amf0BorrowedReader::new(self.data),
+            remaining: self.len,
+        }
+    }
+
+    pub fn to_owned_values(&self) -> Result<Vec<Amf0ValueType>, Amf0ReadError> {
+        self.iter()
+            .map(|value| value.and_then(|v| v.to_owned_value()))
+            .collect()
+    }
+}
+
+impl<'a> IntoIterator for &Amf0Array<'a> {
+    type Item = Result<Amf0Value<'a>, Amf0ReadError>;
+    type IntoIter = Amf0ArrayIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct Amf0ArrayIter<'a> {
+    reader: Amf0BorrowedReader<'a>,
+    remaining: u32,
+}
+
+impl<'a> Iterator for Amf0ArrayIter<'a> {
+    type Item = Result<Amf0Value<'a>, Amf0ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let value = self.reader.read_any();
+        self.remaining = if value.is_ok() { self.remaining - 1 } else { 0 };
+        Some(value)
+    }
+}
+
+pub struct Amf0ObjectIter<'a> {
+    reader: Amf0BorrowedReader<'a>,
+    done: bool,
+}
+
+impl<'a> Iterator for Amf0ObjectIter<'a> {
+    type Item = Result<(&'a str, Amf0Value<'a>), Amf0ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let property = match self.reader.is_read_object_eof() {
+            Ok(true) => {
+                self.done = true;
+                return None;
+            }
+            Ok(false) => self.reader.read_raw_string().and_then(|key| {
+                let value = self.reader.read_any()?;
+                Ok((key, value))
+            }),
+            Err(err) => Err(err),
+        };
+
+        if property.is_err() {
+            self.done = true;
+        }
+        Some(property)
+    }
+}
+
+/// Reads AMF0 values out of a borrowed slice without copying.
+pub struct Amf0BorrowedReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Amf0BorrowedReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.position.min(self.data.len())..]
+    }
+
+    // Read all and call read_any for each
+    pub fn read_all(&mut self) -> Result<Vec<Amf0Value<'a>>, Amf0ReadError> {
+        let mut results = vec![];
+        loop {
+            let result = self.read_any()?;
+
+            match result {
+                Amf0Value::END => break,
+                _ => results.push(result),
+            }
+        }
+        Ok(results)
+    }
+
+    pub fn read_any(&mut self) -> Result<Amf0Value<'a>, Amf0ReadError> {
+        if self.is_empty() {
+            return Ok(Amf0Value::END);
+        }
+
+        let markers = self.read_u8()?;
+
+        if markers == amf0_markers::OBJECT_END {
+            return Ok(Amf0Value::END);
+        }
+
+        match markers {
+            amf0_markers::NUMBER => Ok(Amf0Value::Number(self.read_f64()?)),
+            amf0_markers::BOOLEAN => Ok(Amf0Value::Boolean(self.read_u8()? == 1)),
+            amf0_markers::STRING => Ok(Amf0Value::UTF8String(self.read_raw_string()?)),
+            amf0_markers::OBJECT => Ok(Amf0Value::Object(self.read_object()?)),
+            amf0_markers::NULL => Ok(Amf0Value::Null),
+            amf0_markers::UNDEFINED => Ok(Amf0Value::Undefined),
+            amf0_markers::ECMA_ARRAY => {
+                // The length is advisory only, see Amf0Reader::read_ecma_array.
+                self.read_u32()?;
+                Ok(Amf0Value::EcmaArray(self.read_object()?))
+            }
+            amf0_markers::STRICT_ARRAY => Ok(Amf0Value::StrictArray(self.read_strict_array()?)),
+            amf0_markers::DATE => {
+                let unix_time = self.read_f64()?;
+                let time_zone = self.read_u16()? as i16;
+                Ok(Amf0Value::Date {
+                    unix_time,
+                    time_zone,
+                })
+            }
+            amf0_markers::LONG_STRING => {
+                let l = self.read_u32()?;
+                let bytes = self.take(l as usize)?;
+                Ok(Amf0Value::LongUTF8String(str::from_utf8(bytes)?))
+            }
+            _ => Err(Amf0ReadError {
+                value: Amf0ReadErrorValue::UnknownMarker { marker: markers },
+            }),
+        }
+    }
+
+    pub fn read_raw_string(&mut self) -> Result<&'a str, Amf0ReadError> {
+        let l = self.read_u16()?;
+        let bytes = self.take(l as usize)?;
+        Ok(str::from_utf8(bytes)?)
+    }
+
+    fn read_object(&mut self) -> Result<Amf0Object<'a>, Amf0ReadError> {
+        let start = self.position;
+
+        // Walk the properties once to find where the object ends. Nested values are
+        // skipped the same way, so nothing is allocated here.
+        while !self.is_read_object_eof()? {
+            self.read_raw_string()?;
+            self.read_any()?;
+        }
+
+        Ok(Amf0Object {
+            data: &self.data[start..self.position],
+        })
+    }
+
+    fn read_strict_array(&mut self) -> Result<Amf0Array<'a>, Amf0ReadError> {
+        let len = self.read_u32()?;
+        let start = self.position;
+
+        for _ in 0..len {
+            self.read_any()?;
+        }
+
+        Ok(Amf0Array {
+            len,
+            data: &self.data[start..self.position],
+        })
+    }
+
+    fn is_read_object_eof(&mut self) -> Result<bool, Amf0ReadError> {
+        let marker = self.peek(3)?;
+        if marker == [0, 0, amf0_markers::OBJECT_END] {
+            self.position += 3;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn peek(&self, len: usize) -> Result<&'a [u8], Amf0ReadError> {
+        match self.position.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(&self.data[self.position..end]),
+            _ => Err(BytesReadError {
+                value: BytesReadErrorValue::NotEnoughBytes,
+            }
+            .into()),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Amf0ReadError> {
+        let bytes = self.peek(len)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Amf0ReadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Amf0ReadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Amf0ReadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_f64(&mut self) -> Result<f64, Amf0ReadError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(f64::from_be_bytes(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Amf0BorrowedReader, Amf0Value};
+    use crate::server::connection::message::amf0::amf0_reader::Amf0Reader;
+
+    use bytes::BytesMut;
+    use bytesio::bytes_reader::BytesReader;
+
+    // connect command from OBS, see amf0_reader::tests::test_amf_reader
+    const CONNECT: [u8; 177] = [
+        2, 0, 7, 99, 111, 110, 110, 101, 99, 116, 0, 63, 240, 0, 0, 0, 0, 0, 0, //body
+        3, 0, 3, 97, 112, 112, 2, 0, 6, 104, 97, 114, 108, 97, 110, 0, 4, 116, 121, 112, 101, 2, 0,
+        10, 110, 111, 110, 112, 114, 105, 118, 97, 116, 101, 0, 8, 102, 108, 97, 115, 104, 86, 101,
+        114, 2, 0, 31, 70, 77, 76, 69, 47, 51, 46, 48, 32, 40, 99, 111, 109, 112, 97, 116, 105, 98,
+        108, 101, 59, 32, 70, 77, 83, 99, 47, 49, 46, 48, 41, 0, 6, 115, 119, 102, 85, 114, 108, 2,
+        0, 28, 114, 116, 109, 112, 58, 47, 47, 108, 111, 99, 97, 108, 104, 111, 115, 116, 58, 49,
+        57, 51, 53, 47, 104, 97, 114, 108, 97, 110, 0, 5, 116, 99, 85, 114, 108, 2, 0, 28, 114,
+        116, 109, 112, 58, 47, 47, 108, 111, 99, 97, 108, 104, 111, 115, 116, 58, 49, 57, 51, 53,
+        47, 104, 97, 114, 108, 97, 110, 0, 0, 9,
+    ];
+
+    #[test]
+    fn test_borrowed_connect() {
+        let mut reader = Amf0BorrowedReader::new(&CONNECT);
+
+        assert_eq!(reader.read_any().unwrap(), Amf0Value::UTF8String("connect"));
+        assert_eq!(reader.read_any().unwrap(), Amf0Value::Number(1.0));
+
+        let command_obj = reader.read_any().unwrap();
+        let command_obj = command_obj.as_object().unwrap();
+        assert_eq!(
+            command_obj.get("app").unwrap(),
+            Some(Amf0Value::UTF8String("harlan"))
+        );
+        assert_eq!(
+            command_obj.get("tcUrl").unwrap().and_then(|v| v.as_str()),
+            Some("rtmp://localhost:1935/harlan")
+        );
+        assert_eq!(command_obj.get("missing").unwrap(), None);
+        assert_eq!(command_obj.iter().count(), 5);
+
+        assert_eq!(reader.read_any().unwrap(), Amf0Value::END);
+    }
+
+    #[test]
+    fn test_borrowed_matches_owned() {
+        let borrowed = Amf0BorrowedReader::new(&CONNECT).read_all().unwrap();
+        let owned = Amf0Reader::new(BytesReader::new(BytesMut::from(&CONNECT[..])))
+            .read_all()
+            .unwrap();
+
+        let converted = borrowed
+            .iter()
+            .map(|v| v.to_owned_value().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(converted, owned);
+    }
+
+    #[test]
+    fn test_borrowed_truncated() {
+        // cut off inside the command object
+        let mut reader = Amf0BorrowedReader::new(&CONNECT[..40]);
+        reader.read_any().unwrap();
+        reader.read_any().unwrap();
+        assert!(reader.read_any().is_err());
+    }
+}
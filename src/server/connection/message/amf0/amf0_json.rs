@@ -0,0 +1,302 @@
+// Conversion between AMF0 values and JSON, used to expose connect objects and
+// metadata in logs and APIs.
+//
+// Plain objects, arrays, strings, numbers, booleans and null map directly onto their
+// JSON counterparts. Values JSON can not express are written as tagged objects:
+//
+//     {"@type": "EcmaArray", "value": {...}}
+//     {"@type": "Date", "value": 1700000000000.0, "timeZone": 0}
+//     {"@type": "Undefined"}
+//     {"@type": "Number", "value": "NaN"}
+//
+// A plain object that itself has an "@type" key is wrapped as
+// {"@type": "Object", "value": {...}} so it can not be mistaken for a tag.
+
+// Path: src/server/connection/message/amf0/amf0_json.rs
+use {
+    super::{
+        define::Amf0ValueType,
+        errors::{Amf0ReadError, Amf0ReadErrorValue},
+    },
+    indexmap::IndexMap,
+    serde_json::{Map, Number, Value},
+    std::fmt,
+};
+
+const TYPE_TAG: &str = "@type";
+const VALUE_TAG: &str = "value";
+const TIME_ZONE_TAG: &str = "timeZone";
+
+impl Amf0ValueType {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Amf0ValueType::Number(n) => match Number::from_f64(*n) {
+                Some(number) => Value::Number(number),
+                None => tagged("Number", Some(Value::String(non_finite_to_str(*n)))),
+            },
+            Amf0ValueType::Boolean(b) => Value::Bool(*b),
+            Amf0ValueType::UTF8String(s) | Amf0ValueType::LongUTF8String(s) => {
+                Value::String(s.clone())
+            }
+            Amf0ValueType::Object(properties) => {
+                let object = properties_to_json(properties);
+                if properties.contains_key(TYPE_TAG) {
+                    tagged("Object", Some(object))
+                } else {
+                    object
+                }
+            }
+            Amf0ValueType::Null => Value::Null,
+            Amf0ValueType::Undefined => tagged("Undefined", None),
+            Amf0ValueType::EcmaArray(properties) => {
+                tagged("EcmaArray", Some(properties_to_json(properties)))
+            }
+            Amf0ValueType::StrictArray(values) => {
+                Value::Array(values.iter().map(Amf0ValueType::to_json).collect())
+            }
+            Amf0ValueType::Date {
+                unix_time,
+                time_zone,
+            } => {
+                let mut date = Map::new();
+                date.insert(TYPE_TAG.to_owned(), Value::String("Date".to_owned()));
+                date.insert(
+                    VALUE_TAG.to_owned(),
+                    Number::from_f64(*unix_time)
+                        .map(Value::Number)
+                        .unwrap_or(Value::Null),
+                );
+                date.insert(TIME_ZONE_TAG.to_owned(), Value::from(*time_zone));
+                Value::Object(date)
+            }
+            Amf0ValueType::END => tagged("ObjectEnd", None),
+        }
+    }
+
+    pub fn from_json(value: &Value) -> Result<Amf0ValueType, Amf0ReadError> {
+        let amf0_value = match value {
+            Value::Null => Amf0ValueType::Null,
+            Value::Bool(b) => Amf0ValueType::Boolean(*b),
+            Value::Number(n) => match n.as_f64() {
+                Some(n) => Amf0ValueType::Number(n),
+                None => return Err(invalid_json(format!("number out of range: {}", n))),
+            },
+            Value::String(s) if s.len() > u16::MAX as usize => {
+                Amf0ValueType::LongUTF8String(s.clone())
+            }
+            Value::String(s) => Amf0ValueType::UTF8String(s.clone()),
+            Value::Array(values) => Amf0ValueType::StrictArray(
+                values
+                    .iter()
+                    .map(Amf0ValueType::from_json)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(object) => match object.get(TYPE_TAG) {
+                Some(Value::String(tag)) => tagged_from_json(tag, object)?,
+                _ => Amf0ValueType::Object(properties_from_json(object)?),
+            },
+        };
+        Ok(amf0_value)
+    }
+}
+
+impl fmt::Display for Amf0ValueType {
+    // `{}` prints compact JSON, `{:#}` pretty prints it over several lines.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let json = self.to_json();
+        if f.alternate() {
+            let pretty = serde_json::to_string_pretty(&json).map_err(|_| fmt::Error)?;
+            f.write_str(&pretty)
+        } else {
+            fmt::Display::fmt(&json, f)
+        }
+    }
+}
+
+impl From<&Amf0ValueType> for Value {
+    fn from(value: &Amf0ValueType) -> Self {
+        value.to_json()
+    }
+}
+
+impl TryFrom<&Value> for Amf0ValueType {
+    type Error = Amf0ReadError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        Amf0ValueType::from_json(value)
+    }
+}
+
+fn tagged(tag: &str, value: Option<Value>) -> Value {
+    let mut object = Map::new();
+    object.insert(TYPE_TAG.to_owned(), Value::String(tag.to_owned()));
+    if let Some(value) = value {
+        object.insert(VALUE_TAG.to_owned(), value);
+    }
+    Value::Object(object)
+}
+
+fn non_finite_to_str(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_owned()
+    } else if n.is_sign_positive() {
+        "Infinity".to_owned()
+    } else {
+        "-Infinity".to_owned()
+    }
+}
+
+pub fn properties_to_json(properties: &IndexMap<String, Amf0ValueType>) -> Value {
+    Value::Object(
+        properties
+            .iter()
+            .map(|(key, value)| (key.clone(), value.to_json()))
+            .collect(),
+    )
+}
+
+fn properties_from_json(
+    object: &Map<String, Value>,
+) -> Result<IndexMap<String, Amf0ValueType>, Amf0ReadError> {
+    let mut properties = IndexMap::new();
+    for (key, value) in object {
+        properties.insert(key.clone(), Amf0ValueType::from_json(value)?);
+    }
+    Ok(properties)
+}
+
+fn tagged_from_json(
+    tag: &str,
+    object: &Map<String, Value>,
+) -> Result<Amf0ValueType, Amf0ReadError> {
+    let value = object.get(VALUE_TAG);
+
+    match (tag, value) {
+        ("Undefined", _) => Ok(Amf0ValueType::Undefined),
+        ("ObjectEnd", _) => Ok(Amf0ValueType::END),
+        ("Object", Some(Value::Object(properties))) => {
+            Ok(Amf0ValueType::Object(properties_from_json(properties)?))
+        }
+        ("EcmaArray", Some(Value::Object(properties))) => {
+            Ok(Amf0ValueType::EcmaArray(properties_from_json(properties)?))
+        }
+        ("Number", Some(Value::String(n))) => match n.as_str() {
+            "NaN" => Ok(Amf0ValueType::Number(f64::NAN)),
+            "Infinity" => Ok(Amf0ValueType::Number(f64::INFINITY)),
+            "-Infinity" => Ok(Amf0ValueType::Number(f64::NEG_INFINITY)),
+            _ => Err(invalid_json(format!("unknown number {:?}", n))),
+        },
+        ("Date", Some(unix_time)) => {
+            let unix_time = match unix_time {
+                Value::Number(n) => n.as_f64().unwrap_or(f64::NAN),
+                Value::Null => f64::NAN,
+                _ => return Err(invalid_json("date value must be a number".to_owned())),
+            };
+            let time_zone = match object.get(TIME_ZONE_TAG) {
+                Some(Value::Number(n)) => n.as_i64().unwrap_or(0) as i16,
+                _ => 0,
+            };
+            Ok(Amf0ValueType::Date {
+                unix_time,
+                time_zone,
+            })
+        }
+        _ => Err(invalid_json(format!("malformed {:?} tag", tag))),
+    }
+}
+
+fn invalid_json(reason: String) -> Amf0ReadError {
+    Amf0ReadError {
+        value: Amf0ReadErrorValue::InvalidJson(reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Amf0ValueType;
+    use crate::server::connection::message::amf0::{
+        amf0_reader::Amf0Reader, amf0_writer::Amf0Writer,
+    };
+
+    use bytesio::{bytes_reader::BytesReader, bytes_writer::BytesWriter};
+    use indexmap::IndexMap;
+    use serde_json::json;
+
+    fn metadata() -> Amf0ValueType {
+        let mut properties = IndexMap::new();
+        properties.insert("width".to_owned(), Amf0ValueType::Number(1920.0));
+        properties.insert("stereo".to_owned(), Amf0ValueType::Boolean(true));
+        properties.insert(
+            "encoder".to_owned(),
+            Amf0ValueType::UTF8String("obs-output module".to_owned()),
+        );
+        properties.insert(
+            "created".to_owned(),
+            Amf0ValueType::Date {
+                unix_time: 1_700_000_000_000.0,
+                time_zone: 0,
+            },
+        );
+        properties.insert(
+            "tags".to_owned(),
+            Amf0ValueType::StrictArray(vec![Amf0ValueType::Null, Amf0ValueType::Undefined]),
+        );
+        Amf0ValueType::EcmaArray(properties)
+    }
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(
+            metadata().to_json(),
+            json!({
+                "@type": "EcmaArray",
+                "value": {
+                    "width": 1920.0,
+                    "stereo": true,
+                    "encoder": "obs-output module",
+                    "created": {"@type": "Date", "value": 1_700_000_000_000.0, "timeZone": 0},
+                    "tags": [null, {"@type": "Undefined"}],
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut tricky = IndexMap::new();
+        tricky.insert(
+            "@type".to_owned(),
+            Amf0ValueType::UTF8String("Undefined".to_owned()),
+        );
+
+        for value in [
+            metadata(),
+            Amf0ValueType::Object(tricky),
+            Amf0ValueType::Number(f64::INFINITY),
+        ] {
+            let json = value.to_json();
+            assert_eq!(Amf0ValueType::from_json(&json).unwrap(), value);
+        }
+
+        assert!(Amf0ValueType::from_json(&json!({"@type": "Date", "value": "x"})).is_err());
+    }
+
+    #[test]
+    fn test_new_markers_round_trip() {
+        let mut writer = Amf0Writer::new(BytesWriter::new());
+        writer.write_any(&metadata()).unwrap();
+
+        let mut reader = Amf0Reader::new(BytesReader::new(writer.extract_current_bytes()));
+        assert_eq!(reader.read_any().unwrap(), metadata());
+    }
+
+    #[test]
+    fn test_display() {
+        let value = Amf0ValueType::StrictArray(vec![
+            Amf0ValueType::Number(1.0),
+            Amf0ValueType::UTF8String("live".to_owned()),
+        ]);
+        assert_eq!(format!("{}", value), r#"[1.0,"live"]"#);
+        assert_eq!(format!("{:#}", value), "[\n  1.0,\n  \"live\"\n]");
+    }
+}
@@ -3,6 +3,9 @@ pub const BOOLEAN: u8 = 0x01;
 pub const STRING: u8 = 0x02;
 pub const OBJECT: u8 = 0x03;
 pub const NULL: u8 = 0x05;
+pub const UNDEFINED: u8 = 0x06;
 pub const ECMA_ARRAY: u8 = 0x08;
 pub const OBJECT_END: u8 = 0x09;
+pub const STRICT_ARRAY: u8 = 0x0a;
+pub const DATE: u8 = 0x0b;
 pub const LONG_STRING: u8 = 0x0c;
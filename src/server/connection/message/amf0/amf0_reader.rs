@@ -51,7 +51,10 @@ impl Amf0Reader {
             amf0_markers::STRING => self.read_string(),
             amf0_markers::OBJECT => self.read_object(),
             amf0_markers::NULL => self.read_null(),
+            amf0_markers::UNDEFINED => self.read_undefined(),
             amf0_markers::ECMA_ARRAY => self.read_ecma_array(),
+            amf0_markers::STRICT_ARRAY => self.read_strict_array(),
+            amf0_markers::DATE => self.read_date(),
             amf0_markers::LONG_STRING => self.read_long_string(),
             _ => Err(Amf0ReadError {
                 value: Amf0ReadErrorValue::UnknownMarker { marker: markers },
@@ -103,6 +106,10 @@ impl Amf0Reader {
         Ok(Amf0ValueType::Null)
     }
 
+    pub fn read_undefined(&mut self) -> Result<Amf0ValueType, Amf0ReadError> {
+        Ok(Amf0ValueType::Undefined)
+    }
+
     pub fn is_read_object_eof(&mut self) -> Result<bool, Amf0ReadError> {
         let marker = self.reader.advance_u24::<BigEndian>()?;
         if marker == amf0_markers::OBJECT_END as u32 {
@@ -148,7 +155,28 @@ impl Amf0Reader {
             log::warn!("the ecma array length is not correct!");
         }
 
-        Ok(Amf0ValueType::Object(properties))
+        Ok(Amf0ValueType::EcmaArray(properties))
+    }
+
+    pub fn read_strict_array(&mut self) -> Result<Amf0ValueType, Amf0ReadError> {
+        let len = self.reader.read_u32::<BigEndian>()?;
+
+        let mut values = Vec::new();
+        for _ in 0..len {
+            values.push(self.read_any()?);
+        }
+
+        Ok(Amf0ValueType::StrictArray(values))
+    }
+
+    pub fn read_date(&mut self) -> Result<Amf0ValueType, Amf0ReadError> {
+        let unix_time = self.reader.read_f64::<BigEndian>()?;
+        let time_zone = self.reader.read_u16::<BigEndian>()? as i16;
+
+        Ok(Amf0ValueType::Date {
+            unix_time,
+            time_zone,
+        })
     }
 
     pub fn read_long_string(&mut self) -> Result<Amf0ValueType, Amf0ReadError> {
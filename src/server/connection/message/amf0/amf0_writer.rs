@@ -30,7 +30,15 @@ impl Amf0Writer {
             Amf0ValueType::Number(ref val) => self.write_number(val),
             Amf0ValueType::UTF8String(ref val) => self.write_string(val),
             Amf0ValueType::Object(ref val) => self.write_object(val),
-            _ => Ok(()),
+            Amf0ValueType::Undefined => self.write_undefined(),
+            Amf0ValueType::EcmaArray(ref val) => self.write_ecma_array(val),
+            Amf0ValueType::StrictArray(ref val) => self.write_strict_array(val),
+            Amf0ValueType::Date {
+                ref unix_time,
+                ref time_zone,
+            } => self.write_date(unix_time, time_zone),
+            Amf0ValueType::LongUTF8String(ref val) => self.write_long_string(val),
+            Amf0ValueType::END => Ok(()),
         }
     }
 
@@ -47,7 +55,7 @@ impl Amf0Writer {
     }
 
     pub fn write_string(&mut self, value: &String) -> Result<(), Amf0WriteError> {
-        if value.len() > (u16::max_value() as usize) {
+        if value.len() > (u16::MAX as usize) {
             return Err(Amf0WriteError {
                 value: Amf0WriteErrorValue::NormalStringTooLong,
             });
@@ -60,11 +68,37 @@ impl Amf0Writer {
         Ok(())
     }
 
+    pub fn write_long_string(&mut self, value: &String) -> Result<(), Amf0WriteError> {
+        if value.len() > (u32::MAX as usize) {
+            return Err(Amf0WriteError {
+                value: Amf0WriteErrorValue::LongStringTooLong,
+            });
+        }
+
+        self.writer.write_u8(amf0_markers::LONG_STRING)?;
+        self.writer.write_u32::<BigEndian>(value.len() as u32)?;
+        self.writer.write(value.as_bytes())?;
+
+        Ok(())
+    }
+
     pub fn write_null(&mut self) -> Result<(), Amf0WriteError> {
         self.writer.write_u8(amf0_markers::NULL)?;
         Ok(())
     }
 
+    pub fn write_undefined(&mut self) -> Result<(), Amf0WriteError> {
+        self.writer.write_u8(amf0_markers::UNDEFINED)?;
+        Ok(())
+    }
+
+    pub fn write_date(&mut self, unix_time: &f64, time_zone: &i16) -> Result<(), Amf0WriteError> {
+        self.writer.write_u8(amf0_markers::DATE)?;
+        self.writer.write_f64::<BigEndian>(*unix_time)?;
+        self.writer.write_u16::<BigEndian>(*time_zone as u16)?;
+        Ok(())
+    }
+
     pub fn write_object_eof(&mut self) -> Result<(), Amf0WriteError> {
         self.writer
             .write_u24::<BigEndian>(amf0_markers::OBJECT_END as u32)?;
@@ -87,6 +121,38 @@ impl Amf0Writer {
         Ok(())
     }
 
+    pub fn write_ecma_array(
+        &mut self,
+        properties: &IndexMap<String, Amf0ValueType>,
+    ) -> Result<(), Amf0WriteError> {
+        self.writer.write_u8(amf0_markers::ECMA_ARRAY)?;
+        self.writer
+            .write_u32::<BigEndian>(properties.len() as u32)?;
+
+        for (key, value) in properties {
+            self.writer.write_u16::<BigEndian>(key.len() as u16)?;
+            self.writer.write(key.as_bytes())?;
+            self.write_any(value)?;
+        }
+
+        self.write_object_eof()?;
+        Ok(())
+    }
+
+    pub fn write_strict_array(
+        &mut self,
+        values: &Vec<Amf0ValueType>,
+    ) -> Result<(), Amf0WriteError> {
+        self.writer.write_u8(amf0_markers::STRICT_ARRAY)?;
+        self.writer.write_u32::<BigEndian>(values.len() as u32)?;
+
+        for value in values {
+            self.write_any(value)?;
+        }
+
+        Ok(())
+    }
+
     // pub async fn flush(&mut self) -> Result<(), Amf0WriteError> {
     //     self.writer.flush()?;
     // }
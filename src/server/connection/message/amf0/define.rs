@@ -1,6 +1,6 @@
-#[allow(clippy::upper_case_acronyms)]
 use indexmap::IndexMap;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Clone, Debug)]
 pub enum Amf0ValueType {
     Number(f64),
@@ -8,7 +8,11 @@ pub enum Amf0ValueType {
     UTF8String(String),
     Object(IndexMap<String, Amf0ValueType>),
     Null,
+    Undefined,
     EcmaArray(IndexMap<String, Amf0ValueType>),
+    StrictArray(Vec<Amf0ValueType>),
+    // milliseconds since the unix epoch, the time zone is reserved and should be 0
+    Date { unix_time: f64, time_zone: i16 },
     LongUTF8String(String),
     END,
 }
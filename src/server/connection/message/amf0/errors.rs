@@ -1,9 +1,12 @@
+// The failure derive emits its Display impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use log::error;
 use {
     bytesio::bytes_errors::{BytesReadError, BytesWriteError},
-    failure::{Fail},
+    failure::Fail,
     std::{
-        fmt, {io, string},
+        fmt, {io, str, string},
     },
 };
 
@@ -13,10 +16,14 @@ pub enum Amf0ReadErrorValue {
     UnknownMarker { marker: u8 },
     #[fail(display = "parser string error: {}\n", _0)]
     StringParseError(#[cause] string::FromUtf8Error),
+    #[fail(display = "parser str error: {}\n", _0)]
+    StrParseError(#[cause] str::Utf8Error),
     #[fail(display = "bytes read error :{}\n", _0)]
     BytesReadError(BytesReadError),
     #[fail(display = "wrong type")]
     WrongType,
+    #[fail(display = "invalid json value: {}\n", _0)]
+    InvalidJson(String),
 }
 
 #[derive(Debug)]
@@ -33,6 +40,15 @@ impl From<string::FromUtf8Error> for Amf0ReadError {
     }
 }
 
+impl From<str::Utf8Error> for Amf0ReadError {
+    fn from(error: str::Utf8Error) -> Self {
+        error!("str parse error: {}", error);
+        Amf0ReadError {
+            value: Amf0ReadErrorValue::StrParseError(error),
+        }
+    }
+}
+
 impl From<BytesReadError> for Amf0ReadError {
     fn from(error: BytesReadError) -> Self {
         error!("bytes read error: {}", error);
@@ -48,6 +64,8 @@ impl std::error::Error for Amf0ReadError {}
 pub enum Amf0WriteErrorValue {
     #[fail(display = "normal string too long")]
     NormalStringTooLong,
+    #[fail(display = "long string too long")]
+    LongStringTooLong,
     #[fail(display = "io error\n")]
     BufferWriteError(io::Error),
     #[fail(display = "bytes write error\n")]
@@ -1,3 +1,5 @@
+pub mod amf0_borrowed;
+pub mod amf0_json;
 pub mod amf0_markers;
 pub mod amf0_reader;
 pub mod amf0_writer;
@@ -0,0 +1,836 @@
+// This file defines the typed model for AMF0 command messages (message type 20). Every
+// NetConnection and NetStream command has its own struct with a parse and a serialize
+// method, and Command ties them together for dispatching.
+
+// Path: src/server/connection/message/command.rs
+use bytes::BytesMut;
+use bytesio::bytes_writer::BytesWriter;
+use indexmap::IndexMap;
+
+use crate::server::connection::message::amf0::{
+    amf0_borrowed::{Amf0BorrowedReader, Amf0Value},
+    amf0_writer::Amf0Writer,
+    define::Amf0ValueType,
+    errors::Amf0WriteError,
+};
+use crate::server::connection::message::message::{
+    BasicCommand, ConnectMessage, CreateStream, FCPublish, PauseMessage, PlayMessage, Publish,
+    ReleaseStream,
+};
+
+pub mod command_name {
+    // NetConnection
+    pub const CONNECT: &str = "connect";
+    pub const CALL: &str = "call";
+    pub const CLOSE: &str = "close";
+    pub const CREATE_STREAM: &str = "createStream";
+
+    // NetStream
+    pub const DELETE_STREAM: &str = "deleteStream";
+    pub const CLOSE_STREAM: &str = "closeStream";
+    pub const RELEASE_STREAM: &str = "releaseStream";
+    pub const FC_PUBLISH: &str = "FCPublish";
+    pub const FC_UNPUBLISH: &str = "FCUnpublish";
+    pub const FC_SUBSCRIBE: &str = "FCSubscribe";
+    pub const PUBLISH: &str = "publish";
+    pub const PLAY: &str = "play";
+    pub const PLAY2: &str = "play2";
+    pub const PAUSE: &str = "pause";
+    pub const SEEK: &str = "seek";
+    pub const RECEIVE_AUDIO: &str = "receiveAudio";
+    pub const RECEIVE_VIDEO: &str = "receiveVideo";
+    pub const GET_STREAM_LENGTH: &str = "getStreamLength";
+    pub const CHECK_BANDWIDTH: &str = "_checkbw";
+
+    // Responses
+    pub const RESULT: &str = "_result";
+    pub const ERROR: &str = "_error";
+    pub const ON_STATUS: &str = "onStatus";
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Connect(ConnectMessage),
+    Call(Call),
+    Close(Close),
+    CreateStream(CreateStream),
+    DeleteStream(DeleteStream),
+    CloseStream(CloseStream),
+    ReleaseStream(ReleaseStream),
+    FCPublish(FCPublish),
+    FCUnpublish(FCUnpublish),
+    FCSubscribe(FCSubscribe),
+    Publish(Publish),
+    Play(PlayMessage),
+    Play2(Play2),
+    Pause(PauseMessage),
+    Seek(Seek),
+    ReceiveAudio(ReceiveAudio),
+    ReceiveVideo(ReceiveVideo),
+    GetStreamLength(GetStreamLength),
+    CheckBandwidth(CheckBandwidth),
+}
+
+impl Command {
+    // Any command name the server does not know is treated as a call to a remote
+    // procedure of that name, which is how NetConnection.call puts it on the wire.
+    pub fn parse(data: &[u8]) -> Result<Command, Box<dyn std::error::Error>> {
+        let command_name = BasicCommand::parse(data)?.command_name;
+
+        let command = match command_name.as_str() {
+            command_name::CONNECT => Command::Connect(ConnectMessage::parse(data)?),
+            command_name::CLOSE => Command::Close(Close::parse(data)?),
+            command_name::CREATE_STREAM => Command::CreateStream(CreateStream::parse(data)?),
+            command_name::DELETE_STREAM => Command::DeleteStream(DeleteStream::parse(data)?),
+            command_name::CLOSE_STREAM => Command::CloseStream(CloseStream::parse(data)?),
+            command_name::RELEASE_STREAM => Command::ReleaseStream(ReleaseStream::parse(data)?),
+            command_name::FC_PUBLISH => Command::FCPublish(FCPublish::parse(data)?),
+            command_name::FC_UNPUBLISH => Command::FCUnpublish(FCUnpublish::parse(data)?),
+            command_name::FC_SUBSCRIBE => Command::FCSubscribe(FCSubscribe::parse(data)?),
+            command_name::PUBLISH => Command::Publish(Publish::parse(data)?),
+            command_name::PLAY => Command::Play(PlayMessage::parse(data)?),
+            command_name::PLAY2 => Command::Play2(Play2::parse(data)?),
+            command_name::PAUSE => Command::Pause(PauseMessage::parse(data)?),
+            command_name::SEEK => Command::Seek(Seek::parse(data)?),
+            command_name::RECEIVE_AUDIO => Command::ReceiveAudio(ReceiveAudio::parse(data)?),
+            command_name::RECEIVE_VIDEO => Command::ReceiveVideo(ReceiveVideo::parse(data)?),
+            command_name::GET_STREAM_LENGTH => {
+                Command::GetStreamLength(GetStreamLength::parse(data)?)
+            }
+            command_name::CHECK_BANDWIDTH => Command::CheckBandwidth(CheckBandwidth::parse(data)?),
+            _ => Command::Call(Call::parse(data)?),
+        };
+
+        Ok(command)
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        match self {
+            Command::Connect(cmd) => cmd.serialize(),
+            Command::Call(cmd) => cmd.serialize(),
+            Command::Close(cmd) => cmd.serialize(),
+            Command::CreateStream(cmd) => cmd.serialize(),
+            Command::DeleteStream(cmd) => cmd.serialize(),
+            Command::CloseStream(cmd) => cmd.serialize(),
+            Command::ReleaseStream(cmd) => cmd.serialize(),
+            Command::FCPublish(cmd) => cmd.serialize(),
+            Command::FCUnpublish(cmd) => cmd.serialize(),
+            Command::FCSubscribe(cmd) => cmd.serialize(),
+            Command::Publish(cmd) => cmd.serialize(),
+            Command::Play(cmd) => cmd.serialize(),
+            Command::Play2(cmd) => cmd.serialize(),
+            Command::Pause(cmd) => cmd.serialize(),
+            Command::Seek(cmd) => cmd.serialize(),
+            Command::ReceiveAudio(cmd) => cmd.serialize(),
+            Command::ReceiveVideo(cmd) => cmd.serialize(),
+            Command::GetStreamLength(cmd) => cmd.serialize(),
+            Command::CheckBandwidth(cmd) => cmd.serialize(),
+        }
+    }
+
+    pub fn command_name(&self) -> &str {
+        match self {
+            Command::Connect(_) => command_name::CONNECT,
+            Command::Call(cmd) => &cmd.procedure_name,
+            Command::Close(_) => command_name::CLOSE,
+            Command::CreateStream(_) => command_name::CREATE_STREAM,
+            Command::DeleteStream(_) => command_name::DELETE_STREAM,
+            Command::CloseStream(_) => command_name::CLOSE_STREAM,
+            Command::ReleaseStream(_) => command_name::RELEASE_STREAM,
+            Command::FCPublish(_) => command_name::FC_PUBLISH,
+            Command::FCUnpublish(_) => command_name::FC_UNPUBLISH,
+            Command::FCSubscribe(_) => command_name::FC_SUBSCRIBE,
+            Command::Publish(_) => command_name::PUBLISH,
+            Command::Play(_) => command_name::PLAY,
+            Command::Play2(_) => command_name::PLAY2,
+            Command::Pause(_) => command_name::PAUSE,
+            Command::Seek(_) => command_name::SEEK,
+            Command::ReceiveAudio(_) => command_name::RECEIVE_AUDIO,
+            Command::ReceiveVideo(_) => command_name::RECEIVE_VIDEO,
+            Command::GetStreamLength(_) => command_name::GET_STREAM_LENGTH,
+            Command::CheckBandwidth(_) => command_name::CHECK_BANDWIDTH,
+        }
+    }
+
+    pub fn transaction_id(&self) -> usize {
+        match self {
+            Command::Connect(cmd) => cmd.id,
+            Command::Call(cmd) => cmd.transaction_id,
+            Command::Close(cmd) => cmd.transaction_id,
+            Command::CreateStream(cmd) => cmd.transaction_id,
+            Command::DeleteStream(cmd) => cmd.transaction_id,
+            Command::CloseStream(cmd) => cmd.transaction_id,
+            Command::ReleaseStream(cmd) => cmd.transaction_id,
+            Command::FCPublish(cmd) => cmd.transaction_id,
+            Command::FCUnpublish(cmd) => cmd.transaction_id,
+            Command::FCSubscribe(cmd) => cmd.transaction_id,
+            Command::Publish(cmd) => cmd.transaction_id,
+            Command::Play(cmd) => cmd.transaction_id,
+            Command::Play2(cmd) => cmd.transaction_id,
+            Command::Pause(cmd) => cmd.transaction_id,
+            Command::Seek(cmd) => cmd.transaction_id,
+            Command::ReceiveAudio(cmd) => cmd.transaction_id,
+            Command::ReceiveVideo(cmd) => cmd.transaction_id,
+            Command::GetStreamLength(cmd) => cmd.transaction_id,
+            Command::CheckBandwidth(cmd) => cmd.transaction_id,
+        }
+    }
+}
+
+/// A known command whose arguments could not be read. Only its transaction fails, the
+/// connection goes on.
+#[derive(Debug)]
+pub struct InvalidCommand {
+    pub command_name: String,
+    pub transaction_id: usize,
+    pub error: String,
+}
+
+impl InvalidCommand {
+    // Only the name and the transaction id are read, whatever follows them is what failed
+    pub fn parse(data: &[u8], error: String) -> Result<InvalidCommand, Box<dyn std::error::Error>> {
+        let mut reader = Amf0BorrowedReader::new(data);
+        let command_name = reader
+            .read_any()?
+            .as_str()
+            .ok_or_else(|| invalid_data("Expected command name"))?
+            .to_owned();
+        // Without a transaction id there is nothing to answer
+        let transaction_id = reader
+            .read_any()
+            .ok()
+            .and_then(|value| value.as_number())
+            .map_or(0, |id| id as usize);
+        Ok(InvalidCommand {
+            command_name,
+            transaction_id,
+            error,
+        })
+    }
+}
+
+/// A remote procedure call, `procedure_name` is sent as the command name.
+#[derive(Debug)]
+pub struct Call {
+    pub procedure_name: String,
+    pub transaction_id: usize,
+    pub command_object: Amf0ValueType,
+    pub arguments: Vec<Amf0ValueType>,
+}
+
+impl Call {
+    pub fn new(procedure_name: String, transaction_id: usize) -> Call {
+        Call {
+            procedure_name,
+            transaction_id,
+            command_object: Amf0ValueType::Null,
+            arguments: Vec::new(),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Call, Box<dyn std::error::Error>> {
+        let mut decoded_msg = decode(data)?.into_iter();
+        let procedure_name = match decoded_msg.next() {
+            Some(Amf0ValueType::UTF8String(name)) => name,
+            _ => return Err(invalid_data("Expected procedure name")),
+        };
+        let transaction_id = match decoded_msg.next() {
+            Some(Amf0ValueType::Number(transaction_id)) => transaction_id as usize,
+            _ => return Err(invalid_data("Expected transaction id")),
+        };
+        let command_object = decoded_msg.next().unwrap_or(Amf0ValueType::Null);
+
+        Ok(Call {
+            procedure_name,
+            transaction_id,
+            command_object,
+            arguments: decoded_msg.collect(),
+        })
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(&self.procedure_name, self.transaction_id);
+        values.push(self.command_object.clone());
+        values.extend(self.arguments.iter().cloned());
+        encode(&values)
+    }
+}
+
+#[derive(Debug)]
+pub struct Close {
+    pub transaction_id: usize,
+}
+
+impl Close {
+    pub fn new() -> Close {
+        Close { transaction_id: 0 }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Close, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        Ok(Close {
+            transaction_id: transaction_id(&decoded_msg)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::CLOSE, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        encode(&values)
+    }
+}
+
+impl Default for Close {
+    fn default() -> Close {
+        Close::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct DeleteStream {
+    pub transaction_id: usize,
+    pub stream_id: u32,
+}
+
+impl DeleteStream {
+    pub fn new(stream_id: u32) -> DeleteStream {
+        DeleteStream {
+            transaction_id: 0,
+            stream_id,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<DeleteStream, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        Ok(DeleteStream {
+            transaction_id: transaction_id(&decoded_msg)?,
+            stream_id: expect_number(&decoded_msg, 3, "Expected stream id")? as u32,
+        })
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::DELETE_STREAM, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        values.push(Amf0ValueType::Number(self.stream_id as f64));
+        encode(&values)
+    }
+}
+
+/// closeStream is sent on the message stream it closes, some clients also append the
+/// stream id as an argument.
+#[derive(Debug)]
+pub struct CloseStream {
+    pub transaction_id: usize,
+    pub stream_id: Option<u32>,
+}
+
+impl CloseStream {
+    pub fn new() -> CloseStream {
+        CloseStream {
+            transaction_id: 0,
+            stream_id: None,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<CloseStream, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        let stream_id = match decoded_msg.get(3) {
+            Some(Amf0ValueType::Number(stream_id)) => Some(*stream_id as u32),
+            _ => None,
+        };
+        Ok(CloseStream {
+            transaction_id: transaction_id(&decoded_msg)?,
+            stream_id,
+        })
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::CLOSE_STREAM, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        if let Some(stream_id) = self.stream_id {
+            values.push(Amf0ValueType::Number(stream_id as f64));
+        }
+        encode(&values)
+    }
+}
+
+impl Default for CloseStream {
+    fn default() -> CloseStream {
+        CloseStream::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct FCUnpublish {
+    pub transaction_id: usize,
+    pub stream_key: String,
+}
+
+impl FCUnpublish {
+    pub fn new(transaction_id: usize, stream_key: String) -> FCUnpublish {
+        FCUnpublish {
+            transaction_id,
+            stream_key,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<FCUnpublish, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        Ok(FCUnpublish::new(
+            transaction_id(&decoded_msg)?,
+            expect_string(&decoded_msg, 3, "Expected stream key")?,
+        ))
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        stream_name_command(
+            command_name::FC_UNPUBLISH,
+            self.transaction_id,
+            &self.stream_key,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct FCSubscribe {
+    pub transaction_id: usize,
+    pub stream_name: String,
+}
+
+impl FCSubscribe {
+    pub fn new(transaction_id: usize, stream_name: String) -> FCSubscribe {
+        FCSubscribe {
+            transaction_id,
+            stream_name,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<FCSubscribe, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        Ok(FCSubscribe::new(
+            transaction_id(&decoded_msg)?,
+            expect_string(&decoded_msg, 3, "Expected stream name")?,
+        ))
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        stream_name_command(
+            command_name::FC_SUBSCRIBE,
+            self.transaction_id,
+            &self.stream_name,
+        )
+    }
+}
+
+/// play2 switches a playing stream, the options mirror NetStreamPlayOptions.
+#[derive(Debug)]
+pub struct Play2 {
+    pub transaction_id: usize,
+    pub stream_name: String,
+    pub old_stream_name: Option<String>,
+    pub start: f64,
+    pub len: f64,
+    pub offset: Option<f64>,
+    pub transition: String,
+}
+
+impl Play2 {
+    pub fn new(stream_name: String) -> Play2 {
+        Play2 {
+            transaction_id: 0,
+            stream_name,
+            old_stream_name: None,
+            start: -2.0,
+            len: -1.0,
+            offset: None,
+            transition: "switch".to_owned(),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Play2, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        let options = match decoded_msg.get(3) {
+            Some(Amf0ValueType::Object(options)) | Some(Amf0ValueType::EcmaArray(options)) => {
+                options
+            }
+            _ => return Err(invalid_data("Expected play options")),
+        };
+
+        let mut play2 = Play2::new(String::new());
+        play2.transaction_id = transaction_id(&decoded_msg)?;
+        for (key, value) in options {
+            match (key.as_str(), value) {
+                ("streamName", Amf0ValueType::UTF8String(s)) => play2.stream_name = s.clone(),
+                ("oldStreamName", Amf0ValueType::UTF8String(s)) => {
+                    play2.old_stream_name = Some(s.clone())
+                }
+                ("start", Amf0ValueType::Number(n)) => play2.start = *n,
+                ("len", Amf0ValueType::Number(n)) => play2.len = *n,
+                ("offset", Amf0ValueType::Number(n)) => play2.offset = Some(*n),
+                ("transition", Amf0ValueType::UTF8String(s)) => play2.transition = s.clone(),
+                _ => {}
+            }
+        }
+
+        Ok(play2)
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut options = IndexMap::new();
+        options.insert(
+            "streamName".to_owned(),
+            Amf0ValueType::UTF8String(self.stream_name.clone()),
+        );
+        if let Some(old_stream_name) = &self.old_stream_name {
+            options.insert(
+                "oldStreamName".to_owned(),
+                Amf0ValueType::UTF8String(old_stream_name.clone()),
+            );
+        }
+        options.insert("start".to_owned(), Amf0ValueType::Number(self.start));
+        options.insert("len".to_owned(), Amf0ValueType::Number(self.len));
+        if let Some(offset) = self.offset {
+            options.insert("offset".to_owned(), Amf0ValueType::Number(offset));
+        }
+        options.insert(
+            "transition".to_owned(),
+            Amf0ValueType::UTF8String(self.transition.clone()),
+        );
+
+        let mut values = command_header(command_name::PLAY2, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        values.push(Amf0ValueType::Object(options));
+        encode(&values)
+    }
+}
+
+#[derive(Debug)]
+pub struct Seek {
+    pub transaction_id: usize,
+    pub milliseconds: f64,
+}
+
+impl Seek {
+    pub fn new(milliseconds: f64) -> Seek {
+        Seek {
+            transaction_id: 0,
+            milliseconds,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Seek, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        Ok(Seek {
+            transaction_id: transaction_id(&decoded_msg)?,
+            milliseconds: expect_number(&decoded_msg, 3, "Expected seek position")?,
+        })
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::SEEK, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        values.push(Amf0ValueType::Number(self.milliseconds));
+        encode(&values)
+    }
+}
+
+#[derive(Debug)]
+pub struct ReceiveAudio {
+    pub transaction_id: usize,
+    pub receive: bool,
+}
+
+impl ReceiveAudio {
+    pub fn new(receive: bool) -> ReceiveAudio {
+        ReceiveAudio {
+            transaction_id: 0,
+            receive,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<ReceiveAudio, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        Ok(ReceiveAudio {
+            transaction_id: transaction_id(&decoded_msg)?,
+            receive: expect_bool(&decoded_msg, 3, "Expected receive flag")?,
+        })
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::RECEIVE_AUDIO, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        values.push(Amf0ValueType::Boolean(self.receive));
+        encode(&values)
+    }
+}
+
+#[derive(Debug)]
+pub struct ReceiveVideo {
+    pub transaction_id: usize,
+    pub receive: bool,
+}
+
+impl ReceiveVideo {
+    pub fn new(receive: bool) -> ReceiveVideo {
+        ReceiveVideo {
+            transaction_id: 0,
+            receive,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<ReceiveVideo, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        Ok(ReceiveVideo {
+            transaction_id: transaction_id(&decoded_msg)?,
+            receive: expect_bool(&decoded_msg, 3, "Expected receive flag")?,
+        })
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::RECEIVE_VIDEO, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        values.push(Amf0ValueType::Boolean(self.receive));
+        encode(&values)
+    }
+}
+
+#[derive(Debug)]
+pub struct GetStreamLength {
+    pub transaction_id: usize,
+    pub stream_name: String,
+}
+
+impl GetStreamLength {
+    pub fn new(transaction_id: usize, stream_name: String) -> GetStreamLength {
+        GetStreamLength {
+            transaction_id,
+            stream_name,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<GetStreamLength, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        Ok(GetStreamLength::new(
+            transaction_id(&decoded_msg)?,
+            expect_string(&decoded_msg, 3, "Expected stream name")?,
+        ))
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        stream_name_command(
+            command_name::GET_STREAM_LENGTH,
+            self.transaction_id,
+            &self.stream_name,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct CheckBandwidth {
+    pub transaction_id: usize,
+}
+
+impl CheckBandwidth {
+    pub fn new(transaction_id: usize) -> CheckBandwidth {
+        CheckBandwidth { transaction_id }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<CheckBandwidth, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+        Ok(CheckBandwidth::new(transaction_id(&decoded_msg)?))
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::CHECK_BANDWIDTH, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        encode(&values)
+    }
+}
+
+// Helpers shared by the command structs in this file and in message.rs.
+
+// Read in place, the values are copied out once instead of the whole message first
+pub(crate) fn decode(data: &[u8]) -> Result<Vec<Amf0ValueType>, Box<dyn std::error::Error>> {
+    let values = Amf0BorrowedReader::new(data).read_all()?;
+    Ok(values
+        .iter()
+        .map(Amf0Value::to_owned_value)
+        .collect::<Result<_, _>>()?)
+}
+
+pub(crate) fn encode(values: &[Amf0ValueType]) -> Result<BytesMut, Amf0WriteError> {
+    let mut writer = Amf0Writer::new(BytesWriter::new());
+    for value in values {
+        writer.write_any(value)?;
+    }
+    Ok(writer.extract_current_bytes())
+}
+
+pub(crate) fn command_header(command_name: &str, transaction_id: usize) -> Vec<Amf0ValueType> {
+    vec![
+        Amf0ValueType::UTF8String(command_name.to_owned()),
+        Amf0ValueType::Number(transaction_id as f64),
+    ]
+}
+
+pub(crate) fn stream_name_command(
+    command_name: &str,
+    transaction_id: usize,
+    stream_name: &str,
+) -> Result<BytesMut, Amf0WriteError> {
+    let mut values = command_header(command_name, transaction_id);
+    values.push(Amf0ValueType::Null);
+    values.push(Amf0ValueType::UTF8String(stream_name.to_owned()));
+    encode(&values)
+}
+
+pub(crate) fn invalid_data(msg: &str) -> Box<dyn std::error::Error> {
+    Box::new(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+pub(crate) fn transaction_id(
+    decoded_msg: &[Amf0ValueType],
+) -> Result<usize, Box<dyn std::error::Error>> {
+    Ok(expect_number(decoded_msg, 1, "Expected transaction id")? as usize)
+}
+
+pub(crate) fn expect_number(
+    decoded_msg: &[Amf0ValueType],
+    idx: usize,
+    msg: &str,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    match decoded_msg.get(idx) {
+        Some(Amf0ValueType::Number(n)) => Ok(*n),
+        _ => Err(invalid_data(msg)),
+    }
+}
+
+pub(crate) fn expect_bool(
+    decoded_msg: &[Amf0ValueType],
+    idx: usize,
+    msg: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    match decoded_msg.get(idx) {
+        Some(Amf0ValueType::Boolean(b)) => Ok(*b),
+        _ => Err(invalid_data(msg)),
+    }
+}
+
+pub(crate) fn expect_string(
+    decoded_msg: &[Amf0ValueType],
+    idx: usize,
+    msg: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match decoded_msg.get(idx) {
+        Some(Amf0ValueType::UTF8String(s)) | Some(Amf0ValueType::LongUTF8String(s)) => {
+            Ok(s.clone())
+        }
+        _ => Err(invalid_data(msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connection::message::message::ConnectObject;
+
+    fn round_trip(command: Command) -> Command {
+        let data = command.serialize().unwrap();
+        Command::parse(&data).unwrap()
+    }
+
+    #[test]
+    fn test_parse_obs_commands() {
+        // releaseStream, FCPublish and createStream as sent by OBS, without chunk headers
+        let release_stream: &[u8] = &[
+            2, 0, 13, 114, 101, 108, 101, 97, 115, 101, 83, 116, 114, 101, 97, 109, 0, 64, 0, 0, 0,
+            0, 0, 0, 0, 5, 2, 0, 9, 115, 116, 114, 101, 97, 109, 107, 101, 121,
+        ];
+        match Command::parse(release_stream).unwrap() {
+            Command::ReleaseStream(msg) => {
+                assert_eq!(msg.transaction_id, 2);
+                assert_eq!(msg.stream_key, "streamkey");
+            }
+            other => panic!("Expected releaseStream, got {:?}", other),
+        }
+
+        let create_stream: &[u8] = &[
+            2, 0, 12, 99, 114, 101, 97, 116, 101, 83, 116, 114, 101, 97, 109, 0, 64, 16, 0, 0, 0,
+            0, 0, 0, 5,
+        ];
+        let command = Command::parse(create_stream).unwrap();
+        assert_eq!(command.command_name(), "createStream");
+        assert_eq!(command.transaction_id(), 4);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut connect_object = ConnectObject::new("live".to_owned());
+        connect_object.tc_url = "rtmp://localhost/live".to_owned();
+        connect_object
+            .extra
+            .insert("fpad".to_owned(), Amf0ValueType::Boolean(false));
+
+        let commands = vec![
+            Command::Connect(ConnectMessage::new(1, connect_object)),
+            Command::Call(Call::new("getServerTime".to_owned(), 7)),
+            Command::Close(Close::new()),
+            Command::CreateStream(CreateStream::new(2)),
+            Command::DeleteStream(DeleteStream::new(1)),
+            Command::CloseStream(CloseStream::new()),
+            Command::ReleaseStream(ReleaseStream::new(
+                "releaseStream".to_owned(),
+                3,
+                Amf0ValueType::Null,
+                "key".to_owned(),
+            )),
+            Command::FCPublish(FCPublish::new(
+                "FCPublish".to_owned(),
+                4,
+                Amf0ValueType::Null,
+                "key".to_owned(),
+            )),
+            Command::FCUnpublish(FCUnpublish::new(5, "key".to_owned())),
+            Command::FCSubscribe(FCSubscribe::new(6, "key".to_owned())),
+            Command::Publish(Publish::new(
+                "publish".to_owned(),
+                5,
+                Amf0ValueType::Null,
+                "key".to_owned(),
+                "live".to_owned(),
+            )),
+            Command::Play(PlayMessage::new("key".to_owned())),
+            Command::Play2(Play2::new("key_720p".to_owned())),
+            Command::Pause(PauseMessage::new(true, 1500.0)),
+            Command::Seek(Seek::new(3000.0)),
+            Command::ReceiveAudio(ReceiveAudio::new(false)),
+            Command::ReceiveVideo(ReceiveVideo::new(true)),
+            Command::GetStreamLength(GetStreamLength::new(8, "vod".to_owned())),
+            Command::CheckBandwidth(CheckBandwidth::new(9)),
+        ];
+
+        for command in commands {
+            let expected = format!("{:?}", command);
+            let parsed = round_trip(command);
+            assert_eq!(format!("{:?}", parsed), expected);
+        }
+    }
+
+    #[test]
+    fn test_unknown_command_is_call() {
+        let call = Call {
+            procedure_name: "onFCPublishCustom".to_owned(),
+            transaction_id: 11,
+            command_object: Amf0ValueType::Null,
+            arguments: vec![Amf0ValueType::Number(1.0)],
+        };
+        match round_trip(Command::Call(call)) {
+            Command::Call(call) => {
+                assert_eq!(call.procedure_name, "onFCPublishCustom");
+                assert_eq!(call.transaction_id, 11);
+                assert_eq!(call.arguments, vec![Amf0ValueType::Number(1.0)]);
+            }
+            other => panic!("Expected call, got {:?}", other),
+        }
+    }
+}
@@ -11,40 +11,51 @@
 */
 
 use bytes::BytesMut;
-use bytesio::bytes_reader::BytesReader;
 use indexmap::IndexMap;
 
 use crate::server::connection::message::amf0::{
-    amf0_reader::Amf0Reader, amf0_writer::Amf0Writer, define::Amf0ValueType,
+    amf0_borrowed::{Amf0BorrowedReader, Amf0Object, Amf0Value},
+    amf0_json::properties_to_json,
+    amf0_writer::Amf0Writer,
+    define::Amf0ValueType,
 };
 
-use super::amf0::errors::{Amf0WriteError};
-use log::{error};
+use super::amf0::errors::Amf0WriteError;
+use super::command::{
+    command_header, command_name, decode, encode, expect_bool, expect_number, expect_string,
+    invalid_data, stream_name_command, transaction_id, Command, InvalidCommand,
+};
+use super::status::{StatusCode, StatusLevel};
+use crate::media::{
+    enhanced::EnhancedCapabilities,
+    errors::MediaError,
+    flv::{AudioTag, VideoTag},
+};
+use log::{error, info};
 
 #[derive(Debug)]
 pub enum RtmpMessage {
     BasicCommand(BasicCommand),
-    Connect(ConnectMessage),
-    CreateStream(CreateStream),
-    _Play(PlayMessage),
-    _Pause(PauseMessage),
+    Command(Command),
+    InvalidCommand(InvalidCommand),
     ResultObject(ResultObject),
     SetChunkSize(SetChunkSizeMessage),
     Acknowledgement(AcknowledgementMessage),
-    ReleaseStream(ReleaseStream),
-    FCPublish(FCPublish),
-    Publish(Publish),
     Event(Event),
     OnStatus(OnStatus),
     SetDataFrame(SetDataFrame),
     VideoData(VideoData),
     AudioData(AudioData),
+    // Understood but not acted on, carries the message type id
+    Ignored(u8),
     // Add other message types as needed
 }
 
 #[derive(Debug)]
 pub struct AudioData {
     pub stream_id: u32,
+    // From the chunk header, in milliseconds
+    pub timestamp: u32,
     pub data: Vec<u8>,
 }
 
@@ -52,14 +63,26 @@ impl AudioData {
     pub fn new(stream_id: u32, data: Vec<u8>) -> AudioData {
         AudioData {
             stream_id,
+            timestamp: 0,
             data,
         }
     }
+
+    pub fn with_timestamp(mut self, timestamp: u32) -> AudioData {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn tag(&self) -> Result<AudioTag<'_>, MediaError> {
+        AudioTag::parse(&self.data)
+    }
 }
 
 #[derive(Debug)]
 pub struct VideoData {
     pub stream_id: u32,
+    // From the chunk header, in milliseconds
+    pub timestamp: u32,
     pub data: Vec<u8>,
 }
 
@@ -67,9 +90,19 @@ impl VideoData {
     pub fn new(stream_id: u32, data: Vec<u8>) -> VideoData {
         VideoData {
             stream_id,
+            timestamp: 0,
             data,
         }
     }
+
+    pub fn with_timestamp(mut self, timestamp: u32) -> VideoData {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn tag(&self) -> Result<VideoTag<'_>, MediaError> {
+        VideoTag::parse(&self.data)
+    }
 }
 
 #[derive(Debug)]
@@ -126,9 +159,8 @@ impl Publish {
     }
 
     pub fn parse(data: &[u8]) -> Result<Publish, Box<dyn std::error::Error>> {
-        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(data)));
-        let decoded_msg = reader.read_all().unwrap();
-        let command_name = match decoded_msg.get(0) {
+        let decoded_msg = decode(data)?;
+        let command_name = match decoded_msg.first() {
             Some(Amf0ValueType::UTF8String(command_name)) => command_name.to_owned(),
             _ => {
                 return Err(Box::new(std::io::Error::new(
@@ -164,8 +196,10 @@ impl Publish {
                 )))
             }
         };
+        // The publishing type is optional and defaults to live
         let stream_type = match decoded_msg.get(4) {
             Some(Amf0ValueType::UTF8String(stream_type)) => stream_type.to_owned(),
+            None => "live".to_owned(),
             _ => {
                 return Err(Box::new(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
@@ -181,6 +215,14 @@ impl Publish {
             stream_type,
         ))
     }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::PUBLISH, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        values.push(Amf0ValueType::UTF8String(self.stream_key.clone()));
+        values.push(Amf0ValueType::UTF8String(self.stream_type.clone()));
+        encode(&values)
+    }
 }
 
 #[derive(Debug)]
@@ -209,9 +251,8 @@ impl FCPublish {
     }
 
     pub fn parse(data: &[u8]) -> Result<FCPublish, Box<dyn std::error::Error>> {
-        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(data)));
-        let decoded_msg = reader.read_all().unwrap();
-        let command_name = match decoded_msg.get(0) {
+        let decoded_msg = decode(data)?;
+        let command_name = match decoded_msg.first() {
             Some(Amf0ValueType::UTF8String(command_name)) => command_name.to_owned(),
             _ => {
                 return Err(Box::new(std::io::Error::new(
@@ -254,6 +295,14 @@ impl FCPublish {
             stream_key,
         ))
     }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        stream_name_command(
+            command_name::FC_PUBLISH,
+            self.transaction_id,
+            &self.stream_key,
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -280,9 +329,8 @@ impl ReleaseStream {
     }
 
     pub fn parse(data: &[u8]) -> Result<ReleaseStream, Box<dyn std::error::Error>> {
-        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(data)));
-        let decoded_msg = reader.read_all()?;
-        let command_name = match decoded_msg.get(0) {
+        let decoded_msg = decode(data)?;
+        let command_name = match decoded_msg.first() {
             Some(Amf0ValueType::UTF8String(command_name)) => command_name.to_owned(),
             _ => {
                 return Err(Box::new(std::io::Error::new(
@@ -327,57 +375,177 @@ impl ReleaseStream {
             stream_name,
         ))
     }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        stream_name_command(
+            command_name::RELEASE_STREAM,
+            self.transaction_id,
+            &self.stream_key,
+        )
+    }
 }
 
-#[derive(Debug)]
+/// The info object sent with onStatus, _result and _error messages.
+#[derive(Debug, Clone)]
 pub struct OnStatusObject {
-    pub level: String,
-    pub code: String,
+    pub level: StatusLevel,
+    pub code: StatusCode,
     pub description: String,
+    pub details: Option<String>,
+    pub client_id: Option<String>,
+    // Written as ex.redirect, tells a rejected client where to go instead
+    pub redirect: Option<String>,
+    // Any other properties, e.g. objectEncoding on NetConnection.Connect.Success
+    pub extra: IndexMap<String, Amf0ValueType>,
 }
 
 impl OnStatusObject {
-    pub fn default() -> OnStatusObject {
+    // Starts out with the level the code is documented with
+    pub fn new(code: StatusCode) -> OnStatusObject {
         OnStatusObject {
-            level: "status".to_owned(),
-            code: "NetStream.Publish.Start".to_owned(),
-            description: "[/] Publishing stream . . .".to_owned(),
+            level: code.level(),
+            code,
+            description: String::new(),
+            details: None,
+            client_id: None,
+            redirect: None,
+            extra: IndexMap::new(),
         }
     }
 
+    pub fn level(mut self, level: StatusLevel) -> OnStatusObject {
+        self.level = level;
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> OnStatusObject {
+        self.description = description.into();
+        self
+    }
+
+    pub fn details(mut self, details: impl Into<String>) -> OnStatusObject {
+        self.details = Some(details.into());
+        self
+    }
+
+    pub fn client_id(mut self, client_id: impl Into<String>) -> OnStatusObject {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    pub fn redirect(mut self, redirect: Option<String>) -> OnStatusObject {
+        self.redirect = redirect;
+        self
+    }
+
+    pub fn property(mut self, key: &str, value: Amf0ValueType) -> OnStatusObject {
+        self.extra.insert(key.to_owned(), value);
+        self
+    }
+
     pub fn parse(&self) -> IndexMap<String, Amf0ValueType> {
-        let _writer = Amf0Writer::new(bytesio::bytes_writer::BytesWriter::new());
         let mut obj_map = IndexMap::new();
         obj_map.insert(
             "level".to_owned(),
-            Amf0ValueType::UTF8String(self.level.to_owned()),
+            Amf0ValueType::UTF8String(self.level.as_str().to_owned()),
         );
         obj_map.insert(
             "code".to_owned(),
-            Amf0ValueType::UTF8String(self.code.to_owned()),
+            Amf0ValueType::UTF8String(self.code.as_str().to_owned()),
         );
         obj_map.insert(
             "description".to_owned(),
             Amf0ValueType::UTF8String(self.description.to_owned()),
         );
+        if let Some(details) = &self.details {
+            obj_map.insert(
+                "details".to_owned(),
+                Amf0ValueType::UTF8String(details.to_owned()),
+            );
+        }
+        if let Some(client_id) = &self.client_id {
+            obj_map.insert(
+                "clientid".to_owned(),
+                Amf0ValueType::UTF8String(client_id.to_owned()),
+            );
+        }
+        if let Some(redirect) = &self.redirect {
+            let mut ex = IndexMap::new();
+            ex.insert("code".to_owned(), Amf0ValueType::Number(302.0));
+            ex.insert(
+                "redirect".to_owned(),
+                Amf0ValueType::UTF8String(redirect.to_owned()),
+            );
+            obj_map.insert("ex".to_owned(), Amf0ValueType::Object(ex));
+        }
+        for (key, value) in &self.extra {
+            obj_map.insert(key.to_owned(), value.clone());
+        }
         obj_map
     }
 }
 
+/// An `_error` reply to a command, carrying an info object with level "error".
+#[derive(Debug)]
+pub struct ErrorResponse {
+    pub transaction_id: usize,
+    pub info: OnStatusObject,
+}
+
+impl ErrorResponse {
+    pub fn new(transaction_id: usize, code: StatusCode, description: &str) -> ErrorResponse {
+        ErrorResponse {
+            transaction_id,
+            info: OnStatusObject::new(code)
+                .level(StatusLevel::Error)
+                .description(description),
+        }
+    }
+
+    pub fn connect_rejected(transaction_id: usize, description: &str) -> ErrorResponse {
+        ErrorResponse::new(transaction_id, StatusCode::ConnectRejected, description)
+    }
+
+    pub fn call_failed(transaction_id: usize, description: &str) -> ErrorResponse {
+        ErrorResponse::new(transaction_id, StatusCode::CallFailed, description)
+    }
+
+    pub fn with_redirect(mut self, redirect: Option<String>) -> ErrorResponse {
+        self.info.redirect = redirect;
+        self
+    }
+
+    pub fn parse(&self) -> Result<BytesMut, Amf0WriteError> {
+        encode(&[
+            Amf0ValueType::UTF8String(command_name::ERROR.to_owned()),
+            Amf0ValueType::Number(self.transaction_id as f64),
+            Amf0ValueType::Null,
+            Amf0ValueType::Object(self.info.parse()),
+        ])
+    }
+}
+
 #[derive(Debug)]
 pub struct OnStatus {
     command_name: String,
     transaction_id: usize,
+    info: OnStatusObject,
 }
 
 impl OnStatus {
-    pub fn new(transaction_id: usize) -> OnStatus {
+    // onStatus is a notification, so the transaction id is always 0
+    pub fn new(info: OnStatusObject) -> OnStatus {
         OnStatus {
-            command_name: "onStatus".to_owned(),
-            transaction_id,
+            command_name: command_name::ON_STATUS.to_owned(),
+            transaction_id: 0,
+            info,
         }
     }
 
+    pub fn info(&self) -> &OnStatusObject {
+        &self.info
+    }
+
     pub fn parse(&self) -> Result<BytesMut, Box<dyn std::error::Error>> {
         let mut writer = Amf0Writer::new(bytesio::bytes_writer::BytesWriter::new());
         writer.write_string(&self.command_name)?;
@@ -385,8 +553,7 @@ impl OnStatus {
         writer.write_number(&tmp)?;
         writer.write_null()?;
 
-        let on_status_object = OnStatusObject::default();
-        let on_status_data = on_status_object.parse();
+        let on_status_data = self.info.parse();
 
         writer.write_object(&on_status_data)?;
         let data = writer.extract_current_bytes();
@@ -396,9 +563,11 @@ impl OnStatus {
 
 #[derive(Debug)]
 pub struct SetDataFrame {
-    data_name: String,
-    metadata: String,
-    data: SetDataFrameData,
+    pub data_name: String,
+    pub metadata: String,
+    pub data: SetDataFrameData,
+    // Every property as sent, including the ones SetDataFrameData does not know
+    pub properties: IndexMap<String, Amf0ValueType>,
 }
 
 impl SetDataFrame {
@@ -407,45 +576,55 @@ impl SetDataFrame {
             data_name,
             metadata,
             data,
+            properties: IndexMap::new(),
         }
     }
 
     pub fn parse(data: &[u8]) -> Result<SetDataFrame, Box<dyn std::error::Error>> {
-        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(data)));
-        let decoded_msg = reader.read_all()?;
-        let data_name = match decoded_msg.get(0) {
-            Some(Amf0ValueType::UTF8String(data_name)) => data_name.to_owned(),
-            _ => {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Expected data name",
-                )))
-            }
-        };
-        let metadata = match decoded_msg.get(1) {
-            Some(Amf0ValueType::UTF8String(metadata)) => metadata.to_owned(),
-            _ => {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Expected metadata",
-                )))
-            }
-        };
-        let data_obj = match decoded_msg.get(2) {
-            Some(Amf0ValueType::Object(data_obj)) => data_obj.to_owned(),
-            _ => {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Expected data",
-                )))
-            }
-        };
+        // Sent with every publish and stored in every FLV, so the properties are read in
+        // place and only copied once, for SetDataFrame::properties
+        let mut reader = Amf0BorrowedReader::new(data);
+        let data_name = reader.read_any()?;
+        let data_name = data_name
+            .as_str()
+            .ok_or_else(|| invalid_data("Expected data name"))?;
+        let metadata = reader.read_any()?;
+        let metadata = metadata
+            .as_str()
+            .ok_or_else(|| invalid_data("Expected metadata"))?;
+        let data_obj = reader.read_any()?;
+        let data_obj = data_obj
+            .as_object()
+            .ok_or_else(|| invalid_data("Expected data"))?;
+
+        let mut set_data_frame = SetDataFrame::new(
+            data_name.to_owned(),
+            metadata.to_owned(),
+            SetDataFrameData::parse_object(data_obj)?,
+        );
+        set_data_frame.properties = data_obj.to_owned_properties()?;
+        Ok(set_data_frame)
+    }
 
-        Ok(SetDataFrame::new(
-            data_name,
-            metadata,
-            SetDataFrameData::parse(data_obj).unwrap(),
-        ))
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut writer = Amf0Writer::new(bytesio::bytes_writer::BytesWriter::new());
+        writer.write_string(&self.data_name)?;
+        writer.write_string(&self.metadata)?;
+        writer.write_ecma_array(&self.properties)?;
+        Ok(writer.extract_current_bytes())
+    }
+
+    // Every property, for the logs and the API
+    pub fn to_json(&self) -> serde_json::Value {
+        properties_to_json(&self.properties)
+    }
+
+    // The metadata as players and FLV files expect it, without the @setDataFrame
+    pub fn on_metadata(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut writer = Amf0Writer::new(bytesio::bytes_writer::BytesWriter::new());
+        writer.write_string(&self.metadata)?;
+        writer.write_ecma_array(&self.properties)?;
+        Ok(writer.extract_current_bytes())
     }
 }
 
@@ -473,8 +652,8 @@ pub struct SetDataFrameData {
     pub encoder: String,
 }
 
-impl SetDataFrameData {
-    pub fn default() -> SetDataFrameData {
+impl Default for SetDataFrameData {
+    fn default() -> SetDataFrameData {
         SetDataFrameData {
             duration: 0.0,
             file_size: 0.0,
@@ -498,232 +677,81 @@ impl SetDataFrameData {
             encoder: "".to_owned(),
         }
     }
+}
+
+impl SetDataFrameData {
     pub fn parse(
         data: IndexMap<String, Amf0ValueType>,
     ) -> Result<SetDataFrameData, Box<dyn std::error::Error>> {
         let mut set_data_frame_data = SetDataFrameData::default();
+        for (key, value) in &data {
+            // Only scalars are looked at, anything else is as wrong as a missing value
+            let value = match value {
+                Amf0ValueType::Number(number) => Amf0Value::Number(*number),
+                Amf0ValueType::Boolean(boolean) => Amf0Value::Boolean(*boolean),
+                Amf0ValueType::UTF8String(string) => Amf0Value::UTF8String(string),
+                Amf0ValueType::LongUTF8String(string) => Amf0Value::LongUTF8String(string),
+                _ => Amf0Value::Null,
+            };
+            set_data_frame_data.set(key, &value)?;
+        }
+        Ok(set_data_frame_data)
+    }
 
-        for (key, value) in data {
-            match key.as_str() {
-                "duration" => match value {
-                    Amf0ValueType::Number(duration) => set_data_frame_data.duration = duration,
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "fileSize" => match value {
-                    Amf0ValueType::Number(file_size) => set_data_frame_data.file_size = file_size,
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "width" => match value {
-                    Amf0ValueType::Number(width) => set_data_frame_data.width = width,
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "height" => match value {
-                    Amf0ValueType::Number(height) => set_data_frame_data.height = height,
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "videocodecid" => match value {
-                    Amf0ValueType::Number(video_codec_id) => {
-                        set_data_frame_data.video_codec_id = video_codec_id
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "videodatarate" => match value {
-                    Amf0ValueType::Number(video_data_rate) => {
-                        set_data_frame_data.video_data_rate = video_data_rate
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "framerate" => match value {
-                    Amf0ValueType::Number(frame_rate) => {
-                        set_data_frame_data.frame_rate = frame_rate
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "audiocodecid" => match value {
-                    Amf0ValueType::Number(audio_codec_id) => {
-                        set_data_frame_data.audio_codec_id = audio_codec_id
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "audiodatarate" => match value {
-                    Amf0ValueType::Number(audio_data_rate) => {
-                        set_data_frame_data.audio_data_rate = audio_data_rate
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "audiosamplerate" => match value {
-                    Amf0ValueType::Number(audio_sample_rate) => {
-                        set_data_frame_data.audio_sample_rate = audio_sample_rate
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "audiosamplesize" => match value {
-                    Amf0ValueType::Number(audio_sample_size) => {
-                        set_data_frame_data.audio_sample_size = audio_sample_size
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "audiochannels" => match value {
-                    Amf0ValueType::Number(audio_channels) => {
-                        set_data_frame_data.audio_channels = audio_channels
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected number",
-                        )))
-                    }
-                },
-                "stereo" => match value {
-                    Amf0ValueType::Boolean(stereo) => set_data_frame_data.stereo = stereo,
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected boolean",
-                        )))
-                    }
-                },
-                "2.1" => match value {
-                    Amf0ValueType::Boolean(two_point_one) => {
-                        set_data_frame_data.two_point_one = two_point_one
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected boolean",
-                        )))
-                    }
-                },
-                "3.1" => match value {
-                    Amf0ValueType::Boolean(three_point_one) => {
-                        set_data_frame_data.three_point_one = three_point_one
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected boolean",
-                        )))
-                    }
-                },
-                "4.0" => match value {
-                    Amf0ValueType::Boolean(four_point_zero) => {
-                        set_data_frame_data.four_point_zero = four_point_zero
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected boolean",
-                        )))
-                    }
-                },
-                "4.1" => match value {
-                    Amf0ValueType::Boolean(four_point_one) => {
-                        set_data_frame_data.four_point_one = four_point_one
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected boolean",
-                        )))
-                    }
-                },
-                "5.1" => match value {
-                    Amf0ValueType::Boolean(five_point_one) => {
-                        set_data_frame_data.five_point_one = five_point_one
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected boolean",
-                        )))
-                    }
-                },
-                "7.1" => match value {
-                    Amf0ValueType::Boolean(seven_point_one) => {
-                        set_data_frame_data.seven_point_one = seven_point_one
-                    }
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected boolean",
-                        )))
-                    }
-                },
-                "encoder" => match value {
-                    Amf0ValueType::UTF8String(encoder) => set_data_frame_data.encoder = encoder,
-                    _ => {
-                        return Err(Box::new(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Expected string",
-                        )))
-                    }
-                },
-                _ => {
-                    error!("Unexpected key {:?}", key);
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Unexpected key",
-                    )));
-                }
-            }
+    /// Reads the properties where they are, without decoding the object first.
+    pub fn parse_object(
+        data: &Amf0Object<'_>,
+    ) -> Result<SetDataFrameData, Box<dyn std::error::Error>> {
+        let mut set_data_frame_data = SetDataFrameData::default();
+        for property in data {
+            let (key, value) = property?;
+            set_data_frame_data.set(key, &value)?;
         }
         Ok(set_data_frame_data)
     }
+
+    fn set(&mut self, key: &str, value: &Amf0Value<'_>) -> Result<(), Box<dyn std::error::Error>> {
+        let number = || {
+            value
+                .as_number()
+                .ok_or_else(|| invalid_data("Expected number"))
+        };
+        let boolean = || {
+            value
+                .as_bool()
+                .ok_or_else(|| invalid_data("Expected boolean"))
+        };
+        match key {
+            "duration" => self.duration = number()?,
+            "fileSize" => self.file_size = number()?,
+            "width" => self.width = number()?,
+            "height" => self.height = number()?,
+            "videocodecid" => self.video_codec_id = number()?,
+            "videodatarate" => self.video_data_rate = number()?,
+            "framerate" => self.frame_rate = number()?,
+            "audiocodecid" => self.audio_codec_id = number()?,
+            "audiodatarate" => self.audio_data_rate = number()?,
+            "audiosamplerate" => self.audio_sample_rate = number()?,
+            "audiosamplesize" => self.audio_sample_size = number()?,
+            "audiochannels" => self.audio_channels = number()?,
+            "stereo" => self.stereo = boolean()?,
+            "2.1" => self.two_point_one = boolean()?,
+            "3.1" => self.three_point_one = boolean()?,
+            "4.0" => self.four_point_zero = boolean()?,
+            "4.1" => self.four_point_one = boolean()?,
+            "5.1" => self.five_point_one = boolean()?,
+            "7.1" => self.seven_point_one = boolean()?,
+            "encoder" => {
+                let encoder = value
+                    .as_str()
+                    .ok_or_else(|| invalid_data("Expected string"))?;
+                self.encoder = encoder.to_owned();
+            }
+            // Encoders add their own keys, they stay available in SetDataFrame::properties
+            _ => info!("Ignoring metadata key {:?}", key),
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -733,9 +761,7 @@ pub struct AcknowledgementMessage {
 
 impl AcknowledgementMessage {
     pub fn new(sequence_number: u32) -> AcknowledgementMessage {
-        AcknowledgementMessage {
-            sequence_number,
-        }
+        AcknowledgementMessage { sequence_number }
     }
 }
 
@@ -746,9 +772,7 @@ pub struct SetChunkSizeMessage {
 
 impl SetChunkSizeMessage {
     pub fn new(chunk_size: u32) -> SetChunkSizeMessage {
-        SetChunkSizeMessage {
-            chunk_size,
-        }
+        SetChunkSizeMessage { chunk_size }
     }
 }
 
@@ -758,6 +782,8 @@ pub struct ResultObject {
     pub transaction_id: usize,
     pub command_object: Option<CommandObject>,
     pub stream_id: usize,
+    // Sent instead of the stream id, e.g. NetConnection.Connect.Success for connect
+    pub info: Option<OnStatusObject>,
 }
 
 impl ResultObject {
@@ -767,6 +793,7 @@ impl ResultObject {
             transaction_id,
             command_object: None,
             stream_id,
+            info: None,
         }
     }
 
@@ -774,6 +801,10 @@ impl ResultObject {
         self.command_object = Some(command_object);
     }
 
+    pub fn set_info(&mut self, info: OnStatusObject) {
+        self.info = Some(info);
+    }
+
     pub fn parse(&self) -> Result<BytesMut, Amf0WriteError> {
         let mut writer = Amf0Writer::new(bytesio::bytes_writer::BytesWriter::new());
         writer
@@ -793,6 +824,9 @@ impl ResultObject {
                 "capabilities".to_string(),
                 Amf0ValueType::Number(self.command_object.as_ref().unwrap().capabilities as f64),
             );
+            for (key, value) in &self.command_object.as_ref().unwrap().extra {
+                command_obj_map.insert(key.clone(), value.clone());
+            }
 
             writer
                 .write_any(&Amf0ValueType::Object(command_obj_map))
@@ -800,9 +834,10 @@ impl ResultObject {
         } else {
             writer.write_any(&Amf0ValueType::Null).unwrap();
         }
-        writer
-            .write_any(&Amf0ValueType::Number(self.stream_id as f64))
-            .unwrap();
+        match &self.info {
+            Some(info) => writer.write_any(&Amf0ValueType::Object(info.parse()))?,
+            None => writer.write_any(&Amf0ValueType::Number(self.stream_id as f64))?,
+        }
         let tmp = writer.extract_current_bytes();
         Ok(tmp)
     }
@@ -812,16 +847,23 @@ impl ResultObject {
 pub struct CommandObject {
     fms_ver: String,
     capabilities: usize,
+    // Written after fmsVer and capabilities, e.g. the negotiated Enhanced RTMP codecs
+    extra: IndexMap<String, Amf0ValueType>,
 }
 
 impl CommandObject {
     pub fn new(fms_ver: String, capabilities: usize) -> CommandObject {
-        
         CommandObject {
             fms_ver,
             capabilities,
+            extra: IndexMap::new(),
         }
     }
+
+    pub fn with_properties(mut self, properties: IndexMap<String, Amf0ValueType>) -> CommandObject {
+        self.extra.extend(properties);
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -831,9 +873,23 @@ pub struct ConnectObject {
     pub swf_url: String,
     pub tc_url: String,
     pub stream_type: String,
+    // Every other property of the command object, e.g. fpad, capabilities or audioCodecs
+    pub extra: IndexMap<String, Amf0ValueType>,
 }
 
 impl ConnectObject {
+    // The codecs an Enhanced RTMP client announced, None for legacy clients
+    pub fn enhanced_capabilities(&self) -> Option<EnhancedCapabilities> {
+        EnhancedCapabilities::from_properties(&self.extra)
+    }
+
+    pub fn new(app: String) -> ConnectObject {
+        ConnectObject {
+            app,
+            ..ConnectObject::default()
+        }
+    }
+
     pub fn _new(
         app: String,
         tc_url: String,
@@ -847,6 +903,7 @@ impl ConnectObject {
             swf_url: sw_url,
             tc_url,
             stream_type,
+            extra: IndexMap::new(),
         }
     }
 
@@ -857,7 +914,30 @@ impl ConnectObject {
             swf_url: "".to_string(),
             tc_url: "".to_string(),
             stream_type: "".to_string(),
+            extra: IndexMap::new(),
+        }
+    }
+
+    pub fn to_properties(&self) -> IndexMap<String, Amf0ValueType> {
+        let mut properties = IndexMap::new();
+        properties.insert(
+            "app".to_owned(),
+            Amf0ValueType::UTF8String(self.app.clone()),
+        );
+        for (key, value) in [
+            ("type", &self.stream_type),
+            ("flashVer", &self.flash_ver),
+            ("swfUrl", &self.swf_url),
+            ("tcUrl", &self.tc_url),
+        ] {
+            if !value.is_empty() {
+                properties.insert(key.to_owned(), Amf0ValueType::UTF8String(value.clone()));
+            }
         }
+        for (key, value) in &self.extra {
+            properties.insert(key.clone(), value.clone());
+        }
+        properties
     }
 
     pub fn parse(
@@ -893,7 +973,9 @@ impl ConnectObject {
                         connect_object.stream_type = s;
                     }
                 }
-                _ => {}
+                _ => {
+                    connect_object.extra.insert(key, value);
+                }
             }
         }
 
@@ -908,24 +990,17 @@ pub struct BasicCommand {
 
 impl BasicCommand {
     pub fn new(command_name: String) -> BasicCommand {
-        BasicCommand {
-            command_name,
-        }
+        BasicCommand { command_name }
     }
 
     pub fn parse(data: &[u8]) -> Result<BasicCommand, Box<dyn std::error::Error>> {
-        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(data)));
+        // Only the name is needed to dispatch, so peek at it without decoding the
+        // rest of the message.
+        let mut reader = Amf0BorrowedReader::new(data);
 
-        let decoded_msg = reader.read_all()?;
-
-        let command_name = match decoded_msg.get(0) {
-            Some(Amf0ValueType::UTF8String(s)) => s.clone(),
-            _ => {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Invalid command name",
-                )))
-            }
+        let command_name = match reader.read_any()? {
+            Amf0Value::UTF8String(s) => s.to_owned(),
+            _ => return Err(Box::new(std::io::Error::other("Invalid command name"))),
         };
 
         Ok(BasicCommand::new(command_name))
@@ -940,17 +1015,13 @@ pub struct ConnectMessage {
 
 impl ConnectMessage {
     pub fn new(id: usize, connect_object: ConnectObject) -> ConnectMessage {
-        ConnectMessage {
-            connect_object,
-            id,
-        }
+        ConnectMessage { connect_object, id }
     }
 
     pub fn parse(data: &[u8]) -> Result<ConnectMessage, Box<dyn std::error::Error>> {
-        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(data)));
         let mut connect_message = ConnectMessage::new(0, ConnectObject::default());
 
-        let decoded_msg = reader.read_all()?;
+        let decoded_msg = decode(data)?;
 
         connect_message.id = match decoded_msg.get(1) {
             Some(&Amf0ValueType::Number(n)) => n as usize,
@@ -978,6 +1049,12 @@ impl ConnectMessage {
         connect_message.connect_object = ConnectObject::parse(decoded_obj.clone())?;
         Ok(connect_message)
     }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::CONNECT, self.id);
+        values.push(Amf0ValueType::Object(self.connect_object.to_properties()));
+        encode(&values)
+    }
 }
 
 #[derive(Debug)]
@@ -997,9 +1074,7 @@ impl CreateStream {
     }
 
     pub fn parse(data: &[u8]) -> Result<CreateStream, Box<dyn std::error::Error>> {
-        let mut reader = Amf0Reader::new(BytesReader::new(BytesMut::from(data)));
-
-        let decoded_msg = reader.read_all()?;
+        let decoded_msg = decode(data)?;
 
         let transaction_id = match decoded_msg.get(1) {
             Some(&Amf0ValueType::Number(n)) => n as usize,
@@ -1013,20 +1088,144 @@ impl CreateStream {
 
         Ok(CreateStream::new(transaction_id))
     }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::CREATE_STREAM, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        encode(&values)
+    }
 }
 
 #[derive(Debug)]
 pub struct PlayMessage {
-    // Define the fields for a Play message
-    // For example:
+    pub transaction_id: usize,
     pub stream_name: String,
-    // Add other fields as needed
+    // -2 plays live or recorded, -1 live only, >= 0 recorded from that offset in ms
+    pub start: f64,
+    // -1 plays until the end, 0 a single frame, > 0 that many ms
+    pub duration: f64,
+    pub reset: bool,
+}
+
+impl PlayMessage {
+    pub fn new(stream_name: String) -> PlayMessage {
+        PlayMessage {
+            transaction_id: 0,
+            stream_name,
+            start: -2.0,
+            duration: -1.0,
+            reset: true,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<PlayMessage, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+
+        let mut play = PlayMessage::new(expect_string(&decoded_msg, 3, "Expected stream name")?);
+        play.transaction_id = transaction_id(&decoded_msg)?;
+        if decoded_msg.len() > 4 {
+            play.start = expect_number(&decoded_msg, 4, "Expected start")?;
+        }
+        if decoded_msg.len() > 5 {
+            play.duration = expect_number(&decoded_msg, 5, "Expected duration")?;
+        }
+        if decoded_msg.len() > 6 {
+            play.reset = expect_bool(&decoded_msg, 6, "Expected reset")?;
+        }
+
+        Ok(play)
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::PLAY, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        values.push(Amf0ValueType::UTF8String(self.stream_name.clone()));
+        values.push(Amf0ValueType::Number(self.start));
+        values.push(Amf0ValueType::Number(self.duration));
+        values.push(Amf0ValueType::Boolean(self.reset));
+        encode(&values)
+    }
 }
 
 #[derive(Debug)]
 pub struct PauseMessage {
-    // Define the fields for a Pause message
-    // For example:
+    pub transaction_id: usize,
     pub is_paused: bool,
-    // Add other fields as needed
+    // stream time at which the stream was paused or resumed
+    pub milliseconds: f64,
+}
+
+impl PauseMessage {
+    pub fn new(is_paused: bool, milliseconds: f64) -> PauseMessage {
+        PauseMessage {
+            transaction_id: 0,
+            is_paused,
+            milliseconds,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<PauseMessage, Box<dyn std::error::Error>> {
+        let decoded_msg = decode(data)?;
+
+        let mut pause = PauseMessage::new(
+            expect_bool(&decoded_msg, 3, "Expected pause flag")?,
+            expect_number(&decoded_msg, 4, "Expected pause time").unwrap_or(0.0),
+        );
+        pause.transaction_id = transaction_id(&decoded_msg)?;
+        Ok(pause)
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut, Amf0WriteError> {
+        let mut values = command_header(command_name::PAUSE, self.transaction_id);
+        values.push(Amf0ValueType::Null);
+        values.push(Amf0ValueType::Boolean(self.is_paused));
+        values.push(Amf0ValueType::Number(self.milliseconds));
+        encode(&values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SetDataFrame, SetDataFrameData};
+    use crate::server::connection::message::amf0::{
+        amf0_writer::Amf0Writer, define::Amf0ValueType,
+    };
+    use bytes::BytesMut;
+    use bytesio::bytes_writer::BytesWriter;
+    use indexmap::IndexMap;
+
+    fn set_data_frame(properties: &IndexMap<String, Amf0ValueType>) -> BytesMut {
+        let mut writer = Amf0Writer::new(BytesWriter::new());
+        for value in [
+            Amf0ValueType::UTF8String("@setDataFrame".to_owned()),
+            Amf0ValueType::UTF8String("onMetaData".to_owned()),
+            Amf0ValueType::Object(properties.clone()),
+        ] {
+            writer.write_any(&value).unwrap();
+        }
+        writer.extract_current_bytes()
+    }
+
+    #[test]
+    fn test_parse_set_data_frame() {
+        let mut properties = IndexMap::new();
+        properties.insert("width".to_owned(), Amf0ValueType::Number(1280.0));
+        properties.insert("stereo".to_owned(), Amf0ValueType::Boolean(true));
+        properties.insert(
+            "encoder".to_owned(),
+            Amf0ValueType::UTF8String("obs".to_owned()),
+        );
+
+        let parsed = SetDataFrame::parse(&set_data_frame(&properties)).unwrap();
+        assert_eq!(parsed.data_name, "@setDataFrame");
+        assert_eq!(parsed.metadata, "onMetaData");
+        assert_eq!(parsed.data.width, 1280.0);
+        assert!(parsed.data.stereo);
+        assert_eq!(parsed.data.encoder, "obs");
+
+        // Known keys have to have the right type, also when parsed from owned values
+        properties.insert("height".to_owned(), Amf0ValueType::Boolean(true));
+        assert!(SetDataFrame::parse(&set_data_frame(&properties)).is_err());
+        assert!(SetDataFrameData::parse(properties).is_err());
+    }
 }
@@ -1,2 +1,4 @@
 pub mod amf0;
+pub mod command;
 pub mod message;
+pub mod status;
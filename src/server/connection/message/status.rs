@@ -0,0 +1,156 @@
+// This file defines the status codes sent in the info object of onStatus, _result and
+// _error messages, together with the level each code is normally sent with.
+
+// Path: src/server/connection/message/status.rs
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusLevel {
+    Status,
+    Warning,
+    Error,
+}
+
+impl StatusLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatusLevel::Status => "status",
+            StatusLevel::Warning => "warning",
+            StatusLevel::Error => "error",
+        }
+    }
+
+    pub fn from_name(level: &str) -> Option<StatusLevel> {
+        match level {
+            "status" => Some(StatusLevel::Status),
+            "warning" => Some(StatusLevel::Warning),
+            "error" => Some(StatusLevel::Error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StatusLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Each entry is the variant, the code on the wire and its documented level.
+macro_rules! status_codes {
+    ($($variant:ident => $code:literal, $level:ident;)*) => {
+        /// The documented NetConnection and NetStream status codes.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum StatusCode {
+            $($variant,)*
+        }
+
+        impl StatusCode {
+            pub const ALL: &'static [StatusCode] = &[$(StatusCode::$variant,)*];
+
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(StatusCode::$variant => $code,)*
+                }
+            }
+
+            pub fn level(&self) -> StatusLevel {
+                match self {
+                    $(StatusCode::$variant => StatusLevel::$level,)*
+                }
+            }
+
+            pub fn from_code(code: &str) -> Option<StatusCode> {
+                match code {
+                    $($code => Some(StatusCode::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    // NetConnection
+    CallBadVersion => "NetConnection.Call.BadVersion", Error;
+    CallFailed => "NetConnection.Call.Failed", Error;
+    CallProhibited => "NetConnection.Call.Prohibited", Error;
+    ConnectAppShutdown => "NetConnection.Connect.AppShutdown", Error;
+    ConnectClosed => "NetConnection.Connect.Closed", Status;
+    ConnectFailed => "NetConnection.Connect.Failed", Error;
+    ConnectIdleTimeout => "NetConnection.Connect.IdleTimeout", Status;
+    ConnectInvalidApp => "NetConnection.Connect.InvalidApp", Error;
+    ConnectNetworkChange => "NetConnection.Connect.NetworkChange", Status;
+    ConnectRejected => "NetConnection.Connect.Rejected", Error;
+    ConnectSuccess => "NetConnection.Connect.Success", Status;
+
+    // NetStream
+    BufferEmpty => "NetStream.Buffer.Empty", Status;
+    BufferFlush => "NetStream.Buffer.Flush", Status;
+    BufferFull => "NetStream.Buffer.Full", Status;
+    StreamConnectClosed => "NetStream.Connect.Closed", Status;
+    StreamConnectFailed => "NetStream.Connect.Failed", Error;
+    StreamConnectRejected => "NetStream.Connect.Rejected", Error;
+    StreamConnectSuccess => "NetStream.Connect.Success", Status;
+    DataStart => "NetStream.Data.Start", Status;
+    DrmUpdateNeeded => "NetStream.DRM.UpdateNeeded", Status;
+    Failed => "NetStream.Failed", Error;
+    MulticastStreamReset => "NetStream.MulticastStream.Reset", Status;
+    PauseNotify => "NetStream.Pause.Notify", Status;
+    PlayComplete => "NetStream.Play.Complete", Status;
+    PlayFailed => "NetStream.Play.Failed", Error;
+    PlayFileStructureInvalid => "NetStream.Play.FileStructureInvalid", Error;
+    PlayInsufficientBW => "NetStream.Play.InsufficientBW", Warning;
+    PlayNoSupportedTrackFound => "NetStream.Play.NoSupportedTrackFound", Error;
+    PlayPublishNotify => "NetStream.Play.PublishNotify", Status;
+    PlayReset => "NetStream.Play.Reset", Status;
+    PlayStart => "NetStream.Play.Start", Status;
+    PlayStop => "NetStream.Play.Stop", Status;
+    PlayStreamNotFound => "NetStream.Play.StreamNotFound", Error;
+    PlaySwitch => "NetStream.Play.Switch", Status;
+    PlayTransition => "NetStream.Play.Transition", Status;
+    PlayUnpublishNotify => "NetStream.Play.UnpublishNotify", Status;
+    PublishBadName => "NetStream.Publish.BadName", Error;
+    PublishIdle => "NetStream.Publish.Idle", Status;
+    PublishStart => "NetStream.Publish.Start", Status;
+    RecordAlreadyExists => "NetStream.Record.AlreadyExists", Status;
+    RecordDiskQuotaExceeded => "NetStream.Record.DiskQuotaExceeded", Error;
+    RecordFailed => "NetStream.Record.Failed", Error;
+    RecordNoAccess => "NetStream.Record.NoAccess", Error;
+    RecordStart => "NetStream.Record.Start", Status;
+    RecordStop => "NetStream.Record.Stop", Status;
+    SeekFailed => "NetStream.Seek.Failed", Error;
+    SeekInvalidTime => "NetStream.Seek.InvalidTime", Error;
+    SeekNotify => "NetStream.Seek.Notify", Status;
+    StepNotify => "NetStream.Step.Notify", Status;
+    UnpauseNotify => "NetStream.Unpause.Notify", Status;
+    UnpublishSuccess => "NetStream.Unpublish.Success", Status;
+    VideoDimensionChange => "NetStream.Video.DimensionChange", Status;
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StatusCode, StatusLevel};
+
+    #[test]
+    fn test_status_codes_round_trip() {
+        for code in StatusCode::ALL {
+            assert_eq!(StatusCode::from_code(code.as_str()), Some(*code));
+        }
+        assert_eq!(StatusCode::from_code("NetStream.Made.Up"), None);
+
+        assert_eq!(StatusCode::PlayStart.level(), StatusLevel::Status);
+        assert_eq!(StatusCode::PlayInsufficientBW.level(), StatusLevel::Warning);
+        assert_eq!(StatusCode::PublishBadName.level(), StatusLevel::Error);
+        assert_eq!(
+            StatusCode::UnpublishSuccess.to_string(),
+            "NetStream.Unpublish.Success"
+        );
+    }
+}
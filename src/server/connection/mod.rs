@@ -1,3 +1,3 @@
 pub mod connection;
-mod define;
+pub(crate) mod define;
 pub mod message;
@@ -2,31 +2,112 @@
 
 // Path: src/server.rs
 
+use crate::config::Config;
+use crate::dash::DashOutput;
+use crate::hls::HlsOutput;
+use crate::http::server::HttpServer;
+use crate::llhls::{LlHlsOutput, LlHlsStreams};
+use crate::record::flv::FlvRecordings;
+use crate::record::mp4::Mp4Recordings;
+use crate::relay::pull::PullRelays;
+use crate::relay::push::PushRelays;
+use crate::relay::Relays;
 use crate::server::connection::connection::Connection;
+use crate::stream::StreamRegistry;
+use crate::thumbnail::Thumbnailer;
+use crate::tls::{self, Transport};
 use log::{error, info};
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 use tokio::task;
+use tokio_rustls::TlsAcceptor;
+
+/// State shared by every connection of a server.
+#[derive(Debug, Clone, Default)]
+pub struct ServerContext {
+    pub config: Arc<Config>,
+    pub streams: StreamRegistry,
+    pub llhls: LlHlsStreams,
+    pub relays: Relays,
+}
+
+impl ServerContext {
+    pub fn new(config: Config) -> ServerContext {
+        ServerContext {
+            config: Arc::new(config),
+            streams: StreamRegistry::new(),
+            llhls: LlHlsStreams::new(),
+            relays: Relays::new(),
+        }
+    }
+}
 
 pub struct Server {
     address: String,
+    context: ServerContext,
 }
 
 impl Server {
-    pub fn new(address: String) -> Server {
-        Server { address }
+    pub fn new(config: Config) -> Server {
+        Server {
+            address: config.address.clone(),
+            context: ServerContext::new(config),
+        }
     }
 
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(&self.address).await?;
         info!("Listening on {}", self.address);
+
+        let config = &self.context.config;
+        if config.thumbnails.enabled {
+            let thumbnailer =
+                Thumbnailer::new(config.thumbnails.clone(), self.context.streams.clone());
+            tokio::spawn(thumbnailer.run());
+        }
+        tokio::spawn(FlvRecordings::new(self.context.clone()).run());
+        if config.record.mp4.enabled {
+            tokio::spawn(Mp4Recordings::new(self.context.clone()).run());
+        }
+        if config.hls.enabled {
+            tokio::spawn(HlsOutput::new(self.context.clone()).run());
+        }
+        if config.dash.enabled {
+            tokio::spawn(DashOutput::new(self.context.clone()).run());
+        }
+        if config.llhls.enabled {
+            tokio::spawn(LlHlsOutput::new(self.context.clone()).run());
+        }
+        if config.apps.iter().any(|app| !app.push.is_empty()) {
+            tokio::spawn(PushRelays::new(self.context.clone()).run());
+        }
+        if config.has_pull_sources() {
+            tokio::spawn(PullRelays::new(self.context.clone()).run());
+        }
+        if config.rtmps.enabled {
+            let acceptor = tls::acceptor(&config.rtmps)?;
+            let listener = TcpListener::bind(&config.rtmps.address).await?;
+            info!("Listening for RTMPS on {}", config.rtmps.address);
+            tokio::spawn(Self::accept_rtmps(listener, acceptor, self.context.clone()));
+        }
+        if config.http.enabled {
+            let http = HttpServer::new(self.context.clone());
+            tokio::spawn(async move {
+                if let Err(err) = http.run().await {
+                    error!("HTTP server stopped: {}", err);
+                }
+            });
+        }
+
         loop {
             let (stream, _) = listener.accept().await?;
+            let context = self.context.clone();
 
             task::spawn_blocking(move || {
                 if let Err(err) = tokio::runtime::Runtime::new()
                     .unwrap()
-                    .block_on(Self::handle_connection(stream))
+                    .block_on(Self::handle_connection(stream, context))
                 {
                     error!("Failed to handle connection: {}", err);
                 } else {
@@ -36,9 +117,128 @@ impl Server {
         }
     }
 
-    async fn handle_connection(stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
-        let mut connection = Connection::new(stream);
+    async fn handle_connection(
+        stream: TcpStream,
+        context: ServerContext,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut connection = Connection::with_context(stream, context);
         connection.handle().await?;
         Ok(())
     }
+
+    // Connections of the RTMPS listener are handled like the plain ones, the TLS handshake
+    // is done on their own runtime too
+    async fn accept_rtmps(listener: TcpListener, acceptor: TlsAcceptor, context: ServerContext) {
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("RTMPS listener stopped: {}", err);
+                    return;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let context = context.clone();
+
+            task::spawn_blocking(move || {
+                let connection = async move {
+                    let stream = acceptor.accept(stream).await?;
+                    Self::handle_transport(stream, remote.to_string(), context).await
+                };
+                if let Err(err) = tokio::runtime::Runtime::new().unwrap().block_on(connection) {
+                    error!("Failed to handle RTMPS connection: {}", err);
+                }
+            });
+        }
+    }
+
+    async fn handle_transport(
+        stream: impl Transport + 'static,
+        remote: String,
+        context: ServerContext,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut connection = Connection::with_transport(stream, remote, context);
+        connection.handle().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Server, ServerContext};
+    use crate::client::RtmpClient;
+    use crate::config::RtmpsConfig;
+    use crate::server::connection::message::message::ConnectObject;
+    use crate::tls::{acceptor, connector_with_roots};
+    use rustls_pki_types::ServerName;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::RootCertStore;
+
+    // Writes a new self-signed certificate for localhost, returns its roots
+    fn write_certificate(config: &RtmpsConfig, modified: SystemTime) -> RootCertStore {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let write = |path: &Path, contents: String| {
+            std::fs::write(path, contents).unwrap();
+            let file = std::fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(modified).unwrap();
+        };
+        write(&config.cert_path, certified.cert.pem());
+        write(&config.key_path, certified.key_pair.serialize_pem());
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        roots
+    }
+
+    #[tokio::test]
+    async fn test_rtmps() {
+        let directory = std::env::temp_dir().join(format!("rtmps-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let config = RtmpsConfig {
+            enabled: true,
+            address: "127.0.0.1:0".to_owned(),
+            cert_path: directory.join("cert.pem"),
+            key_path: directory.join("key.pem"),
+            reload_interval_secs: 0.05,
+        };
+        let roots = write_certificate(&config, SystemTime::now());
+        let listener = TcpListener::bind(&config.address).await.unwrap();
+        let address = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let context = ServerContext::default();
+        let acceptor = acceptor(&config).unwrap();
+        tokio::spawn(Server::accept_rtmps(listener, acceptor, context.clone()));
+
+        // A full RTMP session inside TLS
+        let connect_object = ConnectObject::new("live".to_owned());
+        let mut client = RtmpClient::connect_tls(
+            address.as_str(),
+            &connector_with_roots(roots),
+            connect_object,
+        )
+        .await
+        .unwrap();
+        client.publish("key").await.unwrap();
+        assert!(context.streams.is_publishing("live", "key"));
+
+        // A new certificate is served once the watcher has seen it
+        let later = SystemTime::now() + Duration::from_secs(10);
+        let connector = connector_with_roots(write_certificate(&config, later));
+        let handshake = async {
+            loop {
+                let stream = TcpStream::connect(&address).await.unwrap();
+                let server_name = ServerName::try_from("localhost").unwrap();
+                if connector.connect(server_name, stream).await.is_ok() {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), handshake)
+            .await
+            .expect("The new certificate should be served");
+
+        client.close().await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
 }
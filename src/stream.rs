@@ -1,3 +1,625 @@
 // This file will handle RTMP streams. It should define a Stream struct that represents a single RTMP stream and handles sending and receiving messages.
 
-// Path: src/stream.rs
\ No newline at end of file
+// Path: src/stream.rs
+use crate::media::aac::AudioSpecificConfig;
+use crate::media::avc::{AvcDecoderConfigurationRecord, ChromaFormat, Sps};
+use crate::media::enhanced::FourCc;
+use crate::media::errors::MediaError;
+use crate::media::flv::{AudioHeader, AudioTag, TagType, VideoHeader, VideoTag};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+// Frames a subscriber may fall behind by before it starts missing some
+const FRAME_BUFFER: usize = 1024;
+// Longer GOPs are not cached, players joining then wait for the next keyframe
+const GOP_CACHE_FRAMES: usize = 1024;
+
+/// What is known about a published stream, filled in as sequence headers arrive.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamInfo {
+    pub video: Option<VideoInfo>,
+    pub audio: Option<AudioInfo>,
+    // The last onMetaData, as JSON
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// The video parameters as signalled in the bitstream itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoInfo {
+    // RFC 6381 codec string, e.g. avc1.64001f
+    pub codec: String,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: Option<f64>,
+    pub chroma_format: ChromaFormat,
+    pub bit_depth: u8,
+}
+
+impl From<&Sps> for VideoInfo {
+    fn from(sps: &Sps) -> VideoInfo {
+        VideoInfo {
+            codec: sps.codec_string(),
+            width: sps.width(),
+            height: sps.height(),
+            frame_rate: sps.frame_rate(),
+            chroma_format: sps.chroma_format,
+            bit_depth: sps.bit_depth_luma,
+        }
+    }
+}
+
+/// The audio parameters as signalled in the bitstream itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioInfo {
+    // RFC 6381 codec string, e.g. mp4a.40.2
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub sbr: bool,
+    pub ps: bool,
+}
+
+impl From<&AudioSpecificConfig> for AudioInfo {
+    fn from(config: &AudioSpecificConfig) -> AudioInfo {
+        AudioInfo {
+            codec: config.codec_string(),
+            sample_rate: config.output_sampling_frequency(),
+            channels: config.channels(),
+            sbr: config.sbr,
+            ps: config.ps,
+        }
+    }
+}
+
+/// The most recent H.264 keyframe of a stream, with the sequence header needed to decode it.
+#[derive(Debug, Clone)]
+pub struct Keyframe {
+    // Counts up with every keyframe, so consumers can tell a new one from one they have seen
+    pub sequence: u64,
+    pub config: Arc<AvcDecoderConfigurationRecord>,
+    // Length prefixed NAL units as sent by the publisher
+    pub data: Arc<Vec<u8>>,
+}
+
+/// An audio, video or script message of a published stream as passed on to its subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaFrame {
+    pub tag_type: TagType,
+    // In milliseconds
+    pub timestamp: u32,
+    // The FLV tag body, for script frames the AMF0 encoded onMetaData
+    pub data: Bytes,
+}
+
+impl MediaFrame {
+    pub fn new(tag_type: TagType, timestamp: u32, data: impl Into<Bytes>) -> MediaFrame {
+        MediaFrame {
+            tag_type,
+            timestamp,
+            data: data.into(),
+        }
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        match self.tag_type {
+            TagType::Audio => AudioHeader::parse(&self.data).is_ok_and(|h| h.is_sequence_header()),
+            TagType::Video => VideoHeader::parse(&self.data).is_ok_and(|h| h.is_sequence_header()),
+            TagType::Script => false,
+        }
+    }
+
+    // A video frame a decoder can start at
+    pub fn is_keyframe(&self) -> bool {
+        self.tag_type == TagType::Video
+            && VideoHeader::parse(&self.data)
+                .is_ok_and(|h| h.is_keyframe() && !h.is_sequence_header())
+    }
+}
+
+/// A live feed of a published stream. The headers come first, they are what the stream
+/// sent before the subscriber joined and what a decoder needs to make sense of the frames.
+/// The cached GOP follows, so players can start right away instead of at the next keyframe.
+#[derive(Debug)]
+pub struct Subscription {
+    // onMetaData, then the video and audio sequence headers, whichever were sent
+    pub headers: Vec<MediaFrame>,
+    // The latest keyframe and every frame since, empty if there is none
+    pub gop: Vec<MediaFrame>,
+    pub frames: broadcast::Receiver<MediaFrame>,
+}
+
+/// Drops frames after some were missed, playback resumes at the next keyframe. Metadata and
+/// sequence headers are never dropped, and audio only streams resume right away.
+#[derive(Debug)]
+pub struct KeyframeGate {
+    has_video: bool,
+    skipping: bool,
+}
+
+impl KeyframeGate {
+    pub fn new(headers: &[MediaFrame]) -> KeyframeGate {
+        KeyframeGate {
+            has_video: headers.iter().any(|frame| frame.tag_type == TagType::Video),
+            skipping: false,
+        }
+    }
+
+    // Frames were missed, the ones that follow are useless until the next keyframe
+    pub fn resync(&mut self) {
+        self.skipping = true;
+    }
+
+    pub fn skip(&mut self, frame: &MediaFrame) -> bool {
+        let header = frame.tag_type == TagType::Script || frame.is_sequence_header();
+        if header && frame.tag_type == TagType::Video {
+            self.has_video = true;
+        }
+        if frame.is_keyframe() || !self.has_video {
+            self.skipping = false;
+        }
+        self.skipping && !header
+    }
+}
+
+/// Someone watching a published stream, over any of the playback protocols.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerInfo {
+    pub id: u64,
+    // rtmp, http-flv and so on
+    pub protocol: &'static str,
+    pub remote: String,
+    pub started: SystemTime,
+}
+
+#[derive(Debug)]
+struct PublishedStream {
+    info: StreamInfo,
+    avc_config: Option<Arc<AvcDecoderConfigurationRecord>>,
+    keyframe: Option<Keyframe>,
+    keyframe_count: u64,
+    // Flipped through the API for streams recorded in manual mode
+    recording: Arc<AtomicBool>,
+    frames: broadcast::Sender<MediaFrame>,
+    metadata: Option<MediaFrame>,
+    video_header: Option<MediaFrame>,
+    audio_header: Option<MediaFrame>,
+    // None until the first keyframe, or when the GOP got too long to cache
+    gop: Option<Vec<MediaFrame>>,
+    players: HashMap<u64, PlayerInfo>,
+}
+
+impl Default for PublishedStream {
+    fn default() -> PublishedStream {
+        PublishedStream {
+            info: StreamInfo::default(),
+            avc_config: None,
+            keyframe: None,
+            keyframe_count: 0,
+            recording: Arc::default(),
+            frames: broadcast::channel(FRAME_BUFFER).0,
+            metadata: None,
+            video_header: None,
+            audio_header: None,
+            gop: None,
+            players: HashMap::new(),
+        }
+    }
+}
+
+/// Tracks which streams are being published across all connections.
+#[derive(Debug, Clone)]
+pub struct StreamRegistry {
+    publishers: Arc<Mutex<HashMap<String, PublishedStream>>>,
+    // The path of every stream as it starts being published
+    published: broadcast::Sender<String>,
+    next_player_id: Arc<AtomicU64>,
+}
+
+impl Default for StreamRegistry {
+    fn default() -> StreamRegistry {
+        StreamRegistry {
+            publishers: Arc::default(),
+            published: broadcast::channel(FRAME_BUFFER).0,
+            next_player_id: Arc::default(),
+        }
+    }
+}
+
+impl StreamRegistry {
+    pub fn new() -> StreamRegistry {
+        StreamRegistry::default()
+    }
+
+    // Streams are identified by app and name, any query string (tokens etc.) is ignored.
+    pub fn stream_path(app: &str, stream_name: &str) -> String {
+        let name = stream_name.split('?').next().unwrap_or_default();
+        format!("{}/{}", app, name)
+    }
+
+    /// Marks the stream as published. Returns None if someone is already publishing it,
+    /// otherwise a guard that releases the stream when dropped.
+    pub fn claim_publish(&self, app: &str, stream_name: &str) -> Option<PublishGuard> {
+        let path = StreamRegistry::stream_path(app, stream_name);
+        let mut publishers = self.publishers.lock().unwrap();
+        if publishers.contains_key(&path) {
+            return None;
+        }
+        publishers.insert(path.clone(), PublishedStream::default());
+        // Nobody may be watching, that is fine
+        let _ = self.published.send(path.clone());
+
+        Some(PublishGuard {
+            registry: self.clone(),
+            path,
+        })
+    }
+
+    pub fn is_publishing(&self, app: &str, stream_name: &str) -> bool {
+        let path = StreamRegistry::stream_path(app, stream_name);
+        self.publishers.lock().unwrap().contains_key(&path)
+    }
+
+    pub fn stream_info(&self, app: &str, stream_name: &str) -> Option<StreamInfo> {
+        let path = StreamRegistry::stream_path(app, stream_name);
+        self.publishers
+            .lock()
+            .unwrap()
+            .get(&path)
+            .map(|stream| stream.info.clone())
+    }
+
+    pub fn stream_paths(&self) -> Vec<String> {
+        self.publishers.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn latest_keyframe(&self, path: &str) -> Option<Keyframe> {
+        self.publishers
+            .lock()
+            .unwrap()
+            .get(path)
+            .and_then(|stream| stream.keyframe.clone())
+    }
+
+    /// Paths of streams as they start being published, for outputs that follow every stream.
+    pub fn watch_published(&self) -> broadcast::Receiver<String> {
+        self.published.subscribe()
+    }
+
+    pub fn subscribe(&self, path: &str) -> Option<Subscription> {
+        let publishers = self.publishers.lock().unwrap();
+        let stream = publishers.get(path)?;
+        let headers = [&stream.metadata, &stream.video_header, &stream.audio_header]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        Some(Subscription {
+            headers,
+            gop: stream.gop.clone().unwrap_or_default(),
+            frames: stream.frames.subscribe(),
+        })
+    }
+
+    /// Counts a player of the stream until the guard is dropped, None if it is not published.
+    pub fn add_player(
+        &self,
+        path: &str,
+        protocol: &'static str,
+        remote: impl Into<String>,
+    ) -> Option<PlayerGuard> {
+        let mut publishers = self.publishers.lock().unwrap();
+        let stream = publishers.get_mut(path)?;
+        let id = self.next_player_id.fetch_add(1, Ordering::Relaxed);
+        let player = PlayerInfo {
+            id,
+            protocol,
+            remote: remote.into(),
+            started: SystemTime::now(),
+        };
+        stream.players.insert(id, player);
+        Some(PlayerGuard {
+            registry: self.clone(),
+            path: path.to_owned(),
+            id,
+        })
+    }
+
+    pub fn players(&self, path: &str) -> Vec<PlayerInfo> {
+        let publishers = self.publishers.lock().unwrap();
+        let mut players: Vec<PlayerInfo> = publishers
+            .get(path)
+            .map(|stream| stream.players.values().cloned().collect())
+            .unwrap_or_default();
+        players.sort_by_key(|player| player.id);
+        players
+    }
+
+    pub fn recording_switch(&self, path: &str) -> Option<Arc<AtomicBool>> {
+        self.publishers
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|stream| stream.recording.clone())
+    }
+
+    /// Starts or stops a manual recording, returns false if the stream is not published.
+    pub fn set_recording(&self, path: &str, recording: bool) -> bool {
+        match self.publishers.lock().unwrap().get(path) {
+            Some(stream) => {
+                stream.recording.store(recording, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The parameters a sequence header signalled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SequenceHeader {
+    Video(VideoInfo),
+    Audio(AudioInfo),
+}
+
+#[derive(Debug)]
+pub struct PublishGuard {
+    registry: StreamRegistry,
+    path: String,
+}
+
+impl PublishGuard {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn recording_switch(&self) -> Arc<AtomicBool> {
+        self.registry
+            .recording_switch(&self.path)
+            .unwrap_or_default()
+    }
+
+    /// Passes a frame on to the subscribers, sequence headers and metadata are also kept
+    /// for the ones that join later.
+    pub fn publish_frame(&self, frame: MediaFrame) {
+        self.update(|stream| {
+            match frame.tag_type {
+                TagType::Script => stream.metadata = Some(frame.clone()),
+                TagType::Video if frame.is_sequence_header() => {
+                    // The cached frames belong to the old parameter sets
+                    stream.video_header = Some(frame.clone());
+                    stream.gop = None;
+                }
+                TagType::Audio if frame.is_sequence_header() => {
+                    stream.audio_header = Some(frame.clone())
+                }
+                _ => {
+                    if frame.is_keyframe() {
+                        stream.gop = Some(Vec::new());
+                    }
+                    if let Some(gop) = &mut stream.gop {
+                        gop.push(frame.clone());
+                        if gop.len() > GOP_CACHE_FRAMES {
+                            stream.gop = None;
+                        }
+                    }
+                }
+            }
+            // Without subscribers the frame is simply dropped
+            let _ = stream.frames.send(frame);
+        });
+    }
+
+    /// Keeps what a frame tells about the stream, for the API, the thumbnails and the players
+    /// that join later: the parameters of AVC and AAC sequence headers and the latest keyframe.
+    /// Frames that can not be parsed are published all the same, the error says what was wrong.
+    pub fn update_stream_info(
+        &self,
+        frame: &MediaFrame,
+    ) -> Result<Option<SequenceHeader>, MediaError> {
+        match frame.tag_type {
+            TagType::Video => {
+                let tag = VideoTag::parse(&frame.data)?;
+                if tag.header.fourcc() != Some(FourCc::AVC) {
+                    return Ok(None);
+                }
+                if !tag.header.is_sequence_header() {
+                    if tag.header.is_keyframe() && tag.header.is_coded_frames() {
+                        self.set_keyframe(tag.body.to_vec());
+                    }
+                    return Ok(None);
+                }
+                let record = AvcDecoderConfigurationRecord::parse(tag.body)?;
+                let video = VideoInfo::from(&record.sequence_parameter_set()?);
+                self.set_video_info(video.clone());
+                self.set_avc_config(record);
+                Ok(Some(SequenceHeader::Video(video)))
+            }
+            TagType::Audio => {
+                let tag = AudioTag::parse(&frame.data)?;
+                if !tag.header.is_sequence_header() || tag.header.fourcc() != Some(FourCc::AAC) {
+                    return Ok(None);
+                }
+                let audio = AudioInfo::from(&AudioSpecificConfig::parse(tag.body)?);
+                self.set_audio_info(audio.clone());
+                Ok(Some(SequenceHeader::Audio(audio)))
+            }
+            TagType::Script => Ok(None),
+        }
+    }
+
+    fn update(&self, update: impl FnOnce(&mut PublishedStream)) {
+        if let Some(stream) = self.registry.publishers.lock().unwrap().get_mut(&self.path) {
+            update(stream);
+        }
+    }
+
+    pub fn set_video_info(&self, video: VideoInfo) {
+        self.update(|stream| stream.info.video = Some(video));
+    }
+
+    pub fn set_audio_info(&self, audio: AudioInfo) {
+        self.update(|stream| stream.info.audio = Some(audio));
+    }
+
+    pub fn set_metadata(&self, metadata: serde_json::Value) {
+        self.update(|stream| stream.info.metadata = Some(metadata));
+    }
+
+    pub fn set_avc_config(&self, config: AvcDecoderConfigurationRecord) {
+        self.update(|stream| {
+            // Keyframes coded against the old parameter sets can not be decoded any more
+            stream.avc_config = Some(Arc::new(config));
+            stream.keyframe = None;
+        });
+    }
+
+    // Ignored until the sequence header has been seen
+    pub fn set_keyframe(&self, data: Vec<u8>) {
+        self.update(|stream| {
+            if let Some(config) = &stream.avc_config {
+                stream.keyframe_count += 1;
+                stream.keyframe = Some(Keyframe {
+                    sequence: stream.keyframe_count,
+                    config: config.clone(),
+                    data: Arc::new(data),
+                });
+            }
+        });
+    }
+}
+
+/// Removes the player from the stream when dropped.
+#[derive(Debug)]
+pub struct PlayerGuard {
+    registry: StreamRegistry,
+    path: String,
+    id: u64,
+}
+
+impl PlayerGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for PlayerGuard {
+    fn drop(&mut self) {
+        if let Some(stream) = self.registry.publishers.lock().unwrap().get_mut(&self.path) {
+            stream.players.remove(&self.id);
+        }
+    }
+}
+
+impl Drop for PublishGuard {
+    fn drop(&mut self) {
+        self.registry.publishers.lock().unwrap().remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MediaFrame, SequenceHeader, StreamRegistry};
+    use crate::media::avc;
+    use crate::media::flv::TagType;
+
+    #[test]
+    fn test_claim_publish() {
+        let registry = StreamRegistry::new();
+
+        let guard = registry.claim_publish("live", "key?token=abc").unwrap();
+        assert_eq!(guard.path(), "live/key");
+        assert!(registry.is_publishing("live", "key"));
+        assert!(registry.claim_publish("live", "key").is_none());
+        assert!(registry.claim_publish("other", "key").is_some());
+
+        let recording = guard.recording_switch();
+        assert!(registry.set_recording("live/key", true));
+        assert!(recording.load(std::sync::atomic::Ordering::Relaxed));
+
+        drop(guard);
+        assert!(!registry.is_publishing("live", "key"));
+        assert!(!registry.set_recording("live/key", true));
+        assert!(registry.claim_publish("live", "key").is_some());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let registry = StreamRegistry::new();
+        let mut published = registry.watch_published();
+        let guard = registry.claim_publish("live", "key").unwrap();
+        assert_eq!(published.try_recv().unwrap(), "live/key");
+
+        let sequence_header = MediaFrame::new(TagType::Video, 0, vec![0x17, 0x00, 0, 0, 0, 0x01]);
+        let keyframe = MediaFrame::new(TagType::Video, 40, vec![0x17, 0x01, 0, 0, 0, 0xaa]);
+        assert!(sequence_header.is_sequence_header() && !sequence_header.is_keyframe());
+        assert!(keyframe.is_keyframe());
+
+        let mut early = registry.subscribe("live/key").unwrap();
+        assert!(early.headers.is_empty());
+        guard.publish_frame(sequence_header.clone());
+        guard.publish_frame(keyframe.clone());
+        assert_eq!(early.frames.try_recv().unwrap(), sequence_header);
+        assert_eq!(early.frames.try_recv().unwrap(), keyframe);
+
+        // Late subscribers get the sequence header up front, then the GOP so far
+        let inter = MediaFrame::new(TagType::Video, 80, vec![0x27, 0x01, 0, 0, 0, 0xbb]);
+        guard.publish_frame(inter.clone());
+        let late = registry.subscribe("live/key").unwrap();
+        assert_eq!(late.headers, vec![sequence_header]);
+        assert_eq!(late.gop, vec![keyframe, inter.clone()]);
+
+        let player = registry
+            .add_player("live/key", "http-flv", "127.0.0.1:5000")
+            .unwrap();
+        let players = registry.players("live/key");
+        assert_eq!(players.len(), 1);
+        assert_eq!(
+            (players[0].id, players[0].protocol),
+            (player.id(), "http-flv")
+        );
+        drop(player);
+        assert!(registry.players("live/key").is_empty());
+        assert!(registry.add_player("other/key", "rtmp", "").is_none());
+
+        drop(guard);
+        assert!(registry.subscribe("live/key").is_none());
+        assert_eq!(early.frames.try_recv().unwrap(), inter);
+        assert!(early.frames.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_update_stream_info() {
+        let registry = StreamRegistry::new();
+        let guard = registry.claim_publish("live", "key").unwrap();
+        let keyframe = MediaFrame::new(TagType::Video, 40, vec![0x17, 0x01, 0, 0, 0, 0xaa]);
+        // A keyframe before the sequence header can not be decoded
+        assert_eq!(guard.update_stream_info(&keyframe).unwrap(), None);
+        assert!(registry.latest_keyframe("live/key").is_none());
+
+        let header = MediaFrame::new(TagType::Video, 0, avc::fixtures::sequence_header());
+        match guard.update_stream_info(&header).unwrap() {
+            Some(SequenceHeader::Video(video)) => {
+                assert_eq!((video.width, video.height), (1920, 1080))
+            }
+            other => panic!("Expected video info, got {:?}", other),
+        }
+        guard.update_stream_info(&keyframe).unwrap();
+        assert_eq!(
+            *registry.latest_keyframe("live/key").unwrap().data,
+            vec![0xaa]
+        );
+
+        let audio = MediaFrame::new(TagType::Audio, 0, vec![0xaf, 0x00, 0x12, 0x10]);
+        guard.update_stream_info(&audio).unwrap();
+        let info = registry.stream_info("live", "key").unwrap();
+        assert_eq!(info.video.unwrap().codec, "avc1.42c028");
+        assert_eq!(info.audio.unwrap().sample_rate, 44100);
+        assert!(guard
+            .update_stream_info(&MediaFrame::new(TagType::Video, 0, vec![0x17]))
+            .is_err());
+    }
+}
@@ -0,0 +1,293 @@
+// This file renders preview images of live streams. At a fixed interval the latest H.264
+// keyframe of every published stream is decoded on the CPU with openh264, scaled down and
+// written to disk as a JPEG or PNG for the HTTP API to serve.
+
+// Path: src/thumbnail.rs
+use crate::config::{ImageFormat, ThumbnailConfig};
+use crate::media::avc::AvcDecoderConfigurationRecord;
+use crate::media::nal::{avcc_to_annexb, NalCodec};
+use crate::stream::StreamRegistry;
+use crate::utils::sanitize;
+use log::{info, warn};
+use openh264::decoder::Decoder;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// An 8 bit RGB picture, rows top to bottom without padding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl RgbImage {
+    // Box filtered, every output pixel averages the source pixels it covers
+    pub fn scale(&self, factor: f64) -> RgbImage {
+        let width = ((self.width as f64 * factor).round() as usize).clamp(1, self.width);
+        let height = ((self.height as f64 * factor).round() as usize).clamp(1, self.height);
+        if (width, height) == (self.width, self.height) {
+            return self.clone();
+        }
+
+        let mut data = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            let (top, bottom) = (y * self.height / height, (y + 1) * self.height / height);
+            for x in 0..width {
+                let (left, right) = (x * self.width / width, (x + 1) * self.width / width);
+                let mut sum = [0u32; 3];
+                for row in top..bottom {
+                    let start = (row * self.width + left) * 3;
+                    for pixel in self.data[start..start + (right - left) * 3].chunks_exact(3) {
+                        for (total, &value) in sum.iter_mut().zip(pixel) {
+                            *total += value as u32;
+                        }
+                    }
+                }
+                let count = ((bottom - top) * (right - left)) as u32;
+                data.extend(sum.iter().map(|total| (total / count) as u8));
+            }
+        }
+        RgbImage {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn encode(
+        &self,
+        format: ImageFormat,
+        quality: u8,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut encoded = Vec::new();
+        match format {
+            ImageFormat::Jpeg => {
+                let encoder = jpeg_encoder::Encoder::new(&mut encoded, quality.clamp(1, 100));
+                encoder.encode(
+                    &self.data,
+                    u16::try_from(self.width)?,
+                    u16::try_from(self.height)?,
+                    jpeg_encoder::ColorType::Rgb,
+                )?;
+            }
+            ImageFormat::Png => {
+                let mut encoder =
+                    png::Encoder::new(&mut encoded, self.width as u32, self.height as u32);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                let mut writer = encoder.write_header()?;
+                writer.write_image_data(&self.data)?;
+                writer.finish()?;
+            }
+        }
+        Ok(encoded)
+    }
+}
+
+/// Decodes a single keyframe, given as length prefixed NAL units, to RGB.
+pub fn decode_keyframe(
+    config: &AvcDecoderConfigurationRecord,
+    frame: &[u8],
+) -> Result<RgbImage, Box<dyn std::error::Error + Send + Sync>> {
+    let annexb = avcc_to_annexb(
+        frame,
+        config.nal_length_size,
+        NalCodec::Avc,
+        &config.parameter_sets(),
+    )?;
+    let mut decoder = Decoder::new()?;
+    let yuv = decoder
+        .decode(&annexb)?
+        .ok_or("the decoder did not output a picture for the keyframe")?;
+    let (width, height) = yuv.dimension_rgb();
+    let mut data = vec![0; width * height * 3];
+    yuv.write_rgb8(&mut data);
+    Ok(RgbImage {
+        width,
+        height,
+        data,
+    })
+}
+
+/// Where the thumbnail of a stream path (app/stream) is stored.
+pub fn thumbnail_path(config: &ThumbnailConfig, stream_path: &str) -> PathBuf {
+    let (app, stream) = stream_path.split_once('/').unwrap_or(("", stream_path));
+    config.directory.join(sanitize(app)).join(format!(
+        "{}.{}",
+        sanitize(stream),
+        config.format.extension()
+    ))
+}
+
+/// Periodically renders the thumbnails of all live H.264 streams.
+pub struct Thumbnailer {
+    config: ThumbnailConfig,
+    streams: StreamRegistry,
+    // Stream path to the sequence number of the keyframe last rendered for it
+    rendered: HashMap<String, u64>,
+}
+
+impl Thumbnailer {
+    pub fn new(config: ThumbnailConfig, streams: StreamRegistry) -> Thumbnailer {
+        Thumbnailer {
+            config,
+            streams,
+            rendered: HashMap::new(),
+        }
+    }
+
+    pub async fn run(mut self) {
+        info!(
+            "Rendering thumbnails every {}s to {}",
+            self.config.interval_secs,
+            self.config.directory.display()
+        );
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            self.render_all().await;
+        }
+    }
+
+    pub async fn render_all(&mut self) {
+        let paths = self.streams.stream_paths();
+
+        // Streams that went away take their thumbnail with them
+        let ended: Vec<String> = self
+            .rendered
+            .keys()
+            .filter(|path| !paths.contains(path))
+            .cloned()
+            .collect();
+        for path in ended {
+            self.rendered.remove(&path);
+            let _ = tokio::fs::remove_file(thumbnail_path(&self.config, &path)).await;
+        }
+
+        for path in paths {
+            let keyframe = match self.streams.latest_keyframe(&path) {
+                Some(keyframe) => keyframe,
+                None => continue,
+            };
+            let sequence = keyframe.sequence;
+            if self.rendered.get(&path) == Some(&sequence) {
+                continue;
+            }
+
+            let (scale, format, quality) =
+                (self.config.scale, self.config.format, self.config.quality);
+            let image = tokio::task::spawn_blocking(move || {
+                decode_keyframe(&keyframe.config, &keyframe.data)?
+                    .scale(scale)
+                    .encode(format, quality)
+            })
+            .await;
+            match image {
+                Ok(Ok(image)) => {
+                    let file = thumbnail_path(&self.config, &path);
+                    match write_atomically(&file, &image).await {
+                        Ok(()) => {
+                            self.rendered.insert(path, sequence);
+                        }
+                        Err(err) => warn!("Failed to write {}: {}", file.display(), err),
+                    }
+                }
+                Ok(Err(err)) => warn!("Failed to render a thumbnail of {}: {}", path, err),
+                Err(err) => warn!("Thumbnail task of {} failed: {}", path, err),
+            }
+        }
+    }
+}
+
+// Readers never see a half written image
+async fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let temporary = path.with_extension("tmp");
+    tokio::fs::write(&temporary, data).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::avc::nal_unit_type;
+    use crate::media::nal::{split_annexb, write_avcc};
+    use openh264::encoder::{Encoder, EncoderConfig};
+    use openh264::formats::YUVBuffer;
+
+    #[test]
+    fn test_scale() {
+        // 4x2, left half black, right half white
+        let mut data = Vec::new();
+        for _ in 0..2 {
+            data.extend([0; 6]);
+            data.extend([255; 6]);
+        }
+        let image = RgbImage {
+            width: 4,
+            height: 2,
+            data,
+        };
+        let scaled = image.scale(0.5);
+        assert_eq!((scaled.width, scaled.height), (2, 1));
+        assert_eq!(scaled.data, vec![0, 0, 0, 255, 255, 255]);
+        assert_eq!(image.scale(1.0), image);
+    }
+
+    #[test]
+    fn test_render_keyframe() {
+        let (width, height) = (64, 48);
+        let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i % 251) as u8).collect();
+        let mut encoder =
+            Encoder::with_config(EncoderConfig::new(width as u32, height as u32)).unwrap();
+        let frame = encoder
+            .encode(&YUVBuffer::with_rgb(width, height, &rgb))
+            .unwrap()
+            .to_vec();
+
+        // Split it the way a publisher sends it, parameter sets in the sequence header
+        let units = split_annexb(&frame);
+        let of_type = |nal_type| {
+            units
+                .iter()
+                .filter(|unit| unit[0] & 0x1f == nal_type)
+                .map(|unit| unit.to_vec())
+                .collect::<Vec<_>>()
+        };
+        let config = AvcDecoderConfigurationRecord::from_parameter_sets(
+            of_type(nal_unit_type::SPS),
+            of_type(nal_unit_type::PPS),
+            4,
+        )
+        .unwrap();
+        let coded: Vec<&[u8]> = units
+            .iter()
+            .filter(|unit| unit[0] & 0x1f == nal_unit_type::IDR)
+            .copied()
+            .collect();
+        let keyframe = write_avcc(&coded, 4).unwrap();
+
+        let image = decode_keyframe(&config, &keyframe).unwrap();
+        assert_eq!((image.width, image.height), (64, 48));
+
+        let thumbnail = image.scale(0.5);
+        let jpeg = thumbnail.encode(ImageFormat::Jpeg, 80).unwrap();
+        assert_eq!(&jpeg[..2], &[0xff, 0xd8]);
+        let png = thumbnail.encode(ImageFormat::Png, 80).unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+    }
+
+    #[test]
+    fn test_thumbnail_path() {
+        let config = ThumbnailConfig::default();
+        assert_eq!(
+            thumbnail_path(&config, "live/../secret key"),
+            Path::new("thumbnails/live/_secret_key.jpg")
+        );
+    }
+}
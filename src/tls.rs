@@ -0,0 +1,217 @@
+// This file holds the TLS side of RTMPS. The server terminates TLS with a certificate and key
+// read from PEM files. A watcher task reads them again when they change, so renewed certificates
+// are served without a restart, and handshakes only ever take the loaded one. The client checks
+// servers against the webpki roots.
+
+// Path: src/tls.rs
+use crate::config::RtmpsConfig;
+use log::{info, warn};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// What an RTMP session runs over, a TCP stream with or without TLS.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+#[derive(Debug)]
+struct LoadedCertificate {
+    // Of the certificate and the key file when they were read
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    key: Arc<CertifiedKey>,
+}
+
+/// Serves the certificate in the PEM files, read again by `watch` when one of them changes.
+#[derive(Debug)]
+pub struct ReloadingCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    loaded: RwLock<LoadedCertificate>,
+}
+
+impl ReloadingCertificate {
+    pub fn load(
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> Result<ReloadingCertificate, Box<dyn std::error::Error>> {
+        Ok(ReloadingCertificate {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            loaded: RwLock::new(load_certificate(cert_path, key_path)?),
+        })
+    }
+
+    /// Checks the files every interval and reloads them once they changed. Stops when the
+    /// certificate is not used anywhere else anymore.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if Arc::strong_count(&self) == 1 {
+                return;
+            }
+            // The file system is not touched on the runtime threads
+            let certificate = self.clone();
+            let _ = tokio::task::spawn_blocking(move || certificate.reload_if_changed()).await;
+        }
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = modified(&self.cert_path, &self.key_path);
+        if modified == self.loaded.read().unwrap().modified {
+            return;
+        }
+        match load_certificate(&self.cert_path, &self.key_path) {
+            Ok(reloaded) => {
+                info!("Reloaded the certificate {}", self.cert_path.display());
+                *self.loaded.write().unwrap() = reloaded;
+            }
+            // Likely halfway through being replaced, the old one is served until then
+            Err(err) => warn!(
+                "Failed to reload the certificate {}: {}",
+                self.cert_path.display(),
+                err
+            ),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.loaded.read().unwrap().key.clone())
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(cert_path), modified(key_path))
+}
+
+fn load_certificate(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<LoadedCertificate, Box<dyn std::error::Error>> {
+    let modified = modified(cert_path, key_path);
+    let chain = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    if chain.is_empty() {
+        return Err(format!("No certificate in {}", cert_path.display()).into());
+    }
+    let key = ring::sign::any_supported_type(&PrivateKeyDer::from_pem_file(key_path)?)?;
+    Ok(LoadedCertificate {
+        modified,
+        key: Arc::new(CertifiedKey::new(chain, key)),
+    })
+}
+
+/// Terminates TLS for the RTMPS listener, fails if the certificate can not be read. Changes
+/// to the certificate are picked up by a watcher task on the current runtime.
+pub fn acceptor(config: &RtmpsConfig) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+    let certificate = Arc::new(ReloadingCertificate::load(
+        &config.cert_path,
+        &config.key_path,
+    )?);
+    let interval = Duration::from_secs_f64(config.reload_interval_secs.max(0.01));
+    tokio::spawn(certificate.clone().watch(interval));
+    let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(certificate);
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Checks servers against the webpki roots.
+pub fn connector() -> TlsConnector {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    connector_with_roots(roots)
+}
+
+/// Checks servers against the given roots only, for private CAs and self-signed certificates.
+pub fn connector_with_roots(roots: RootCertStore) -> TlsConnector {
+    let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(client_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a new self-signed pair and dates both files to `modified`
+    fn write_pair(cert_path: &Path, key_path: &Path, modified: SystemTime) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        write(cert_path, &certified.cert.pem(), modified);
+        write(key_path, &certified.key_pair.serialize_pem(), modified);
+    }
+
+    fn write(path: &Path, contents: &str, modified: SystemTime) {
+        std::fs::write(path, contents).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    fn loaded_key(certificate: &ReloadingCertificate) -> Arc<CertifiedKey> {
+        certificate.loaded.read().unwrap().key.clone()
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let directory = std::env::temp_dir().join(format!("tls-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let cert_path = directory.join("cert.pem");
+        let key_path = directory.join("key.pem");
+        let start = SystemTime::now() - Duration::from_secs(60);
+        write_pair(&cert_path, &key_path, start);
+        let certificate = ReloadingCertificate::load(&cert_path, &key_path).unwrap();
+        let first = loaded_key(&certificate);
+
+        // A new pair with the old modification times is not looked at
+        write_pair(&cert_path, &key_path, start);
+        certificate.reload_if_changed();
+        assert!(Arc::ptr_eq(&first, &loaded_key(&certificate)));
+
+        // A valid new pair is swapped in
+        let renewed = start + Duration::from_secs(10);
+        write_pair(&cert_path, &key_path, renewed);
+        certificate.reload_if_changed();
+        let second = loaded_key(&certificate);
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_ne!(first.cert, second.cert);
+        assert_eq!(
+            certificate.loaded.read().unwrap().modified,
+            (Some(renewed), Some(renewed))
+        );
+
+        // A half written certificate keeps the loaded one, and is tried again on the next check
+        let pem = std::fs::read_to_string(&cert_path).unwrap();
+        let halfway = start + Duration::from_secs(20);
+        write(&cert_path, &pem[..pem.len() / 2], halfway);
+        certificate.reload_if_changed();
+        assert!(Arc::ptr_eq(&second, &loaded_key(&certificate)));
+
+        // So does a key that is not a key
+        write_pair(&cert_path, &key_path, start + Duration::from_secs(30));
+        write(&key_path, "not a key", start + Duration::from_secs(30));
+        certificate.reload_if_changed();
+        assert!(Arc::ptr_eq(&second, &loaded_key(&certificate)));
+
+        // Once both files are complete the new pair is served
+        write_pair(&cert_path, &key_path, start + Duration::from_secs(40));
+        certificate.reload_if_changed();
+        assert!(!Arc::ptr_eq(&second, &loaded_key(&certificate)));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
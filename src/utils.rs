@@ -1,3 +1,88 @@
 // This file will contain utility functions that are used throughout the project.
 
-// Path: src/utils.rs
\ No newline at end of file
+// Path: src/utils.rs
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Stream names come from clients, keep them from escaping the directories files are written to
+pub fn sanitize(component: &str) -> String {
+    let name: String = component
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    name.trim_start_matches('.').to_owned()
+}
+
+/// The current UTC time as YYYYMMDD-HHMMSS, safe to use in file names.
+pub fn utc_timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    format_utc_timestamp(seconds)
+}
+
+pub fn format_utc_timestamp(unix_seconds: u64) -> String {
+    let (year, month, day) = civil_date(unix_seconds / 86400);
+    let time = unix_seconds % 86400;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// ISO 8601 UTC date and time with milliseconds, as XML schemas like the DASH MPD want it.
+pub fn format_iso8601(unix_millis: u64) -> String {
+    let unix_seconds = unix_millis / 1000;
+    let (year, month, day) = civil_date(unix_seconds / 86400);
+    let time = unix_seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        unix_millis % 1000
+    )
+}
+
+// Days since the epoch to a civil date, Howard Hinnant's algorithm
+fn civil_date(days: u64) -> (i64, i64, i64) {
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_iso8601, format_utc_timestamp, sanitize};
+
+    #[test]
+    fn test_utils() {
+        assert_eq!(format_utc_timestamp(0), "19700101-000000");
+        assert_eq!(format_utc_timestamp(951782400 + 3661), "20000229-010101");
+        assert_eq!(format_utc_timestamp(1_700_000_000), "20231114-221320");
+        assert_eq!(
+            format_iso8601(1_700_000_000_042),
+            "2023-11-14T22:13:20.042Z"
+        );
+        assert_eq!(sanitize("../secret key"), "_secret_key");
+    }
+}
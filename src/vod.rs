@@ -0,0 +1,592 @@
+// This file plays FLV files, like the recordings, to RTMP players. A file is indexed when it
+// is opened: the keyframes listed in its onMetaData if it has them, otherwise the keyframes
+// found by scanning the tag headers. Tags are handed out in real time by their timestamps,
+// a little ahead of the clock, and playback can be paused and moved to the keyframe at or
+// before any time.
+
+// Path: src/vod.rs
+use crate::config::VodConfig;
+use crate::media::flv::{TagType, FLV_TAG_HEADER_SIZE};
+use crate::server::connection::message::amf0::amf0_borrowed::{
+    Amf0BorrowedReader, Amf0Object, Amf0Value,
+};
+use crate::stream::MediaFrame;
+use crate::utils::sanitize;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Tags go out this far ahead of the clock, so players can keep a small buffer
+const LEAD: Duration = Duration::from_millis(500);
+// Enough of a video tag body to tell keyframes and sequence headers apart
+const HEADER_PREFIX: usize = 16;
+// Files without video are indexed at audio frames at least this far apart
+const AUDIO_INDEX_INTERVAL: u32 = 1000;
+
+/// The file a play request in an app is for, `flv:name` and `name.flv` both read name.flv.
+pub fn file_path(config: &VodConfig, app: &str, stream_name: &str) -> Option<PathBuf> {
+    let name = stream_name.split('?').next().unwrap_or_default();
+    let name = name.strip_prefix("flv:").unwrap_or(name);
+    let name = sanitize(name.strip_suffix(".flv").unwrap_or(name));
+    if name.is_empty() {
+        return None;
+    }
+    Some(
+        config
+            .directory
+            .join(sanitize(app))
+            .join(format!("{}.flv", name)),
+    )
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+// Where a decoder can start, by timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IndexEntry {
+    timestamp: u32,
+    position: u64,
+}
+
+// The 11 byte header in front of every tag
+#[derive(Debug, Clone, Copy)]
+struct TagHeader {
+    tag_type: Option<TagType>,
+    size: usize,
+    timestamp: u32,
+}
+
+/// An FLV file opened for playback, with the index of its keyframes.
+pub struct FlvFile {
+    reader: BufReader<File>,
+    // Where the first tag starts
+    data_offset: u64,
+    // onMetaData and the sequence headers before the first coded frame
+    headers: Vec<MediaFrame>,
+    index: Vec<IndexEntry>,
+    duration_ms: u32,
+}
+
+impl FlvFile {
+    pub fn open(path: &Path) -> std::io::Result<FlvFile> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 9];
+        reader.read_exact(&mut header)?;
+        if &header[..3] != b"FLV" {
+            return Err(invalid_data("not an FLV file"));
+        }
+        // PreviousTagSize0 follows the header
+        let data_offset =
+            u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as u64 + 4;
+
+        let mut file = FlvFile {
+            reader,
+            data_offset,
+            headers: Vec::new(),
+            index: Vec::new(),
+            duration_ms: 0,
+        };
+        file.scan()?;
+        file.rewind()?;
+        Ok(file)
+    }
+
+    pub fn duration_ms(&self) -> u32 {
+        self.duration_ms
+    }
+
+    pub fn headers(&self) -> &[MediaFrame] {
+        &self.headers
+    }
+
+    // Goes over the tag headers once, only reading the bodies it has to look into. A file
+    // with a keyframe index and a duration in its metadata is only read up to the first frame.
+    fn scan(&mut self) -> std::io::Result<()> {
+        self.rewind()?;
+        let mut video_index = Vec::new();
+        let mut audio_index: Vec<IndexEntry> = Vec::new();
+        let mut described = false;
+        let mut metadata_read = false;
+        let mut coded = false;
+        loop {
+            let position = self.reader.stream_position()?;
+            let Some(header) = self.read_header()? else {
+                break;
+            };
+            let prefix = match header.tag_type {
+                Some(TagType::Script) if !metadata_read => header.size,
+                Some(TagType::Audio | TagType::Video) if !coded => header.size,
+                Some(TagType::Video) => header.size.min(HEADER_PREFIX),
+                _ => 0,
+            };
+            let mut data = vec![0u8; prefix];
+            if self.reader.read_exact(&mut data).is_err() {
+                break;
+            }
+            self.reader
+                .seek_relative((header.size - prefix) as i64 + 4)?;
+            self.duration_ms = self.duration_ms.max(header.timestamp);
+
+            let Some(tag_type) = header.tag_type else {
+                continue;
+            };
+            let frame = MediaFrame::new(tag_type, header.timestamp, data);
+            match tag_type {
+                TagType::Script if !metadata_read => {
+                    if let Some(index) = self.read_metadata(&frame) {
+                        metadata_read = true;
+                        described = !index.is_empty() && self.duration_ms > 0;
+                        video_index = index;
+                        self.headers.insert(0, frame);
+                    }
+                }
+                TagType::Script => {}
+                _ if !coded && frame.is_sequence_header() => self.headers.push(frame),
+                TagType::Video => {
+                    coded = true;
+                    if frame.is_keyframe() && !described {
+                        video_index.push(IndexEntry {
+                            timestamp: header.timestamp,
+                            position,
+                        });
+                    }
+                }
+                TagType::Audio => {
+                    coded = true;
+                    let due = match audio_index.last() {
+                        Some(last) => header.timestamp >= last.timestamp + AUDIO_INDEX_INTERVAL,
+                        None => true,
+                    };
+                    if due {
+                        audio_index.push(IndexEntry {
+                            timestamp: header.timestamp,
+                            position,
+                        });
+                    }
+                }
+            }
+            if described && coded {
+                break;
+            }
+        }
+        self.index = if video_index.is_empty() {
+            audio_index
+        } else {
+            video_index
+        };
+        Ok(())
+    }
+
+    // The keyframes of onMetaData, with the duration it claims when the scan found less
+    fn read_metadata(&mut self, frame: &MediaFrame) -> Option<Vec<IndexEntry>> {
+        // The keyframe index of a long recording is big, it is read where it is
+        let mut reader = Amf0BorrowedReader::new(&frame.data);
+        if reader.read_any().ok()?.as_str() != Some("onMetaData") {
+            return None;
+        }
+        let properties = reader.read_any().ok()?;
+        let Some(properties) = properties.as_object() else {
+            return Some(Vec::new());
+        };
+        if let Ok(Some(Amf0Value::Number(duration))) = properties.get("duration") {
+            self.duration_ms = self.duration_ms.max((duration * 1000.0) as u32);
+        }
+        let numbers = |keyframes: &Amf0Object, key: &str| -> Vec<f64> {
+            match keyframes.get(key) {
+                Ok(Some(Amf0Value::StrictArray(values))) => values
+                    .iter()
+                    .filter_map(|value| value.ok()?.as_number())
+                    .collect(),
+                _ => Vec::new(),
+            }
+        };
+        let Ok(Some(keyframes)) = properties.get("keyframes") else {
+            return Some(Vec::new());
+        };
+        let Some(keyframes) = keyframes.as_object() else {
+            return Some(Vec::new());
+        };
+        let times = numbers(keyframes, "times");
+        let positions = numbers(keyframes, "filepositions");
+        Some(
+            times
+                .iter()
+                .zip(&positions)
+                .map(|(time, position)| IndexEntry {
+                    timestamp: (time * 1000.0) as u32,
+                    position: *position as u64,
+                })
+                .collect(),
+        )
+    }
+
+    fn read_header(&mut self) -> std::io::Result<Option<TagHeader>> {
+        let mut header = [0u8; FLV_TAG_HEADER_SIZE];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        // The top bits flag filtered (encrypted) tags, which are not played
+        let tag_type = match header[0] {
+            8 => Some(TagType::Audio),
+            9 => Some(TagType::Video),
+            18 => Some(TagType::Script),
+            _ => None,
+        };
+        Ok(Some(TagHeader {
+            tag_type,
+            size: u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize,
+            // The upper 8 bits come after the lower 24
+            timestamp: u32::from_be_bytes([header[7], header[4], header[5], header[6]]),
+        }))
+    }
+
+    /// The next audio, video or script tag, None at the end of the file. A tag cut short
+    /// by the end of a file that is still being written is left for later.
+    pub fn read_tag(&mut self) -> std::io::Result<Option<MediaFrame>> {
+        loop {
+            let position = self.reader.stream_position()?;
+            let Some(header) = self.read_header()? else {
+                return Ok(None);
+            };
+            let mut data = vec![0u8; header.size + 4];
+            if let Err(err) = self.reader.read_exact(&mut data) {
+                self.reader.seek(SeekFrom::Start(position))?;
+                return match err.kind() {
+                    ErrorKind::UnexpectedEof => Ok(None),
+                    _ => Err(err),
+                };
+            }
+            data.truncate(header.size);
+            if let Some(tag_type) = header.tag_type {
+                return Ok(Some(MediaFrame::new(tag_type, header.timestamp, data)));
+            }
+        }
+    }
+
+    /// Moves to the keyframe at or before the time and returns its timestamp.
+    pub fn seek(&mut self, milliseconds: u32) -> std::io::Result<u32> {
+        let entry = self
+            .index
+            .iter()
+            .take_while(|entry| entry.timestamp <= milliseconds)
+            .last()
+            .copied();
+        match entry {
+            Some(entry) => {
+                self.reader.seek(SeekFrom::Start(entry.position))?;
+                Ok(entry.timestamp)
+            }
+            None => {
+                self.rewind()?;
+                Ok(0)
+            }
+        }
+    }
+
+    fn rewind(&mut self) -> std::io::Result<()> {
+        self.reader.seek(SeekFrom::Start(self.data_offset))?;
+        Ok(())
+    }
+}
+
+/// What a player has to do next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Playback {
+    // The next tag is due then
+    Due(Instant),
+    // The file or the requested duration is over, reported once
+    Ended,
+    // Paused, or ended and reported
+    Idle,
+}
+
+/// Paces the tags of a file for one player.
+pub struct VodPlayer {
+    file: FlvFile,
+    // The stream time the clock started at, and when that was
+    clock: (u32, Instant),
+    paused: bool,
+    // Read but not due yet
+    pending: Option<MediaFrame>,
+    // Timestamp of the last tag handed out
+    position: u32,
+    // Where a play request with a duration stops
+    end: Option<u32>,
+    at_end: bool,
+    reported: bool,
+}
+
+impl VodPlayer {
+    pub fn open(path: &Path, start: u32, duration: Option<u32>) -> std::io::Result<VodPlayer> {
+        let mut file = FlvFile::open(path)?;
+        let position = file.seek(start)?;
+        Ok(VodPlayer {
+            file,
+            clock: (position, Instant::now()),
+            paused: false,
+            pending: None,
+            position,
+            end: duration.map(|duration| start.saturating_add(duration)),
+            at_end: false,
+            reported: false,
+        })
+    }
+
+    pub fn duration_ms(&self) -> u32 {
+        self.file.duration_ms()
+    }
+
+    /// What a decoder needs before the first frame.
+    pub fn headers(&self) -> &[MediaFrame] {
+        self.file.headers()
+    }
+
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn poll(&mut self) -> std::io::Result<Playback> {
+        if self.paused || self.reported {
+            return Ok(Playback::Idle);
+        }
+        self.fill()?;
+        match &self.pending {
+            Some(frame) => Ok(Playback::Due(self.due(frame.timestamp))),
+            None => {
+                self.reported = true;
+                Ok(Playback::Ended)
+            }
+        }
+    }
+
+    /// The tags that are due by now.
+    pub fn take_due(&mut self, now: Instant) -> std::io::Result<Vec<MediaFrame>> {
+        let mut frames = Vec::new();
+        if self.paused {
+            return Ok(frames);
+        }
+        loop {
+            self.fill()?;
+            match self.pending.take() {
+                Some(frame) if self.due(frame.timestamp) <= now => {
+                    self.position = frame.timestamp;
+                    frames.push(frame);
+                }
+                pending => {
+                    self.pending = pending;
+                    return Ok(frames);
+                }
+            }
+        }
+    }
+
+    // Resuming restarts the clock where playback stopped
+    pub fn pause(&mut self, paused: bool) {
+        if !paused && self.paused {
+            let position = self
+                .pending
+                .as_ref()
+                .map_or(self.position, |frame| frame.timestamp);
+            self.clock = (position, Instant::now());
+        }
+        self.paused = paused;
+    }
+
+    /// Moves to the keyframe at or before the time and returns its timestamp.
+    pub fn seek(&mut self, milliseconds: u32) -> std::io::Result<u32> {
+        let position = self.file.seek(milliseconds)?;
+        self.clock = (position, Instant::now());
+        self.pending = None;
+        self.position = position;
+        self.at_end = false;
+        self.reported = false;
+        Ok(position)
+    }
+
+    fn fill(&mut self) -> std::io::Result<()> {
+        if self.pending.is_some() || self.at_end {
+            return Ok(());
+        }
+        self.pending = self.file.read_tag()?;
+        let past_end = match (&self.pending, self.end) {
+            (Some(frame), Some(end)) => frame.timestamp > end,
+            (pending, _) => pending.is_none(),
+        };
+        if past_end {
+            self.pending = None;
+            self.at_end = true;
+        }
+        Ok(())
+    }
+
+    fn due(&self, timestamp: u32) -> Instant {
+        let (start, started) = self.clock;
+        let offset = Duration::from_millis(timestamp.saturating_sub(start) as u64);
+        (started + offset).checked_sub(LEAD).unwrap_or(started)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_path, FlvFile, Playback, VodPlayer};
+    use crate::config::VodConfig;
+    use crate::media::flv::{flv_header, FlvTag, TagType};
+    use crate::server::connection::message::amf0::amf0_writer::Amf0Writer;
+    use crate::server::connection::message::amf0::define::Amf0ValueType;
+    use bytesio::bytes_writer::BytesWriter;
+    use indexmap::IndexMap;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    const AVC_SEQUENCE_HEADER: [u8; 6] = [0x17, 0x00, 0x00, 0x00, 0x00, 0x01];
+    const AAC_SEQUENCE_HEADER: [u8; 4] = [0xaf, 0x00, 0x12, 0x10];
+
+    fn on_metadata(properties: IndexMap<String, Amf0ValueType>) -> Vec<u8> {
+        let mut writer = Amf0Writer::new(BytesWriter::new());
+        writer.write_string(&"onMetaData".to_owned()).unwrap();
+        writer.write_ecma_array(&properties).unwrap();
+        writer.extract_current_bytes().to_vec()
+    }
+
+    // 4 s at 10 fps with a keyframe every second, the metadata may come with a keyframe index
+    fn write_file(path: &Path, indexed: bool) {
+        // The script tag body is padded to this size, so the positions in it are known up front
+        const SCRIPT_SIZE: usize = 512;
+        let mut tags = vec![
+            FlvTag::new(TagType::Video, 0, AVC_SEQUENCE_HEADER.to_vec()),
+            FlvTag::new(TagType::Audio, 0, AAC_SEQUENCE_HEADER.to_vec()),
+        ];
+        for index in 0..40 {
+            let frame_type = if index % 10 == 0 { 0x17 } else { 0x27 };
+            let timestamp = index * 100;
+            tags.push(FlvTag::new(
+                TagType::Video,
+                timestamp,
+                vec![frame_type, 0x01, 0, 0, 0, index as u8],
+            ));
+            tags.push(FlvTag::new(TagType::Audio, timestamp, vec![0xaf, 0x01]));
+        }
+
+        let mut properties = IndexMap::new();
+        properties.insert("duration".to_owned(), Amf0ValueType::Number(3.9));
+        if indexed {
+            let mut times = Vec::new();
+            let mut positions = Vec::new();
+            let mut position = 13 + 11 + SCRIPT_SIZE + 4;
+            for tag in &tags {
+                if tag.tag_type == TagType::Video && tag.data[..2] == [0x17, 0x01] {
+                    times.push(Amf0ValueType::Number(tag.timestamp as f64 / 1000.0));
+                    positions.push(Amf0ValueType::Number(position as f64));
+                }
+                position += tag.serialize().len();
+            }
+            let mut keyframes = IndexMap::new();
+            keyframes.insert("times".to_owned(), Amf0ValueType::StrictArray(times));
+            keyframes.insert(
+                "filepositions".to_owned(),
+                Amf0ValueType::StrictArray(positions),
+            );
+            properties.insert("keyframes".to_owned(), Amf0ValueType::Object(keyframes));
+        }
+        let mut script = on_metadata(properties);
+        script.resize(SCRIPT_SIZE, 0);
+
+        let mut file = flv_header(true, true).to_vec();
+        file.extend(FlvTag::new(TagType::Script, 0, script).serialize());
+        for tag in tags {
+            file.extend(tag.serialize());
+        }
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn test_file_path() {
+        let config = VodConfig {
+            enabled: true,
+            directory: "recordings".into(),
+        };
+        let expected = Some(Path::new("recordings/live/key-0.flv").to_path_buf());
+        assert_eq!(file_path(&config, "live", "key-0"), expected);
+        assert_eq!(
+            file_path(&config, "live", "flv:key-0.flv?token=1"),
+            expected
+        );
+        assert_eq!(
+            file_path(&config, "live", "../key"),
+            Some(Path::new("recordings/live/_key.flv").to_path_buf())
+        );
+        assert_eq!(file_path(&config, "live", ""), None);
+    }
+
+    #[test]
+    fn test_index_and_seek() {
+        let directory = std::env::temp_dir().join(format!("vod-index-{}", std::process::id()));
+        for indexed in [false, true] {
+            let path = directory.join(format!("{}.flv", indexed));
+            write_file(&path, indexed);
+            let mut file = FlvFile::open(&path).unwrap();
+            assert_eq!(file.duration_ms(), 3900);
+            let types: Vec<TagType> = file.headers().iter().map(|h| h.tag_type).collect();
+            assert_eq!(types, vec![TagType::Script, TagType::Video, TagType::Audio]);
+
+            // The keyframe before the time, whichever way the index was built
+            assert_eq!(file.seek(2500).unwrap(), 2000);
+            let frame = file.read_tag().unwrap().unwrap();
+            assert_eq!(
+                (frame.timestamp, frame.data[0], frame.data[5]),
+                (2000, 0x17, 20)
+            );
+            assert_eq!(file.seek(50).unwrap(), 0);
+            assert_eq!(file.read_tag().unwrap().unwrap().data[0], 0x17);
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_pacing() {
+        let directory = std::env::temp_dir().join(format!("vod-pacing-{}", std::process::id()));
+        let path = directory.join("key.flv");
+        write_file(&path, false);
+
+        // From the keyframe before 3.2 s, half a second ahead of the clock
+        let mut player = VodPlayer::open(&path, 3200, None).unwrap();
+        let now = Instant::now();
+        let frames = player.take_due(now).unwrap();
+        assert_eq!(frames.first().unwrap().timestamp, 3000);
+        assert_eq!(player.position(), 3500);
+        let Playback::Due(due) = player.poll().unwrap() else {
+            panic!("frames are left");
+        };
+        assert!(due > now);
+
+        player.pause(true);
+        assert_eq!(player.poll().unwrap(), Playback::Idle);
+        let later = now + Duration::from_secs(10);
+        assert!(player.take_due(later).unwrap().is_empty());
+        player.pause(false);
+        assert_eq!(player.take_due(later).unwrap().len(), 8);
+        assert_eq!(player.poll().unwrap(), Playback::Ended);
+        assert_eq!(player.poll().unwrap(), Playback::Idle);
+
+        // Seeking back starts over, even after the end
+        assert_eq!(player.seek(1999).unwrap(), 1000);
+        assert!(matches!(player.poll().unwrap(), Playback::Due(_)));
+
+        // A play duration ends early
+        let mut player = VodPlayer::open(&path, 0, Some(300)).unwrap();
+        let frames = player.take_due(later).unwrap();
+        assert_eq!(frames.last().unwrap().timestamp, 300);
+        assert_eq!(player.poll().unwrap(), Playback::Ended);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}